@@ -0,0 +1,124 @@
+//! End-to-end test harness: boots the real server and drives it with the native client
+//!
+//! The tests live in `tests/`, each one against its own server (and simulation)
+
+use std::{future::Future, sync::Arc, time::Duration};
+
+use protocol::{ServerToClientMessage, Subprotocol};
+use tokio::net::TcpListener;
+use ws_client::{Client, ClientError, StateUpdate};
+use ws_server::{serve_metrics, serve_with, Acceptor, PlainTcp, ServerState};
+
+/// Upper bound of any wait in the tests, so that a broken server fails instead of hanging
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Delay between two `State` requests while polling
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A server listening on an ephemeral port of the loopback interface
+pub struct TestServer {
+    url: String,
+    state: Arc<ServerState>,
+}
+
+impl TestServer {
+    /// Binds an ephemeral port and serves it from a task of the current runtime
+    pub async fn start() -> Self {
+        TestServer::start_with(ServerState::new()).await
+    }
+
+    /// Same as `start` with a configured server (e.g. with a zstd dictionary)
+    pub async fn start_with(state: ServerState) -> Self {
+        TestServer::start_with_acceptor(state, PlainTcp).await
+    }
+
+    /// Same as `start_with` with the connections going through the acceptor
+    pub async fn start_with_acceptor(state: ServerState, acceptor: impl Acceptor) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind an ephemeral port");
+        let address = listener
+            .local_addr()
+            .expect("Bound listener has an address");
+        let state = Arc::new(state);
+        tokio::spawn(serve_with(listener, Arc::clone(&state), acceptor));
+        Self {
+            url: format!("ws://{address}"),
+            state,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Serves the metrics of the server on another ephemeral port, returns its address
+    pub async fn serve_metrics(&self) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind an ephemeral port");
+        let address = listener
+            .local_addr()
+            .expect("Bound listener has an address");
+        tokio::spawn(serve_metrics(listener, Arc::clone(&self.state)));
+        address.to_string()
+    }
+
+    /// To check what the server keeps about its clients
+    pub fn state(&self) -> &ServerState {
+        &self.state
+    }
+
+    pub async fn connect(&self) -> Client {
+        within_timeout(Client::connect(&self.url))
+            .await
+            .expect("Failed to connect to the test server")
+    }
+
+    pub async fn connect_with_subprotocol(&self, subprotocol: Subprotocol) -> Client {
+        within_timeout(Client::connect_with_subprotocol(&self.url, subprotocol))
+            .await
+            .expect("Failed to connect to the test server")
+    }
+}
+
+/// Requests the state until `condition` holds and returns that state
+/// Panics after `TIMEOUT` (the simulation keeps stepping in the background meanwhile)
+pub async fn wait_for_state(
+    client: &mut Client,
+    mut condition: impl FnMut(&StateUpdate) -> bool,
+) -> StateUpdate {
+    within_timeout(async {
+        loop {
+            let update = request_state(client).await?;
+            if condition(&update) {
+                return Ok::<_, ClientError>(update);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    })
+    .await
+    .expect("Failed to request the state")
+}
+
+/// Sends a `State` request and waits for its reply
+pub async fn request_state(client: &mut Client) -> Result<StateUpdate, ClientError> {
+    client.request_state().await?;
+    within_timeout(client.next_state_update())
+        .await
+        .expect("The server closed the connection")
+}
+
+/// Waits for the next message of the server (e.g. a state pushed to a subscriber)
+pub async fn next_message(client: &mut Client) -> ServerToClientMessage {
+    within_timeout(client.next_message())
+        .await
+        .expect("The server closed the connection")
+        .expect("Failed to read the message")
+}
+
+async fn within_timeout<T>(future: impl Future<Output = T>) -> T {
+    tokio::time::timeout(TIMEOUT, future)
+        .await
+        .expect("Timed out waiting for the server")
+}
@@ -0,0 +1,1198 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use e2e_tests::{next_message, request_state, wait_for_state, TestServer, TIMEOUT};
+use futures_util::{SinkExt, StreamExt};
+use nbody::{
+    constraints::Constraint,
+    physics::{Body, BodyId, ExternalForce},
+    quadtree::SquareBox,
+    scenarios::{Scenario, ScenarioKind},
+    simulation::{BoundaryCondition, PhyiscsParameters, SolverParameters},
+};
+use protocol::{
+    decode, dictionary::Dictionary, encode, ClientToServerMessage, Role, RoomId,
+    ServerToClientMessage, Subprotocol, MAX_TICKS_PER_BUNDLE,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+use tokio_tungstenite::{client_async, tungstenite::Message};
+use ws_client::{Client, ClientError};
+use ws_server::{lock, Acceptor, ConfigError, ServerArgs, ServerConfig, ServerState, Tls};
+
+/// Bodies at rest, far enough apart not to collide for a while
+fn bodies_at_rest(count: usize) -> Vec<Body> {
+    (0..count)
+        .map(|i| Body::default().with_position([100.0 * i as f64, 0.0]))
+        .collect()
+}
+
+/// A dictionary trained on the states of a few scenarios
+fn dictionary() -> Dictionary {
+    let states: Vec<_> = (0..100)
+        .map(|seed| ServerToClientMessage::StateUpdate {
+            bodies: Scenario {
+                count: 20,
+                seed,
+                ..Scenario::default()
+            }
+            .generate(1.0),
+            physical_time: seed as f64,
+            kinetic_energy: 0.0,
+            potential_energy: 0.0,
+            linear_momentum: [0.0, 0.0],
+            angular_momentum: 0.0,
+        })
+        .collect();
+    Dictionary::train(&states, 4 * 1024).unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn add_bodies_test() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+    client.subscribe().await.unwrap();
+
+    let initial = request_state(&mut client).await.unwrap();
+    assert!(initial.bodies.is_empty());
+
+    client.add_bodies(bodies_at_rest(3)).await.unwrap();
+    let first = wait_for_state(&mut client, |state| state.bodies.len() == 3).await;
+    assert_eq!(first.bodies[2].mass, 1.0);
+
+    // The server keeps stepping the simulation on its own
+    let later = wait_for_state(&mut client, |state| {
+        state.physical_time > first.physical_time
+    })
+    .await;
+    assert_eq!(later.bodies.len(), 3);
+    assert!(later.kinetic_energy > 0.0);
+    let conservation = later.conservation.unwrap();
+    assert!(conservation.potential_energy < 0.0);
+    assert!(later.total_energy().unwrap() < later.kinetic_energy);
+
+    client.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reset_test() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+    client
+        .set_solver_parameters(SolverParameters::new(1.0, 0.0))
+        .await
+        .unwrap();
+    client.add_bodies(bodies_at_rest(2)).await.unwrap();
+    let before = wait_for_state(&mut client, |state| state.physical_time >= 5.0).await;
+
+    client.reset().await.unwrap();
+    let after = wait_for_state(&mut client, |state| state.bodies.is_empty()).await;
+    assert!(after.physical_time < before.physical_time);
+    assert_eq!(after.kinetic_energy, 0.0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn parameters_test() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+
+    // Requests are handled in order: the reset restarts the time with the new dt
+    client
+        .set_solver_parameters(SolverParameters::new(0.5, 0.0))
+        .await
+        .unwrap();
+    client
+        .set_physics_parameters(PhyiscsParameters::new(0.0))
+        .await
+        .unwrap();
+    client.reset().await.unwrap();
+    client.add_bodies(bodies_at_rest(2)).await.unwrap();
+
+    let state = wait_for_state(&mut client, |state| state.physical_time >= 2.0).await;
+    assert_eq!(state.physical_time % 0.5, 0.0);
+    // No gravity: the bodies stay at rest
+    assert!(state.bodies.iter().all(|body| body.velocity == [0.0, 0.0]));
+
+    client
+        .set_physics_parameters(PhyiscsParameters::default())
+        .await
+        .unwrap();
+    let state = wait_for_state(&mut client, |state| state.kinetic_energy > 0.0).await;
+    // Attracted towards each other
+    assert!(state.bodies[0].velocity[0] > 0.0);
+    assert!(state.bodies[1].velocity[0] < 0.0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn boundary_condition_test() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+    let world = SquareBox::new([0.0, 0.0], 10.0);
+    client
+        .set_physics_parameters(PhyiscsParameters::new(0.0))
+        .await
+        .unwrap();
+    client
+        .set_boundary_condition(BoundaryCondition::Reflective(SquareBox::new(
+            [0.0, 0.0],
+            -1.0,
+        )))
+        .await
+        .unwrap();
+    let Some(Err(ClientError::Server(error))) = client.next_message().await else {
+        panic!("Expected the boundary to be rejected");
+    };
+    assert!(error.contains("world"));
+
+    client
+        .set_boundary_condition(BoundaryCondition::Reflective(world))
+        .await
+        .unwrap();
+    client
+        .add_bodies(vec![Body::default().with_velocity([100.0, 0.0])])
+        .await
+        .unwrap();
+    // Bounced back from the wall on the right
+    let state = wait_for_state(&mut client, |state| {
+        state
+            .bodies
+            .first()
+            .is_some_and(|body| body.velocity[0] < 0.0)
+    })
+    .await;
+    assert!(state.bodies[0].position[0] <= 9.0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn external_forces_test() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+    client
+        .set_external_forces(vec![ExternalForce::LinearDrag { coefficient: -1.0 }])
+        .await
+        .unwrap();
+    let Some(Err(ClientError::Server(error))) = client.next_message().await else {
+        panic!("Expected the drag to be rejected");
+    };
+    assert!(error.contains("external force 0"));
+
+    client
+        .set_physics_parameters(PhyiscsParameters::new(0.0))
+        .await
+        .unwrap();
+    client
+        .set_external_forces(vec![ExternalForce::Uniform {
+            acceleration: [0.0, -10.0],
+        }])
+        .await
+        .unwrap();
+    client.add_bodies(vec![Body::default()]).await.unwrap();
+    let state = wait_for_state(&mut client, |state| {
+        state
+            .bodies
+            .first()
+            .is_some_and(|body| body.velocity[1] < 0.0)
+    })
+    .await;
+    assert_eq!(state.bodies[0].velocity[0], 0.0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn constraints_test() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+    client
+        .set_physics_parameters(PhyiscsParameters::new(0.0))
+        .await
+        .unwrap();
+    client
+        .add_bodies(vec![
+            Body::default(),
+            Body::default()
+                .with_position([10.0, 0.0])
+                .with_velocity([5.0, 0.0]),
+        ])
+        .await
+        .unwrap();
+    let state = request_state(&mut client).await.unwrap();
+    let [a, b] = [0, 1].map(|i| state.bodies[i].id);
+
+    client
+        .add_constraint(Constraint::rod(a, BodyId(1000), 1.0))
+        .await
+        .unwrap();
+    let Some(Err(ClientError::Server(error))) = client.next_message().await else {
+        panic!("Expected the constraint to be rejected");
+    };
+    assert!(error.contains("1000"));
+
+    // The rod drags the first body along
+    client
+        .add_constraint(Constraint::rod(a, b, 10.0))
+        .await
+        .unwrap();
+    let state = wait_for_state(&mut client, |state| {
+        state
+            .bodies
+            .first()
+            .is_some_and(|body| body.position[0] > 1.0)
+    })
+    .await;
+    let [first, second] = [&state.bodies[0], &state.bodies[1]];
+    assert!((second.position[0] - first.position[0] - 10.0).abs() < 1e-6);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn invalid_parameters_test() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+    client
+        .set_solver_parameters(SolverParameters::new(0.5, 0.0))
+        .await
+        .unwrap();
+    client
+        .set_solver_parameters(SolverParameters::new(-1.0, 0.0))
+        .await
+        .unwrap();
+    let Some(Err(ClientError::Server(error))) = client.next_message().await else {
+        panic!("Expected the parameters to be rejected");
+    };
+    assert!(error.contains("time step"));
+    client
+        .set_physics_parameters(PhyiscsParameters::default().with_softening(-1.0))
+        .await
+        .unwrap();
+    let Some(Err(ClientError::Server(error))) = client.next_message().await else {
+        panic!("Expected the parameters to be rejected");
+    };
+    assert!(error.contains("softening"));
+    client
+        .set_physics_parameters(PhyiscsParameters::default().with_restitution(2.0))
+        .await
+        .unwrap();
+    let Some(Err(ClientError::Server(error))) = client.next_message().await else {
+        panic!("Expected the parameters to be rejected");
+    };
+    assert!(error.contains("restitution"));
+
+    // The previous parameters are kept
+    client.reset().await.unwrap();
+    let state = wait_for_state(&mut client, |state| state.physical_time >= 1.0).await;
+    assert_eq!(state.physical_time % 0.5, 0.0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn shared_simulation_test() {
+    let server = TestServer::start().await;
+    let (mut alice, mut bob) = (server.connect().await, server.connect().await);
+
+    alice.add_bodies(bodies_at_rest(4)).await.unwrap();
+    wait_for_state(&mut bob, |state| state.bodies.len() == 4).await;
+
+    // A client leaving does not affect the others
+    alice.close().await.unwrap();
+    bob.add_bodies(bodies_at_rest(1)).await.unwrap();
+    wait_for_state(&mut bob, |state| state.bodies.len() == 5).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn history_test() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+    client
+        .set_solver_parameters(SolverParameters::new(0.5, 0.0))
+        .await
+        .unwrap();
+    client.reset().await.unwrap();
+    client.add_bodies(bodies_at_rest(2)).await.unwrap();
+    wait_for_state(&mut client, |state| state.physical_time >= 3.0).await;
+
+    client.request_history(1.0, 2.0, 100.0).await.unwrap();
+    let mut times = Vec::new();
+    for _ in 0..3 {
+        let state = client.next_state_update().await.unwrap().unwrap();
+        assert_eq!(state.bodies.len(), 2);
+        times.push(state.physical_time);
+    }
+    assert_eq!(times, [1.0, 1.5, 2.0]);
+
+    for (from_time, to_time, rate) in [
+        (2.0, 1.0, 100.0),
+        (1.0, 2.0, 1e-300),
+        (1.0, 2.0, f64::INFINITY),
+    ] {
+        client
+            .request_history(from_time, to_time, rate)
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.next_message().await,
+            Some(Err(ClientError::Server(_)))
+        ));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn diagnostics_test() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+    client
+        .set_solver_parameters(SolverParameters::new(0.5, 0.0))
+        .await
+        .unwrap();
+    client.reset().await.unwrap();
+    client.add_bodies(bodies_at_rest(2)).await.unwrap();
+    wait_for_state(&mut client, |state| state.physical_time >= 5.0).await;
+
+    client.query_diagnostics(0.0, 100.0, 3).await.unwrap();
+    let Some(Ok(ServerToClientMessage::Diagnostics(samples))) = client.next_message().await else {
+        panic!("Expected the diagnostics");
+    };
+    assert!((2..=3).contains(&samples.len()));
+    assert!(samples
+        .windows(2)
+        .all(|pair| pair[0].physical_time < pair[1].physical_time));
+    assert_eq!(samples.last().unwrap().body_count, 2);
+    // The bodies only attract each other
+    assert!(samples
+        .iter()
+        .all(|sample| sample.momentum.iter().all(|p| p.abs() < 1e-9)));
+
+    client.query_diagnostics(0.0, 100.0, 0).await.unwrap();
+    assert!(matches!(
+        client.next_message().await,
+        Some(Err(ClientError::Server(_)))
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn query_region_test() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+    client
+        .set_physics_parameters(PhyiscsParameters::new(0.0))
+        .await
+        .unwrap();
+    client.add_bodies(bodies_at_rest(5)).await.unwrap();
+    wait_for_state(&mut client, |state| state.bodies.len() == 5).await;
+
+    client
+        .query_region(SquareBox::new([150.0, 0.0], 60.0))
+        .await
+        .unwrap();
+    let Some(Ok(ServerToClientMessage::Region { bodies, .. })) = client.next_message().await else {
+        panic!("Expected the bodies of the region");
+    };
+    let ids: Vec<_> = bodies.iter().map(|body| body.id).collect();
+    assert_eq!(ids, [BodyId(1), BodyId(2)]);
+    assert_eq!(bodies[1].position, [200.0, 0.0]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn get_body_test() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+    client
+        .set_physics_parameters(PhyiscsParameters::new(0.0))
+        .await
+        .unwrap();
+    client.add_bodies(bodies_at_rest(5)).await.unwrap();
+    wait_for_state(&mut client, |state| state.bodies.len() == 5).await;
+
+    client.get_body(BodyId(3)).await.unwrap();
+    let Some(Ok(ServerToClientMessage::BodyDetails { body, speed, .. })) =
+        client.next_message().await
+    else {
+        panic!("Expected the details of the body");
+    };
+    assert_eq!(body.id, BodyId(3));
+    assert_eq!(body.position, [300.0, 0.0]);
+    assert_eq!(speed, 0.0);
+
+    client.get_body(BodyId(5)).await.unwrap();
+    assert!(matches!(
+        client.next_message().await,
+        Some(Err(ClientError::Server(_)))
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn remove_bodies_test() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+    client
+        .set_physics_parameters(PhyiscsParameters::new(0.0))
+        .await
+        .unwrap();
+    client.add_bodies(bodies_at_rest(5)).await.unwrap();
+    client
+        .remove_bodies(vec![BodyId(1), BodyId(3)])
+        .await
+        .unwrap();
+    let state = request_state(&mut client).await.unwrap();
+    let x: Vec<_> = state.bodies.iter().map(|body| body.position[0]).collect();
+    assert_eq!(x, [0.0, 200.0, 400.0]);
+    // The remaining bodies keep their ids
+    let ids: Vec<_> = state.bodies.iter().map(|body| body.id.0).collect();
+    assert_eq!(ids, [0, 2, 4]);
+
+    // Nothing is removed if any id is unknown
+    client
+        .remove_bodies(vec![BodyId(0), BodyId(3)])
+        .await
+        .unwrap();
+    assert!(matches!(
+        client.next_message().await,
+        Some(Err(ClientError::Server(_)))
+    ));
+    let state = request_state(&mut client).await.unwrap();
+    assert_eq!(state.bodies.len(), 3);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn queued_mutations_test() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+    // Applied between two steps, but before the next request of the client is served
+    for count in 1..=5 {
+        client.reset().await.unwrap();
+        client.add_bodies(bodies_at_rest(count)).await.unwrap();
+        let state = request_state(&mut client).await.unwrap();
+        assert_eq!(state.bodies.len(), count);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn get_history_test() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+    client.reset().await.unwrap();
+    client.add_bodies(bodies_at_rest(2)).await.unwrap();
+    wait_for_state(&mut client, |state| state.physical_time >= 1.0).await;
+
+    client.get_history(5).await.unwrap();
+    let states = loop {
+        match client.next_message().await {
+            Some(Ok(ServerToClientMessage::History(states))) => break states,
+            Some(Ok(_)) => continue,
+            other => panic!("Expected the history, got {other:?}"),
+        }
+    };
+    assert_eq!(states.len(), 5);
+    assert!(states
+        .windows(2)
+        .all(|pair| pair[0].physical_time < pair[1].physical_time));
+    assert!(states.iter().all(|state| state.bodies.len() == 2));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn get_trails_test() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+    client.reset().await.unwrap();
+    client.add_bodies(bodies_at_rest(2)).await.unwrap();
+    wait_for_state(&mut client, |state| state.physical_time >= 1.0).await;
+
+    client.get_trails(5).await.unwrap();
+    let (trails, physical_time) = loop {
+        match client.next_message().await {
+            Some(Ok(ServerToClientMessage::Trails {
+                trails,
+                physical_time,
+            })) => break (trails, physical_time),
+            Some(Ok(_)) => continue,
+            other => panic!("Expected the trails, got {other:?}"),
+        }
+    };
+    assert!(physical_time >= 1.0);
+    assert_eq!(trails.len(), 2);
+    assert!(trails[0].id < trails[1].id);
+    assert!(trails.iter().all(|trail| trail.positions.len() == 5));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn subscribe_bundled_test() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+    for ticks_per_bundle in [0, MAX_TICKS_PER_BUNDLE + 1] {
+        client.subscribe_bundled(ticks_per_bundle).await.unwrap();
+        assert!(matches!(
+            client.next_message().await,
+            Some(Err(ClientError::Server(_)))
+        ));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn broadcast_test() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+    client.add_bodies(bodies_at_rest(2)).await.unwrap();
+    client.subscribe().await.unwrap();
+    // Pushed without being requested, a new step each time
+    let mut times = Vec::new();
+    for _ in 0..3 {
+        let ServerToClientMessage::StateUpdate {
+            bodies,
+            physical_time,
+            ..
+        } = next_message(&mut client).await
+        else {
+            panic!("Expected a pushed state");
+        };
+        assert_eq!(bodies.len(), 2);
+        times.push(physical_time);
+    }
+    assert!(times.windows(2).all(|pair| pair[0] < pair[1]));
+
+    let mut bundled = server.connect().await;
+    bundled.subscribe_bundled(4).await.unwrap();
+    let ServerToClientMessage::StateBundle(states) = next_message(&mut bundled).await else {
+        panic!("Expected a pushed bundle");
+    };
+    assert_eq!(states.len(), 4);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn state_delta_test() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+    client.add_bodies(bodies_at_rest(3)).await.unwrap();
+    client.subscribe_deltas().await.unwrap();
+
+    // The whole state until one is acknowledged
+    let ServerToClientMessage::StateDelta {
+        tick,
+        baseline_tick: None,
+        delta,
+        ..
+    } = next_message(&mut client).await
+    else {
+        panic!("Expected a whole state");
+    };
+    let baseline = delta.apply(&[]);
+    assert_eq!(baseline.len(), 3);
+    client.ack_state(tick).await.unwrap();
+    let (baseline_tick, delta) = loop {
+        if let ServerToClientMessage::StateDelta {
+            baseline_tick: Some(baseline_tick),
+            delta,
+            ..
+        } = next_message(&mut client).await
+        {
+            break (baseline_tick, delta);
+        }
+    };
+    assert_eq!(baseline_tick, tick);
+    assert!(delta.added.is_empty() && delta.removed.is_empty());
+    let bodies = delta.apply(&baseline);
+    let ids: Vec<_> = bodies.iter().map(|body| body.id.0).collect();
+    assert_eq!(ids, [0, 1, 2]);
+    // Pulled towards each other
+    assert!(bodies[0].velocity[0] > 0.0);
+
+    // Acknowledging a state the server does not have falls back to the whole state
+    client.ack_state(u64::MAX).await.unwrap();
+    loop {
+        if let ServerToClientMessage::StateDelta {
+            baseline_tick: None,
+            delta,
+            ..
+        } = next_message(&mut client).await
+        {
+            assert_eq!(delta.added.len(), 3);
+            break;
+        }
+    }
+
+    let mut unsubscribed = server.connect().await;
+    unsubscribed.ack_state(tick).await.unwrap();
+    assert!(matches!(
+        unsubscribed.next_message().await,
+        Some(Err(ClientError::Server(_)))
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn broadcast_interval_test() {
+    let interval = Duration::from_millis(100);
+    let server = TestServer::start_with(ServerState::new().with_broadcast_interval(interval)).await;
+    let mut client = server.connect().await;
+    client.subscribe().await.unwrap();
+    next_message(&mut client).await;
+    let start = Instant::now();
+    next_message(&mut client).await;
+    next_message(&mut client).await;
+    // Some slack for the delivery of the first one
+    assert!(start.elapsed() >= interval * 3 / 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn ping_test() {
+    let server = TestServer::start().await;
+    for subprotocol in [Subprotocol::BincodeGzip, Subprotocol::Protobuf] {
+        let mut client = server.connect_with_subprotocol(subprotocol).await;
+        client.ping().await.unwrap();
+        let Some(Ok(ServerToClientMessage::Pong {
+            client_time,
+            server_time,
+            ..
+        })) = client.next_message().await
+        else {
+            panic!("Expected a Pong");
+        };
+        // Same machine, same clock
+        assert!(client_time > 0.0);
+        assert!((server_time - client_time).abs() < 5_000.0);
+    }
+}
+
+async fn server_tick(client: &mut Client) -> u64 {
+    client.ping().await.unwrap();
+    let Some(Ok(ServerToClientMessage::Pong { tick, .. })) = client.next_message().await else {
+        panic!("Expected a Pong");
+    };
+    tick
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pause_test() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+    client.single_step().await.unwrap();
+    assert!(matches!(
+        client.next_message().await,
+        Some(Err(ClientError::Server(_)))
+    ));
+
+    client.pause().await.unwrap();
+    // Lets the step in progress (if any) complete
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let paused = server_tick(&mut client).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(server_tick(&mut client).await, paused);
+
+    // Commands are still applied while paused
+    client.add_bodies(bodies_at_rest(2)).await.unwrap();
+    client.single_step().await.unwrap();
+    client.single_step().await.unwrap();
+    assert_eq!(server_tick(&mut client).await, paused + 2);
+    assert_eq!(request_state(&mut client).await.unwrap().bodies.len(), 2);
+
+    client.resume().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(server_tick(&mut client).await > paused + 2);
+}
+
+async fn joined_room(client: &mut Client) -> RoomId {
+    let ServerToClientMessage::RoomJoined(room) = next_message(client).await else {
+        panic!("Expected a RoomJoined");
+    };
+    room
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rooms_test() {
+    let server = TestServer::start().await;
+    let mut host = server.connect().await;
+    let mut guest = server.connect().await;
+    let mut lobby = server.connect().await;
+    host.create_room().await.unwrap();
+    let room = joined_room(&mut host).await;
+    assert_ne!(room, RoomId::LOBBY);
+    guest.join_room(room).await.unwrap();
+    assert_eq!(joined_room(&mut guest).await, room);
+
+    // Same simulation for the clients of the room, isolated from the lobby
+    host.add_bodies(bodies_at_rest(2)).await.unwrap();
+    wait_for_state(&mut guest, |state| state.bodies.len() == 2).await;
+    assert!(request_state(&mut lobby).await.unwrap().bodies.is_empty());
+    guest.subscribe().await.unwrap();
+    let ServerToClientMessage::StateUpdate { bodies, .. } = next_message(&mut guest).await else {
+        panic!("Expected a StateUpdate");
+    };
+    assert_eq!(bodies.len(), 2);
+
+    guest.leave_room().await.unwrap();
+    assert_eq!(joined_room(&mut guest).await, RoomId::LOBBY);
+    assert!(request_state(&mut guest).await.unwrap().bodies.is_empty());
+
+    // Closed with its last client
+    host.leave_room().await.unwrap();
+    joined_room(&mut host).await;
+    host.join_room(room).await.unwrap();
+    assert!(matches!(
+        host.next_message().await,
+        Some(Err(ClientError::Server(_)))
+    ));
+}
+
+async fn server_error(client: &mut Client) -> String {
+    let Some(Err(ClientError::Server(error))) = client.next_message().await else {
+        panic!("Expected an error");
+    };
+    error
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn authentication_test() {
+    let server = TestServer::start_with(
+        ServerState::new()
+            .with_token("root", Role::Admin)
+            .with_token("guest", Role::Spectator),
+    )
+    .await;
+
+    // Anything else first, or an unknown token, and the connection is closed
+    let mut anonymous = server.connect().await;
+    anonymous.subscribe().await.unwrap();
+    assert!(server_error(&mut anonymous)
+        .await
+        .contains("Authentication"));
+    assert!(anonymous.next_message().await.is_none());
+    let mut impostor = server.connect().await;
+    impostor.authenticate("nope").await.unwrap();
+    assert!(server_error(&mut impostor).await.contains("Authentication"));
+    assert!(impostor.next_message().await.is_none());
+
+    let mut admin = server.connect().await;
+    admin.authenticate("root").await.unwrap();
+    assert!(matches!(
+        next_message(&mut admin).await,
+        ServerToClientMessage::Authenticated(Role::Admin)
+    ));
+    let mut spectator = server.connect().await;
+    spectator.authenticate("guest").await.unwrap();
+    assert!(matches!(
+        next_message(&mut spectator).await,
+        ServerToClientMessage::Authenticated(Role::Spectator)
+    ));
+
+    // Spectators only watch
+    spectator.add_bodies(bodies_at_rest(1)).await.unwrap();
+    assert!(server_error(&mut spectator).await.contains("Controller"));
+    spectator.reset().await.unwrap();
+    assert!(server_error(&mut spectator).await.contains("Admin"));
+    admin.add_bodies(bodies_at_rest(2)).await.unwrap();
+    wait_for_state(&mut spectator, |state| state.bodies.len() == 2).await;
+
+    admin.reset().await.unwrap();
+    wait_for_state(&mut spectator, |state| state.bodies.is_empty()).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn snapshot_test() {
+    let dir = std::env::temp_dir().join(format!("nbody-snapshots-{}", std::process::id()));
+    let server = TestServer::start_with(ServerState::new().with_snapshot_dir(&dir).unwrap()).await;
+    let mut client = server.connect().await;
+    client.add_bodies(bodies_at_rest(3)).await.unwrap();
+    wait_for_state(&mut client, |state| state.bodies.len() == 3).await;
+    client.save_snapshot("three-bodies").await.unwrap();
+    let ServerToClientMessage::SnapshotSaved {
+        name,
+        physical_time,
+    } = next_message(&mut client).await
+    else {
+        panic!("Expected SnapshotSaved");
+    };
+    assert_eq!(name, "three-bodies");
+
+    client.reset().await.unwrap();
+    client.load_snapshot("three-bodies").await.unwrap();
+    let restored = request_state(&mut client).await.unwrap();
+    assert_eq!(restored.bodies.len(), 3);
+    assert!(restored.physical_time >= physical_time);
+
+    // Saved on disk, a restarted server loads it back
+    let restarted =
+        TestServer::start_with(ServerState::new().with_snapshot_dir(&dir).unwrap()).await;
+    let mut client = restarted.connect().await;
+    client.load_snapshot("three-bodies").await.unwrap();
+    assert_eq!(request_state(&mut client).await.unwrap().bodies.len(), 3);
+
+    client.load_snapshot("missing").await.unwrap();
+    assert!(server_error(&mut client).await.contains("No snapshot"));
+    for name in ["../escape", ""] {
+        client.load_snapshot(name).await.unwrap();
+        assert!(server_error(&mut client)
+            .await
+            .contains("Invalid snapshot name"));
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let mut client = TestServer::start().await.connect().await;
+    client.save_snapshot("anything").await.unwrap();
+    assert!(server_error(&mut client).await.contains("not enabled"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn scenario_test() {
+    let server = TestServer::start_with(ServerState::new().with_max_bodies(50)).await;
+    let mut client = server.connect().await;
+    let binary = Scenario {
+        kind: ScenarioKind::Binary {
+            semi_major_axis: 50.0,
+            eccentricity: 0.2,
+            mass_ratio: 0.5,
+        },
+        ..Scenario::default()
+    };
+    client.load_scenario(binary).await.unwrap();
+    let state = wait_for_state(&mut client, |state| state.bodies.len() == 2).await;
+    assert_eq!(state.bodies[1].mass, 0.5 * state.bodies[0].mass);
+
+    client
+        .load_scenario(Scenario {
+            count: 100,
+            ..Scenario::default()
+        })
+        .await
+        .unwrap();
+    assert!(server_error(&mut client).await.contains("at most 50"));
+}
+
+/// Lets every other connection through
+#[derive(Default)]
+struct AlternateAcceptor(AtomicUsize);
+
+impl Acceptor for AlternateAcceptor {
+    type Stream = TcpStream;
+
+    async fn accept(&self, stream: TcpStream) -> io::Result<TcpStream> {
+        match self.0.fetch_add(1, Ordering::Relaxed) % 2 {
+            0 => Ok(stream),
+            _ => Err(io::Error::other("refused")),
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn acceptor_test() {
+    let server =
+        TestServer::start_with_acceptor(ServerState::new(), AlternateAcceptor::default()).await;
+    let mut client = server.connect().await;
+    client.add_bodies(bodies_at_rest(1)).await.unwrap();
+    assert_eq!(request_state(&mut client).await.unwrap().bodies.len(), 1);
+
+    // Dropped before the WebSocket handshake
+    assert!(Client::connect(server.url()).await.is_err());
+    server.connect().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tls_test() {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let dir = std::env::temp_dir().join(format!("nbody-tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+    std::fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+    let tls = Tls::from_pem_files(&cert_path, &key_path).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    let server = TestServer::start_with_acceptor(ServerState::new(), tls).await;
+
+    // Trusting the certificate of the server only
+    let mut roots = RootCertStore::empty();
+    roots.add(certified.cert.der().clone()).unwrap();
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let address = server.url().trim_start_matches("ws://");
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(
+            ServerName::try_from("localhost").unwrap(),
+            TcpStream::connect(address).await.unwrap(),
+        )
+        .await
+        .unwrap();
+    let (mut socket, _) = client_async("wss://localhost/", stream).await.unwrap();
+    let ping = ClientToServerMessage::Ping { client_time: 1.5 };
+    socket
+        .send(Message::binary(encode(&ping).unwrap()))
+        .await
+        .unwrap();
+    let Some(Ok(Message::Binary(frame))) = socket.next().await else {
+        panic!("Expected a binary frame");
+    };
+    assert!(matches!(
+        decode(&frame),
+        Ok(ServerToClientMessage::Pong { client_time, .. }) if client_time == 1.5
+    ));
+
+    // Not a TLS client
+    assert!(Client::connect(server.url()).await.is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn disconnect_test() {
+    let server = TestServer::start().await;
+    let subscribers = || lock!(server.state().lobby().connected_clients).len();
+    let connections = || server.state().connections.load(Ordering::Relaxed);
+    let mut client = server.connect().await;
+    client.subscribe().await.unwrap();
+    client.request_history(0.0, f64::MAX, 1.0).await.unwrap();
+    next_message(&mut client).await;
+    assert_eq!((subscribers(), connections()), (1, 1));
+
+    drop(client);
+    let deadline = Instant::now() + TIMEOUT;
+    while (subscribers(), connections()) != (0, 0) {
+        assert!(Instant::now() < deadline, "The client was not cleaned up");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tick_rate_test() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+    client.set_tick_rate(500.0).await.unwrap();
+    let from = server_tick(&mut client).await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    // Well above the 12 steps of the default rate
+    assert!(server_tick(&mut client).await - from > 40);
+
+    for tick_rate in [0.0, -1.0, f64::NAN, 1e9] {
+        client.set_tick_rate(tick_rate).await.unwrap();
+        assert!(matches!(
+            client.next_message().await,
+            Some(Err(ClientError::Server(_)))
+        ));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn limits_test() {
+    let server =
+        TestServer::start_with(ServerState::new().with_max_clients(1).with_max_bodies(3)).await;
+    let mut client = server.connect().await;
+    request_state(&mut client).await.unwrap();
+    assert!(Client::connect(server.url()).await.is_err());
+
+    client.add_bodies(bodies_at_rest(2)).await.unwrap();
+    wait_for_state(&mut client, |state| state.bodies.len() == 2).await;
+    client.add_bodies(bodies_at_rest(2)).await.unwrap();
+    assert!(server_error(&mut client).await.contains("at most 3"));
+    client.add_bodies(bodies_at_rest(1)).await.unwrap();
+    wait_for_state(&mut client, |state| state.bodies.len() == 3).await;
+}
+
+/// Sends a GET request for the path and returns the response, status line included
+async fn http_get(address: &str, path: &str) -> String {
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    let request = format!("GET {path} HTTP/1.1\r\nHost: {address}\r\n\r\n");
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+/// Value of the sample of the metric (its name and labels) in the Prometheus text format
+fn metric(metrics: &str, sample: &str) -> f64 {
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(sample)?.strip_prefix(' '))
+        .unwrap_or_else(|| panic!("No {sample} in the metrics"))
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn metrics_test() {
+    let server = TestServer::start().await;
+    let address = server.serve_metrics().await;
+    let mut client = server.connect().await;
+    client.add_bodies(bodies_at_rest(2)).await.unwrap();
+    wait_for_state(&mut client, |state| state.bodies.len() == 2).await;
+
+    let response = http_get(&address, "/metrics").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert_eq!(metric(&response, "nbody_bodies{room=\"0\"}"), 2.0);
+    assert_eq!(metric(&response, "nbody_connected_clients"), 1.0);
+    assert!(metric(&response, "nbody_step_duration_seconds_count") > 0.0);
+    assert!(metric(&response, "nbody_messages_received_total") >= 2.0);
+    assert!(metric(&response, "nbody_sent_bytes_total") > 0.0);
+    assert_eq!(metric(&response, "nbody_dropped_frames_total"), 0.0);
+
+    assert!(http_get(&address, "/")
+        .await
+        .starts_with("HTTP/1.1 404 Not Found"));
+}
+
+#[test]
+fn config_test() {
+    let config = ServerConfig::from_toml(
+        "address = \"127.0.0.1\"\nport = 6000\nmax_clients = 8\ncompression_level = 9",
+    )
+    .unwrap();
+    assert_eq!(config.socket_address(), "127.0.0.1:6000".parse().unwrap());
+    assert_eq!(config.max_clients, Some(8));
+    assert_eq!(config.compression_level, Some(9));
+    assert_eq!(config.max_bodies, None);
+    assert_eq!(
+        ServerConfig::from_toml("").unwrap(),
+        ServerConfig::default()
+    );
+    assert!(ServerConfig::from_toml("prot = 6000").is_err());
+    // Values the server would panic on
+    for toml in [
+        "tick_rate = 0.0",
+        "tick_rate = -1.0",
+        "tick_rate = nan",
+        "compression_level = 10",
+    ] {
+        assert!(matches!(
+            ServerConfig::from_toml(toml),
+            Err(ConfigError::OutOfRange(_))
+        ));
+    }
+
+    // The flags take precedence over the file
+    let path = std::env::temp_dir().join(format!("nbody-config-{}.toml", std::process::id()));
+    std::fs::write(&path, "port = 6000\nmax_bodies = 100").unwrap();
+    let args = ServerArgs::try_parse_from([
+        "ws-server".as_ref(),
+        "--config".as_ref(),
+        path.as_os_str(),
+        "--port".as_ref(),
+        "7000".as_ref(),
+    ])
+    .unwrap();
+    let config = args.into_config().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!((config.port, config.max_bodies), (7000, Some(100)));
+    assert!(ServerArgs::try_parse_from(["ws-server", "--compression-level", "10"]).is_err());
+    let args = ServerArgs::try_parse_from(["ws-server", "--tick-rate", "0"]).unwrap();
+    assert!(args.into_config().is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn worker_threads_test() {
+    // The server takes the parallel gravity path, across a pool of the configured size
+    const { assert!(nbody::PARALLEL) };
+    let config = ServerConfig::from_toml("worker_threads = 2").unwrap();
+    let state = config.build_state();
+    assert_eq!(state.worker_threads(), 2);
+
+    let server = TestServer::start_with(state).await;
+    let mut client = server.connect().await;
+    client
+        .load_scenario(Scenario {
+            count: 500,
+            ..Scenario::default()
+        })
+        .await
+        .unwrap();
+    let state = wait_for_state(&mut client, |state| {
+        state.bodies.len() == 500 && state.physical_time > 0.0
+    })
+    .await;
+    assert!(state.bodies.iter().all(|body| body.position[0].is_finite()));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn subprotocol_test() {
+    let server = TestServer::start_with(ServerState::new().with_dictionary(dictionary())).await;
+    for subprotocol in Subprotocol::ALL {
+        let mut client = server.connect_with_subprotocol(subprotocol).await;
+        client.reset().await.unwrap();
+        client.add_bodies(bodies_at_rest(3)).await.unwrap();
+        let state = request_state(&mut client).await.unwrap();
+        assert_eq!(state.bodies.len(), 3, "{}", subprotocol.name());
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn zstd_dictionary_test() {
+    let server = TestServer::start_with(ServerState::new().with_dictionary(dictionary())).await;
+    let mut client = server
+        .connect_with_subprotocol(Subprotocol::BincodeZstd)
+        .await;
+    let bodies = Scenario {
+        count: 50,
+        ..Scenario::default()
+    }
+    .generate(1.0);
+    client.add_bodies(bodies).await.unwrap();
+    let state = wait_for_state(&mut client, |state| state.bodies.len() == 50).await;
+    assert!(state.bodies.iter().all(|body| body.mass == 1.0));
+
+    // Refused by a server without a dictionary
+    let server = TestServer::start().await;
+    assert!(
+        ws_client::Client::connect_with_subprotocol(server.url(), Subprotocol::BincodeZstd)
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn journal_recovery_test() {
+    let path = std::env::temp_dir().join(format!("nbody-journal-{}.wal", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    // Checkpointed in the middle of the run, where the quadtree of the live simulation depends
+    // on how the bodies moved before
+    let journaled = ServerState::new()
+        .with_journal(&path)
+        .unwrap()
+        .with_checkpoint_interval(20);
+    let server = TestServer::start_with(journaled).await;
+    let mut client = server.connect().await;
+    client.add_bodies(bodies_at_rest(3)).await.unwrap();
+    client.reset().await.unwrap();
+    let bodies = Scenario {
+        count: 300,
+        seed: 7,
+        ..Scenario::default()
+    }
+    .generate(1.0);
+    client.add_bodies(bodies).await.unwrap();
+    wait_for_state(&mut client, |state| state.physical_time >= 0.5).await;
+    // Applied in the middle of the run, after the steps replayed before it
+    let heavy = bodies_at_rest(2)
+        .into_iter()
+        .map(|body| body.with_mass(5.0))
+        .collect();
+    client.add_bodies(heavy).await.unwrap();
+    wait_for_state(&mut client, |state| state.bodies.len() == 302).await;
+    let added = request_state(&mut client).await.unwrap().physical_time;
+    wait_for_state(&mut client, |state| state.physical_time >= added + 0.5).await;
+
+    // Held still while compared
+    let live = server.state().lobby();
+    let live_simulation = lock!(live.simulation.1);
+    let live_tick = live.simulation.0.load(Ordering::Relaxed);
+
+    // A server restarted on the journal (as after a crash) resumes from the last command
+    let recovered = ServerState::new().with_journal(&path).unwrap();
+    let lobby = recovered.lobby();
+    lobby.run_state.pause();
+    let mut simulation = lock!(lobby.simulation.1);
+    let tick = lobby.simulation.0.load(Ordering::Relaxed);
+    assert!(tick < live_tick);
+    assert_eq!(simulation.bodies().len(), 302);
+
+    // And from there steps exactly as the live run did
+    for _ in tick..live_tick {
+        simulation.step();
+    }
+    assert_eq!(
+        simulation.bodies().positions(),
+        live_simulation.bodies().positions()
+    );
+    assert_eq!(simulation.state_hash(), live_simulation.state_hash());
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,16 @@
+//! Frames received by the server (`deserializeClientMsg` on the browser side)
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nbody_fuzz::{gzip_frame, MAX_DECOMPRESSED_SIZE};
+use protocol::{decode_with_limit, protobuf::decode_any, ClientToServerMessage};
+
+fuzz_target!(|data: &[u8]| {
+    // Hostile frames: version byte, gzip layer, protobuf
+    let _ = decode_with_limit::<ClientToServerMessage>(data, MAX_DECOMPRESSED_SIZE);
+    let _ = decode_any::<ClientToServerMessage>(data);
+
+    // Malformed messages behind a valid gzip layer
+    let _ = decode_with_limit::<ClientToServerMessage>(&gzip_frame(data), MAX_DECOMPRESSED_SIZE);
+});
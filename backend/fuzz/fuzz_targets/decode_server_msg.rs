@@ -0,0 +1,27 @@
+//! Frames received by the clients (`deserializeServerMsg`, `ServerMsgDecoder`, FlatBuffers view)
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nbody_fuzz::{gzip_frame, MAX_DECOMPRESSED_SIZE};
+use protocol::{
+    decode_with_limit, flatbuffers::StateUpdateView, protobuf::decode_any, ServerToClientMessage,
+};
+use wasm_bindings::ServerMsgDecoder;
+
+fuzz_target!(|data: &[u8]| {
+    let mut decoder = ServerMsgDecoder::new().with_max_output_size(MAX_DECOMPRESSED_SIZE);
+
+    // Hostile frames: version byte, gzip layer, protobuf, FlatBuffers
+    let _ = decode_with_limit::<ServerToClientMessage>(data, MAX_DECOMPRESSED_SIZE);
+    let _ = decoder.decode_as::<ServerToClientMessage>(data);
+    let _ = decode_any::<ServerToClientMessage>(data);
+    if let Ok(view) = StateUpdateView::from_frame(data) {
+        let _ = view.to_message();
+    }
+
+    // Malformed messages behind a valid gzip layer
+    let frame = gzip_frame(data);
+    let _ = decode_with_limit::<ServerToClientMessage>(&frame, MAX_DECOMPRESSED_SIZE);
+    let _ = decoder.decode_as::<ServerToClientMessage>(&frame);
+});
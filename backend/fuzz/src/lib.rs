@@ -0,0 +1,21 @@
+//! Helpers shared by the fuzz targets
+
+use std::io::Write;
+
+use flate2::{write::GzEncoder, Compression};
+use protocol::{Codec, PROTOCOL_VERSION};
+
+/// Upper bound of a decompressed frame during the runs
+/// (smaller than the default so that gzip bombs are caught fast)
+pub const MAX_DECOMPRESSED_SIZE: usize = 1 << 20;
+
+/// Wraps arbitrary bytes into a valid frame: [PROTOCOL_VERSION, Codec::Gzip, gzip(data)...]
+/// so that the fuzzer reaches the bincode layer instead of stopping at the gzip checksum
+pub fn gzip_frame(data: &[u8]) -> Vec<u8> {
+    let mut e = GzEncoder::new(
+        vec![PROTOCOL_VERSION, Codec::Gzip as u8],
+        Compression::fast(),
+    );
+    e.write_all(data).expect("writing into a Vec cannot fail");
+    e.finish().expect("writing into a Vec cannot fail")
+}
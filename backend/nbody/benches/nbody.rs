@@ -0,0 +1,121 @@
+//! Hot paths of a step across body counts and opening angles
+//! Run with `cargo bench -p nbody` (add `--features simd` or `parallel` to compare the builds)
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use nbody::{
+    physics::{compute_collisions, gravity_forces, CollisionModel, ForceWorkspace},
+    quadtree::{SquareBox, SquareQuadtree},
+    scenarios::{Scenario, ScenarioKind},
+    simulation::{Simulation, SolverParameters},
+    storage::BodyStorage,
+};
+
+const BODY_COUNTS: [usize; 3] = [1_000, 10_000, 100_000];
+const THETAS: [f64; 3] = [0.3, 0.7, 1.0];
+
+/// Bodies of radius 1 spread at the same density whatever their number
+fn bodies(count: usize) -> BodyStorage {
+    Scenario {
+        kind: ScenarioKind::Random {
+            half_size: 10.0 * (count as f64).sqrt(),
+            max_speed: 1.0,
+        },
+        count,
+        ..Scenario::default()
+    }
+    .generate(1.0)
+    .into()
+}
+
+fn quadtree(bodies: &BodyStorage) -> SquareQuadtree {
+    let mut qt = SquareQuadtree::new(SquareBox::from_bodies(bodies).padded(0.1));
+    (0..bodies.len()).for_each(|i| qt.insert_unchecked(i, bodies));
+    qt
+}
+
+fn quadtree_build(c: &mut Criterion) {
+    let mut group = c.benchmark_group("quadtree_build");
+    for count in BODY_COUNTS {
+        let bodies = bodies(count);
+        group.bench_with_input(BenchmarkId::from_parameter(count), &bodies, |b, bodies| {
+            b.iter(|| quadtree(bodies))
+        });
+    }
+    group.finish();
+}
+
+fn gravity(c: &mut Criterion) {
+    let mut group = c.benchmark_group("gravity");
+    group.sample_size(10);
+    for count in BODY_COUNTS {
+        let bodies = bodies(count);
+        let qt = quadtree(&bodies);
+        let mut forces = vec![[0.0, 0.0]; count];
+        let mut workspace = ForceWorkspace::new();
+        for theta in THETAS {
+            let id = BenchmarkId::new(format!("theta_{theta}"), count);
+            group.bench_function(id, |b| {
+                b.iter(|| {
+                    gravity_forces(
+                        &mut forces,
+                        &bodies,
+                        &qt,
+                        theta * theta,
+                        1.0,
+                        0.0,
+                        &mut workspace,
+                    )
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+fn collisions(c: &mut Criterion) {
+    let mut group = c.benchmark_group("collisions");
+    for count in BODY_COUNTS {
+        let bodies = bodies(count);
+        let qt = quadtree(&bodies);
+        let mut workspace = ForceWorkspace::new();
+        let mut events = Vec::new();
+        group.bench_function(BenchmarkId::from_parameter(count), |b| {
+            // The pass changes the velocities of the bodies it resolves
+            b.iter_batched_ref(
+                || bodies.clone(),
+                |bodies| {
+                    events.clear();
+                    compute_collisions(
+                        bodies,
+                        &qt,
+                        0.01,
+                        CollisionModel::Elastic,
+                        1.0,
+                        &mut events,
+                        &mut workspace,
+                    )
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn step(c: &mut Criterion) {
+    let mut group = c.benchmark_group("step");
+    group.sample_size(10);
+    for count in BODY_COUNTS {
+        for theta in THETAS {
+            let mut simulation = Simulation::new();
+            simulation.set_solver_parameters(SolverParameters::new(0.01, theta));
+            simulation.add_bodies(bodies(count).to_vec());
+            let id = BenchmarkId::new(format!("theta_{theta}"), count);
+            group.bench_function(id, |b| b.iter(|| simulation.step()));
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, quadtree_build, gravity, collisions, step);
+criterion_main!(benches);
@@ -0,0 +1,213 @@
+//! Springs and rods linking pairs of bodies, e.g. to build compound structures
+//!
+//! The springs are forces added after the gravity, the rods are enforced after the integration
+//! by moving the two bodies back to their length and cancelling their relative velocity along it
+
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
+
+use crate::{physics::BodyId, storage::BodyStorage, SMALL};
+#[cfg(not(any(feature = "std", test)))]
+use num_traits::Float;
+
+/// Passes over the rods per step, so that chains of rods settle
+const ROD_ITERATIONS: usize = 4;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+#[serde(rename_all = "camelCase")]
+pub enum ConstraintKind {
+    /// Hooke spring pulling the bodies back to `rest_length` apart with `stiffness` per unit of
+    /// stretch, `damping` opposing their relative speed along the spring
+    #[serde(rename_all = "camelCase")]
+    Spring {
+        rest_length: f64,
+        stiffness: f64,
+        #[serde(default)]
+        damping: f64,
+    },
+
+    /// Massless rigid rod keeping the bodies exactly `length` apart
+    Rod { length: f64 },
+}
+
+/// Link between two bodies, at most one per pair
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+pub struct Constraint {
+    pub bodies: [BodyId; 2],
+    pub kind: ConstraintKind,
+}
+
+impl Constraint {
+    pub fn spring(a: BodyId, b: BodyId, rest_length: f64, stiffness: f64) -> Self {
+        Constraint {
+            bodies: [a, b],
+            kind: ConstraintKind::Spring {
+                rest_length,
+                stiffness,
+                damping: 0.0,
+            },
+        }
+    }
+
+    pub fn rod(a: BodyId, b: BodyId, length: f64) -> Self {
+        Constraint {
+            bodies: [a, b],
+            kind: ConstraintKind::Rod { length },
+        }
+    }
+
+    /// Whether it links the same two bodies, in either order
+    pub fn links(&self, a: BodyId, b: BodyId) -> bool {
+        self.bodies == [a, b] || self.bodies == [b, a]
+    }
+
+    /// Whether it links two different bodies with finite and positive parameters
+    pub fn is_valid(&self) -> bool {
+        let positive = |value: f64| value >= 0.0 && value.is_finite();
+        self.bodies[0] != self.bodies[1]
+            && match self.kind {
+                ConstraintKind::Spring {
+                    rest_length,
+                    stiffness,
+                    damping,
+                } => positive(rest_length) && positive(stiffness) && positive(damping),
+                ConstraintKind::Rod { length } => positive(length),
+            }
+    }
+}
+
+/// Indices of the two bodies, None if either is gone
+fn indices(bodies: &BodyStorage, constraint: &Constraint) -> Option<(usize, usize)> {
+    let index = |id| bodies.ids.binary_search(&id).ok();
+    Some((index(constraint.bodies[0])?, index(constraint.bodies[1])?))
+}
+
+/// Unit vector from a to b and their distance, None if they are on top of each other
+fn axis(bodies: &BodyStorage, a: usize, b: usize) -> Option<([f64; 2], f64)> {
+    let (pa, pb) = (bodies.positions[a], bodies.positions[b]);
+    let delta = [pb[0] - pa[0], pb[1] - pa[1]];
+    let distance = (delta[0] * delta[0] + delta[1] * delta[1]).sqrt();
+    (distance > SMALL).then(|| ([delta[0] / distance, delta[1] / distance], distance))
+}
+
+/// Adds the forces of the springs to `forces` (indexed as the bodies)
+pub(crate) fn spring_forces(
+    constraints: &[Constraint],
+    bodies: &BodyStorage,
+    forces: &mut [[f64; 2]],
+) {
+    for constraint in constraints {
+        let ConstraintKind::Spring {
+            rest_length,
+            stiffness,
+            damping,
+        } = constraint.kind
+        else {
+            continue;
+        };
+        let Some((a, b)) = indices(bodies, constraint) else {
+            continue;
+        };
+        let Some((axis, distance)) = axis(bodies, a, b) else {
+            continue;
+        };
+        let (va, vb) = (bodies.velocities[a], bodies.velocities[b]);
+        let closing_speed = (vb[0] - va[0]) * axis[0] + (vb[1] - va[1]) * axis[1];
+        // Pulls a towards b when stretched
+        let magnitude = stiffness * (distance - rest_length) + damping * closing_speed;
+        for (k, direction) in axis.into_iter().enumerate() {
+            forces[a][k] += magnitude * direction;
+            forces[b][k] -= magnitude * direction;
+        }
+    }
+}
+
+/// Moves the bodies linked by rods back to the length of their rod and removes their relative
+/// velocity along it, sharing the correction by inverse mass (static bodies stay put)
+pub(crate) fn solve_rods(constraints: &[Constraint], bodies: &mut BodyStorage) {
+    for _ in 0..ROD_ITERATIONS {
+        for constraint in constraints {
+            let ConstraintKind::Rod { length } = constraint.kind else {
+                continue;
+            };
+            let Some((a, b)) = indices(bodies, constraint) else {
+                continue;
+            };
+            let (wa, wb) = (bodies.inverse_mass(a), bodies.inverse_mass(b));
+            let total = wa + wb;
+            let Some((axis, distance)) = axis(bodies, a, b) else {
+                continue;
+            };
+            if !(total > 0.0 && total.is_finite()) {
+                continue;
+            }
+            let (va, vb) = (bodies.velocities[a], bodies.velocities[b]);
+            let stretch = (distance - length) / total;
+            let closing_speed = ((vb[0] - va[0]) * axis[0] + (vb[1] - va[1]) * axis[1]) / total;
+            for (k, direction) in axis.into_iter().enumerate() {
+                bodies.positions[a][k] += wa * stretch * direction;
+                bodies.positions[b][k] -= wb * stretch * direction;
+                bodies.velocities[a][k] += wa * closing_speed * direction;
+                bodies.velocities[b][k] -= wb * closing_speed * direction;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::Body;
+    use alloc::vec;
+
+    fn pair(distance: f64) -> BodyStorage {
+        let body = |id: u64, x: f64| Body {
+            id: BodyId(id),
+            ..Body::default().with_position([x, 0.0])
+        };
+        BodyStorage::from(vec![body(0, 0.0), body(1, distance)])
+    }
+
+    #[test]
+    fn spring_test() {
+        let spring = Constraint::spring(BodyId(0), BodyId(1), 1.0, 2.0);
+        let mut forces = [[0.0, 0.0]; 2];
+        spring_forces(&[spring], &pair(3.0), &mut forces);
+        assert_eq!(forces, [[4.0, 0.0], [-4.0, 0.0]]);
+
+        // Compressed, it pushes the bodies apart
+        let mut forces = [[0.0, 0.0]; 2];
+        spring_forces(&[spring], &pair(0.5), &mut forces);
+        assert_eq!(forces, [[-1.0, 0.0], [1.0, 0.0]]);
+
+        assert!(spring.is_valid());
+        assert!(!Constraint::spring(BodyId(0), BodyId(0), 1.0, 2.0).is_valid());
+        assert!(!Constraint::rod(BodyId(0), BodyId(1), f64::NAN).is_valid());
+        assert!(spring.links(BodyId(1), BodyId(0)));
+    }
+
+    #[test]
+    fn rod_test() {
+        let rod = Constraint::rod(BodyId(0), BodyId(1), 2.0);
+        let mut bodies = pair(3.0);
+        bodies.velocities[1] = [1.0, 1.0];
+        solve_rods(&[rod], &mut bodies);
+        // Both bodies have the same mass, so they share the correction
+        assert_eq!(bodies.positions, [[0.5, 0.0], [2.5, 0.0]]);
+        assert_eq!(bodies.velocities, [[0.5, 0.0], [0.5, 1.0]]);
+
+        // A static body does not move, the other one takes the whole correction
+        let mut bodies = pair(3.0);
+        bodies.statics[0] = true;
+        solve_rods(&[rod], &mut bodies);
+        assert_eq!(bodies.positions, [[0.0, 0.0], [2.0, 0.0]]);
+
+        // Links to removed bodies are skipped
+        let mut bodies = pair(3.0);
+        solve_rods(&[Constraint::rod(BodyId(0), BodyId(7), 1.0)], &mut bodies);
+        assert_eq!(bodies.positions, [[0.0, 0.0], [3.0, 0.0]]);
+    }
+}
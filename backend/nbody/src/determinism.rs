@@ -0,0 +1,79 @@
+//! Reproducible runs: in deterministic mode the same bodies and parameters give bit-identical
+//! states, even after a simulation was restored from a snapshot in the middle of a run
+//!
+//! `Simulation::state_hash` fingerprints a state, so that two runs (e.g. on the server and in
+//! the browser) can be checked against each other without shipping the bodies
+
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
+
+/// See `Simulation::set_deterministic_mode`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+#[serde(rename_all = "camelCase")]
+pub struct DeterministicMode {
+    /// Seed of `Simulation::rng`, the draws start over from it on reset
+    pub seed: u64,
+}
+
+impl DeterministicMode {
+    pub fn with_seed(seed: u64) -> Self {
+        Self { seed }
+    }
+}
+
+/// 64-bit FNV-1a over the bit patterns of the values, the same on every platform
+pub struct StateHasher(u64);
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+impl Default for StateHasher {
+    fn default() -> Self {
+        Self(FNV_OFFSET_BASIS)
+    }
+}
+
+impl StateHasher {
+    pub fn new() -> Self {
+        StateHasher::default()
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        for byte in value.to_le_bytes() {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    /// -0.0 and 0.0 (or two NaNs with different payloads) hash differently
+    pub fn write_f64(&mut self, value: f64) {
+        self.write_u64(value.to_bits());
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_hasher_test() {
+        // Reference value of FNV-1a 64 for the 8 zero bytes
+        let mut hasher = StateHasher::new();
+        hasher.write_u64(0);
+        assert_eq!(hasher.finish(), 0xa8c7_f832_281a_39c5);
+
+        let hash = |value: f64| {
+            let mut hasher = StateHasher::new();
+            hasher.write_f64(value);
+            hasher.finish()
+        };
+        assert_eq!(hash(1.5), hash(1.5));
+        assert_ne!(hash(0.0), hash(-0.0));
+    }
+}
@@ -0,0 +1,32 @@
+use thiserror::Error;
+
+use crate::physics::BodyId;
+
+#[cfg(feature = "std")]
+use crate::simulation::SNAPSHOT_FORMAT_VERSION;
+
+/// Errors raised by the simulation engine
+#[derive(Debug, Error)]
+pub enum PhysicsError {
+    /// Invalid input: there is no body at that index
+    #[error("There is no body at index {index} (number of bodies: {len})")]
+    BodyIndexOutOfRange { index: usize, len: usize },
+
+    /// Invalid input: there is no body with that id (or not anymore)
+    #[error("There is no body with id {}", .0 .0)]
+    UnknownBody(BodyId),
+
+    /// Invalid input: a constraint must link two different bodies with positive parameters
+    #[error("Invalid constraint between the bodies {} and {}", .0[0].0, .0[1].0)]
+    InvalidConstraint([BodyId; 2]),
+
+    /// The snapshot was produced by an incompatible format (`None` when empty)
+    #[cfg(feature = "std")]
+    #[error("Unsupported snapshot format {0:?} (expected {SNAPSHOT_FORMAT_VERSION})")]
+    UnsupportedSnapshotVersion(Option<u8>),
+
+    /// The snapshot could not be serialized or deserialized
+    #[cfg(feature = "std")]
+    #[error("Invalid snapshot: {0}")]
+    InvalidSnapshot(#[from] bincode::Error),
+}
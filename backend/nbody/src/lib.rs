@@ -1,5 +1,37 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod constraints;
+pub mod determinism;
+mod error;
+pub mod octree;
 pub mod physics;
 pub mod quadtree;
+pub mod scenarios;
+#[cfg(feature = "simd")]
+mod simd;
 pub mod simulation;
+pub mod simulation3d;
+pub mod storage;
+pub mod timeline;
+pub mod trails;
+pub mod validation;
+
+pub use error::PhysicsError;
+
+/// Whether the gravity is computed across the rayon pool the simulation is stepped in
+/// (i.e. built with the `parallel` feature)
+pub const PARALLEL: bool = cfg!(feature = "parallel");
 
 const SMALL: f64 = 1e-5;
+
+/// Enters a tracing span until the end of the enclosing scope
+/// (no-op without the `tracing` feature)
+macro_rules! phase_span {
+    ($($span:tt)*) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!($($span)*).entered();
+    };
+}
+pub(crate) use phase_span;
@@ -0,0 +1,335 @@
+/// The 3D counterpart of the quadtree: partitions the space into 8 octants recursively
+/// for the Barnes-Hut gravity of `simulation3d`
+use alloc::{vec, vec::Vec};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
+
+use crate::physics::Body3;
+
+const DEFAULT_CAPACITY: usize = 32;
+
+/// Same as the quadtree, leaves this deep are not subdivided anymore
+const DEFAULT_MAX_DEPTH: usize = 32;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+#[serde(rename_all = "camelCase")]
+pub struct CubeBox {
+    /// The center of the cube
+    center: [f64; 3],
+
+    /// Half the side-length of the cube
+    half_size: f64,
+}
+
+impl CubeBox {
+    pub fn new(center: [f64; 3], half_size: f64) -> Self {
+        Self { center, half_size }
+    }
+
+    pub fn from_bodies(bodies: &[Body3]) -> Self {
+        let (lower, upper) = bodies.iter().fold(
+            ([f64::MAX; 3], [f64::MIN; 3]),
+            |(mut lower, mut upper), body| {
+                for axis in 0..3 {
+                    lower[axis] = lower[axis].min(body.position[axis]);
+                    upper[axis] = upper[axis].max(body.position[axis]);
+                }
+                (lower, upper)
+            },
+        );
+        let center = core::array::from_fn(|axis| 0.5 * (lower[axis] + upper[axis]));
+        let half_size = (0..3)
+            .map(|axis| 0.5 * (upper[axis] - lower[axis]))
+            .fold(0.0, f64::max);
+        Self { center, half_size }
+    }
+
+    pub fn center(&self) -> [f64; 3] {
+        self.center
+    }
+
+    pub fn half_size(&self) -> f64 {
+        self.half_size
+    }
+
+    pub fn size(&self) -> f64 {
+        2.0 * self.half_size
+    }
+
+    /// Same cube grown by `padding` (a fraction of its size) on every side
+    pub fn padded(&self, padding: f64) -> Self {
+        Self {
+            center: self.center,
+            half_size: self.half_size * (1.0 + 2.0 * padding),
+        }
+    }
+
+    pub fn contains(&self, point: &[f64; 3]) -> bool {
+        (0..3).all(|axis| (point[axis] - self.center[axis]).abs() <= self.half_size)
+    }
+
+    pub fn contains_box(&self, other: &CubeBox) -> bool {
+        (0..3).all(|axis| {
+            (other.center[axis] - self.center[axis]).abs() + other.half_size <= self.half_size
+        })
+    }
+
+    pub fn intersects(&self, other: &CubeBox) -> bool {
+        (0..3).all(|axis| {
+            (other.center[axis] - self.center[axis]).abs() <= self.half_size + other.half_size
+        })
+    }
+
+    /// Index of the octant of the point: one bit per axis (x, y, z), set on the positive side
+    /// It does not check if the point is within the cube
+    pub fn get_octant_unchecked(&self, point: &[f64; 3]) -> usize {
+        (0..3)
+            .filter(|&axis| point[axis] >= self.center[axis])
+            .map(|axis| 1 << axis)
+            .sum()
+    }
+
+    /// The octant of that index (see `get_octant_unchecked`)
+    pub fn octant(&self, index: usize) -> Self {
+        let half_size = self.half_size / 2.0;
+        let center = core::array::from_fn(|axis| {
+            let side = if index & (1 << axis) != 0 { 1.0 } else { -1.0 };
+            self.center[axis] + side * half_size
+        });
+        Self { center, half_size }
+    }
+}
+
+/// Represents a given octant (subdivision) of an octree
+pub struct OctreeNode {
+    boundary: CubeBox,
+
+    /// The indexes of the bodies stored in this octant
+    /// Empty unless this is a leaf node
+    referenced_indices: Vec<usize>,
+
+    /// The index of where the 8 children nodes start in the nodes vector
+    children_idx: usize,
+
+    /// Sum of the masses of the bodies living in this octant (including its children)
+    mass: f64,
+
+    /// Sum of the positions of these bodies weighted by their masses
+    weighted_position: [f64; 3],
+}
+
+impl OctreeNode {
+    fn new(boundary: CubeBox) -> Self {
+        Self {
+            boundary,
+            referenced_indices: Vec::with_capacity(DEFAULT_CAPACITY),
+            children_idx: 0,
+            mass: 0.0,
+            weighted_position: [0.0; 3],
+        }
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.children_idx == 0
+    }
+
+    pub fn referenced_indices(&self) -> &[usize] {
+        self.referenced_indices.as_slice()
+    }
+
+    pub fn boundary(&self) -> &CubeBox {
+        &self.boundary
+    }
+
+    pub fn children_idx(&self) -> usize {
+        self.children_idx
+    }
+
+    pub fn mass(&self) -> f64 {
+        self.mass
+    }
+
+    /// Where the bodies of the octant attract the others from, when seen from afar
+    /// (the center of the octant if it is empty)
+    pub fn center_of_mass(&self) -> [f64; 3] {
+        if self.mass > 0.0 {
+            self.weighted_position.map(|weighted| weighted / self.mass)
+        } else {
+            self.boundary.center
+        }
+    }
+
+    fn add_mass(&mut self, body: &Body3) {
+        self.mass += body.mass;
+        for (weighted, position) in self.weighted_position.iter_mut().zip(body.position) {
+            *weighted += body.mass * position;
+        }
+    }
+}
+
+pub struct Octree {
+    /// Maximum number of bodies stored in a given octant
+    /// (exceeded by the leaves at `max_depth`)
+    capacity: usize,
+
+    /// Depth of the deepest leaves
+    max_depth: usize,
+
+    /// The nodes of the tree, the root first
+    nodes: Vec<OctreeNode>,
+}
+
+impl Octree {
+    const ROOT_IDX: usize = 0;
+
+    pub fn new(boundary: CubeBox) -> Self {
+        Octree {
+            capacity: DEFAULT_CAPACITY,
+            max_depth: DEFAULT_MAX_DEPTH,
+            nodes: vec![OctreeNode::new(boundary)],
+        }
+    }
+
+    /// Builder method to set the capacity of the octree
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Builder method to set the maximum depth of the octree
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Clear the octree but maintain the capacity
+    pub fn clear(&mut self, boundary: CubeBox) {
+        self.nodes.clear();
+        self.nodes.push(OctreeNode::new(boundary));
+    }
+
+    /// Inserts a body in the octree provided its reference index
+    /// Returns true if the body was inserted in the tree
+    pub fn insert(&mut self, index: usize, bodies: &[Body3]) -> bool {
+        if !self.nodes[Self::ROOT_IDX]
+            .boundary
+            .contains(&bodies[index].position)
+        {
+            return false;
+        }
+        self.insert_unchecked(index, bodies);
+        true
+    }
+
+    /// Inserts a body in the octree provided its reference index
+    /// It does not check if the point is within the boundary of the root node
+    pub fn insert_unchecked(&mut self, index: usize, bodies: &[Body3]) {
+        let mut node_idx = Self::ROOT_IDX;
+        let mut depth = 0;
+        loop {
+            self.nodes[node_idx].add_mass(&bodies[index]);
+            if self.nodes[node_idx].is_leaf() {
+                let full = self.nodes[node_idx].referenced_indices.len() >= self.capacity;
+                if !full || depth >= self.max_depth {
+                    self.nodes[node_idx].referenced_indices.push(index);
+                    return;
+                }
+                self.subdivide(node_idx, bodies);
+            }
+            let octant = self.nodes[node_idx]
+                .boundary
+                .get_octant_unchecked(&bodies[index].position);
+            node_idx = self.nodes[node_idx].children_idx + octant;
+            depth += 1;
+        }
+    }
+
+    pub fn query_range(&self, boundary: CubeBox, bodies: &[Body3]) -> Vec<usize> {
+        let mut result = Vec::new();
+        let mut stack = vec![Self::ROOT_IDX];
+        while let Some(node_idx) = stack.pop() {
+            let node = &self.nodes[node_idx];
+            if !boundary.intersects(&node.boundary) {
+                continue;
+            }
+            if !node.is_leaf() {
+                stack.extend(node.children_idx..node.children_idx + 8);
+            } else if boundary.contains_box(&node.boundary) {
+                result.extend(node.referenced_indices());
+            } else {
+                result.extend(
+                    node.referenced_indices()
+                        .iter()
+                        .filter(|&&idx| boundary.contains(&bodies[idx].position)),
+                );
+            }
+        }
+        result
+    }
+
+    /// Returns the nodes of the octree
+    pub fn get_nodes(&self) -> &[OctreeNode] {
+        self.nodes.as_slice()
+    }
+}
+
+/// Private of the Octree
+impl Octree {
+    fn subdivide(&mut self, parent_idx: usize, bodies: &[Body3]) {
+        let first_child = self.nodes.len();
+        self.nodes[parent_idx].children_idx = first_child;
+        let boundary = self.nodes[parent_idx].boundary;
+        self.nodes
+            .extend((0..8).map(|octant| OctreeNode::new(boundary.octant(octant))));
+
+        for idx in core::mem::take(&mut self.nodes[parent_idx].referenced_indices) {
+            let child =
+                &mut self.nodes[first_child + boundary.get_octant_unchecked(&bodies[idx].position)];
+            child.referenced_indices.push(idx);
+            child.add_mass(&bodies[idx]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn octant_test() {
+        let cube = CubeBox::new([0.0, 0.0, 0.0], 1.0);
+        for octant in 0..8 {
+            let child = cube.octant(octant);
+            assert_eq!(child.half_size(), 0.5);
+            assert!(cube.contains_box(&child));
+            assert_eq!(cube.get_octant_unchecked(&child.center()), octant);
+        }
+        assert_eq!(cube.get_octant_unchecked(&[0.5, -0.5, 0.5]), 0b101);
+        assert!(!cube.contains(&[0.0, 0.0, 1.5]));
+    }
+
+    #[test]
+    fn octree_test() {
+        let cube = CubeBox::new([0.0, 0.0, 0.0], 1.0);
+        let mut octree = Octree::new(cube).with_capacity(1);
+        let bodies: Vec<_> = (0..8)
+            .map(|octant| Body3::default().with_position(cube.octant(octant).center()))
+            .collect();
+        (0..bodies.len()).for_each(|i| assert!(octree.insert(i, &bodies)));
+        assert!(!octree.insert(0, &[Body3::default().with_position([2.0, 0.0, 0.0])]));
+
+        let nodes = octree.get_nodes();
+        assert_eq!(nodes.len(), 9);
+        assert_eq!(nodes[0].mass(), 8.0);
+        assert_eq!(nodes[0].center_of_mass(), [0.0; 3]);
+        assert!(nodes[1..]
+            .iter()
+            .all(|node| node.is_leaf() && node.referenced_indices().len() == 1));
+
+        assert_eq!(octree.query_range(cube, &bodies).len(), 8);
+        let corner = CubeBox::new([0.5, 0.5, 0.5], 0.01);
+        assert_eq!(octree.query_range(corner, &bodies), [7]);
+    }
+}
@@ -1,20 +1,44 @@
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "wasm")]
 use tsify::Tsify;
 
 use crate::{
-    quadtree::{SquareBox, SquareQuadtree},
+    quadtree::{QuadTreeNode, SquareQuadtree},
+    storage::BodyStorage,
     SMALL,
 };
-use std::collections::{HashSet, VecDeque};
+use alloc::vec::Vec;
+// The test harness links std, which brings the float methods back
+#[cfg(not(any(feature = "std", test)))]
+use num_traits::Float;
 
-#[derive(Tsify, Serialize, Deserialize, Copy, Clone, Debug)]
-#[tsify(from_wasm_abi, into_wasm_abi)]
+/// Identifies a body for as long as it lives, assigned by the simulation it is added to
+/// (unlike its index, it does not change when other bodies are removed)
+#[derive(
+    Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord,
+)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+#[serde(transparent)]
+pub struct BodyId(pub u64);
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
 pub struct Body {
+    /// Overwritten when the body is added to a simulation
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub id: BodyId,
     pub position: [f64; 2],
     pub velocity: [f64; 2],
     pub mass: f64,
     pub radius: f64,
     pub color: [u8; 4], // rgba
+
+    /// Pulls the other bodies but never moves, as if of infinite inertia (e.g. a pinned star)
+    /// Its velocity is kept at zero
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub is_static: bool,
 }
 
 impl Body {
@@ -33,178 +57,1380 @@ impl Body {
         self
     }
 
+    pub fn with_static(mut self, is_static: bool) -> Self {
+        self.is_static = is_static;
+        self
+    }
+
     pub fn kinectic_energy(&self) -> f64 {
         0.5 * self.mass
             * (self.velocity[0] * self.velocity[0] + self.velocity[1] * self.velocity[1])
     }
+
+    pub fn speed(&self) -> f64 {
+        self.velocity[0].hypot(self.velocity[1])
+    }
 }
 
 impl Default for Body {
     fn default() -> Self {
         Body {
+            id: BodyId::default(),
             position: [0.0, 0.0],
             velocity: [0.0, 0.0],
             mass: 1.0,
             radius: 1.0,
             color: [255; 4],
+            is_static: false,
+        }
+    }
+}
+
+/// Body of the 3D simulation (see `simulation3d`)
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+pub struct Body3 {
+    /// Overwritten when the body is added to a simulation
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub id: BodyId,
+    pub position: [f64; 3],
+    pub velocity: [f64; 3],
+    pub mass: f64,
+    pub radius: f64,
+    pub color: [u8; 4], // rgba
+}
+
+impl Body3 {
+    pub fn with_mass(mut self, mass: f64) -> Self {
+        self.mass = mass;
+        self
+    }
+
+    pub fn with_position(mut self, position: [f64; 3]) -> Self {
+        self.position = position;
+        self
+    }
+
+    pub fn with_velocity(mut self, velocity: [f64; 3]) -> Self {
+        self.velocity = velocity;
+        self
+    }
+
+    pub fn kinectic_energy(&self) -> f64 {
+        0.5 * self.mass * self.velocity.iter().map(|v| v * v).sum::<f64>()
+    }
+}
+
+impl Default for Body3 {
+    fn default() -> Self {
+        Body3 {
+            id: BodyId::default(),
+            position: [0.0; 3],
+            velocity: [0.0; 3],
+            mass: 1.0,
+            radius: 1.0,
+            color: [255; 4],
+        }
+    }
+}
+
+/// A list of bodies crossing the JS boundary (wasm-bindgen cannot pass `Vec<Body>` directly)
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+#[serde(transparent)]
+pub struct Bodies(pub Vec<Body>);
+
+/// A collision resolved between two bodies during a step
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+#[serde(rename_all = "camelCase")]
+pub struct CollisionEvent {
+    pub ith: usize,
+    pub jth: usize,
+
+    /// Contact point between the two bodies
+    pub position: [f64; 2],
+
+    /// Relative speed along the contact normal right before the impact
+    pub impact_speed: f64,
+}
+
+/// How the contacts between bodies are resolved
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+#[serde(rename_all = "camelCase")]
+pub enum CollisionModel {
+    /// The bodies bounce off each other, as elastically as the restitution allows
+    #[default]
+    Elastic,
+
+    /// Perfectly inelastic: the bodies merge into one with their combined mass, momentum and
+    /// area (e.g. for planet formation)
+    Merge,
+}
+
+/// Massless particle moved by the gravity field of the bodies and the external forces
+/// without exerting any force nor colliding (e.g. to visualize the flow)
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+pub struct Tracer {
+    pub position: [f64; 2],
+    pub velocity: [f64; 2],
+}
+
+impl Tracer {
+    pub fn new(position: [f64; 2]) -> Self {
+        Tracer {
+            position,
+            velocity: [0.0, 0.0],
+        }
+    }
+
+    pub fn with_velocity(mut self, velocity: [f64; 2]) -> Self {
+        self.velocity = velocity;
+        self
+    }
+}
+
+/// Custom force added to the gravity of every body on each step
+/// (e.g. a force law experimented with from a script)
+pub trait ForceHook: Send {
+    /// Force on the body at the given physical time (seconds)
+    fn force(&self, body: &Body, time: f64) -> [f64; 2];
+}
+
+/// Force field acting on the bodies on top of their mutual gravity, applied on each step
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+#[serde(rename_all = "camelCase")]
+pub enum ExternalForce {
+    /// Same acceleration everywhere (e.g. the gravity near the surface of a planet)
+    Uniform { acceleration: [f64; 2] },
+
+    /// Inverse-square pull towards a fixed point, `strength` being the G * M of a mass there
+    /// (a negative strength pushes the bodies away)
+    Attractor { position: [f64; 2], strength: f64 },
+
+    /// Opposes the velocity, proportionally to the speed (e.g. a viscous medium)
+    LinearDrag { coefficient: f64 },
+
+    /// Opposes the velocity, proportionally to the squared speed (e.g. air resistance)
+    QuadraticDrag { coefficient: f64 },
+}
+
+impl ExternalForce {
+    /// Force on a body with that position, velocity and mass
+    pub fn force(&self, position: [f64; 2], velocity: [f64; 2], mass: f64) -> [f64; 2] {
+        match *self {
+            ExternalForce::Uniform { acceleration } => {
+                [mass * acceleration[0], mass * acceleration[1]]
+            }
+            ExternalForce::Attractor {
+                position: center,
+                strength,
+            } => {
+                let delta = [center[0] - position[0], center[1] - position[1]];
+                let distance_sqr = delta[0] * delta[0] + delta[1] * delta[1];
+                if distance_sqr < SMALL {
+                    return [0.0, 0.0];
+                }
+                let scale = strength * mass / (distance_sqr * distance_sqr.sqrt());
+                [scale * delta[0], scale * delta[1]]
+            }
+            ExternalForce::LinearDrag { coefficient } => {
+                [-coefficient * velocity[0], -coefficient * velocity[1]]
+            }
+            ExternalForce::QuadraticDrag { coefficient } => {
+                let speed = (velocity[0] * velocity[0] + velocity[1] * velocity[1]).sqrt();
+                [
+                    -coefficient * speed * velocity[0],
+                    -coefficient * speed * velocity[1],
+                ]
+            }
+        }
+    }
+
+    /// Whether the parameters are finite (and the drag coefficients positive)
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            ExternalForce::Uniform { acceleration } => acceleration.iter().all(|a| a.is_finite()),
+            ExternalForce::Attractor { position, strength } => {
+                position.iter().all(|p| p.is_finite()) && strength.is_finite()
+            }
+            ExternalForce::LinearDrag { coefficient }
+            | ExternalForce::QuadraticDrag { coefficient } => {
+                coefficient >= 0.0 && coefficient.is_finite()
+            }
         }
     }
 }
 
+/// Gravity field sampled at a point, per unit of mass
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+pub struct FieldSample {
+    /// Force on a unit mass placed at the point (i.e. its acceleration)
+    pub force: [f64; 2],
+
+    /// Potential energy of a unit mass placed at the point
+    pub potential: f64,
+}
+
 /// Compute the gravity forces on the i-th Body using the Barnes-Hut algorithm
+/// `stack` is the scratch buffer of the tree walk, reused across the calls
+#[allow(clippy::too_many_arguments)]
 pub fn compute_gravity_forces(
     ith_body: usize,
     forces: &mut [[f64; 2]],
-    bodies: &[Body],
+    bodies: &BodyStorage,
     qt: &SquareQuadtree,
     theta_sqr_threshold: f64,
     gravity_constant: f64,
+    softening_sqr: f64,
+    stack: &mut Vec<usize>,
 ) {
-    let body = &bodies[ith_body];
+    let force = gravity_force(
+        ith_body,
+        bodies,
+        qt,
+        theta_sqr_threshold,
+        gravity_constant,
+        softening_sqr,
+        stack,
+    );
+    forces[ith_body][0] += force[0];
+    forces[ith_body][1] += force[1];
+}
+
+/// Returns the gravity force on the i-th Body using the Barnes-Hut algorithm
+/// It only reads shared data, so it can be evaluated for several bodies in parallel
+/// (one `stack` per thread, the scratch buffer of the tree walk)
+pub fn gravity_force(
+    ith_body: usize,
+    bodies: &BodyStorage,
+    qt: &SquareQuadtree,
+    theta_sqr_threshold: f64,
+    gravity_constant: f64,
+    softening_sqr: f64,
+    stack: &mut Vec<usize>,
+) -> [f64; 2] {
+    let position = bodies.positions[ith_body];
     let qt_nodes = qt.get_nodes();
+    let mut force = [0.0, 0.0];
 
-    let mut stack: VecDeque<usize> = vec![0].into();
-    while let Some(node_idx) = stack.pop_front() {
+    stack.clear();
+    stack.push(0);
+    while let Some(node_idx) = stack.pop() {
         if qt_nodes[node_idx].is_leaf() {
             // Brute-force gravity computation
             for &nbr_body in qt_nodes[node_idx].referenced_indices() {
                 if nbr_body != ith_body {
                     // TODO: Can we make use of symmetry to avoid double computation?
-                    accumulate_gravity_force(ith_body, nbr_body, forces, bodies, gravity_constant);
+                    accumulate_gravity_force(
+                        ith_body,
+                        nbr_body,
+                        &mut force,
+                        bodies,
+                        gravity_constant,
+                        softening_sqr,
+                    );
                 }
             }
         } else {
-            let bdry = qt_nodes[node_idx].boundary();
-            let center = bdry.center();
-            let size = bdry.size();
-            let dx = center[0] - body.position[0];
-            let dy = center[1] - body.position[1];
+            let center = qt_nodes[node_idx].center_of_mass();
+            let size = qt_nodes[node_idx].boundary().size();
+            let dx = center[0] - position[0];
+            let dy = center[1] - position[1];
             let distance_sqr = dx * dx + dy * dy;
             if distance_sqr < SMALL {
                 continue;
             }
 
             if size * size / distance_sqr < theta_sqr_threshold {
-                let force = gravity_constant * bodies[ith_body].mass * qt_nodes[node_idx].mass()
-                    / distance_sqr;
-                let distance = distance_sqr.sqrt();
-                forces[ith_body][0] += force * dx / distance;
-                forces[ith_body][1] += force * dy / distance;
+                let softened_sqr = distance_sqr + softening_sqr;
+                let magnitude =
+                    gravity_constant * bodies.masses[ith_body] * qt_nodes[node_idx].mass()
+                        / softened_sqr;
+                let distance = softened_sqr.sqrt();
+                force[0] += magnitude * dx / distance;
+                force[1] += magnitude * dy / distance;
             } else {
                 let first_idx = qt_nodes[node_idx].children_idx();
                 stack.extend(first_idx..first_idx + 4);
             }
         }
     }
+    force
+}
+
+/// Samples the gravity field at a point that need not be a body
+/// (e.g. circular-orbit velocities of spawned bodies, vector-field overlays)
+/// Same Barnes-Hut approximation as for the bodies, `theta` being the opening threshold
+pub fn compute_force_at(
+    point: [f64; 2],
+    qt: &SquareQuadtree,
+    bodies: &BodyStorage,
+    theta: f64,
+    gravity_constant: f64,
+    softening: f64,
+) -> FieldSample {
+    let mut stack = Vec::new();
+    field_at_into(
+        point,
+        qt,
+        bodies,
+        theta * theta,
+        gravity_constant,
+        softening * softening,
+        &mut stack,
+    )
+}
+
+/// Same as `compute_force_at`, with the squared theta and reusing the stack of the tree walk
+pub(crate) fn field_at_into(
+    point: [f64; 2],
+    qt: &SquareQuadtree,
+    bodies: &BodyStorage,
+    theta_sqr_threshold: f64,
+    gravity_constant: f64,
+    softening_sqr: f64,
+    stack: &mut Vec<usize>,
+) -> FieldSample {
+    let qt_nodes = qt.get_nodes();
+    let mut sample = FieldSample::default();
+
+    stack.clear();
+    stack.push(0);
+    while let Some(node_idx) = stack.pop() {
+        let node = &qt_nodes[node_idx];
+        if node.is_leaf() {
+            for &nbr_body in node.referenced_indices() {
+                accumulate_field(
+                    point,
+                    bodies.positions[nbr_body],
+                    bodies.masses[nbr_body],
+                    gravity_constant,
+                    softening_sqr,
+                    &mut sample,
+                );
+            }
+            continue;
+        }
+        let center = node.center_of_mass();
+        let dx = center[0] - point[0];
+        let dy = center[1] - point[1];
+        let distance_sqr = dx * dx + dy * dy;
+        let size = node.boundary().size();
+        if distance_sqr >= SMALL && size * size / distance_sqr < theta_sqr_threshold {
+            accumulate_field(
+                point,
+                center,
+                node.mass(),
+                gravity_constant,
+                softening_sqr,
+                &mut sample,
+            );
+        } else {
+            let first_idx = node.children_idx();
+            stack.extend(first_idx..first_idx + 4);
+        }
+    }
+    sample
+}
+
+/// Accumulates the field at `point` due to a mass at `source`
+/// (nothing if they are on top of each other, without softening)
+#[inline(always)]
+fn accumulate_field(
+    point: [f64; 2],
+    source: [f64; 2],
+    mass: f64,
+    gravity_constant: f64,
+    softening_sqr: f64,
+    sample: &mut FieldSample,
+) {
+    let dx = source[0] - point[0];
+    let dy = source[1] - point[1];
+    let distance_sqr = dx * dx + dy * dy + softening_sqr;
+    if distance_sqr < SMALL {
+        return;
+    }
+    let distance = distance_sqr.sqrt();
+    let magnitude = gravity_constant * mass / distance_sqr;
+    sample.force[0] += magnitude * dx / distance;
+    sample.force[1] += magnitude * dy / distance;
+    sample.potential -= gravity_constant * mass / distance;
+}
+
+/// Scratch buffers of the gravity and collision passes
+///
+/// Kept from one step to the next so that, once they have grown to the size of the problem,
+/// the passes do not allocate anymore
+#[derive(Default)]
+pub struct ForceWorkspace {
+    /// Nodes left to visit during a tree walk
+    stack: Vec<usize>,
+
+    /// Forces on the bodies of the current leaf
+    leaf_forces: Vec<[f64; 2]>,
+
+    /// Bodies found by a range query
+    neighbours: Vec<usize>,
+
+    /// Bodies merged into another one during the collision pass
+    absorbed: Vec<bool>,
+}
+
+impl ForceWorkspace {
+    pub fn new() -> Self {
+        ForceWorkspace::default()
+    }
+}
+
+/// Returns the gravity forces on all the bodies of the tree (indexed like `bodies`)
+/// The tree is walked once per leaf instead of once per body:
+/// a node far enough from the whole leaf is approximated for all the bodies of the leaf at once
+/// Returns the potential energy of the bodies, summed along the way
+pub fn gravity_forces(
+    forces: &mut [[f64; 2]],
+    bodies: &BodyStorage,
+    qt: &SquareQuadtree,
+    theta_sqr_threshold: f64,
+    gravity_constant: f64,
+    softening_sqr: f64,
+    workspace: &mut ForceWorkspace,
+) -> f64 {
+    let mut potential = 0.0;
+    for leaf in qt.get_nodes().iter().filter(|node| node.is_leaf()) {
+        let (leaf_forces, leaf_potential) = leaf_gravity_forces(
+            leaf,
+            bodies,
+            qt,
+            theta_sqr_threshold,
+            gravity_constant,
+            softening_sqr,
+            workspace,
+        );
+        for (&i, force) in leaf.referenced_indices().iter().zip(leaf_forces) {
+            forces[i] = *force;
+        }
+        potential += leaf_potential;
+    }
+    // Every pair was counted from both of its bodies
+    0.5 * potential
+}
+
+/// Buffers of `parallel_gravity_forces`, kept from one step to the next
+#[cfg(feature = "parallel")]
+#[derive(Default)]
+pub(crate) struct LeafPass {
+    /// Indices of the leaves of the tree
+    leaves: Vec<usize>,
+
+    /// Forces on the bodies of each leaf in turn, in the order of their referenced indices
+    forces: Vec<[f64; 2]>,
+
+    /// Potential energy of the bodies of each leaf
+    potentials: Vec<f64>,
+}
+
+/// Leaves a task handles on its own rather than splitting them further
+#[cfg(feature = "parallel")]
+const LEAVES_PER_TASK: usize = 8;
+
+#[cfg(feature = "parallel")]
+std::thread_local! {
+    /// Walk stack and leaf forces of each thread of the pool
+    static LEAF_WORKSPACE: core::cell::RefCell<ForceWorkspace> = Default::default();
+}
+
+/// Same as `gravity_forces` with the leaves spread across the current rayon pool
+/// The leaves are split in halves recursively, each half writing into its own part of the
+/// buffers of `pass`, so that nothing is allocated once the buffers have grown
+/// The potentials are summed in the order of the leaves, as the serial pass does
+#[cfg(feature = "parallel")]
+pub(crate) fn parallel_gravity_forces(
+    forces: &mut [[f64; 2]],
+    bodies: &BodyStorage,
+    qt: &SquareQuadtree,
+    theta_sqr_threshold: f64,
+    gravity_constant: f64,
+    softening_sqr: f64,
+    pass: &mut LeafPass,
+) -> f64 {
+    let nodes = qt.get_nodes();
+    let LeafPass {
+        leaves,
+        forces: leaf_forces,
+        potentials,
+    } = pass;
+    leaves.clear();
+    leaves.extend((0..nodes.len()).filter(|&i| nodes[i].is_leaf()));
+    let len = leaves
+        .iter()
+        .map(|&leaf| nodes[leaf].referenced_indices().len())
+        .sum();
+    leaf_forces.clear();
+    leaf_forces.resize(len, [0.0, 0.0]);
+    potentials.clear();
+    potentials.resize(leaves.len(), 0.0);
+
+    let walk = LeafWalk {
+        bodies,
+        qt,
+        theta_sqr_threshold,
+        gravity_constant,
+        softening_sqr,
+    };
+    walk.run(leaves, leaf_forces, potentials);
+
+    let mut leaf_forces = leaf_forces.iter();
+    for &leaf in leaves.iter() {
+        for (&i, force) in nodes[leaf]
+            .referenced_indices()
+            .iter()
+            .zip(&mut leaf_forces)
+        {
+            forces[i] = *force;
+        }
+    }
+    // Every pair was counted from both of its bodies
+    0.5 * potentials.iter().sum::<f64>()
+}
+
+/// What the tasks of `parallel_gravity_forces` share
+#[cfg(feature = "parallel")]
+struct LeafWalk<'a> {
+    bodies: &'a BodyStorage,
+    qt: &'a SquareQuadtree,
+    theta_sqr_threshold: f64,
+    gravity_constant: f64,
+    softening_sqr: f64,
+}
+
+#[cfg(feature = "parallel")]
+impl LeafWalk<'_> {
+    fn run(&self, leaves: &[usize], forces: &mut [[f64; 2]], potentials: &mut [f64]) {
+        let nodes = self.qt.get_nodes();
+        if leaves.len() <= LEAVES_PER_TASK {
+            LEAF_WORKSPACE.with_borrow_mut(|workspace| {
+                let mut forces = forces;
+                for (&leaf, potential) in leaves.iter().zip(potentials) {
+                    let (leaf_forces, leaf_potential) = leaf_gravity_forces(
+                        &nodes[leaf],
+                        self.bodies,
+                        self.qt,
+                        self.theta_sqr_threshold,
+                        self.gravity_constant,
+                        self.softening_sqr,
+                        workspace,
+                    );
+                    let (head, tail) = forces.split_at_mut(leaf_forces.len());
+                    head.copy_from_slice(leaf_forces);
+                    *potential = leaf_potential;
+                    forces = tail;
+                }
+            });
+            return;
+        }
+        let (left, right) = leaves.split_at(leaves.len() / 2);
+        let left_len = left
+            .iter()
+            .map(|&leaf| nodes[leaf].referenced_indices().len())
+            .sum();
+        let (left_forces, right_forces) = forces.split_at_mut(left_len);
+        let (left_potentials, right_potentials) = potentials.split_at_mut(left.len());
+        rayon::join(
+            || self.run(left, left_forces, left_potentials),
+            || self.run(right, right_forces, right_potentials),
+        );
+    }
+}
+
+/// Gravity forces on the bodies of a leaf, in the order of its referenced indices
+/// (held by the workspace, shared between the leaves), and the sum of their potential energies
+pub(crate) fn leaf_gravity_forces<'w>(
+    leaf: &QuadTreeNode,
+    bodies: &BodyStorage,
+    qt: &SquareQuadtree,
+    theta_sqr_threshold: f64,
+    gravity_constant: f64,
+    softening_sqr: f64,
+    workspace: &'w mut ForceWorkspace,
+) -> (&'w [[f64; 2]], f64) {
+    let ForceWorkspace {
+        stack, leaf_forces, ..
+    } = workspace;
+    let group = leaf.referenced_indices();
+    leaf_forces.clear();
+    leaf_forces.resize(group.len(), [0.0, 0.0]);
+    let mut potential = 0.0;
+    if group.is_empty() {
+        return (leaf_forces, potential);
+    }
+    let leaf_box = leaf.boundary();
+    let qt_nodes = qt.get_nodes();
+
+    stack.clear();
+    stack.push(0);
+    while let Some(node_idx) = stack.pop() {
+        let node = &qt_nodes[node_idx];
+        if node.is_leaf() {
+            // Brute-force gravity computation
+            for (force, &ith_body) in leaf_forces.iter_mut().zip(group) {
+                cfg_if::cfg_if! {
+                    if #[cfg(feature = "simd")] {
+                        crate::simd::accumulate_gravity_forces(
+                            ith_body,
+                            node.referenced_indices(),
+                            force,
+                            &mut potential,
+                            bodies,
+                            gravity_constant,
+                            softening_sqr,
+                        );
+                    } else {
+                        for &nbr_body in node.referenced_indices() {
+                            if nbr_body != ith_body {
+                                potential += accumulate_gravity_force(
+                                    ith_body,
+                                    nbr_body,
+                                    force,
+                                    bodies,
+                                    gravity_constant,
+                                    softening_sqr,
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+            continue;
+        }
+
+        // Closest the node's center of mass can be to any body of the leaf
+        let center = node.center_of_mass();
+        let dx = center[0] - center[0].clamp(leaf_box.x_min(), leaf_box.x_max());
+        let dy = center[1] - center[1].clamp(leaf_box.y_min(), leaf_box.y_max());
+        let min_distance_sqr = dx * dx + dy * dy;
+        let size = node.boundary().size();
+        if min_distance_sqr < SMALL || size * size / min_distance_sqr >= theta_sqr_threshold {
+            let first_idx = node.children_idx();
+            stack.extend(first_idx..first_idx + 4);
+            continue;
+        }
+
+        for (force, &ith_body) in leaf_forces.iter_mut().zip(group) {
+            let dx = center[0] - bodies.positions[ith_body][0];
+            let dy = center[1] - bodies.positions[ith_body][1];
+            let distance_sqr = dx * dx + dy * dy + softening_sqr;
+            let magnitude = gravity_constant * bodies.masses[ith_body] * node.mass() / distance_sqr;
+            let distance = distance_sqr.sqrt();
+            force[0] += magnitude * dx / distance;
+            force[1] += magnitude * dy / distance;
+            potential += -(magnitude * distance);
+        }
+    }
+    (leaf_forces, potential)
 }
 
 /// Accumulates the gravity force on the i-th body due to the j-th body
+/// and returns their potential energy (G mi mj / r, with the same softening)
 /// It does not make use of symmetry as this cannot be mixed with Barnes-Hut
+/// The softening (Plummer) bounds the force between bodies that get very close
 #[inline(always)]
-fn accumulate_gravity_force(
+pub(crate) fn accumulate_gravity_force(
     ith: usize,
     jth: usize,
-    forces: &mut [[f64; 2]],
-    bodies: &[Body],
+    force: &mut [f64; 2],
+    bodies: &BodyStorage,
     gravity_constant: f64,
-) {
-    let dx = bodies[jth].position[0] - bodies[ith].position[0];
-    let dy = bodies[jth].position[1] - bodies[ith].position[1];
-    let distance_sqr = dx * dx + dy * dy;
+    softening_sqr: f64,
+) -> f64 {
+    let dx = bodies.positions[jth][0] - bodies.positions[ith][0];
+    let dy = bodies.positions[jth][1] - bodies.positions[ith][1];
+    let distance_sqr = dx * dx + dy * dy + softening_sqr;
     if distance_sqr < SMALL {
-        return;
+        return 0.0;
     }
 
-    let force = gravity_constant * bodies[ith].mass * bodies[jth].mass / distance_sqr;
+    let magnitude = gravity_constant * bodies.masses[ith] * bodies.masses[jth] / distance_sqr;
 
     let distance = distance_sqr.sqrt();
-    forces[ith][0] += force * dx / distance;
-    forces[ith][1] += force * dy / distance;
+    force[0] += magnitude * dx / distance;
+    force[1] += magnitude * dy / distance;
+    -(magnitude * distance)
 }
 
+/// Impulse-based resolution of a contact: conserves the momentum exactly,
+/// and the kinetic energy as far as the restitution allows
+/// Bodies not touching yet collide too if they would meet within `dt` (so that fast bodies
+/// cannot pass through each other between two steps)
 fn elastic_collission(
-    bodies: &mut [Body],
+    bodies: &mut BodyStorage,
     ith: usize,
     jth: usize,
-    colliding_bodies: &mut HashSet<usize>,
-) {
+    dt: f64,
+    restitution: f64,
+) -> Option<CollisionEvent> {
     let relative_position = [
-        bodies[jth].position[0] - bodies[ith].position[0],
-        bodies[jth].position[1] - bodies[ith].position[1],
+        bodies.positions[jth][0] - bodies.positions[ith][0],
+        bodies.positions[jth][1] - bodies.positions[ith][1],
+    ];
+    let relative_velocity = [
+        bodies.velocities[jth][0] - bodies.velocities[ith][0],
+        bodies.velocities[jth][1] - bodies.velocities[ith][1],
     ];
 
     let distance_sqr =
         relative_position[0] * relative_position[0] + relative_position[1] * relative_position[1];
 
-    let radii_sum = bodies[ith].radius + bodies[jth].radius;
-    if distance_sqr > radii_sum * radii_sum {
-        // Not colliding
-        return;
-    }
-    colliding_bodies.insert(ith);
-    colliding_bodies.insert(jth);
+    let radii_sum = bodies.radii[ith] + bodies.radii[jth];
 
-    let distance = distance_sqr.sqrt();
+    // A static body takes no share of the impulse nor of the separation
+    let inv_m_i = bodies.inverse_mass(ith);
+    let inv_m_j = bodies.inverse_mass(jth);
+    let inv_m_sum = inv_m_i + inv_m_j;
+    if inv_m_sum == 0.0 {
+        // Two static bodies stay where they are
+        return None;
+    }
 
-    let unit_delta_pos = [
-        relative_position[0] / distance,
-        relative_position[1] / distance,
-    ];
+    let (unit_delta_pos, time_of_impact) = if distance_sqr <= radii_sum * radii_sum {
+        let distance = distance_sqr.sqrt();
 
-    // Move the bodies so that they are just touching
-    bodies[jth].position[0] = bodies[ith].position[0] + unit_delta_pos[0] * radii_sum;
-    bodies[jth].position[1] = bodies[ith].position[1] + unit_delta_pos[1] * radii_sum;
+        // Bodies at the very same position are pushed apart along an arbitrary axis
+        let unit_delta_pos = if distance > 0.0 {
+            [
+                relative_position[0] / distance,
+                relative_position[1] / distance,
+            ]
+        } else {
+            [1.0, 0.0]
+        };
 
-    let relative_velocity = [
-        bodies[jth].velocity[0] - bodies[ith].velocity[0],
-        bodies[jth].velocity[1] - bodies[ith].velocity[1],
-    ];
+        // Move the bodies so that they are just touching, the lighter one the most
+        // (which leaves the center of mass in place)
+        let overlap = radii_sum - distance;
+        for (idx, share) in [(ith, -inv_m_i / inv_m_sum), (jth, inv_m_j / inv_m_sum)] {
+            bodies.positions[idx][0] += unit_delta_pos[0] * overlap * share;
+            bodies.positions[idx][1] += unit_delta_pos[1] * overlap * share;
+        }
+        (unit_delta_pos, 0.0)
+    } else {
+        // Not colliding, unless they meet during the step
+        let time = time_of_impact(relative_position, relative_velocity, radii_sum)
+            .filter(|&time| time <= dt)?;
+        let contact = [
+            relative_position[0] + relative_velocity[0] * time,
+            relative_position[1] + relative_velocity[1] * time,
+        ];
+        ([contact[0] / radii_sum, contact[1] / radii_sum], time)
+    };
 
     let impact_speed =
         relative_velocity[0] * unit_delta_pos[0] + relative_velocity[1] * unit_delta_pos[1];
 
-    if impact_speed > 0.0 {
+    if impact_speed >= 0.0 {
         // Not approaching
-        return;
+        return None;
     }
 
-    let m_i = bodies[ith].mass;
-    let m_j = bodies[jth].mass;
+    let position = [
+        bodies.positions[ith][0]
+            + bodies.velocities[ith][0] * time_of_impact
+            + unit_delta_pos[0] * bodies.radii[ith],
+        bodies.positions[ith][1]
+            + bodies.velocities[ith][1] * time_of_impact
+            + unit_delta_pos[1] * bodies.radii[ith],
+    ];
+
+    // Equal and opposite, along the contact normal
+    let impulse = -(1.0 + restitution) * impact_speed / inv_m_sum;
+
+    bodies.velocities[ith][0] -= unit_delta_pos[0] * impulse * inv_m_i;
+    bodies.velocities[ith][1] -= unit_delta_pos[1] * impulse * inv_m_i;
+
+    bodies.velocities[jth][0] += unit_delta_pos[0] * impulse * inv_m_j;
+    bodies.velocities[jth][1] += unit_delta_pos[1] * impulse * inv_m_j;
+
+    Some(CollisionEvent {
+        ith,
+        jth,
+        position,
+        impact_speed: -impact_speed,
+    })
+}
+
+/// Merges the jth body into the ith one if they touch, or would meet within `dt`
+/// The ith body keeps its id and color, at the center of mass of the pair with its momentum
+/// (unless it is static, then it stays in place at rest)
+/// The jth body is left as is, for the caller to remove
+fn merge_collision(
+    bodies: &mut BodyStorage,
+    ith: usize,
+    jth: usize,
+    dt: f64,
+) -> Option<CollisionEvent> {
+    let relative_position = [
+        bodies.positions[jth][0] - bodies.positions[ith][0],
+        bodies.positions[jth][1] - bodies.positions[ith][1],
+    ];
+    let relative_velocity = [
+        bodies.velocities[jth][0] - bodies.velocities[ith][0],
+        bodies.velocities[jth][1] - bodies.velocities[ith][1],
+    ];
+    let radii_sum = bodies.radii[ith] + bodies.radii[jth];
 
-    let impulse = 2.0 * impact_speed / (m_i + m_j);
+    let distance = relative_position[0].hypot(relative_position[1]);
+    let (unit_delta_pos, time_of_impact) = if distance <= radii_sum {
+        let unit_delta_pos = if distance > 0.0 {
+            [
+                relative_position[0] / distance,
+                relative_position[1] / distance,
+            ]
+        } else {
+            [1.0, 0.0]
+        };
+        (unit_delta_pos, 0.0)
+    } else {
+        let time = time_of_impact(relative_position, relative_velocity, radii_sum)
+            .filter(|&time| time <= dt)?;
+        let contact = [
+            relative_position[0] + relative_velocity[0] * time,
+            relative_position[1] + relative_velocity[1] * time,
+        ];
+        ([contact[0] / radii_sum, contact[1] / radii_sum], time)
+    };
 
-    let ke_start = bodies[ith].kinectic_energy() + bodies[jth].kinectic_energy();
+    // Overlapping bodies merge even if they are not approaching
+    let impact_speed =
+        relative_velocity[0] * unit_delta_pos[0] + relative_velocity[1] * unit_delta_pos[1];
+    let position = [
+        bodies.positions[ith][0]
+            + bodies.velocities[ith][0] * time_of_impact
+            + unit_delta_pos[0] * bodies.radii[ith],
+        bodies.positions[ith][1]
+            + bodies.velocities[ith][1] * time_of_impact
+            + unit_delta_pos[1] * bodies.radii[ith],
+    ];
 
-    bodies[ith].velocity[0] += unit_delta_pos[0] * impulse * m_j;
-    bodies[ith].velocity[1] += unit_delta_pos[1] * impulse * m_j;
+    let (other, mut body) = (bodies.body(jth), bodies.body(ith));
+    let mass = body.mass + other.mass;
+    if !body.is_static {
+        for axis in 0..2 {
+            body.position[axis] =
+                (body.mass * body.position[axis] + other.mass * other.position[axis]) / mass;
+            body.velocity[axis] =
+                (body.mass * body.velocity[axis] + other.mass * other.velocity[axis]) / mass;
+        }
+    }
+    body.mass = mass;
+    body.radius = body.radius.hypot(other.radius);
+    bodies.set(ith, body);
 
-    bodies[jth].velocity[0] -= unit_delta_pos[0] * impulse * m_i;
-    bodies[jth].velocity[1] -= unit_delta_pos[1] * impulse * m_i;
+    Some(CollisionEvent {
+        ith,
+        jth,
+        position,
+        impact_speed: f64::max(-impact_speed, 0.0),
+    })
+}
 
-    // Keep constant the kinetic energy
-    let curr_ke_jth = bodies[jth].kinectic_energy();
-    let correct_ke_jth = ke_start - bodies[ith].kinectic_energy();
-    let ratio = (correct_ke_jth / curr_ke_jth).sqrt();
-    bodies[jth].velocity[0] *= ratio;
-    bodies[jth].velocity[1] *= ratio;
+/// Time until two bodies apart and moving in straight lines are `radii_sum` apart
+/// None if they never get that close
+fn time_of_impact(
+    relative_position: [f64; 2],
+    relative_velocity: [f64; 2],
+    radii_sum: f64,
+) -> Option<f64> {
+    // |position + velocity * t| = radii_sum
+    let a =
+        relative_velocity[0] * relative_velocity[0] + relative_velocity[1] * relative_velocity[1];
+    let b = 2.0
+        * (relative_position[0] * relative_velocity[0]
+            + relative_position[1] * relative_velocity[1]);
+    let c = relative_position[0] * relative_position[0]
+        + relative_position[1] * relative_position[1]
+        - radii_sum * radii_sum;
+    let discriminant = b * b - 4.0 * a * c;
+    if b >= 0.0 || discriminant < 0.0 {
+        // Moving apart or missing each other
+        return None;
+    }
+    Some((-b - discriminant.sqrt()) / (2.0 * a))
 }
 
 /// Compute the collisions between the bodies
-/// Returns true if the bodies have been updated
-pub fn compute_collisions(bodies: &mut [Body], qt: &SquareQuadtree) {
-    let mut colliding_bodies: HashSet<usize> = HashSet::new();
+/// Every pair in contact, or meeting within `dt`, is resolved (once),
+/// so a body can take several contacts per step
+/// The resolved collisions are appended to `events`
+/// `restitution` is the ratio of the separating to the approaching speed of the elastic
+/// collisions (1 for perfectly elastic)
+/// With `CollisionModel::Merge`, the `jth` body of each event was merged into the `ith` one
+/// and is left for the caller to remove (it takes part in no other collision)
+pub fn compute_collisions(
+    bodies: &mut BodyStorage,
+    qt: &SquareQuadtree,
+    dt: f64,
+    model: CollisionModel,
+    restitution: f64,
+    events: &mut Vec<CollisionEvent>,
+    workspace: &mut ForceWorkspace,
+) {
+    // Large enough for the query of a body to find all the bodies it can meet during the step
+    // (swept by their speeds), so that each pair is checked from its lowest index only
+    let speed = |velocity: [f64; 2]| velocity[0].hypot(velocity[1]);
+    let max_radius = bodies.radii.iter().copied().fold(0.0, f64::max);
+    let max_speed = bodies
+        .velocities
+        .iter()
+        .map(|&velocity| speed(velocity))
+        .fold(0.0, f64::max);
 
+    let ForceWorkspace {
+        stack,
+        neighbours,
+        absorbed,
+        ..
+    } = workspace;
+    absorbed.clear();
+    absorbed.resize(bodies.len(), false);
     for ith_body in 0..bodies.len() {
-        if colliding_bodies.contains(&ith_body) {
+        if absorbed[ith_body] {
             continue;
         }
-        let boundary = SquareBox::new(bodies[ith_body].position, 4.0 * bodies[ith_body].radius);
-        let nbr_bodies = qt.query_range(boundary, bodies);
-        for &jth_body in nbr_bodies.iter() {
-            if ith_body == jth_body || colliding_bodies.contains(&jth_body) {
+        let reach = (speed(bodies.velocities[ith_body]) + max_speed) * dt;
+        neighbours.clear();
+        qt.query_radius_into(
+            bodies.positions[ith_body],
+            bodies.radii[ith_body] + max_radius + reach,
+            bodies,
+            stack,
+            neighbours,
+        );
+        for &jth_body in neighbours.iter() {
+            if jth_body <= ith_body || absorbed[jth_body] {
                 continue;
             }
-            elastic_collission(bodies, ith_body, jth_body, &mut colliding_bodies);
+            match model {
+                CollisionModel::Elastic => {
+                    events.extend(elastic_collission(
+                        bodies,
+                        ith_body,
+                        jth_body,
+                        dt,
+                        restitution,
+                    ));
+                }
+                CollisionModel::Merge => {
+                    // A static body absorbs the other one whatever their order,
+                    // two static bodies do not merge
+                    let (into, from) = if bodies.statics[jth_body] {
+                        (jth_body, ith_body)
+                    } else {
+                        (ith_body, jth_body)
+                    };
+                    if bodies.statics[from] {
+                        continue;
+                    }
+                    if let Some(event) = merge_collision(bodies, into, from, dt) {
+                        absorbed[from] = true;
+                        events.push(event);
+                        if from == ith_body {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quadtree::SquareBox;
+    use crate::scenarios::{Scenario, ScenarioKind};
+    use alloc::vec;
+
+    #[test]
+    fn batched_gravity_forces_test() {
+        let bodies = BodyStorage::from(
+            Scenario {
+                kind: ScenarioKind::Random {
+                    half_size: 500.0,
+                    max_speed: 0.0,
+                },
+                count: 300,
+                ..Scenario::default()
+            }
+            .generate(1.0),
+        );
+        let mut qt = SquareQuadtree::new(SquareBox::from_bodies(&bodies).padded(0.1));
+        (0..bodies.len()).for_each(|i| qt.insert_unchecked(i, &bodies));
+        assert!(qt.depth() > 1);
+
+        let forces = |theta: f64| {
+            let mut forces = vec![[0.0, 0.0]; bodies.len()];
+            let mut workspace = ForceWorkspace::new();
+            gravity_forces(
+                &mut forces,
+                &bodies,
+                &qt,
+                theta * theta,
+                1.0,
+                0.0,
+                &mut workspace,
+            );
+            forces
+        };
+        let mut stack = Vec::new();
+        let distance = |a: [f64; 2], b: [f64; 2]| (a[0] - b[0]).hypot(a[1] - b[1]);
+
+        // Same forces as the per-body walk when nothing is approximated
+        let exact = forces(0.0);
+        for (i, force) in exact.iter().enumerate() {
+            let expected = gravity_force(i, &bodies, &qt, 0.0, 1.0, 0.0, &mut stack);
+            assert!(distance(*force, expected) <= 1e-9 * expected[0].hypot(expected[1]));
+        }
+
+        // The leaf-wide opening criterion is stricter than the per-body one
+        let batched = forces(0.5);
+        let (mut batched_error, mut per_body_error) = (0.0, 0.0);
+        for (i, force) in exact.iter().enumerate() {
+            batched_error += distance(batched[i], *force);
+            per_body_error += distance(
+                gravity_force(i, &bodies, &qt, 0.25, 1.0, 0.0, &mut stack),
+                *force,
+            );
+        }
+        assert!(batched_error <= per_body_error);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_gravity_forces_test() {
+        let bodies = BodyStorage::from(
+            Scenario {
+                kind: ScenarioKind::Random {
+                    half_size: 500.0,
+                    max_speed: 0.0,
+                },
+                count: 300,
+                ..Scenario::default()
+            }
+            .generate(1.0),
+        );
+        let mut qt = SquareQuadtree::new(SquareBox::from_bodies(&bodies).padded(0.1));
+        (0..bodies.len()).for_each(|i| qt.insert_unchecked(i, &bodies));
+        let leaves = qt.get_nodes().iter().filter(|node| node.is_leaf()).count();
+        assert!(leaves > LEAVES_PER_TASK);
+
+        let mut serial = vec![[0.0, 0.0]; bodies.len()];
+        let serial_potential = gravity_forces(
+            &mut serial,
+            &bodies,
+            &qt,
+            0.25,
+            1.0,
+            0.0,
+            &mut ForceWorkspace::new(),
+        );
+
+        // Same results as the serial pass, also when the buffers are reused
+        let mut pass = LeafPass::default();
+        for _ in 0..2 {
+            let mut parallel = vec![[0.0, 0.0]; bodies.len()];
+            let potential =
+                parallel_gravity_forces(&mut parallel, &bodies, &qt, 0.25, 1.0, 0.0, &mut pass);
+            assert_eq!(parallel, serial);
+            assert_eq!(potential, serial_potential);
+        }
+        assert_eq!(pass.leaves.len(), leaves);
+    }
+
+    #[test]
+    fn center_of_mass_test() {
+        // A far cluster in the corner of its quadrant, approximated as a whole
+        let mut bodies = BodyStorage::from(vec![Body::default()]);
+        for i in 0..20 {
+            let offset = (i as f64 / 20.0) - 0.5;
+            bodies.push(Body::default().with_position([100.0 + offset, 90.0 - offset]));
         }
+        let mut qt = SquareQuadtree::new(SquareBox::new([50.0, 50.0], 60.0)).with_capacity(4);
+        (0..bodies.len()).for_each(|i| qt.insert_unchecked(i, &bodies));
+
+        let cluster = qt
+            .get_nodes()
+            .iter()
+            .find(|node| !node.is_leaf() && node.mass() == 20.0)
+            .unwrap();
+        let center = cluster.center_of_mass();
+        assert!((center[0] - 99.975).abs() < 1e-9 && (center[1] - 90.025).abs() < 1e-9);
+
+        let mut stack = Vec::new();
+        let exact = gravity_force(0, &bodies, &qt, 0.0, 1.0, 0.0, &mut stack);
+        let approximated = gravity_force(0, &bodies, &qt, 0.25, 1.0, 0.0, &mut stack);
+        let error = (approximated[0] - exact[0]).hypot(approximated[1] - exact[1]);
+        assert!(error / exact[0].hypot(exact[1]) < 1e-3);
+    }
+
+    #[test]
+    fn force_at_test() {
+        let bodies = BodyStorage::from(vec![
+            Body::default().with_mass(2.0),
+            Body::default().with_position([4.0, 0.0]).with_mass(0.5),
+        ]);
+        let mut qt = SquareQuadtree::new(SquareBox::from_bodies(&bodies).padded(0.1));
+        (0..bodies.len()).for_each(|i| qt.insert_unchecked(i, &bodies));
+
+        let sample = compute_force_at([2.0, 0.0], &qt, &bodies, 0.0, 1.0, 0.0);
+        assert_eq!(sample.force, [-0.5 + 0.125, 0.0]);
+        assert_eq!(sample.potential, -1.0 - 0.25);
+
+        // At a body, the field is the force on it per unit of mass (itself excluded)
+        let sample = compute_force_at(bodies.body(1).position, &qt, &bodies, 0.0, 1.0, 0.0);
+        let mut stack = Vec::new();
+        let force = gravity_force(1, &bodies, &qt, 0.0, 1.0, 0.0, &mut stack);
+        assert_eq!(sample.force, [force[0] / 0.5, force[1] / 0.5]);
+    }
+
+    #[test]
+    fn softening_test() {
+        let bodies = BodyStorage::from(vec![
+            Body::default(),
+            Body::default().with_position([3.0, 0.0]),
+        ]);
+        let mut qt = SquareQuadtree::new(SquareBox::from_bodies(&bodies).padded(0.1));
+        (0..bodies.len()).for_each(|i| qt.insert_unchecked(i, &bodies));
+
+        let mut stack = Vec::new();
+        // G m m r / (r^2 + softening^2)^(3/2)
+        let force = gravity_force(0, &bodies, &qt, 0.0, 1.0, 16.0, &mut stack);
+        assert!((force[0] - 3.0 / 125.0).abs() < 1e-12);
+        let sample = compute_force_at([0.0, 0.0], &qt, &bodies, 0.0, 1.0, 4.0);
+        assert!((sample.potential + 1.0 / 5.0 + 1.0 / 4.0).abs() < 1e-12);
+
+        // Bounded for bodies almost on top of each other, unlike the plain inverse square law
+        let bodies = BodyStorage::from(vec![
+            Body::default(),
+            Body::default().with_position([1e-2, 0.0]),
+        ]);
+        let mut qt = SquareQuadtree::new(SquareBox::from_bodies(&bodies).padded(0.1));
+        (0..bodies.len()).for_each(|i| qt.insert_unchecked(i, &bodies));
+        assert!(gravity_force(0, &bodies, &qt, 0.0, 1.0, 0.0, &mut stack)[0] > 1e3);
+        assert!(gravity_force(0, &bodies, &qt, 0.0, 1.0, 1.0, &mut stack)[0] < 1e-1);
+    }
+
+    #[test]
+    fn multiple_contacts_test() {
+        // A body at rest hit by three others at once
+        let mut bodies = BodyStorage::from(vec![Body::default()]);
+        for direction in [[1.0, 0.0], [-1.0, 0.0], [0.0, 1.0]] {
+            bodies.push(
+                Body::default()
+                    .with_position([1.9 * direction[0], 1.9 * direction[1]])
+                    .with_velocity([-direction[0], -direction[1]]),
+            );
+        }
+        let mut qt = SquareQuadtree::new(SquareBox::from_bodies(&bodies).padded(0.1));
+        (0..bodies.len()).for_each(|i| qt.insert_unchecked(i, &bodies));
+
+        let mut events = Vec::new();
+        compute_collisions(
+            &mut bodies,
+            &qt,
+            0.1,
+            CollisionModel::Elastic,
+            1.0,
+            &mut events,
+            &mut ForceWorkspace::new(),
+        );
+        let mut pairs: Vec<_> = events.iter().map(|event| (event.ith, event.jth)).collect();
+        pairs.sort();
+        assert_eq!(pairs, [(0, 1), (0, 2), (0, 3)]);
+    }
+
+    #[test]
+    fn collision_conservation_test() {
+        let momentum = |bodies: &BodyStorage| {
+            bodies.iter().fold([0.0, 0.0], |acc, body| {
+                [
+                    acc[0] + body.mass * body.velocity[0],
+                    acc[1] + body.mass * body.velocity[1],
+                ]
+            })
+        };
+        let energy = |bodies: &BodyStorage| bodies.kinetic_energy();
+
+        // Off-center impact between bodies of different masses
+        let mut bodies = BodyStorage::from(vec![
+            Body::default().with_velocity([1.0, 0.5]).with_mass(3.0),
+            Body::default()
+                .with_position([1.5, 0.8])
+                .with_velocity([-2.0, 0.0])
+                .with_mass(0.5),
+        ]);
+        let (momentum_start, energy_start) = (momentum(&bodies), energy(&bodies));
+        let event = elastic_collission(&mut bodies, 0, 1, 0.0, 1.0).unwrap();
+        assert!(event.impact_speed > 0.0);
+        let momentum_end = momentum(&bodies);
+        assert!((momentum_end[0] - momentum_start[0]).abs() < 1e-12);
+        assert!((momentum_end[1] - momentum_start[1]).abs() < 1e-12);
+        assert!((energy(&bodies) - energy_start).abs() < 1e-12);
+        // Separating now
+        assert!(elastic_collission(&mut bodies, 0, 1, 0.0, 1.0).is_none());
+
+        // Resting contact, even at the same position
+        for position in [[2.0, 0.0], [0.0, 0.0]] {
+            let mut bodies = BodyStorage::from(vec![
+                Body::default(),
+                Body::default().with_position(position),
+            ]);
+            assert!(elastic_collission(&mut bodies, 0, 1, 0.0, 1.0).is_none());
+            assert!(bodies
+                .iter()
+                .all(|body| body.position.iter().all(|x| x.is_finite())));
+            assert_eq!(bodies.body(1).position[0] - bodies.body(0).position[0], 2.0);
+        }
+    }
+
+    #[test]
+    fn restitution_test() {
+        // Head-on, the separating speed is the approaching one scaled by the restitution
+        for restitution in [0.0, 0.5, 1.0] {
+            let mut bodies = BodyStorage::from(vec![
+                Body::default().with_velocity([1.0, 0.0]),
+                Body::default()
+                    .with_position([2.0, 0.0])
+                    .with_velocity([-1.0, 0.0]),
+            ]);
+            elastic_collission(&mut bodies, 0, 1, 0.0, restitution).unwrap();
+            let separating_speed = bodies.body(1).velocity[0] - bodies.body(0).velocity[0];
+            assert!((separating_speed - 2.0 * restitution).abs() < 1e-12);
+            assert!((bodies.body(0).velocity[0] + bodies.body(1).velocity[0]).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn fast_collision_test() {
+        // Would be on the other side of the body at rest after a step
+        let mut bodies = BodyStorage::from(vec![
+            Body::default(),
+            Body::default()
+                .with_position([10.0, 0.5])
+                .with_velocity([-300.0, 0.0]),
+            Body::default().with_position([0.0, 50.0]),
+        ]);
+        let mut qt = SquareQuadtree::new(SquareBox::from_bodies(&bodies).padded(0.1));
+        (0..bodies.len()).for_each(|i| qt.insert_unchecked(i, &bodies));
+
+        let mut events = Vec::new();
+        let mut workspace = ForceWorkspace::new();
+        compute_collisions(
+            &mut bodies,
+            &qt,
+            0.01,
+            CollisionModel::Elastic,
+            1.0,
+            &mut events,
+            &mut workspace,
+        );
+        assert!(events.is_empty());
+
+        compute_collisions(
+            &mut bodies,
+            &qt,
+            0.1,
+            CollisionModel::Elastic,
+            1.0,
+            &mut events,
+            &mut workspace,
+        );
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].ith, events[0].jth), (0, 1));
+        assert!(bodies.body(1).velocity[0] > -300.0);
+        assert!(bodies.body(0).velocity[0] < 0.0);
+        // Not moved, they only meet later in the step
+        assert_eq!(bodies.body(1).position, [10.0, 0.5]);
+        // On the surface of the body at rest, facing the incoming one
+        let [x, y] = events[0].position;
+        assert!((x.hypot(y) - 1.0).abs() < 1e-9);
+        assert!(x > 0.9 && y > 0.0);
+    }
+
+    #[test]
+    fn merge_collision_test() {
+        // Two bodies in contact and a third one they only meet later
+        let mut bodies = BodyStorage::from(vec![
+            Body::default().with_velocity([1.0, 0.0]).with_mass(3.0),
+            Body::default()
+                .with_position([1.5, 0.0])
+                .with_velocity([-1.0, 2.0]),
+            Body::default().with_position([20.0, 0.0]),
+        ]);
+        let mass: f64 = bodies.iter().map(|body| body.mass).sum();
+        let mut qt = SquareQuadtree::new(SquareBox::from_bodies(&bodies).padded(0.1));
+        (0..bodies.len()).for_each(|i| qt.insert_unchecked(i, &bodies));
+
+        let mut events = Vec::new();
+        compute_collisions(
+            &mut bodies,
+            &qt,
+            0.1,
+            CollisionModel::Merge,
+            1.0,
+            &mut events,
+            &mut ForceWorkspace::new(),
+        );
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].ith, events[0].jth), (0, 1));
+
+        // Conserves the mass and the momentum, at the center of mass of the pair
+        let merged = bodies.body(0);
+        assert_eq!(merged.mass + bodies.body(2).mass, mass);
+        assert_eq!(merged.velocity, [0.5, 0.5]);
+        assert_eq!(merged.position, [0.375, 0.0]);
+        assert!((merged.radius - 2.0_f64.sqrt()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn static_collision_test() {
+        let collide = |model: CollisionModel| {
+            // The static body comes second, overlapping the moving one
+            let mut bodies = BodyStorage::from(vec![
+                Body::default().with_velocity([1.0, 0.0]),
+                Body::default().with_position([1.5, 0.0]).with_static(true),
+            ]);
+            let mut qt = SquareQuadtree::new(SquareBox::from_bodies(&bodies).padded(0.1));
+            (0..bodies.len()).for_each(|i| qt.insert_unchecked(i, &bodies));
+            let mut events = Vec::new();
+            compute_collisions(
+                &mut bodies,
+                &qt,
+                0.1,
+                model,
+                1.0,
+                &mut events,
+                &mut ForceWorkspace::new(),
+            );
+            (bodies, events)
+        };
+
+        // Bounces off the static body, which takes all of the separation
+        let (bodies, events) = collide(CollisionModel::Elastic);
+        assert_eq!(events.len(), 1);
+        assert_eq!(bodies.velocities(), [[-1.0, 0.0], [0.0, 0.0]]);
+        assert_eq!(bodies.positions(), [[-0.5, 0.0], [1.5, 0.0]]);
+
+        // Absorbed by the static body, which stays in place
+        let (bodies, events) = collide(CollisionModel::Merge);
+        assert_eq!((events[0].ith, events[0].jth), (1, 0));
+        let anchor = bodies.body(1);
+        assert_eq!(anchor.mass, 2.0);
+        assert_eq!(anchor.position, [1.5, 0.0]);
+        assert_eq!(anchor.velocity, [0.0, 0.0]);
     }
 }
@@ -3,13 +3,27 @@
 /// with the objective of evaluating a phyiscs simulation
 /// that computes both mechanical forces and collisions
 /// amont point particles
-use std::collections::VecDeque;
-
-use crate::physics::Body;
+use alloc::{
+    collections::{BinaryHeap, VecDeque},
+    vec,
+    vec::Vec,
+};
+use core::cmp::{Ordering, Reverse};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
+
+use crate::storage::BodyStorage;
 
 const DEFAULT_CAPACITY: usize = 32;
 
-#[derive(Debug, Clone, Copy)]
+/// Leaves this deep are not subdivided anymore, so that bodies at (nearly) the same position
+/// do not keep splitting them
+const DEFAULT_MAX_DEPTH: usize = 32;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+#[serde(rename_all = "camelCase")]
 pub struct SquareBox {
     /// The center of the square
     center: [f64; 2],
@@ -23,15 +37,13 @@ impl SquareBox {
         Self { center, half_size }
     }
 
-    pub fn from_bodies(bodies: &[Body]) -> Self {
-        let bbox: [f64; 4] =
-            bodies
-                .iter()
-                .fold([f64::MAX, f64::MIN, f64::MAX, f64::MIN], |acc, body| {
-                    let x = body.position[0];
-                    let y = body.position[1];
-                    [acc[0].min(x), acc[1].max(x), acc[2].min(y), acc[3].max(y)]
-                });
+    pub fn from_bodies(bodies: &BodyStorage) -> Self {
+        let bbox: [f64; 4] = bodies
+            .positions
+            .iter()
+            .fold([f64::MAX, f64::MIN, f64::MAX, f64::MIN], |acc, &[x, y]| {
+                [acc[0].min(x), acc[1].max(x), acc[2].min(y), acc[3].max(y)]
+            });
         Self {
             center: [(bbox[0] + bbox[1]) / 2.0, (bbox[2] + bbox[3]) / 2.0],
             half_size: (bbox[1] - bbox[0]).max(bbox[3] - bbox[2]) / 2.0,
@@ -63,6 +75,19 @@ impl SquareBox {
         self.center
     }
 
+    #[inline(always)]
+    pub fn half_size(&self) -> f64 {
+        self.half_size
+    }
+
+    /// Same center, with its size grown by the given fraction
+    pub fn padded(&self, padding: f64) -> Self {
+        SquareBox {
+            center: self.center,
+            half_size: self.half_size * (1.0 + padding.max(0.0)),
+        }
+    }
+
     #[inline(always)]
     pub fn size(&self) -> f64 {
         self.half_size * 2.0
@@ -84,6 +109,22 @@ impl SquareBox {
             && other.y_max() <= self.y_max()
     }
 
+    #[inline(always)]
+    pub fn intersects(&self, other: &SquareBox) -> bool {
+        self.x_min() <= other.x_max()
+            && other.x_min() <= self.x_max()
+            && self.y_min() <= other.y_max()
+            && other.y_min() <= self.y_max()
+    }
+
+    /// Squared distance from the point to the closest point of the square (0 inside)
+    pub fn distance_sqr_to(&self, point: &[f64; 2]) -> f64 {
+        let dx = (point[0] - self.center[0]).abs() - self.half_size;
+        let dy = (point[1] - self.center[1]).abs() - self.half_size;
+        let (dx, dy) = (dx.max(0.0), dy.max(0.0));
+        dx * dx + dy * dy
+    }
+
     /// Returns the quadrant of the square where the point is located
     /// It assumes the point is within the square !!!
     pub fn get_quadrant_unchecked(&self, point: &[f64; 2]) -> usize {
@@ -157,6 +198,9 @@ pub struct QuadTreeNode {
     /// (as in the sum of the masses of the bodies living in this quadrant including its children)
     /// This is done to optimize gravity force computation
     mass: f64,
+
+    /// Sum of the positions of these bodies weighted by their masses
+    weighted_position: [f64; 2],
 }
 
 impl QuadTreeNode {
@@ -166,6 +210,7 @@ impl QuadTreeNode {
             referenced_indices: Vec::with_capacity(DEFAULT_CAPACITY),
             children_idx: 0,
             mass: 0.0,
+            weighted_position: [0.0; 2],
         }
     }
 
@@ -188,13 +233,61 @@ impl QuadTreeNode {
     pub fn mass(&self) -> f64 {
         self.mass
     }
+
+    /// Where the bodies of the quadrant attract the others from, when seen from afar
+    /// (the center of the quadrant if it is empty)
+    pub fn center_of_mass(&self) -> [f64; 2] {
+        if self.mass > 0.0 {
+            self.weighted_position.map(|weighted| weighted / self.mass)
+        } else {
+            self.boundary.center()
+        }
+    }
+
+    fn add_mass(&mut self, bodies: &BodyStorage, index: usize) {
+        let mass = bodies.masses[index];
+        self.mass += mass;
+        self.weighted_position[0] += mass * bodies.positions[index][0];
+        self.weighted_position[1] += mass * bodies.positions[index][1];
+    }
+}
+
+/// Shape of a quadtree, e.g. to tune its capacity
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuadtreeStats {
+    pub nodes: usize,
+    pub leaves: usize,
+    pub depth: usize,
+
+    /// Most bodies stored in a single leaf
+    pub max_leaf_size: usize,
+
+    /// Insertions into a leaf at the maximum depth beyond its capacity
+    /// (non zero when bodies are too close to be separated)
+    pub overflows: usize,
 }
 
 /// Represents a quadtree data structure
 pub struct SquareQuadtree {
     /// Maximum number of nodes stored in a given quadrant
+    /// (exceeded by the leaves at `max_depth`)
     capacity: usize,
 
+    /// Depth of the deepest leaves
+    max_depth: usize,
+
+    /// Insertions beyond the capacity of a leaf since the last `clear`
+    overflows: usize,
+
+    /// Bodies inserted since the last `clear`
+    len: usize,
+
+    /// Bodies moved to another leaf by `relocate` since the last `clear`
+    relocations: usize,
+
+    /// Bodies that left their leaf, scratch of `relocate`
+    moved: Vec<usize>,
+
     /// The nodes of the tree (including the root node)
     /// storing the different subdivisions of the tree
     nodes: Vec<QuadTreeNode>,
@@ -208,6 +301,11 @@ impl SquareQuadtree {
         let root = QuadTreeNode::new(boundary);
         SquareQuadtree {
             capacity: DEFAULT_CAPACITY,
+            max_depth: DEFAULT_MAX_DEPTH,
+            overflows: 0,
+            len: 0,
+            relocations: 0,
+            moved: Vec::new(),
             nodes: vec![root],
         }
     }
@@ -218,18 +316,110 @@ impl SquareQuadtree {
         self
     }
 
+    /// Builder method to set the maximum depth of the quadtree
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
     /// Clear the quadtree but maintain the capacity
     pub fn clear(&mut self, boundary: SquareBox) {
         self.nodes.clear(); // but maintain the capacity
         self.nodes.push(QuadTreeNode::new(boundary));
+        self.overflows = 0;
+        self.len = 0;
+        self.relocations = 0;
+    }
+
+    /// Number of bodies in the tree, the first ones of the slice it was built from
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Bodies moved to another leaf since the last `clear`
+    /// (the emptied nodes are never merged back, so the tree degrades as they grow)
+    pub fn relocations(&self) -> usize {
+        self.relocations
+    }
+
+    /// Inserts the bodies of the given indices, skipping those outside of the root
+    /// Returns the number of bodies inserted
+    pub fn insert_batch(
+        &mut self,
+        indices: impl IntoIterator<Item = usize>,
+        bodies: &BodyStorage,
+    ) -> usize {
+        indices
+            .into_iter()
+            .filter(|&index| self.insert(index, bodies))
+            .count()
+    }
+
+    /// Brings the tree up to date with the bodies it holds, after they moved or changed mass,
+    /// without rebuilding it: only the bodies that left their leaf are moved to their new one
+    /// Returns false, leaving the tree as it was, if one of them left the root
+    /// (the tree must then be rebuilt)
+    pub fn relocate(&mut self, bodies: &BodyStorage) -> bool {
+        let root = self.nodes[Self::ROOT_IDX].boundary;
+        if !bodies.positions[..self.len]
+            .iter()
+            .all(|position| root.contains(position))
+        {
+            return false;
+        }
+
+        let mut moved = core::mem::take(&mut self.moved);
+        moved.clear();
+        for node in self.nodes.iter_mut().filter(|node| node.is_leaf()) {
+            let boundary = node.boundary;
+            node.referenced_indices.retain(|&index| {
+                let stays = boundary.contains(&bodies.positions[index]);
+                if !stays {
+                    moved.push(index);
+                }
+                stays
+            });
+        }
+        for &index in &moved {
+            self.place(index, bodies);
+        }
+        self.relocations += moved.len();
+        self.moved = moved;
+
+        // Children are stored after their parent: the masses are summed up from the leaves
+        for node_idx in (0..self.nodes.len()).rev() {
+            let (parent, children) = self.nodes.split_at_mut(node_idx + 1);
+            let node = &mut parent[node_idx];
+            node.mass = 0.0;
+            node.weighted_position = [0.0; 2];
+            if node.is_leaf() {
+                let indices = core::mem::take(&mut node.referenced_indices);
+                indices
+                    .iter()
+                    .for_each(|&index| node.add_mass(bodies, index));
+                node.referenced_indices = indices;
+            } else {
+                let first_idx = node.children_idx - node_idx - 1;
+                for child in &children[first_idx..first_idx + 4] {
+                    node.mass += child.mass;
+                    node.weighted_position[0] += child.weighted_position[0];
+                    node.weighted_position[1] += child.weighted_position[1];
+                }
+            }
+        }
+        true
     }
 
     /// Inserts a body in the quadtree provided its reference index
     /// Returns true if the body was inserted in the tree
-    pub fn insert(&mut self, index: usize, bodies: &[Body]) -> bool {
+    pub fn insert(&mut self, index: usize, bodies: &BodyStorage) -> bool {
         if !self.nodes[Self::ROOT_IDX]
             .boundary
-            .contains(&bodies[index].position)
+            .contains(&bodies.positions[index])
         {
             return false;
         }
@@ -239,64 +429,133 @@ impl SquareQuadtree {
 
     /// Inserts a body in the quadtree provided its reference index
     /// It does not check if the point is within the boundary of the root node
-    pub fn insert_unchecked(&mut self, index: usize, bodies: &[Body]) {
-        // Breadth-first search to find the leaf node where the point should be inserted
-        let mut deque: VecDeque<usize> = vec![Self::ROOT_IDX].into();
-        while let Some(node_idx) = deque.pop_front() {
-            self.nodes[node_idx].mass += bodies[index].mass;
-            if self.nodes[node_idx].is_leaf() {
-                if self.nodes[node_idx].referenced_indices.len() < self.capacity {
-                    self.nodes[node_idx].referenced_indices.push(index);
-                    return;
-                } else {
-                    // Node's capacity limit reached
-                    self.subdivide(node_idx, bodies);
-                }
-            }
-            let first_idx = self.nodes[node_idx].children_idx;
-            let quadrant = self.nodes[node_idx]
-                .boundary
-                .get_quadrant_unchecked(&bodies[index].position);
-            deque.push_back(first_idx + quadrant);
-        }
+    pub fn insert_unchecked(&mut self, index: usize, bodies: &BodyStorage) {
+        self.len += 1;
+        self.place(index, bodies);
     }
 
-    pub fn query_range(&self, boundary: SquareBox, bodies: &[Body]) -> Vec<usize> {
+    pub fn query_range(&self, boundary: SquareBox, bodies: &BodyStorage) -> Vec<usize> {
         let mut result = Vec::new();
-        let mut deque: VecDeque<usize> = vec![Self::ROOT_IDX].into();
-        while let Some(node_idx) = deque.pop_front() {
+        self.query_range_into(boundary, bodies, &mut Vec::new(), &mut result);
+        result
+    }
+
+    /// Same as `query_range` but appends the indices to `result`, with `stack` as scratch
+    /// (neither allocates once they have grown)
+    pub fn query_range_into(
+        &self,
+        boundary: SquareBox,
+        bodies: &BodyStorage,
+        stack: &mut Vec<usize>,
+        result: &mut Vec<usize>,
+    ) {
+        stack.clear();
+        stack.push(Self::ROOT_IDX);
+        while let Some(node_idx) = stack.pop() {
+            let node = &self.nodes[node_idx];
+            if !boundary.intersects(&node.boundary) {
+                continue;
+            }
+            if !node.is_leaf() {
+                let first_idx = node.children_idx;
+                stack.extend(first_idx..first_idx + 4);
             // Fast-Path: query boundary wraps around this quadtree division
-            if boundary.contains_box(&self.nodes[node_idx].boundary) {
-                if self.nodes[node_idx].is_leaf() {
-                    result.extend(self.nodes[node_idx].referenced_indices());
-                } else {
-                    let first_idx = self.nodes[node_idx].children_idx;
-                    let mut deque: VecDeque<usize> =
-                        Vec::from_iter(first_idx..first_idx + 4).into();
-                    while let Some(child_idx) = deque.pop_front() {
-                        if self.nodes[child_idx].is_leaf() {
-                            result.extend(self.nodes[child_idx].referenced_indices());
-                        } else {
-                            let first_idx = self.nodes[child_idx].children_idx;
-                            deque.extend(first_idx..first_idx + 4);
-                        }
-                    }
-                }
+            } else if boundary.contains_box(&node.boundary) {
+                result.extend(node.referenced_indices());
             // Slow-Path: Brute-force check (boundaries intersection)
-            } else if self.nodes[node_idx].is_leaf() {
-                for &idx in self.nodes[node_idx].referenced_indices() {
-                    if boundary.contains(&bodies[idx].position) {
+            } else {
+                for &idx in node.referenced_indices() {
+                    if boundary.contains(&bodies.positions[idx]) {
                         result.push(idx);
                     }
                 }
-            } else {
-                let first_idx = self.nodes[node_idx].children_idx;
-                deque.extend(first_idx..first_idx + 4);
             }
         }
+    }
+
+    /// Indices of the bodies within `radius` of `center`, the closest first
+    pub fn query_radius(&self, center: [f64; 2], radius: f64, bodies: &BodyStorage) -> Vec<usize> {
+        let mut result = Vec::new();
+        self.query_radius_into(center, radius, bodies, &mut Vec::new(), &mut result);
+        result.sort_by(|&i, &j| {
+            distance_sqr(&bodies.positions[i], &center)
+                .total_cmp(&distance_sqr(&bodies.positions[j], &center))
+        });
         result
     }
 
+    /// Same as `query_radius` but appends the indices to `result` in no particular order,
+    /// with `stack` as scratch (neither allocates once they have grown)
+    pub fn query_radius_into(
+        &self,
+        center: [f64; 2],
+        radius: f64,
+        bodies: &BodyStorage,
+        stack: &mut Vec<usize>,
+        result: &mut Vec<usize>,
+    ) {
+        let radius_sqr = radius * radius;
+        stack.clear();
+        stack.push(Self::ROOT_IDX);
+        while let Some(node_idx) = stack.pop() {
+            let node = &self.nodes[node_idx];
+            if node.boundary.distance_sqr_to(&center) > radius_sqr {
+                continue;
+            }
+            if node.is_leaf() {
+                result.extend(
+                    node.referenced_indices().iter().filter(|&&idx| {
+                        distance_sqr(&bodies.positions[idx], &center) <= radius_sqr
+                    }),
+                );
+            } else {
+                let first_idx = node.children_idx;
+                stack.extend(first_idx..first_idx + 4);
+            }
+        }
+    }
+
+    /// Indices of the (up to) `k` bodies closest to `point`, the closest first
+    /// Best-first walk: the nodes are visited by increasing distance to the point
+    /// until none can hold a body closer than the k-th found so far
+    pub fn nearest_neighbors(&self, point: [f64; 2], k: usize, bodies: &BodyStorage) -> Vec<usize> {
+        if k == 0 {
+            return Vec::new();
+        }
+        // Nodes to visit, the closest on top
+        let mut nodes = BinaryHeap::from([Reverse(ByDistance(0.0, Self::ROOT_IDX))]);
+        // The k closest bodies found so far, the farthest on top
+        let mut nearest: BinaryHeap<ByDistance> = BinaryHeap::with_capacity(k + 1);
+        while let Some(Reverse(ByDistance(node_distance_sqr, node_idx))) = nodes.pop() {
+            if nearest.len() == k && nearest.peek().is_some_and(|far| far.0 <= node_distance_sqr) {
+                break;
+            }
+            let node = &self.nodes[node_idx];
+            if node.is_leaf() {
+                for &idx in node.referenced_indices() {
+                    nearest.push(ByDistance(
+                        distance_sqr(&bodies.positions[idx], &point),
+                        idx,
+                    ));
+                    if nearest.len() > k {
+                        nearest.pop();
+                    }
+                }
+            } else {
+                let first_idx = node.children_idx;
+                nodes.extend((first_idx..first_idx + 4).map(|child| {
+                    let distance_sqr = self.nodes[child].boundary.distance_sqr_to(&point);
+                    Reverse(ByDistance(distance_sqr, child))
+                }));
+            }
+        }
+        nearest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|ByDistance(_, idx)| idx)
+            .collect()
+    }
+
     /// Returns the nodes of the quadtree
     pub fn get_nodes(&self) -> &[QuadTreeNode] {
         self.nodes.as_slice()
@@ -317,34 +576,97 @@ impl SquareQuadtree {
         }
         curr_depth
     }
+
+    pub fn stats(&self) -> QuadtreeStats {
+        let leaves = self.nodes.iter().filter(|node| node.is_leaf());
+        QuadtreeStats {
+            nodes: self.nodes.len(),
+            leaves: leaves.clone().count(),
+            depth: self.depth(),
+            max_leaf_size: leaves
+                .map(|node| node.referenced_indices.len())
+                .max()
+                .unwrap_or(0),
+            overflows: self.overflows,
+        }
+    }
+}
+
+fn distance_sqr(a: &[f64; 2], b: &[f64; 2]) -> f64 {
+    let (dx, dy) = (a[0] - b[0], a[1] - b[1]);
+    dx * dx + dy * dy
+}
+
+/// An index (of a body or a node) ordered by its squared distance, then by itself
+#[derive(PartialEq)]
+struct ByDistance(f64, usize);
+
+impl Eq for ByDistance {}
+
+impl PartialOrd for ByDistance {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ByDistance {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+    }
 }
 
 /// Private of the SquareQuadtree
 impl SquareQuadtree {
-    fn subdivide(&mut self, parent_idx: usize, bodies: &[Body]) {
+    /// Adds the body to the masses down to its leaf, and to the leaf
+    fn place(&mut self, index: usize, bodies: &BodyStorage) {
+        // Breadth-first search to find the leaf node where the point should be inserted
+        let mut deque: VecDeque<(usize, usize)> = vec![(Self::ROOT_IDX, 0)].into();
+        while let Some((node_idx, depth)) = deque.pop_front() {
+            self.nodes[node_idx].add_mass(bodies, index);
+            if self.nodes[node_idx].is_leaf() {
+                let full = self.nodes[node_idx].referenced_indices.len() >= self.capacity;
+                if !full || depth >= self.max_depth {
+                    // Past the maximum depth the leaf overflows instead
+                    self.overflows += usize::from(full);
+                    self.nodes[node_idx].referenced_indices.push(index);
+                    return;
+                } else {
+                    // Node's capacity limit reached
+                    self.subdivide(node_idx, bodies);
+                }
+            }
+            let first_idx = self.nodes[node_idx].children_idx;
+            let quadrant = self.nodes[node_idx]
+                .boundary
+                .get_quadrant_unchecked(&bodies.positions[index]);
+            deque.push_back((first_idx + quadrant, depth + 1));
+        }
+    }
+
+    fn subdivide(&mut self, parent_idx: usize, bodies: &BodyStorage) {
         self.nodes[parent_idx].children_idx = self.nodes.len();
 
-        // Create the 4 children nodes
-        let nw = self.nodes[parent_idx].boundary.north_west();
+        // Create the 4 children nodes, in the order of `get_quadrant_unchecked`
         let ne = self.nodes[parent_idx].boundary.north_east();
+        let nw = self.nodes[parent_idx].boundary.north_west();
         let sw = self.nodes[parent_idx].boundary.south_west();
         let se = self.nodes[parent_idx].boundary.south_east();
 
-        self.nodes.push(QuadTreeNode::new(nw));
         self.nodes.push(QuadTreeNode::new(ne));
+        self.nodes.push(QuadTreeNode::new(nw));
         self.nodes.push(QuadTreeNode::new(sw));
         self.nodes.push(QuadTreeNode::new(se));
 
         // Now transfer the referenced indexes to the new leaf nodes
         let first_child = self.nodes[parent_idx].children_idx;
-        for idx in std::mem::take(&mut self.nodes[parent_idx].referenced_indices) {
+        for idx in core::mem::take(&mut self.nodes[parent_idx].referenced_indices) {
             let quadrant = self.nodes[parent_idx]
                 .boundary
-                .get_quadrant_unchecked(&bodies[idx].position);
+                .get_quadrant_unchecked(&bodies.positions[idx]);
             self.nodes[first_child + quadrant]
                 .referenced_indices
                 .push(idx);
-            self.nodes[first_child + quadrant].mass += bodies[idx].mass;
+            self.nodes[first_child + quadrant].add_mass(bodies, idx);
         }
     }
 }
@@ -353,6 +675,7 @@ impl SquareQuadtree {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::physics::Body;
 
     #[test]
     fn test_square_box() {
@@ -390,13 +713,14 @@ mod tests {
             half_size: 1.0,
         };
         let mut quadtree = SquareQuadtree::new(boundary).with_capacity(2);
-        let bodies = vec![
+        let bodies: BodyStorage = [
             Body {
                 position: [0.5, 0.5],
                 mass: 1.0,
                 velocity: [0.0, 0.0],
                 radius: 1.0,
                 color: [255; 4],
+                ..Body::default()
             },
             Body {
                 position: [-0.5, 0.5],
@@ -404,6 +728,7 @@ mod tests {
                 velocity: [0.0, 0.0],
                 radius: 1.0,
                 color: [255; 4],
+                ..Body::default()
             },
             Body {
                 position: [-0.5, -0.5],
@@ -411,6 +736,7 @@ mod tests {
                 velocity: [0.0, 0.0],
                 radius: 1.0,
                 color: [255; 4],
+                ..Body::default()
             },
             Body {
                 position: [0.5, -0.5],
@@ -418,8 +744,11 @@ mod tests {
                 velocity: [0.0, 0.0],
                 radius: 1.0,
                 color: [255; 4],
+                ..Body::default()
             },
-        ];
+        ]
+        .into_iter()
+        .collect();
 
         for i in 0..bodies.len() {
             quadtree.insert_unchecked(i, &bodies);
@@ -432,11 +761,13 @@ mod tests {
         assert_eq!(root.referenced_indices.len(), 0);
         assert_eq!(root.mass, 4.0);
 
-        let nw = &nodes[1];
-        assert_eq!(nw.referenced_indices.len(), 1);
-
-        let ne = &nodes[2];
+        let ne = &nodes[1];
         assert_eq!(ne.referenced_indices.len(), 1);
+        assert!(ne.boundary.contains(&bodies.positions[0]));
+
+        let nw = &nodes[2];
+        assert_eq!(nw.referenced_indices.len(), 1);
+        assert!(nw.boundary.contains(&bodies.positions[1]));
 
         let sw = &nodes[3];
         assert_eq!(sw.referenced_indices.len(), 1);
@@ -459,4 +790,108 @@ mod tests {
         let result = quadtree.query_range(boundary, &bodies);
         assert_eq!(result.len(), 1);
     }
+
+    #[test]
+    fn leaf_overflow_test() {
+        let boundary = SquareBox::new([0.0, 0.0], 1.0);
+        let mut quadtree = SquareQuadtree::new(boundary)
+            .with_capacity(2)
+            .with_max_depth(8);
+        // Inseparable bodies, plus one elsewhere
+        let mut bodies: BodyStorage = vec![Body::default().with_position([0.25, 0.25]); 50].into();
+        bodies.push(Body::default().with_position([-0.5, -0.5]));
+        (0..bodies.len()).for_each(|i| quadtree.insert_unchecked(i, &bodies));
+
+        let stats = quadtree.stats();
+        assert_eq!(stats.depth, 8);
+        assert_eq!(stats.max_leaf_size, 50);
+        assert_eq!(stats.overflows, 48);
+        assert_eq!(stats.nodes, 1 + 4 * 8);
+        assert_eq!(quadtree.get_nodes()[0].mass(), 51.0);
+        assert_eq!(quadtree.query_range(boundary, &bodies).len(), 51);
+
+        quadtree.clear(boundary);
+        assert_eq!(quadtree.stats().overflows, 0);
+    }
+
+    #[test]
+    fn relocate_test() {
+        let boundary = SquareBox::new([0.0, 0.0], 10.0);
+        let mut bodies: BodyStorage = (0..40)
+            .map(|i| {
+                Body::default()
+                    .with_position([(i % 8) as f64 - 4.0, (i / 8) as f64 - 2.0])
+                    .with_mass(1.0 + i as f64)
+            })
+            .collect();
+        let mut quadtree = SquareQuadtree::new(boundary).with_capacity(4);
+        assert_eq!(quadtree.insert_batch(0..30, &bodies), 30);
+        assert_eq!(quadtree.len(), 30);
+
+        // Moved around, one of them heavier, and the rest added
+        bodies.positions[0] = [9.0, 9.0];
+        bodies.positions[3] = [-7.5, 6.0];
+        bodies.masses[12] = 100.0;
+        assert!(quadtree.relocate(&bodies));
+        assert_eq!(quadtree.relocations(), 2);
+        assert_eq!(quadtree.insert_batch(30..40, &bodies), 10);
+
+        let mut rebuilt = SquareQuadtree::new(boundary).with_capacity(4);
+        rebuilt.insert_batch(0..bodies.len(), &bodies);
+        assert_eq!(quadtree.len(), rebuilt.len());
+        assert_eq!(
+            quadtree.get_nodes()[0].mass(),
+            rebuilt.get_nodes()[0].mass()
+        );
+        let [x, y] = quadtree.get_nodes()[0].center_of_mass();
+        let [rebuilt_x, rebuilt_y] = rebuilt.get_nodes()[0].center_of_mass();
+        assert!((x - rebuilt_x).abs() < 1e-12 && (y - rebuilt_y).abs() < 1e-12);
+        let corner = SquareBox::new([7.5, 7.5], 2.5);
+        assert_eq!(quadtree.query_range(corner, &bodies), [0]);
+        let mut everything = quadtree.query_range(boundary, &bodies);
+        everything.sort_unstable();
+        assert_eq!(everything, (0..40).collect::<Vec<_>>());
+
+        // Out of the root, left to the caller to rebuild
+        bodies.positions[5] = [20.0, 0.0];
+        assert!(!quadtree.relocate(&bodies));
+        quadtree.clear(boundary);
+        assert!(quadtree.is_empty());
+        assert_eq!(quadtree.relocations(), 0);
+    }
+
+    #[test]
+    fn nearest_neighbors_test() {
+        // A grid of bodies, 1 apart
+        let bodies: BodyStorage = (0..100)
+            .map(|i| Body::default().with_position([(i % 10) as f64, (i / 10) as f64]))
+            .collect();
+        let mut quadtree =
+            SquareQuadtree::new(SquareBox::from_bodies(&bodies).padded(0.1)).with_capacity(4);
+        (0..bodies.len()).for_each(|i| quadtree.insert_unchecked(i, &bodies));
+
+        // Brute force, for reference
+        let point = [3.2, 4.4];
+        let mut by_distance: Vec<_> = (0..bodies.len()).collect();
+        by_distance.sort_by(|&i, &j| {
+            distance_sqr(&bodies.positions[i], &point)
+                .total_cmp(&distance_sqr(&bodies.positions[j], &point))
+        });
+
+        assert_eq!(
+            quadtree.nearest_neighbors(point, 5, &bodies),
+            by_distance[..5]
+        );
+        assert_eq!(quadtree.nearest_neighbors(point, 1, &bodies), [43]);
+        assert!(quadtree.nearest_neighbors(point, 0, &bodies).is_empty());
+        assert_eq!(
+            quadtree.nearest_neighbors(point, 1000, &bodies),
+            by_distance
+        );
+
+        let within = quadtree.query_radius(point, 1.5, &bodies);
+        assert_eq!(within, by_distance[..within.len()]);
+        assert_eq!(within.len(), 7);
+        assert!(quadtree.query_radius([-5.0, -5.0], 1.0, &bodies).is_empty());
+    }
 }
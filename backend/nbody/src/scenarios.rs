@@ -0,0 +1,568 @@
+use alloc::{vec, vec::Vec};
+use core::f64::consts::TAU;
+#[cfg(not(any(feature = "std", test)))]
+use num_traits::Float;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
+
+use crate::{physics::Body, SMALL};
+
+/// Initial conditions that can be generated from a handful of parameters
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+#[serde(rename_all = "camelCase")]
+pub struct Scenario {
+    pub kind: ScenarioKind,
+
+    /// Number of generated bodies (a spiral adds its central body on top)
+    pub count: usize,
+    pub center: [f64; 2],
+    /// Lightest mass drawn from `masses` (the mass of every body by default)
+    pub body_mass: f64,
+    pub body_radius: f64,
+
+    /// Same seed, same bodies
+    pub seed: u64,
+
+    #[serde(default)]
+    pub masses: MassDistribution,
+
+    /// Angular velocity of a rigid rotation around the center added to every body
+    /// (counter-clockwise when positive)
+    #[serde(default)]
+    pub spin: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+#[serde(rename_all = "camelCase")]
+pub enum ScenarioKind {
+    /// Bodies uniformly spread over a disc, optionally on circular orbits around its center
+    Disc { radius: f64, rotating: bool },
+
+    /// A massive central body surrounded by a disc whose bodies follow logarithmic spiral arms
+    #[serde(rename_all = "camelCase")]
+    Spiral {
+        radius: f64,
+        arms: u32,
+        central_mass: f64,
+    },
+
+    /// Plummer model (projected on the plane), in virial equilibrium
+    #[serde(rename_all = "camelCase")]
+    Plummer { scale_radius: f64 },
+
+    /// Bodies uniformly spread over a square with random velocities
+    #[serde(rename_all = "camelCase")]
+    Random { half_size: f64, max_speed: f64 },
+
+    /// Two bodies on a Kepler orbit around their center of mass, starting at the apoapsis
+    /// (`count` and `masses` are ignored, the primary weighs `body_mass`)
+    #[serde(rename_all = "camelCase")]
+    Binary {
+        semi_major_axis: f64,
+        /// From 0 (circular orbits) to 1 (exclusive)
+        eccentricity: f64,
+        /// Mass of the secondary over the mass of the primary
+        mass_ratio: f64,
+    },
+}
+
+/// How the masses of the generated bodies are drawn
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+#[serde(rename_all = "camelCase")]
+pub enum MassDistribution {
+    /// Every body weighs `body_mass`
+    #[default]
+    Equal,
+
+    /// Uniform between `body_mass` and `max_mass`
+    #[serde(rename_all = "camelCase")]
+    Uniform { max_mass: f64 },
+
+    /// Density proportional to `m^-exponent` between `body_mass` and `max_mass`,
+    /// 2.35 for the Salpeter initial mass function
+    #[serde(rename_all = "camelCase")]
+    PowerLaw { max_mass: f64, exponent: f64 },
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Scenario {
+            kind: ScenarioKind::Disc {
+                radius: 100.0,
+                rotating: true,
+            },
+            count: 100,
+            center: [0.0, 0.0],
+            body_mass: 1.0,
+            body_radius: 1.0,
+            seed: 0,
+            masses: MassDistribution::Equal,
+            spin: 0.0,
+        }
+    }
+}
+
+impl Scenario {
+    /// Generates the bodies of the scenario
+    /// (orbital velocities depend on the gravity constant of the target simulation)
+    pub fn generate(&self, gravity_constant: f64) -> Vec<Body> {
+        let mut rng = SplitMix64::new(self.seed);
+        // Drawn first: equal masses take no draws, so the positions do not depend on them
+        let masses = match self.kind {
+            ScenarioKind::Binary { .. } => Vec::new(),
+            _ => (0..self.count)
+                .map(|_| self.masses.sample(&mut rng, self.body_mass))
+                .collect(),
+        };
+        let mut bodies = match self.kind {
+            ScenarioKind::Disc { radius, rotating } => {
+                self.disc(&mut rng, &masses, radius, rotating, gravity_constant)
+            }
+            ScenarioKind::Spiral {
+                radius,
+                arms,
+                central_mass,
+            } => self.spiral(
+                &mut rng,
+                &masses,
+                radius,
+                arms,
+                central_mass,
+                gravity_constant,
+            ),
+            ScenarioKind::Plummer { scale_radius } => {
+                self.plummer(&mut rng, &masses, scale_radius, gravity_constant)
+            }
+            ScenarioKind::Random {
+                half_size,
+                max_speed,
+            } => self.random(&mut rng, &masses, half_size, max_speed),
+            ScenarioKind::Binary {
+                semi_major_axis,
+                eccentricity,
+                mass_ratio,
+            } => self.binary(semi_major_axis, eccentricity, mass_ratio, gravity_constant),
+        };
+        for body in bodies.iter_mut() {
+            let [x, y] = body.position;
+            body.velocity[0] -= self.spin * y;
+            body.velocity[1] += self.spin * x;
+            body.position[0] += self.center[0];
+            body.position[1] += self.center[1];
+        }
+        bodies
+    }
+
+    fn body(&self, mass: f64, position: [f64; 2], velocity: [f64; 2]) -> Body {
+        Body {
+            radius: self.body_radius,
+            ..Body::default()
+        }
+        .with_mass(mass)
+        .with_position(position)
+        .with_velocity(velocity)
+    }
+
+    fn disc(
+        &self,
+        rng: &mut SplitMix64,
+        masses: &[f64],
+        radius: f64,
+        rotating: bool,
+        gravity_constant: f64,
+    ) -> Vec<Body> {
+        let total_mass: f64 = masses.iter().sum();
+        masses
+            .iter()
+            .map(|&mass| {
+                let r = radius * rng.next_f64().sqrt();
+                let angle = TAU * rng.next_f64();
+                let velocity = if rotating {
+                    // Mass enclosed by the orbit of a uniform disc
+                    let enclosed = total_mass * (r / radius).powi(2);
+                    tangential(angle, circular_speed(gravity_constant, enclosed, r))
+                } else {
+                    [0.0, 0.0]
+                };
+                self.body(mass, polar(r, angle), velocity)
+            })
+            .collect()
+    }
+
+    fn spiral(
+        &self,
+        rng: &mut SplitMix64,
+        masses: &[f64],
+        radius: f64,
+        arms: u32,
+        central_mass: f64,
+        gravity_constant: f64,
+    ) -> Vec<Body> {
+        // Radians the arms wind by per e-fold of the radius
+        const WINDING: f64 = 2.0;
+        // Angular spread of the bodies around their arm
+        const ARM_SPREAD: f64 = 0.3;
+        const INNER_RADIUS_RATIO: f64 = 0.1;
+
+        let arms = arms.max(1);
+        let inner_radius = INNER_RADIUS_RATIO * radius;
+        let disc_mass: f64 = masses.iter().sum();
+
+        let mut bodies = Vec::with_capacity(masses.len() + 1);
+        bodies.push(Body {
+            radius: 4.0 * self.body_radius,
+            ..Body::default().with_mass(central_mass)
+        });
+        bodies.extend(masses.iter().enumerate().map(|(i, &mass)| {
+            let u = rng.next_f64();
+            let r = (inner_radius.powi(2) + u * (radius.powi(2) - inner_radius.powi(2))).sqrt();
+            let arm = (i as u32 % arms) as f64;
+            let angle = TAU * arm / arms as f64
+                + WINDING * (r / inner_radius).ln()
+                + ARM_SPREAD * rng.next_gaussian();
+            let enclosed = central_mass + disc_mass * u;
+            self.body(
+                mass,
+                polar(r, angle),
+                tangential(angle, circular_speed(gravity_constant, enclosed, r)),
+            )
+        }));
+        bodies
+    }
+
+    fn plummer(
+        &self,
+        rng: &mut SplitMix64,
+        masses: &[f64],
+        scale_radius: f64,
+        gravity_constant: f64,
+    ) -> Vec<Body> {
+        // Avoids the few bodies sampled extremely far from the core
+        const MAX_RADIUS_RATIO: f64 = 10.0;
+
+        let total_mass: f64 = masses.iter().sum();
+        masses
+            .iter()
+            .map(|&mass| {
+                // Inverse of the cumulative mass profile (Aarseth, Henon & Wielen 1974)
+                let r = loop {
+                    let u = rng.next_f64().max(SMALL);
+                    let r = scale_radius / (u.powf(-2.0 / 3.0) - 1.0).sqrt();
+                    if r < MAX_RADIUS_RATIO * scale_radius {
+                        break r;
+                    }
+                };
+                // Speed as a fraction of the escape speed, von Neumann rejection sampling
+                let q = loop {
+                    let q = rng.next_f64();
+                    let g = 0.1 * rng.next_f64();
+                    if g < q * q * (1.0 - q * q).powf(3.5) {
+                        break q;
+                    }
+                };
+                let escape_speed = (2.0 * gravity_constant * total_mass).sqrt()
+                    * (r * r + scale_radius * scale_radius).powf(-0.25);
+                self.body(
+                    mass,
+                    polar(r, TAU * rng.next_f64()),
+                    polar(q * escape_speed, TAU * rng.next_f64()),
+                )
+            })
+            .collect()
+    }
+
+    fn random(
+        &self,
+        rng: &mut SplitMix64,
+        masses: &[f64],
+        half_size: f64,
+        max_speed: f64,
+    ) -> Vec<Body> {
+        masses
+            .iter()
+            .map(|&mass| {
+                let position = [
+                    half_size * (2.0 * rng.next_f64() - 1.0),
+                    half_size * (2.0 * rng.next_f64() - 1.0),
+                ];
+                let velocity = polar(max_speed * rng.next_f64().sqrt(), TAU * rng.next_f64());
+                self.body(mass, position, velocity)
+            })
+            .collect()
+    }
+
+    fn binary(
+        &self,
+        semi_major_axis: f64,
+        eccentricity: f64,
+        mass_ratio: f64,
+        gravity_constant: f64,
+    ) -> Vec<Body> {
+        let primary = self.body_mass;
+        let secondary = self.body_mass * mass_ratio;
+        let total_mass = primary + secondary;
+        // Separation and relative speed at the apoapsis (vis-viva equation)
+        let separation = semi_major_axis * (1.0 + eccentricity);
+        let speed =
+            (gravity_constant * total_mass * (1.0 - eccentricity) / separation.max(SMALL)).sqrt();
+        // Each body is offset from the center of mass by the other one's share of the mass
+        let share = |mass: f64| mass / total_mass.max(SMALL);
+        vec![
+            self.body(
+                primary,
+                [-share(secondary) * separation, 0.0],
+                [0.0, -share(secondary) * speed],
+            ),
+            self.body(
+                secondary,
+                [share(primary) * separation, 0.0],
+                [0.0, share(primary) * speed],
+            ),
+        ]
+    }
+}
+
+impl MassDistribution {
+    /// Draws a mass, `min_mass` being the `body_mass` of the scenario
+    fn sample(&self, rng: &mut SplitMix64, min_mass: f64) -> f64 {
+        match *self {
+            MassDistribution::Equal => min_mass,
+            MassDistribution::Uniform { max_mass } => {
+                min_mass + (max_mass - min_mass) * rng.next_f64()
+            }
+            MassDistribution::PowerLaw { max_mass, exponent } => {
+                // Inverse of the cumulative distribution
+                let u = rng.next_f64();
+                let k = 1.0 - exponent;
+                if k.abs() < SMALL {
+                    min_mass * (max_mass / min_mass).powf(u)
+                } else {
+                    let (low, high) = (min_mass.powf(k), max_mass.powf(k));
+                    (low + u * (high - low)).powf(1.0 / k)
+                }
+            }
+        }
+    }
+}
+
+fn polar(r: f64, angle: f64) -> [f64; 2] {
+    [r * angle.cos(), r * angle.sin()]
+}
+
+/// Counter-clockwise velocity of a body at the given polar angle
+fn tangential(angle: f64, speed: f64) -> [f64; 2] {
+    [-speed * angle.sin(), speed * angle.cos()]
+}
+
+fn circular_speed(gravity_constant: f64, enclosed_mass: f64, r: f64) -> f64 {
+    (gravity_constant * enclosed_mass / r.max(SMALL)).sqrt()
+}
+
+/// Small seedable generator, so that scenarios are reproducible on every platform
+/// without pulling a random crate into the wasm builds
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Same state, same draws from there on
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Standard normal distribution (Box-Muller)
+    pub fn next_gaussian(&mut self) -> f64 {
+        let u = self.next_f64().max(f64::MIN_POSITIVE);
+        let v = self.next_f64();
+        (-2.0 * u.ln()).sqrt() * (TAU * v).cos()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scenario(kind: ScenarioKind) -> Scenario {
+        Scenario {
+            kind,
+            count: 500,
+            center: [10.0, -5.0],
+            seed: 42,
+            ..Scenario::default()
+        }
+    }
+
+    #[test]
+    fn seeded_generation_test() {
+        let kinds = [
+            ScenarioKind::Disc {
+                radius: 50.0,
+                rotating: true,
+            },
+            ScenarioKind::Spiral {
+                radius: 50.0,
+                arms: 2,
+                central_mass: 1000.0,
+            },
+            ScenarioKind::Plummer { scale_radius: 10.0 },
+            ScenarioKind::Random {
+                half_size: 50.0,
+                max_speed: 1.0,
+            },
+        ];
+        for kind in kinds {
+            let scenario = scenario(kind);
+            let bodies = scenario.generate(1.0);
+            assert!(bodies.len() >= scenario.count);
+            assert!(bodies.iter().all(|b| b
+                .position
+                .iter()
+                .chain(&b.velocity)
+                .all(|x| x.is_finite())));
+
+            let same_seed = scenario.generate(1.0);
+            assert!(bodies
+                .iter()
+                .zip(&same_seed)
+                .all(|(a, b)| a.position == b.position && a.velocity == b.velocity));
+
+            let other_seed = Scenario {
+                seed: 7,
+                ..scenario
+            }
+            .generate(1.0);
+            assert!(bodies
+                .iter()
+                .zip(&other_seed)
+                .any(|(a, b)| a.position != b.position));
+        }
+    }
+
+    #[test]
+    fn disc_test() {
+        let scenario = scenario(ScenarioKind::Disc {
+            radius: 50.0,
+            rotating: true,
+        });
+        for body in scenario.generate(1.0) {
+            let dx = body.position[0] - scenario.center[0];
+            let dy = body.position[1] - scenario.center[1];
+            assert!(dx.hypot(dy) <= 50.0);
+            // Circular orbit: velocity perpendicular to the radius
+            assert!((dx * body.velocity[0] + dy * body.velocity[1]).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn binary_test() {
+        let scenario = Scenario {
+            body_mass: 10.0,
+            ..scenario(ScenarioKind::Binary {
+                semi_major_axis: 20.0,
+                eccentricity: 0.5,
+                mass_ratio: 0.25,
+            })
+        };
+        let bodies = scenario.generate(2.0);
+        assert_eq!(bodies.len(), 2);
+        let (a, b) = (&bodies[0], &bodies[1]);
+        assert_eq!(b.mass, 2.5);
+
+        // At rest around the center
+        let total_mass = a.mass + b.mass;
+        for axis in 0..2 {
+            let center = (a.mass * a.position[axis] + b.mass * b.position[axis]) / total_mass;
+            assert!((center - scenario.center[axis]).abs() < 1e-9);
+            assert!((a.mass * a.velocity[axis] + b.mass * b.velocity[axis]).abs() < 1e-9);
+        }
+
+        // Apoapsis at a(1 + e), and the energy of an orbit of semi-major axis a
+        let separation = (a.position[0] - b.position[0]).hypot(a.position[1] - b.position[1]);
+        assert!((separation - 30.0).abs() < 1e-9);
+        let relative_speed = (a.velocity[0] - b.velocity[0]).hypot(a.velocity[1] - b.velocity[1]);
+        let specific_energy = relative_speed.powi(2) / 2.0 - 2.0 * total_mass / separation;
+        assert!((specific_energy + 2.0 * total_mass / 40.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn mass_distribution_test() {
+        let distributions = [
+            MassDistribution::Uniform { max_mass: 5.0 },
+            MassDistribution::PowerLaw {
+                max_mass: 100.0,
+                exponent: 2.35,
+            },
+            MassDistribution::PowerLaw {
+                max_mass: 100.0,
+                exponent: 1.0,
+            },
+        ];
+        for masses in distributions {
+            let bodies = Scenario {
+                masses,
+                ..scenario(ScenarioKind::Plummer { scale_radius: 10.0 })
+            }
+            .generate(1.0);
+            let max_mass = match masses {
+                MassDistribution::Uniform { max_mass }
+                | MassDistribution::PowerLaw { max_mass, .. } => max_mass,
+                MassDistribution::Equal => unreachable!(),
+            };
+            assert!(bodies.iter().all(|b| (1.0..=max_mass).contains(&b.mass)));
+            assert!(bodies.iter().any(|b| b.mass > 1.5));
+        }
+
+        // Salpeter: light bodies are far more common than heavy ones
+        let bodies = Scenario {
+            masses: distributions[1],
+            ..scenario(ScenarioKind::Plummer { scale_radius: 10.0 })
+        }
+        .generate(1.0);
+        let light = bodies.iter().filter(|b| b.mass < 2.0).count();
+        assert!(light > bodies.len() / 2);
+    }
+
+    #[test]
+    fn spin_test() {
+        let kind = ScenarioKind::Disc {
+            radius: 50.0,
+            rotating: false,
+        };
+        let still = scenario(kind.clone()).generate(1.0);
+        let spinning = Scenario {
+            spin: 0.1,
+            ..scenario(kind)
+        }
+        .generate(1.0);
+        for (a, b) in still.iter().zip(&spinning) {
+            assert_eq!(a.position, b.position);
+            assert_eq!(a.velocity, [0.0, 0.0]);
+            let dx = b.position[0] - 10.0;
+            let dy = b.position[1] + 5.0;
+            assert!((b.velocity[0] + 0.1 * dy).abs() < 1e-9);
+            assert!((b.velocity[1] - 0.1 * dx).abs() < 1e-9);
+        }
+    }
+}
@@ -1,18 +1,175 @@
 use crate::{
-    physics::{compute_collisions, compute_gravity_forces, Body},
+    constraints::{solve_rods, spring_forces, Constraint},
+    determinism::{DeterministicMode, StateHasher},
+    phase_span,
+    physics::{
+        compute_collisions, compute_force_at, field_at_into, Body, BodyId, CollisionEvent,
+        CollisionModel, ExternalForce, FieldSample, ForceHook, ForceWorkspace, Tracer,
+    },
     quadtree::{SquareBox, SquareQuadtree},
+    scenarios::SplitMix64,
+    storage::BodyStorage,
+    timeline::{Timeline, TimelineAction},
+    trails::Trails,
+    PhysicsError,
 };
 
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::time::Duration;
+#[cfg(not(any(feature = "std", test)))]
+use num_traits::Float;
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "wasm")]
 use tsify::Tsify;
+#[cfg(feature = "wasm")]
 use wasm_bindgen::prelude::*;
 
-#[derive(Tsify, Serialize, Deserialize)]
+/// Time integration scheme of the bodies
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+#[serde(rename_all = "camelCase")]
+pub enum Integrator {
+    /// Semi-implicit Euler: cheapest, but the energy drifts on tight orbits
+    #[default]
+    Euler,
+
+    /// Kick-drift-kick with the forces at the start and at the end of the step,
+    /// so it costs two force evaluations per step
+    VelocityVerlet,
+
+    /// Drift-kick-drift with the forces half way through the step (a single evaluation)
+    Leapfrog,
+}
+
+/// What happens to the bodies reaching the edges of the world, applied after each step
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+#[serde(rename_all = "camelCase")]
+pub enum BoundaryCondition {
+    /// Unbounded space, the bodies can drift away for good
+    #[default]
+    Open,
+
+    /// The bodies bounce off the walls of the box
+    Reflective(SquareBox),
+
+    /// A body leaving the box comes back in from the opposite side
+    /// (only the positions wrap around, not the gravity nor the collisions)
+    Periodic(SquareBox),
+}
+
+impl BoundaryCondition {
+    /// The box the bodies are kept in, None for open space
+    pub fn world(&self) -> Option<SquareBox> {
+        match self {
+            BoundaryCondition::Open => None,
+            BoundaryCondition::Reflective(world) | BoundaryCondition::Periodic(world) => {
+                Some(*world)
+            }
+        }
+    }
+
+    fn apply(&self, position: &mut [f64; 2], velocity: &mut [f64; 2], radius: f64) {
+        match self {
+            BoundaryCondition::Open => {}
+            BoundaryCondition::Reflective(world) => {
+                let lower = [world.x_min(), world.y_min()];
+                let upper = [world.x_max(), world.y_max()];
+                for axis in 0..2 {
+                    // Walls against the surface of the body (its center if it does not fit)
+                    let radius = radius.min(world.half_size());
+                    let (min, max) = (lower[axis] + radius, upper[axis] - radius);
+                    let position = &mut position[axis];
+                    if *position < min {
+                        *position = (2.0 * min - *position).min(max);
+                        velocity[axis] = velocity[axis].abs();
+                    } else if *position > max {
+                        *position = (2.0 * max - *position).max(min);
+                        velocity[axis] = -velocity[axis].abs();
+                    }
+                }
+            }
+            BoundaryCondition::Periodic(world) => {
+                let lower = [world.x_min(), world.y_min()];
+                let size = world.size();
+                for (position, lower) in position.iter_mut().zip(lower) {
+                    *position -= size * ((*position - lower) / size).floor();
+                }
+            }
+        }
+    }
+}
+
+/// Time step following the accelerations of the bodies: each body may only move by a fraction
+/// of its radius under its acceleration, `dt = tolerance * sqrt(radius / acceleration)`
+/// (so that close encounters get smaller steps, which grow back as the bodies part)
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+#[serde(rename_all = "camelCase")]
+pub struct AdaptiveTimestep {
+    min_dt: f64,
+    max_dt: f64,
+    tolerance: f64,
+}
+
+impl AdaptiveTimestep {
+    pub fn new(min_dt: f64, max_dt: f64) -> Self {
+        AdaptiveTimestep {
+            min_dt,
+            max_dt,
+            tolerance: 0.2,
+        }
+    }
+
+    /// Builder method to set the tolerance (the smaller, the smaller the steps)
+    pub fn with_tolerance(mut self, tolerance: f64) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    pub fn min_dt(&self) -> f64 {
+        self.min_dt
+    }
+
+    pub fn max_dt(&self) -> f64 {
+        self.max_dt
+    }
+
+    pub fn tolerance(&self) -> f64 {
+        self.tolerance
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
 #[serde(rename_all = "camelCase")]
-#[tsify(from_wasm_abi, into_wasm_abi)]
 pub struct SolverParameters {
     dt: f64, // seconds
     barnes_hut_theta: f64,
+
+    /// Margin around the bodies when the quadtree root grows (fraction of its size)
+    #[serde(default = "default_root_padding")]
+    root_padding: f64,
+
+    /// Steps the quadtree root must stay oversized before it shrinks back around the bodies
+    #[serde(default = "default_root_shrink_delay")]
+    root_shrink_delay: u32,
+
+    #[serde(default)]
+    integrator: Integrator,
+
+    /// When set, `dt` is only the first step and the next ones follow the accelerations
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    adaptive_timestep: Option<AdaptiveTimestep>,
+}
+
+fn default_root_padding() -> f64 {
+    0.1
+}
+
+fn default_root_shrink_delay() -> u32 {
+    60
 }
 
 impl Default for SolverParameters {
@@ -20,172 +177,1880 @@ impl Default for SolverParameters {
         SolverParameters {
             dt: 0.01,
             barnes_hut_theta: 0.0,
+            root_padding: default_root_padding(),
+            root_shrink_delay: default_root_shrink_delay(),
+            integrator: Integrator::default(),
+            adaptive_timestep: None,
+        }
+    }
+}
+
+impl SolverParameters {
+    pub fn new(dt: f64, barnes_hut_theta: f64) -> Self {
+        SolverParameters {
+            dt,
+            barnes_hut_theta,
+            ..SolverParameters::default()
         }
     }
+
+    /// Builder method to set the margin around the bodies when the quadtree root grows
+    pub fn with_root_padding(mut self, root_padding: f64) -> Self {
+        self.root_padding = root_padding;
+        self
+    }
+
+    /// Builder method to set the steps the quadtree root must stay oversized before shrinking
+    pub fn with_root_shrink_delay(mut self, root_shrink_delay: u32) -> Self {
+        self.root_shrink_delay = root_shrink_delay;
+        self
+    }
+
+    /// Builder method to set the time integration scheme
+    pub fn with_integrator(mut self, integrator: Integrator) -> Self {
+        self.integrator = integrator;
+        self
+    }
+
+    /// Builder method to adapt the time step to the accelerations of the bodies
+    pub fn with_adaptive_timestep(mut self, adaptive_timestep: AdaptiveTimestep) -> Self {
+        self.adaptive_timestep = Some(adaptive_timestep);
+        self
+    }
+
+    pub fn root_padding(&self) -> f64 {
+        self.root_padding
+    }
+
+    pub fn root_shrink_delay(&self) -> u32 {
+        self.root_shrink_delay
+    }
+
+    pub fn dt(&self) -> f64 {
+        self.dt
+    }
+
+    pub fn barnes_hut_theta(&self) -> f64 {
+        self.barnes_hut_theta
+    }
+
+    pub fn integrator(&self) -> Integrator {
+        self.integrator
+    }
+
+    pub fn adaptive_timestep(&self) -> Option<AdaptiveTimestep> {
+        self.adaptive_timestep
+    }
 }
 
-#[derive(Tsify, Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
 #[serde(rename_all = "camelCase")]
-#[tsify(from_wasm_abi, into_wasm_abi)]
 pub struct PhyiscsParameters {
     gravity_constant: f64,
+
+    /// Plummer softening length: the gravity between two bodies at a distance r is computed
+    /// as if they were sqrt(r^2 + softening^2) apart, which bounds the force of close encounters
+    #[serde(default)]
+    softening: f64,
+
+    #[serde(default)]
+    collision_model: CollisionModel,
+
+    /// Ratio of the separating to the approaching speed of a bounce, from 0 (the bodies stick
+    /// together along the contact normal) to 1 (perfectly elastic)
+    #[serde(default = "default_restitution")]
+    restitution: f64,
+}
+
+fn default_restitution() -> f64 {
+    1.0
 }
 
 impl Default for PhyiscsParameters {
     fn default() -> Self {
         PhyiscsParameters {
             gravity_constant: 100.0,
+            softening: 0.0,
+            collision_model: CollisionModel::default(),
+            restitution: default_restitution(),
         }
     }
 }
 
-#[derive(Default)]
+impl PhyiscsParameters {
+    pub fn new(gravity_constant: f64) -> Self {
+        PhyiscsParameters {
+            gravity_constant,
+            ..Default::default()
+        }
+    }
+
+    /// Builder method to soften the gravity at short distances
+    pub fn with_softening(mut self, softening: f64) -> Self {
+        self.softening = softening;
+        self
+    }
+
+    pub fn with_collision_model(mut self, collision_model: CollisionModel) -> Self {
+        self.collision_model = collision_model;
+        self
+    }
+
+    /// Builder method to make the bounces partially inelastic
+    pub fn with_restitution(mut self, restitution: f64) -> Self {
+        self.restitution = restitution;
+        self
+    }
+
+    pub fn gravity_constant(&self) -> f64 {
+        self.gravity_constant
+    }
+
+    pub fn softening(&self) -> f64 {
+        self.softening
+    }
+
+    pub fn collision_model(&self) -> CollisionModel {
+        self.collision_model
+    }
+
+    pub fn restitution(&self) -> f64 {
+        self.restitution
+    }
+}
+
+#[derive(Default, Serialize, Deserialize, Clone, Debug)]
 pub struct SimulationParameters {
     pub solver: SolverParameters,
     pub physics: PhyiscsParameters,
+    #[serde(default)]
+    pub boundary: BoundaryCondition,
+
+    /// Fields added to the gravity of the bodies (and of the tracers), in this order
+    #[serde(default)]
+    pub external_forces: Vec<ExternalForce>,
 }
 
-#[wasm_bindgen]
+/// Everything needed to resume a simulation
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SimulationSnapshot {
+    pub bodies: Vec<Body>,
+    pub parameters: SimulationParameters,
+    pub physical_time: f64, // seconds
+
+    /// Id given to the next body added (at least past the ids of `bodies`)
+    pub next_body_id: u64,
+
+    pub deterministic: Option<DeterministicMode>,
+
+    /// Where the draws of `Simulation::rng` resume from
+    pub rng: SplitMix64,
+
+    #[serde(default)]
+    pub constraints: Vec<Constraint>,
+}
+
+/// Version of the binary snapshot format, first byte of the buffer
+pub const SNAPSHOT_FORMAT_VERSION: u8 = 13;
+
+#[cfg(feature = "std")]
+impl SimulationSnapshot {
+    /// Binary format shared by the server persistence and the local wasm engine:
+    /// [SNAPSHOT_FORMAT_VERSION, bincode(snapshot)...]
+    pub fn to_bytes(&self) -> Result<Vec<u8>, PhysicsError> {
+        let mut buffer = vec![SNAPSHOT_FORMAT_VERSION];
+        bincode::serialize_into(&mut buffer, self)?;
+        Ok(buffer)
+    }
+
+    pub fn from_bytes(buffer: &[u8]) -> Result<Self, PhysicsError> {
+        match buffer.split_first() {
+            Some((&SNAPSHOT_FORMAT_VERSION, data)) => Ok(bincode::deserialize(data)?),
+            Some((&version, _)) => Err(PhysicsError::UnsupportedSnapshotVersion(Some(version))),
+            None => Err(PhysicsError::UnsupportedSnapshotVersion(None)),
+        }
+    }
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
 pub struct Simulation {
     forces: Vec<[f64; 2]>,
-    current_time: std::time::Duration,
-    bodies: Vec<Body>,
+    current_time: Duration,
+    bodies: BodyStorage,
     qt: SquareQuadtree,
     parameters: SimulationParameters,
     kinetic_energy: f64,
+
+    /// Gravitational potential energy of the last force pass
+    potential_energy: f64,
+
+    collisions: Vec<CollisionEvent>,
+
+    /// Massless particles, never inserted in the quadtree
+    tracers: Vec<Tracer>,
+
+    /// Scripted events, sorted by time
+    timeline: Timeline,
+
+    /// First event of the timeline not applied yet
+    next_event: usize,
+
+    /// Custom force added to the gravity
+    force_hook: Option<Box<dyn ForceHook>>,
+
+    /// Consecutive quadtree updates during which the root was oversized
+    root_oversized_steps: u32,
+
+    /// Time step of the last step
+    last_dt: f64,
+
+    /// Id of the next body added: ids are never reused, so the bodies stay sorted by id
+    next_body_id: u64,
+
+    /// Scratch buffers of the gravity and collision passes
+    workspace: ForceWorkspace,
+
+    /// Leaves and per-leaf results of the parallel gravity pass
+    #[cfg(feature = "parallel")]
+    leaf_pass: crate::physics::LeafPass,
+
+    /// Makes the steps depend on the current state only, see `set_deterministic_mode`
+    deterministic: Option<DeterministicMode>,
+
+    /// Randomness of the simulation, seeded by the deterministic mode
+    rng: SplitMix64,
+
+    /// Last positions of the bodies, None unless enabled with `enable_trails`
+    trails: Option<Trails>,
+
+    /// Springs and rods between the bodies, dropped with the bodies they link
+    constraints: Vec<Constraint>,
 }
 
 impl Default for Simulation {
     fn default() -> Self {
         Self {
-            bodies: Vec::new(),
+            bodies: BodyStorage::new(),
             forces: Vec::new(),
-            current_time: std::time::Duration::new(0, 0),
+            current_time: Duration::new(0, 0),
             qt: SquareQuadtree::new(SquareBox::new(
                 /*center=*/ [0.0, 0.0],
                 /*half size=*/ 1.0,
             )),
             parameters: SimulationParameters::default(),
             kinetic_energy: 0.0,
+            potential_energy: 0.0,
+            collisions: Vec::new(),
+            tracers: Vec::new(),
+            timeline: Timeline::new(),
+            next_event: 0,
+            force_hook: None,
+            root_oversized_steps: 0,
+            last_dt: SolverParameters::default().dt,
+            next_body_id: 0,
+            workspace: ForceWorkspace::new(),
+            #[cfg(feature = "parallel")]
+            leaf_pass: Default::default(),
+            deterministic: None,
+            rng: SplitMix64::new(0),
+            trails: None,
+            constraints: Vec::new(),
         }
     }
 }
 
 impl Simulation {
-    pub fn add_bodies(&mut self, bodies: Vec<Body>) {
+    /// Adds the bodies with new ids
+    pub fn add_bodies(&mut self, mut bodies: Vec<Body>) {
+        for body in &mut bodies {
+            body.id = self.next_id();
+        }
         self.forces.extend(vec![[0.0, 0.0]; bodies.len()]);
         self.bodies.extend(bodies);
         self.update_quadtree();
     }
+
+    /// In deterministic mode the quadtree is rebuilt from scratch at each step around the bodies,
+    /// so that its layout (and the order the forces are summed in) depends on the bodies only,
+    /// not on how they moved before. Slower on large simulations, `state_hash` then matches across
+    /// runs from the same state (on the same platform, the float math being the same)
+    pub fn set_deterministic_mode(&mut self, mode: Option<DeterministicMode>) {
+        self.deterministic = mode;
+        self.rng = SplitMix64::new(mode.map_or(0, |mode| mode.seed));
+        self.update_quadtree();
+    }
+
+    pub fn deterministic_mode(&self) -> Option<DeterministicMode> {
+        self.deterministic
+    }
+
+    /// Random numbers of the stochastic features, reproducible in deterministic mode
+    pub fn rng(&mut self) -> &mut SplitMix64 {
+        &mut self.rng
+    }
+
+    /// Fingerprint of the state: the bodies, the tracers, the time and the random numbers
+    /// still to come (not the parameters, which are the same in the runs compared)
+    pub fn state_hash(&self) -> u64 {
+        let mut hasher = StateHasher::new();
+        hasher.write_u64(self.current_time.as_secs());
+        hasher.write_u64(self.current_time.subsec_nanos() as u64);
+        hasher.write_u64(self.next_body_id);
+        hasher.write_u64(self.rng.state());
+        hasher.write_u64(self.bodies.len() as u64);
+        for body in self.bodies.iter() {
+            hasher.write_u64(body.id.0);
+            for value in [
+                body.position[0],
+                body.position[1],
+                body.velocity[0],
+                body.velocity[1],
+                body.mass,
+                body.radius,
+            ] {
+                hasher.write_f64(value);
+            }
+            hasher.write_u64(u32::from_le_bytes(body.color) as u64);
+            hasher.write_u64(body.is_static as u64);
+        }
+        hasher.write_u64(self.tracers.len() as u64);
+        for tracer in &self.tracers {
+            for value in [
+                tracer.position[0],
+                tracer.position[1],
+                tracer.velocity[0],
+                tracer.velocity[1],
+            ] {
+                hasher.write_f64(value);
+            }
+        }
+        hasher.finish()
+    }
+
+    pub fn snapshot(&self) -> SimulationSnapshot {
+        SimulationSnapshot {
+            bodies: self.bodies.to_vec(),
+            parameters: self.parameters.clone(),
+            physical_time: self.current_time.as_secs_f64(),
+            next_body_id: self.next_body_id,
+            deterministic: self.deterministic,
+            rng: self.rng.clone(),
+            constraints: self.constraints.clone(),
+        }
+    }
+
+    /// Replaces the whole state of the simulation with the snapshot's
+    /// The bodies keep their ids, unless they are not sorted by id (then they get new ones)
+    pub fn restore(&mut self, snapshot: SimulationSnapshot) {
+        self.reset();
+        self.parameters = snapshot.parameters;
+        self.deterministic = snapshot.deterministic;
+        self.rng = snapshot.rng;
+        self.current_time = Duration::from_secs_f64(snapshot.physical_time.max(0.0));
+        self.seek_timeline();
+        self.kinetic_energy = snapshot.bodies.iter().map(Body::kinectic_energy).sum();
+        let sorted = snapshot
+            .bodies
+            .windows(2)
+            .all(|pair| pair[0].id < pair[1].id);
+        if !sorted {
+            self.add_bodies(snapshot.bodies);
+            return;
+        }
+        // The constraints only hold with the ids they were made for
+        self.constraints = snapshot.constraints;
+        let past_last = snapshot.bodies.last().map_or(0, |body| body.id.0 + 1);
+        self.next_body_id = snapshot.next_body_id.max(past_last);
+        self.forces = vec![[0.0, 0.0]; snapshot.bodies.len()];
+        self.bodies = snapshot.bodies.into();
+        self.drop_dangling_constraints();
+        self.update_quadtree();
+    }
+
+    /// Replaces the bodies and the time but keeps the current parameters
+    /// (e.g. with the content of a `StateUpdate`, which carries no parameters)
+    pub fn restore_bodies(&mut self, bodies: Vec<Body>, physical_time: f64) {
+        let parameters = self.parameters.clone();
+        self.restore(SimulationSnapshot {
+            bodies,
+            parameters,
+            physical_time,
+            next_body_id: 0,
+            deterministic: self.deterministic,
+            rng: self.rng.clone(),
+            constraints: self.constraints.clone(),
+        });
+    }
+
+    /// Appends the bodies (and tracers) of another simulation, translated by `offset`
+    /// and moving with an extra `velocity_offset` (e.g. to throw two prepared galaxies at each other)
+    /// The parameters and time of `self` are kept, those of `other` are dropped
+    pub fn merge(&mut self, other: Simulation, offset: [f64; 2], velocity_offset: [f64; 2]) {
+        let shift = |position: &mut [f64; 2], velocity: &mut [f64; 2]| {
+            *position = [position[0] + offset[0], position[1] + offset[1]];
+            *velocity = [
+                velocity[0] + velocity_offset[0],
+                velocity[1] + velocity_offset[1],
+            ];
+        };
+        let mut bodies = other.bodies.to_vec();
+        for body in &mut bodies {
+            shift(&mut body.position, &mut body.velocity);
+        }
+        let mut tracers = other.tracers;
+        for tracer in &mut tracers {
+            shift(&mut tracer.position, &mut tracer.velocity);
+        }
+        self.add_bodies(bodies);
+        self.add_tracers(tracers);
+    }
+
+    /// Index of the body with that id, if it is still in the simulation
+    pub fn body_index(&self, id: BodyId) -> Option<usize> {
+        self.bodies.ids.binary_search(&id).ok()
+    }
+
+    /// Replaces the state of the body with that id (which it keeps)
+    pub fn update_body(&mut self, id: BodyId, body: Body) -> Result<(), PhysicsError> {
+        let index = self.body_index(id).ok_or(PhysicsError::UnknownBody(id))?;
+        self.bodies.set(index, Body { id, ..body });
+        self.update_quadtree();
+        Ok(())
+    }
+
+    /// Removes the bodies with the given ids with a single quadtree update
+    /// (unknown and repeated ids are skipped), the remaining ones keep their order
+    /// Returns the removed bodies, in id order
+    pub fn remove_bodies(&mut self, ids: &[BodyId]) -> Vec<Body> {
+        let mut removed = vec![false; self.bodies.len()];
+        for &id in ids {
+            if let Some(index) = self.body_index(id) {
+                removed[index] = true;
+            }
+        }
+        let mut flags = removed.iter();
+        self.forces
+            .retain(|_| !flags.next().is_some_and(|&flag| flag));
+        let removed_bodies = (0..self.bodies.len())
+            .filter(|&index| removed[index])
+            .map(|index| self.bodies.body(index))
+            .collect();
+        self.bodies.retain_indices(|index| !removed[index]);
+        self.drop_dangling_constraints();
+        self.update_quadtree();
+        removed_bodies
+    }
+
+    /// Same as `remove_body` but fails on an unknown id
+    pub fn try_remove_body(&mut self, id: BodyId) -> Result<Body, PhysicsError> {
+        self.remove_body(id).ok_or(PhysicsError::UnknownBody(id))
+    }
+
+    /// Same as `get_body` but fails on an unknown id
+    pub fn try_get_body(&self, id: BodyId) -> Result<Body, PhysicsError> {
+        self.get_body(id).ok_or(PhysicsError::UnknownBody(id))
+    }
+
+    fn next_id(&mut self) -> BodyId {
+        self.next_body_id += 1;
+        BodyId(self.next_body_id - 1)
+    }
+
+    /// Positions of the bodies laid out as [x0, y0, x1, y1, ...], in the order of `bodies`
+    pub fn positions_flat(&self) -> &[f64] {
+        self.bodies.positions.as_flattened()
+    }
+
+    pub fn bodies(&self) -> &BodyStorage {
+        &self.bodies
+    }
+
+    pub fn parameters(&self) -> &SimulationParameters {
+        &self.parameters
+    }
+
+    /// The collisions resolved during the last step
+    /// (indices of the bodies before the merged ones were removed)
+    pub fn collision_events(&self) -> &[CollisionEvent] {
+        self.collisions.as_slice()
+    }
+
+    /// The quadtree built during the last step (or last body insertion)
+    pub fn quadtree(&self) -> &SquareQuadtree {
+        &self.qt
+    }
+
+    /// Indices of the bodies inside the region, found through the quadtree
+    /// The quadtree is built at the start of a step, so a body that entered the region
+    /// during the last step may be missed (those that left it are filtered out)
+    pub fn bodies_in(&self, region: SquareBox) -> Vec<usize> {
+        let mut indices = self.qt.query_range(region, &self.bodies);
+        indices.retain(|&i| region.contains(&self.bodies.positions[i]));
+        indices.sort_unstable();
+        indices
+    }
+
+    /// The body under the point, if any (e.g. the one clicked), the closest one if they overlap
+    /// Same caveat as `bodies_in` about the bodies that moved during the last step
+    pub fn body_at(&self, point: [f64; 2]) -> Option<BodyId> {
+        let max_radius = self.bodies.radii.iter().copied().fold(0.0, f64::max);
+        self.qt
+            .query_radius(point, max_radius, &self.bodies)
+            .into_iter()
+            .map(|i| self.bodies.body(i))
+            .find(|body| {
+                let dx = body.position[0] - point[0];
+                let dy = body.position[1] - point[1];
+                dx * dx + dy * dy <= body.radius * body.radius
+            })
+            .map(|body| body.id)
+    }
+
+    /// Gravity field at a point (which need not be a body), with the current parameters
+    pub fn field_at(&self, point: [f64; 2]) -> FieldSample {
+        compute_force_at(
+            point,
+            &self.qt,
+            &self.bodies,
+            self.parameters.solver.barnes_hut_theta,
+            self.parameters.physics.gravity_constant,
+            self.parameters.physics.softening,
+        )
+    }
+
+    /// Acceleration of the body due to gravity (and the force hook) during the last step
+    /// (zero for a body added since)
+    pub fn get_acceleration(&self, body_idx: usize) -> [f64; 2] {
+        let force = self.forces[body_idx];
+        let mass = self.bodies.masses[body_idx];
+        [force[0] / mass, force[1] / mass]
+    }
+
+    /// Replaces the scripted events, those before the current time are skipped
+    /// The timeline is kept on `reset` and replayed from the start
+    pub fn set_timeline(&mut self, mut timeline: Timeline) {
+        timeline.events.sort_by(|a, b| a.time.total_cmp(&b.time));
+        self.timeline = timeline;
+        self.seek_timeline();
+    }
+
+    pub fn timeline(&self) -> &Timeline {
+        &self.timeline
+    }
+
+    /// Replaces the fields acting on the bodies, from the next step on
+    pub fn set_external_forces(&mut self, forces: Vec<ExternalForce>) {
+        self.parameters.external_forces = forces;
+    }
+
+    /// Links two bodies from the next step on, replacing the constraint between them if any
+    pub fn add_constraint(&mut self, constraint: Constraint) -> Result<(), PhysicsError> {
+        if !constraint.is_valid() {
+            return Err(PhysicsError::InvalidConstraint(constraint.bodies));
+        }
+        for id in constraint.bodies {
+            self.body_index(id).ok_or(PhysicsError::UnknownBody(id))?;
+        }
+        let [a, b] = constraint.bodies;
+        self.remove_constraint(a, b);
+        self.constraints.push(constraint);
+        Ok(())
+    }
+
+    /// Unlinks the two bodies, false if they were not linked
+    pub fn remove_constraint(&mut self, a: BodyId, b: BodyId) -> bool {
+        let len = self.constraints.len();
+        self.constraints
+            .retain(|constraint| !constraint.links(a, b));
+        self.constraints.len() != len
+    }
+
+    pub fn constraints(&self) -> &[Constraint] {
+        &self.constraints
+    }
+
+    /// Sets (or removes) the custom force added to the gravity of every body
+    /// It is kept on `reset`
+    pub fn set_force_hook(&mut self, force_hook: Option<Box<dyn ForceHook>>) {
+        self.force_hook = force_hook;
+    }
+
+    pub fn add_tracers(&mut self, tracers: impl IntoIterator<Item = Tracer>) {
+        self.tracers.extend(tracers);
+    }
+
+    pub fn tracers(&self) -> &[Tracer] {
+        &self.tracers
+    }
+
+    pub fn get_number_of_tracers(&self) -> usize {
+        self.tracers.len()
+    }
+
+    pub fn clear_tracers(&mut self) {
+        self.tracers.clear();
+    }
+
+    /// None unless enabled with `enable_trails`
+    /// The trails of bodies removed since the last step are only dropped on the next one
+    pub fn trails(&self) -> Option<&Trails> {
+        self.trails.as_ref()
+    }
+
+    /// Total linear momentum of the bodies
+    pub fn linear_momentum(&self) -> [f64; 2] {
+        self.bodies
+            .masses
+            .iter()
+            .zip(&self.bodies.velocities)
+            .fold([0.0, 0.0], |acc, (mass, velocity)| {
+                [acc[0] + mass * velocity[0], acc[1] + mass * velocity[1]]
+            })
+    }
 }
 
-#[wasm_bindgen]
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
 impl Simulation {
-    #[wasm_bindgen(constructor)]
+    #[cfg_attr(feature = "wasm", wasm_bindgen(constructor))]
     pub fn new() -> Self {
         Simulation::default()
     }
 
-    #[wasm_bindgen(js_name = addBody)]
-    pub fn add_body(&mut self, body: Body) {
-        self.bodies.push(body);
+    /// Adds the body with a new id, which is returned
+    #[cfg_attr(feature = "wasm", wasm_bindgen(js_name = addBody))]
+    pub fn add_body(&mut self, body: Body) -> BodyId {
+        let id = self.next_id();
+        self.bodies.push(Body { id, ..body });
         self.forces.push([0.0, 0.0]);
         self.update_quadtree();
+        id
+    }
+
+    /// Removes the body with that id and returns it (None if there is none)
+    /// The bodies after it are shifted down by one index
+    #[cfg_attr(feature = "wasm", wasm_bindgen(js_name = removeBody))]
+    pub fn remove_body(&mut self, id: BodyId) -> Option<Body> {
+        let index = self.body_index(id)?;
+        self.forces.remove(index);
+        let body = self.bodies.remove(index);
+        self.drop_dangling_constraints();
+        self.update_quadtree();
+        Some(body)
     }
 
-    #[wasm_bindgen(js_name = setSolverParameters)]
+    #[cfg_attr(feature = "wasm", wasm_bindgen(js_name = setSolverParameters))]
     pub fn set_solver_parameters(&mut self, parameters: SolverParameters) {
         self.parameters.solver = parameters;
     }
 
-    #[wasm_bindgen(js_name = setPhysicsParameters)]
+    #[cfg_attr(feature = "wasm", wasm_bindgen(js_name = setPhysicsParameters))]
     pub fn set_physics_parameters(&mut self, parameters: PhyiscsParameters) {
         self.parameters.physics = parameters;
     }
 
-    #[wasm_bindgen(js_name = getXPosition)]
+    #[cfg_attr(feature = "wasm", wasm_bindgen(js_name = setBoundaryCondition))]
+    pub fn set_boundary_condition(&mut self, boundary: BoundaryCondition) {
+        self.parameters.boundary = boundary;
+    }
+
+    /// Adds a field to the ones acting on the bodies, from the next step on
+    #[cfg_attr(feature = "wasm", wasm_bindgen(js_name = addExternalForce))]
+    pub fn add_external_force(&mut self, force: ExternalForce) {
+        self.parameters.external_forces.push(force);
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(js_name = clearExternalForces))]
+    pub fn clear_external_forces(&mut self) {
+        self.parameters.external_forces.clear();
+    }
+
+    /// Pointer to `positions_flat`, so that JavaScript can view them as a Float64Array
+    /// of `positionsLength` values over the wasm memory instead of calling `getXPosition`
+    /// per body. Invalidated (as the length) by any call that changes the bodies or steps
+    #[cfg_attr(feature = "wasm", wasm_bindgen(js_name = positionsPointer))]
+    pub fn positions_pointer(&self) -> *const f64 {
+        self.bodies.positions.as_ptr().cast()
+    }
+
+    /// Two values per body
+    #[cfg_attr(feature = "wasm", wasm_bindgen(js_name = positionsLength))]
+    pub fn positions_length(&self) -> usize {
+        2 * self.bodies.len()
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(js_name = getXPosition))]
     pub fn get_x_position(&self, body_idx: usize) -> f64 {
-        self.bodies[body_idx].position[0]
+        self.bodies.positions[body_idx][0]
     }
 
-    #[wasm_bindgen(js_name = getYPosition)]
+    #[cfg_attr(feature = "wasm", wasm_bindgen(js_name = getYPosition))]
     pub fn get_y_position(&self, body_idx: usize) -> f64 {
-        self.bodies[body_idx].position[1]
+        self.bodies.positions[body_idx][1]
     }
 
-    #[wasm_bindgen(js_name = getBody)]
-    pub fn get_body(&self, body_idx: usize) -> Body {
-        self.bodies[body_idx]
+    #[cfg_attr(feature = "wasm", wasm_bindgen(js_name = getBody))]
+    pub fn get_body(&self, id: BodyId) -> Option<Body> {
+        self.body_index(id).map(|index| self.bodies.body(index))
     }
 
-    #[wasm_bindgen(js_name = getPhysicalTime)]
+    #[cfg_attr(feature = "wasm", wasm_bindgen(js_name = getPhysicalTime))]
     pub fn get_physical_time(&self) -> f64 {
         self.current_time.as_secs_f64()
     }
 
-    #[wasm_bindgen(js_name = getNumberOfBodies)]
+    #[cfg_attr(feature = "wasm", wasm_bindgen(js_name = getNumberOfBodies))]
     pub fn get_number_of_bodies(&self) -> usize {
         self.bodies.len()
     }
 
-    #[wasm_bindgen(js_name = getKineticEnergy)]
+    #[cfg_attr(feature = "wasm", wasm_bindgen(js_name = getKineticEnergy))]
     pub fn get_kinetic_energy(&self) -> f64 {
         self.kinetic_energy
     }
 
-    pub fn step(&mut self) {
-        self.forces.iter_mut().for_each(|f| *f = [0.0, 0.0]);
-        self.update_quadtree();
+    /// Gravitational potential energy, summed during the last gravity pass (with the same
+    /// softening and Barnes-Hut approximation as the forces)
+    /// That pass sees the positions at the end of the step with Velocity Verlet, but at its start
+    /// with Euler and half way through with Leapfrog
+    #[cfg_attr(feature = "wasm", wasm_bindgen(js_name = potentialEnergy))]
+    pub fn potential_energy(&self) -> f64 {
+        self.potential_energy
+    }
 
-        compute_collisions(&mut self.bodies, &self.qt);
+    /// Kinetic plus potential energy, conserved up to the integration error (and the collisions)
+    #[cfg_attr(feature = "wasm", wasm_bindgen(js_name = totalEnergy))]
+    pub fn total_energy(&self) -> f64 {
+        self.kinetic_energy + self.potential_energy
+    }
 
-        // Update physics
-        let theta_sqr = self.parameters.solver.barnes_hut_theta.powi(2);
-        for i in 0..self.bodies.len() {
-            compute_gravity_forces(
-                i,
-                &mut self.forces,
-                &self.bodies,
-                &self.qt,
-                theta_sqr,
-                self.parameters.physics.gravity_constant,
-            );
-        }
+    /// Total angular momentum of the bodies around the origin
+    #[cfg_attr(feature = "wasm", wasm_bindgen(js_name = angularMomentum))]
+    pub fn angular_momentum(&self) -> f64 {
+        self.bodies
+            .masses
+            .iter()
+            .zip(&self.bodies.positions)
+            .zip(&self.bodies.velocities)
+            .map(|((mass, position), velocity)| {
+                mass * (position[0] * velocity[1] - position[1] * velocity[0])
+            })
+            .sum()
+    }
 
-        // Integrate
-        let dt = self.parameters.solver.dt;
-        self.kinetic_energy = 0.0;
-        for i in 0..self.bodies.len() {
-            let body = &mut self.bodies[i];
-            let force = self.forces[i];
-            let acceleration = [force[0] / body.mass, force[1] / body.mass];
+    /// Records the last `length` positions of every body after each step (0 stops recording)
+    /// The recorded trails are dropped, `reset` drops them too but keeps recording
+    #[cfg_attr(feature = "wasm", wasm_bindgen(js_name = enableTrails))]
+    pub fn enable_trails(&mut self, length: usize) {
+        self.trails = (length > 0).then(|| Trails::new(length));
+    }
 
-            body.velocity[0] += acceleration[0] * dt;
-            body.velocity[1] += acceleration[1] * dt;
-            self.kinetic_energy += body.kinectic_energy();
+    /// Trail of the body laid out as [x0, y0, x1, y1, ...], oldest first
+    /// (empty if the body has none or the trails are not recorded)
+    #[cfg_attr(feature = "wasm", wasm_bindgen(js_name = getTrail))]
+    pub fn get_trail(&self, id: BodyId) -> Vec<f64> {
+        self.trails
+            .as_ref()
+            .and_then(|trails| trails.get(id))
+            .map_or_else(Vec::new, |trail| trail.positions().flatten().collect())
+    }
 
-            body.position[0] += body.velocity[0] * dt;
-            body.position[1] += body.velocity[1] * dt;
+    /// Time step of the last step (e.g. to follow the adaptive one)
+    #[cfg_attr(feature = "wasm", wasm_bindgen(js_name = getLastDt))]
+    pub fn get_last_dt(&self) -> f64 {
+        self.last_dt
+    }
+
+    pub fn step(&mut self) {
+        self.advance(None);
+    }
+
+    /// Advances the simulation by the given time step
+    /// overriding (only for this call) the solver's dt
+    /// A negative or non-finite dt (e.g. a bad value from JavaScript) is ignored
+    #[cfg_attr(feature = "wasm", wasm_bindgen(js_name = stepWithDt))]
+    pub fn step_with_dt(&mut self, dt: f64) {
+        // The physical time could not be advanced by it
+        if Duration::try_from_secs_f64(dt).is_err() {
+            return;
         }
-        self.current_time += std::time::Duration::from_secs_f64(dt);
+        self.advance(Some(dt));
     }
 
     pub fn reset(&mut self) {
         self.bodies.clear();
         self.forces.clear();
-        self.current_time = std::time::Duration::new(0, 0);
+        self.current_time = Duration::new(0, 0);
         self.kinetic_energy = 0.0;
+        self.potential_energy = 0.0;
+        self.collisions.clear();
+        self.tracers.clear();
+        self.constraints.clear();
+        self.next_event = 0;
+        self.root_oversized_steps = 0;
+        self.last_dt = self.parameters.solver.dt;
         self.qt = SquareQuadtree::new(SquareBox::new(
             /*center=*/ [0.0, 0.0],
             /*half size=*/ 1.0,
         ));
+        self.rng = SplitMix64::new(self.deterministic.map_or(0, |mode| mode.seed));
+        if let Some(trails) = &mut self.trails {
+            trails.clear();
+        }
     }
 }
 
 // Private helper functions
 impl Simulation {
+    /// One step of `dt`, or of the solver's time step if None
+    fn advance(&mut self, dt: Option<f64>) {
+        phase_span!("step", bodies = self.bodies.len(), dt = ?dt);
+        self.apply_timeline();
+        self.update_quadtree();
+
+        // The adaptive step follows the forces at the start of the step, which are then integrated
+        let time = self.get_physical_time();
+        let solver = &self.parameters.solver;
+        let (dt, forces_computed) = match (dt, solver.adaptive_timestep) {
+            (Some(dt), _) => (dt, false),
+            (None, Some(adaptive_timestep)) => {
+                self.compute_forces(time);
+                (self.adaptive_dt(&adaptive_timestep), true)
+            }
+            (None, None) => (solver.dt, false),
+        };
+
+        {
+            phase_span!("collisions");
+            self.collisions.clear();
+            let model = self.parameters.physics.collision_model;
+            compute_collisions(
+                &mut self.bodies,
+                &self.qt,
+                dt,
+                model,
+                self.parameters.physics.restitution,
+                &mut self.collisions,
+                &mut self.workspace,
+            );
+            if model == CollisionModel::Merge && !self.collisions.is_empty() {
+                let absorbed: Vec<_> = self
+                    .collisions
+                    .iter()
+                    .map(|event| self.bodies.ids[event.jth])
+                    .collect();
+                self.remove_bodies(&absorbed);
+            }
+        }
+
+        // Update physics
+        let integrator = self.parameters.solver.integrator;
+        if integrator == Integrator::Leapfrog {
+            self.drift(0.5 * dt);
+            self.refresh_quadtree();
+            self.compute_forces(time + 0.5 * dt);
+        } else if !forces_computed {
+            self.compute_forces(time);
+        }
+        self.advance_tracers(dt);
+
+        // Integrate
+        phase_span!("integration");
+        match integrator {
+            Integrator::Euler => {
+                self.kick(dt);
+                self.drift(dt);
+            }
+            Integrator::VelocityVerlet => {
+                self.kick(0.5 * dt);
+                self.drift(dt);
+                self.refresh_quadtree();
+                self.compute_forces(time + dt);
+                self.kick(0.5 * dt);
+            }
+            Integrator::Leapfrog => {
+                self.kick(dt);
+                self.drift(0.5 * dt);
+            }
+        }
+        solve_rods(&self.constraints, &mut self.bodies);
+        let boundary = self.parameters.boundary;
+        let BodyStorage {
+            positions,
+            velocities,
+            radii,
+            statics,
+            ..
+        } = &mut self.bodies;
+        let moving = positions
+            .iter_mut()
+            .zip(velocities)
+            .zip(&*radii)
+            .zip(&*statics)
+            .filter(|(_, &is_static)| !is_static);
+        for (((position, velocity), &radius), _) in moving {
+            boundary.apply(position, velocity, radius);
+        }
+        self.kinetic_energy = self.bodies.kinetic_energy();
+        self.current_time += Duration::from_secs_f64(dt);
+        self.last_dt = dt;
+        if let Some(trails) = &mut self.trails {
+            trails.record(&self.bodies);
+        }
+    }
+
+    /// Drops the constraints linking a body that is not in the simulation anymore
+    fn drop_dangling_constraints(&mut self) {
+        let ids = &self.bodies.ids;
+        self.constraints.retain(|constraint| {
+            constraint
+                .bodies
+                .iter()
+                .all(|id| ids.binary_search(id).is_ok())
+        });
+    }
+
+    /// Applies the events of the timeline reached by the current time
+    fn apply_timeline(&mut self) {
+        let now = self.get_physical_time();
+        while let Some(event) = self.timeline.events.get(self.next_event) {
+            if event.time > now {
+                break;
+            }
+            let action = event.action.clone();
+            self.next_event += 1;
+            match action {
+                TimelineAction::SpawnScenario(scenario) => {
+                    self.add_bodies(scenario.generate(self.parameters.physics.gravity_constant))
+                }
+                TimelineAction::AddBodies(bodies) => self.add_bodies(bodies),
+                TimelineAction::AddTracers(tracers) => self.add_tracers(tracers),
+                TimelineAction::SetGravityConstant(gravity_constant) => {
+                    self.parameters.physics.gravity_constant = gravity_constant
+                }
+                TimelineAction::SetSolverParameters(parameters) => {
+                    self.set_solver_parameters(parameters)
+                }
+                TimelineAction::SetPhysicsParameters(parameters) => {
+                    self.set_physics_parameters(parameters)
+                }
+            }
+        }
+    }
+
+    /// Skips the events of the timeline before the current time
+    fn seek_timeline(&mut self) {
+        let now = self.get_physical_time();
+        self.next_event = self
+            .timeline
+            .events
+            .partition_point(|event| event.time < now);
+    }
+
+    /// Moves the tracers through the field of the bodies and the external forces, per unit
+    /// of mass (before the bodies move, so that the quadtree still matches their positions)
+    fn advance_tracers(&mut self, dt: f64) {
+        if self.tracers.is_empty() {
+            return;
+        }
+        phase_span!("tracers", tracers = self.tracers.len());
+        let theta_sqr = self.parameters.solver.barnes_hut_theta.powi(2);
+        let gravity_constant = self.parameters.physics.gravity_constant;
+        let softening_sqr = self.parameters.physics.softening.powi(2);
+        let (bodies, qt) = (&self.bodies, &self.qt);
+        let external_forces = &self.parameters.external_forces;
+        let advance = |stack: &mut Vec<usize>, tracer: &mut Tracer| {
+            let mut field = field_at_into(
+                tracer.position,
+                qt,
+                bodies,
+                theta_sqr,
+                gravity_constant,
+                softening_sqr,
+                stack,
+            );
+            for external_force in external_forces {
+                let force = external_force.force(tracer.position, tracer.velocity, 1.0);
+                field.force[0] += force[0];
+                field.force[1] += force[1];
+            }
+            tracer.velocity[0] += field.force[0] * dt;
+            tracer.velocity[1] += field.force[1] * dt;
+            tracer.position[0] += tracer.velocity[0] * dt;
+            tracer.position[1] += tracer.velocity[1] * dt;
+        };
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "parallel")] {
+                use rayon::prelude::*;
+                self.tracers.par_iter_mut().for_each_init(Vec::new, advance);
+            } else {
+                let mut stack = Vec::new();
+                for tracer in &mut self.tracers {
+                    advance(&mut stack, tracer);
+                }
+            }
+        }
+    }
+
     fn update_quadtree(&mut self) {
-        self.qt.clear(SquareBox::from_bodies(&self.bodies));
+        phase_span!("quadtree_build", bodies = self.bodies.len());
+        let boundary = self.root_boundary();
+        let indexed = self.qt.len();
+        // Moved in place when the root is the same and no body was removed (or reordered),
+        // rebuilt once the relocations leave too many emptied nodes behind
+        let incremental = self.deterministic.is_none()
+            && *self.qt.get_nodes()[0].boundary() == boundary
+            && indexed <= self.bodies.len()
+            && self.qt.relocations() <= indexed
+            && self.qt.relocate(&self.bodies);
+        if incremental
+            && self
+                .qt
+                .insert_batch(indexed..self.bodies.len(), &self.bodies)
+                == self.bodies.len() - indexed
+        {
+            return;
+        }
+        self.qt.clear(boundary);
         (0..self.bodies.len()).for_each(|i| self.qt.insert_unchecked(i, &self.bodies));
     }
+
+    /// Root box of the quadtree, with hysteresis: grown (padded) as soon as a body leaves it
+    /// but shrunk back around the bodies only after staying oversized for a while,
+    /// instead of following every move of the outermost body
+    fn root_boundary(&mut self) -> SquareBox {
+        let current = *self.qt.get_nodes()[0].boundary();
+        if self.bodies.is_empty() {
+            return current;
+        }
+        let solver = &self.parameters.solver;
+        let tight = SquareBox::from_bodies(&self.bodies);
+        let padded = tight.padded(solver.root_padding);
+        // Without hysteresis, the root then only depends on the bodies
+        if self.deterministic.is_some() {
+            return padded;
+        }
+        if !current.contains_box(&tight) {
+            self.root_oversized_steps = 0;
+            return padded;
+        }
+        // The bodies would fit in a quadrant of the root
+        if current.half_size() <= 2.0 * padded.half_size() {
+            self.root_oversized_steps = 0;
+            return current;
+        }
+        self.root_oversized_steps += 1;
+        if self.root_oversized_steps <= solver.root_shrink_delay {
+            return current;
+        }
+        self.root_oversized_steps = 0;
+        padded
+    }
+
+    /// Adaptive time step for the current forces
+    fn adaptive_dt(&self, adaptive_timestep: &AdaptiveTimestep) -> f64 {
+        let AdaptiveTimestep {
+            min_dt,
+            max_dt,
+            tolerance,
+        } = *adaptive_timestep;
+        let dt = self
+            .bodies
+            .masses
+            .iter()
+            .zip(&self.bodies.radii)
+            .zip(&self.forces)
+            .map(|((mass, radius), force)| {
+                let acceleration = force[0].hypot(force[1]) / mass;
+                tolerance * (radius / acceleration).sqrt()
+            })
+            // Bodies without acceleration impose no bound (and min skips their NaNs)
+            .fold(f64::INFINITY, f64::min);
+        // Not clamp: it panics on inconsistent bounds
+        dt.min(max_dt).max(min_dt)
+    }
+
+    /// Updates the velocities with the current forces
+    fn kick(&mut self, dt: f64) {
+        let BodyStorage {
+            velocities, masses, ..
+        } = &mut self.bodies;
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "simd")] {
+                crate::simd::kick(velocities, masses, &self.forces, dt);
+            } else {
+                for ((velocity, mass), force) in velocities.iter_mut().zip(&*masses).zip(&self.forces) {
+                    velocity[0] += force[0] / mass * dt;
+                    velocity[1] += force[1] / mass * dt;
+                }
+            }
+        }
+    }
+
+    /// Moves the bodies with their current velocities
+    fn drift(&mut self, dt: f64) {
+        let BodyStorage {
+            positions,
+            velocities,
+            ..
+        } = &mut self.bodies;
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "simd")] {
+                crate::simd::drift(positions, velocities, dt);
+            } else {
+                for (position, velocity) in positions.iter_mut().zip(&*velocities) {
+                    position[0] += velocity[0] * dt;
+                    position[1] += velocity[1] * dt;
+                }
+            }
+        }
+    }
+
+    /// Rebuilds the quadtree after the bodies moved in the middle of a step
+    /// (the root hysteresis counts the steps, not the rebuilds)
+    fn refresh_quadtree(&mut self) {
+        let oversized_steps = self.root_oversized_steps;
+        self.update_quadtree();
+        self.root_oversized_steps = self.root_oversized_steps.min(oversized_steps);
+    }
+
+    /// Forces on the bodies at their current positions, `time` being the time of the hook
+    fn compute_forces(&mut self, time: f64) {
+        phase_span!("gravity");
+        let theta_sqr = self.parameters.solver.barnes_hut_theta.powi(2);
+        let gravity_constant = self.parameters.physics.gravity_constant;
+        let softening_sqr = self.parameters.physics.softening.powi(2);
+        let (bodies, qt) = (&self.bodies, &self.qt);
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "parallel")] {
+                use crate::physics::parallel_gravity_forces;
+                // The leaves are spread across the threads of the current pool
+                self.potential_energy = parallel_gravity_forces(
+                    &mut self.forces,
+                    bodies,
+                    qt,
+                    theta_sqr,
+                    gravity_constant,
+                    softening_sqr,
+                    &mut self.leaf_pass,
+                );
+            } else {
+                use crate::physics::gravity_forces;
+                self.potential_energy = gravity_forces(
+                    &mut self.forces,
+                    bodies,
+                    qt,
+                    theta_sqr,
+                    gravity_constant,
+                    softening_sqr,
+                    &mut self.workspace,
+                );
+            }
+        }
+
+        if let Some(force_hook) = &self.force_hook {
+            for (force, body) in self.forces.iter_mut().zip(&self.bodies) {
+                let [fx, fy] = force_hook.force(&body, time);
+                force[0] += fx;
+                force[1] += fy;
+            }
+        }
+
+        for external_force in &self.parameters.external_forces {
+            let bodies = &self.bodies;
+            for (i, force) in self.forces.iter_mut().enumerate() {
+                let [fx, fy] = external_force.force(
+                    bodies.positions[i],
+                    bodies.velocities[i],
+                    bodies.masses[i],
+                );
+                force[0] += fx;
+                force[1] += fy;
+            }
+        }
+
+        spring_forces(&self.constraints, &self.bodies, &mut self.forces);
+
+        // Nothing moves a static body
+        for (force, &is_static) in self.forces.iter_mut().zip(&self.bodies.statics) {
+            if is_static {
+                *force = [0.0, 0.0];
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scenarios::Scenario;
+
+    fn root(simulation: &Simulation) -> SquareBox {
+        *simulation.quadtree().get_nodes()[0].boundary()
+    }
+
+    #[test]
+    fn positions_flat_test() {
+        let flat = |simulation: &Simulation| {
+            simulation
+                .bodies()
+                .iter()
+                .flat_map(|body| body.position)
+                .collect::<Vec<_>>()
+        };
+        let mut simulation = Simulation::new();
+        let first = simulation.add_body(Body::default().with_position([1.0, 2.0]));
+        simulation.add_bodies(vec![
+            Body::default()
+                .with_position([3.0, 4.0])
+                .with_velocity([1.0, 0.0]),
+            Body::default().with_position([-5.0, 6.0]),
+        ]);
+        assert_eq!(simulation.positions_flat(), [1.0, 2.0, 3.0, 4.0, -5.0, 6.0]);
+
+        simulation.step();
+        assert_eq!(simulation.positions_flat(), flat(&simulation));
+        assert_eq!(simulation.positions_length(), 6);
+
+        simulation.remove_body(first);
+        assert_eq!(simulation.positions_flat(), flat(&simulation));
+        simulation.reset();
+        assert!(simulation.positions_flat().is_empty());
+    }
+
+    #[test]
+    fn root_boundary_hysteresis_test() {
+        let mut simulation = Simulation::new();
+        simulation.set_solver_parameters(
+            SolverParameters::new(0.01, 0.0)
+                .with_root_padding(0.5)
+                .with_root_shrink_delay(3),
+        );
+        simulation.add_bodies(vec![
+            Body::default().with_position([-10.0, 0.0]),
+            Body::default().with_position([10.0, 0.0]),
+        ]);
+        // Grown around the bodies, with padding
+        assert_eq!(root(&simulation).half_size(), 15.0);
+
+        // An escaping body grows the root right away
+        simulation.bodies.positions[1] = [100.0, 0.0];
+        simulation.update_quadtree();
+        assert_eq!(root(&simulation).half_size(), 82.5);
+
+        // Back inside: the oversized root is kept for `root_shrink_delay` updates
+        simulation.bodies.positions[1] = [10.0, 0.0];
+        for _ in 0..3 {
+            simulation.update_quadtree();
+            assert_eq!(root(&simulation).half_size(), 82.5);
+        }
+        simulation.update_quadtree();
+        assert_eq!(root(&simulation).half_size(), 15.0);
+    }
+
+    #[test]
+    fn incremental_quadtree_test() {
+        let mut simulation = Simulation::new();
+        simulation.set_solver_parameters(SolverParameters::new(0.01, 0.0).with_root_padding(0.5));
+        simulation.add_bodies(
+            (0..100)
+                .map(|i| Body::default().with_position([(i % 10) as f64, (i / 10) as f64]))
+                .collect(),
+        );
+
+        // Moved across the root and one more added: updated in place
+        simulation.bodies.positions[0] = [8.5, 8.5];
+        simulation.add_body(Body::default().with_position([0.5, 0.5]));
+        assert_eq!(simulation.qt.len(), 101);
+        assert_eq!(simulation.qt.relocations(), 1);
+        assert_eq!(simulation.qt.get_nodes()[0].mass(), 101.0);
+        assert_eq!(
+            simulation.body_at([8.5, 8.5]),
+            Some(simulation.bodies.ids[0])
+        );
+        assert_eq!(
+            simulation.body_at([0.5, 0.5]),
+            Some(simulation.bodies.ids[100])
+        );
+
+        // A removal rebuilds it
+        let id = simulation.bodies.ids[3];
+        simulation.remove_body(id);
+        assert_eq!(simulation.qt.len(), 100);
+        assert_eq!(simulation.qt.relocations(), 0);
+        assert_eq!(simulation.qt.get_nodes()[0].mass(), 100.0);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn deterministic_test() {
+        use crate::scenarios::ScenarioKind;
+
+        let bodies = Scenario {
+            kind: ScenarioKind::Plummer { scale_radius: 20.0 },
+            count: 200,
+            seed: 3,
+            ..Scenario::default()
+        }
+        .generate(1.0);
+        let mut run = Simulation::new();
+        run.set_deterministic_mode(Some(DeterministicMode::with_seed(7)));
+        run.set_physics_parameters(PhyiscsParameters::new(1.0));
+        run.add_bodies(bodies);
+        let start = run.state_hash();
+        for _ in 0..50 {
+            run.step();
+        }
+        assert_ne!(run.state_hash(), start);
+        run.rng().next_u64();
+
+        // Resumed halfway from a snapshot, with a fresh quadtree and root box
+        let snapshot = SimulationSnapshot::from_bytes(&run.snapshot().to_bytes().unwrap()).unwrap();
+        let mut resumed = Simulation::new();
+        resumed.restore(snapshot);
+        assert_eq!(
+            resumed.deterministic_mode(),
+            Some(DeterministicMode::with_seed(7))
+        );
+        assert_eq!(resumed.state_hash(), run.state_hash());
+        for _ in 0..50 {
+            run.step();
+            resumed.step();
+        }
+        assert_eq!(resumed.state_hash(), run.state_hash());
+        assert_eq!(resumed.rng().next_u64(), run.rng().next_u64());
+
+        // The draws start over on reset
+        let first = {
+            let mut fresh = Simulation::new();
+            fresh.set_deterministic_mode(Some(DeterministicMode::with_seed(7)));
+            fresh.rng().next_u64()
+        };
+        run.reset();
+        assert_eq!(run.rng().next_u64(), first);
+    }
+
+    #[test]
+    fn tracers_test() {
+        let bodies = vec![
+            Body::default().with_position([0.0, 0.0]),
+            Body::default()
+                .with_position([5.0, 0.0])
+                .with_velocity([0.0, 1.0]),
+        ];
+        let mut with_tracers = Simulation::new();
+        let mut without_tracers = Simulation::new();
+        for simulation in [&mut with_tracers, &mut without_tracers] {
+            simulation.set_solver_parameters(SolverParameters::new(0.1, 0.0));
+            simulation.set_physics_parameters(PhyiscsParameters::new(1.0));
+            simulation.add_bodies(bodies.clone());
+        }
+        // Overlapping a body: tracers never collide
+        with_tracers.add_tracers([Tracer::new([-2.0, 0.0]), Tracer::new([0.5, 0.0])]);
+
+        with_tracers.step();
+        without_tracers.step();
+
+        // Tracers exert no force
+        for i in 0..bodies.len() {
+            assert_eq!(
+                with_tracers.bodies().body(i).position,
+                without_tracers.bodies().body(i).position
+            );
+        }
+        // Pulled towards the bodies, per unit of mass
+        let expected = with_tracers.tracers()[0].velocity[0];
+        assert!((expected - (0.25 + 1.0 / 49.0) * 0.1).abs() < 1e-12);
+        assert_eq!(with_tracers.tracers()[1].velocity[1], 0.0);
+
+        with_tracers.reset();
+        assert_eq!(with_tracers.get_number_of_tracers(), 0);
+    }
+
+    #[test]
+    fn acceleration_test() {
+        let mut simulation = Simulation::new();
+        simulation.set_solver_parameters(SolverParameters::new(0.1, 0.0));
+        simulation.set_physics_parameters(PhyiscsParameters::new(1.0));
+        simulation.add_bodies(vec![
+            Body::default().with_position([-2.0, 0.0]).with_mass(2.0),
+            Body::default().with_position([2.0, 0.0]),
+        ]);
+        assert_eq!(simulation.get_acceleration(0), [0.0, 0.0]);
+
+        simulation.step();
+        assert_eq!(simulation.get_acceleration(0), [0.0625, 0.0]);
+        assert_eq!(simulation.get_acceleration(1), [-0.125, 0.0]);
+        assert_eq!(simulation.bodies().body(1).velocity, [-0.0125, 0.0]);
+    }
+
+    #[test]
+    fn merge_test() {
+        let mut simulation = Simulation::new();
+        simulation.set_solver_parameters(SolverParameters::new(0.5, 0.0));
+        simulation.add_body(Body::default().with_velocity([1.0, 0.0]));
+
+        let mut other = Simulation::new();
+        other.set_solver_parameters(SolverParameters::new(0.1, 0.0));
+        other.add_body(Body::default().with_position([1.0, 1.0]));
+        other.add_tracers([Tracer::new([2.0, 2.0])]);
+
+        simulation.merge(other, [10.0, 0.0], [0.0, -1.0]);
+        assert_eq!(simulation.get_number_of_bodies(), 2);
+        assert_eq!(simulation.bodies().body(1).position, [11.0, 1.0]);
+        assert_eq!(simulation.bodies().body(1).velocity, [0.0, -1.0]);
+        assert_eq!(simulation.tracers()[0].position, [12.0, 2.0]);
+        assert_eq!(simulation.parameters.solver.dt(), 0.5);
+        assert!(root(&simulation).contains_box(&SquareBox::from_bodies(&simulation.bodies)));
+    }
+
+    #[test]
+    fn body_at_test() {
+        let mut simulation = Simulation::new();
+        let small = simulation.add_body(Body::default().with_position([2.5, 0.0]));
+        let large = simulation.add_body(Body {
+            radius: 5.0,
+            ..Body::default().with_position([-3.0, 0.0])
+        });
+        assert_eq!(simulation.body_at([3.2, 0.0]), Some(small));
+        assert_eq!(simulation.body_at([0.0, 0.0]), Some(large));
+        // Within both, closer to the center of the small one
+        assert_eq!(simulation.body_at([1.8, 0.0]), Some(small));
+        assert_eq!(simulation.body_at([0.0, 4.5]), None);
+    }
+
+    #[test]
+    fn boundary_condition_test() {
+        let world = SquareBox::new([0.0, 0.0], 10.0);
+        let body = Body::default()
+            .with_position([9.5, 0.0])
+            .with_velocity([10.0, -1.0]);
+
+        // Bounces off the wall at x = 10, with its surface
+        let mut simulation = Simulation::new();
+        simulation.set_physics_parameters(PhyiscsParameters::new(0.0));
+        simulation.set_boundary_condition(BoundaryCondition::Reflective(world));
+        simulation.add_body(body);
+        simulation.step_with_dt(0.1);
+        let body_after = simulation.bodies().body(0);
+        assert!((body_after.position[0] - 7.5).abs() < 1e-12);
+        assert_eq!(body_after.velocity, [-10.0, -1.0]);
+
+        // Comes back in on the left
+        simulation.set_boundary_condition(BoundaryCondition::Periodic(world));
+        simulation.update_body(body_after.id, body).unwrap();
+        simulation.step_with_dt(0.1);
+        assert!((simulation.bodies().body(0).position[0] + 9.5).abs() < 1e-12);
+        assert_eq!(simulation.bodies().body(0).velocity, [10.0, -1.0]);
+    }
+
+    #[test]
+    fn invalid_dt_test() {
+        let mut simulation = Simulation::new();
+        simulation.add_body(Body::default().with_velocity([1.0, 0.0]));
+        for dt in [-0.1, f64::NAN, f64::INFINITY, 1e300] {
+            simulation.step_with_dt(dt);
+        }
+        assert_eq!(simulation.get_physical_time(), 0.0);
+        assert_eq!(simulation.bodies().body(0).position, [0.0, 0.0]);
+    }
+
+    #[test]
+    fn merge_collisions_test() {
+        let mut simulation = Simulation::new();
+        simulation.set_physics_parameters(
+            PhyiscsParameters::new(0.0).with_collision_model(CollisionModel::Merge),
+        );
+        simulation.add_bodies(vec![
+            Body::default().with_velocity([1.0, 0.0]),
+            Body::default()
+                .with_position([5.0, 0.0])
+                .with_velocity([-1.0, 0.0]),
+            Body::default().with_position([0.0, 50.0]),
+        ]);
+        let ids: Vec<_> = simulation.bodies().iter().map(|body| body.id).collect();
+
+        while simulation.get_number_of_bodies() == 3 {
+            assert!(simulation.get_physical_time() < 5.0);
+            simulation.step();
+        }
+        assert_eq!(simulation.collision_events().len(), 1);
+        assert!(simulation.get_body(ids[1]).is_none());
+        let merged = simulation.get_body(ids[0]).unwrap();
+        assert_eq!(merged.mass, 2.0);
+        assert_eq!(merged.velocity, [0.0, 0.0]);
+        assert_eq!(simulation.linear_momentum(), [0.0, 0.0]);
+    }
+
+    #[test]
+    fn timeline_test() {
+        let mut simulation = Simulation::new();
+        simulation.set_solver_parameters(SolverParameters::new(1.0, 0.0));
+        simulation.set_timeline(
+            Timeline::new()
+                .with_event(2.0, TimelineAction::SetGravityConstant(50.0))
+                .with_event(
+                    1.0,
+                    TimelineAction::SpawnScenario(Scenario {
+                        count: 10,
+                        ..Scenario::default()
+                    }),
+                )
+                .with_event(1.0, TimelineAction::AddTracers(vec![Tracer::default()])),
+        );
+        assert_eq!(simulation.timeline().events[2].time, 2.0);
+
+        simulation.step();
+        assert_eq!(simulation.get_number_of_bodies(), 0);
+        simulation.step();
+        assert_eq!(simulation.get_number_of_bodies(), 10);
+        assert_eq!(simulation.get_number_of_tracers(), 1);
+        assert_eq!(simulation.parameters.physics.gravity_constant(), 100.0);
+        simulation.step();
+        assert_eq!(simulation.parameters.physics.gravity_constant(), 50.0);
+
+        // Replayed after a reset, not when restoring a later state
+        simulation.reset();
+        simulation.step();
+        simulation.step();
+        assert_eq!(simulation.get_number_of_bodies(), 10);
+        simulation.restore_bodies(Vec::new(), 1.5);
+        simulation.step();
+        assert_eq!(simulation.get_number_of_bodies(), 0);
+    }
+
+    #[test]
+    fn force_hook_test() {
+        struct Wind;
+        impl ForceHook for Wind {
+            fn force(&self, body: &Body, time: f64) -> [f64; 2] {
+                [body.mass * (time + 1.0), 0.0]
+            }
+        }
+
+        let mut simulation = Simulation::new();
+        simulation.set_solver_parameters(SolverParameters::new(1.0, 0.0));
+        simulation.add_body(Body::default().with_mass(2.0));
+        simulation.set_force_hook(Some(Box::new(Wind)));
+
+        simulation.step();
+        simulation.step();
+        assert_eq!(simulation.get_acceleration(0), [2.0, 0.0]);
+        assert_eq!(simulation.bodies().body(0).velocity, [3.0, 0.0]);
+    }
+
+    #[test]
+    fn external_forces_test() {
+        let mut simulation = Simulation::new();
+        simulation.set_solver_parameters(SolverParameters::new(1.0, 0.0));
+        simulation.add_body(Body::default().with_mass(2.0).with_velocity([1.0, 0.0]));
+        simulation.add_external_force(ExternalForce::Uniform {
+            acceleration: [0.0, -1.0],
+        });
+        simulation.add_external_force(ExternalForce::LinearDrag { coefficient: 1.0 });
+        simulation.step();
+        // m a = m g - k v
+        assert_eq!(simulation.get_acceleration(0), [-0.5, -1.0]);
+
+        simulation.set_external_forces(vec![ExternalForce::Attractor {
+            position: [0.0, 2.0],
+            strength: 8.0,
+        }]);
+        simulation.bodies.set(0, Body::default().with_mass(2.0));
+        simulation.step();
+        assert_eq!(simulation.get_acceleration(0), [0.0, 2.0]);
+
+        let drag = ExternalForce::QuadraticDrag { coefficient: 0.5 };
+        assert_eq!(drag.force([0.0, 0.0], [3.0, 4.0], 1.0), [-7.5, -10.0]);
+        assert!(!ExternalForce::LinearDrag { coefficient: -1.0 }.is_valid());
+
+        simulation.clear_external_forces();
+        simulation.step();
+        assert_eq!(simulation.get_acceleration(0), [0.0, 0.0]);
+    }
+
+    #[test]
+    fn external_forces_tracers_test() {
+        let mut simulation = Simulation::new();
+        simulation.set_solver_parameters(SolverParameters::new(1.0, 0.0));
+        simulation.set_physics_parameters(PhyiscsParameters::new(0.0));
+        simulation.add_body(Body::default().with_position([10.0, 0.0]));
+        simulation.add_tracers([Tracer::new([0.0, 0.0])]);
+        simulation.add_external_force(ExternalForce::Uniform {
+            acceleration: [0.0, -1.0],
+        });
+        simulation.step();
+        // Same acceleration as the bodies, whatever their mass
+        assert_eq!(simulation.tracers()[0].velocity, [0.0, -1.0]);
+        assert_eq!(simulation.tracers()[0].position, [0.0, -1.0]);
+
+        // The drag is per unit of mass too
+        simulation.set_external_forces(vec![ExternalForce::LinearDrag { coefficient: 0.5 }]);
+        simulation.step();
+        assert_eq!(simulation.tracers()[0].velocity, [0.0, -0.5]);
+    }
+
+    #[test]
+    fn constraints_test() {
+        let mut simulation = Simulation::new();
+        simulation.add_bodies(vec![
+            Body::default().with_mass(10.0),
+            Body::default()
+                .with_position([5.0, 0.0])
+                .with_velocity([0.0, 3.0]),
+            Body::default().with_position([-5.0, 0.0]),
+        ]);
+        let [a, b, c] = [0, 1, 2].map(|i| simulation.bodies().body(i).id);
+        assert!(matches!(
+            simulation.add_constraint(Constraint::rod(a, BodyId(9), 1.0)),
+            Err(PhysicsError::UnknownBody(BodyId(9)))
+        ));
+        simulation
+            .add_constraint(Constraint::rod(a, b, 5.0))
+            .unwrap();
+        simulation
+            .add_constraint(Constraint::spring(c, a, 5.0, 1.0))
+            .unwrap();
+        // Replaces the spring
+        simulation
+            .add_constraint(Constraint::rod(a, c, 5.0))
+            .unwrap();
+        assert_eq!(simulation.constraints().len(), 2);
+
+        for _ in 0..100 {
+            simulation.step();
+        }
+        let bodies = simulation.bodies();
+        for i in [1, 2] {
+            let [dx, dy] = [0, 1].map(|k| bodies.positions[i][k] - bodies.positions[0][k]);
+            assert!((dx.hypot(dy) - 5.0).abs() < 1e-6);
+        }
+
+        let snapshot = simulation.snapshot();
+        simulation.remove_body(c);
+        assert_eq!(simulation.constraints(), [Constraint::rod(a, b, 5.0)]);
+        assert!(simulation.remove_constraint(b, a));
+        assert!(!simulation.remove_constraint(b, a));
+
+        simulation.restore(snapshot);
+        assert_eq!(simulation.constraints().len(), 2);
+        simulation.reset();
+        assert!(simulation.constraints().is_empty());
+    }
+
+    #[test]
+    fn bodies_in_test() {
+        let mut simulation = Simulation::new();
+        simulation.add_bodies(
+            (0..100)
+                .map(|i| Body::default().with_position([i as f64, -(i as f64)]))
+                .collect(),
+        );
+        let region = SquareBox::new([20.0, -20.0], 5.5);
+        assert_eq!(simulation.bodies_in(region), (15..=25).collect::<Vec<_>>());
+
+        // Moved out since the quadtree was built
+        simulation.bodies.positions[20] = [1000.0, 0.0];
+        assert!(!simulation.bodies_in(region).contains(&20));
+    }
+
+    #[test]
+    fn integrator_energy_test() {
+        // A light body on a circular orbit around a heavy one
+        let energy_drift = |integrator| {
+            let mut simulation = Simulation::new();
+            simulation.set_solver_parameters(
+                SolverParameters::new(0.01, 0.0).with_integrator(integrator),
+            );
+            simulation.add_bodies(vec![
+                Body::default().with_mass(1000.0),
+                Body::default()
+                    .with_position([10.0, 0.0])
+                    .with_velocity([0.0, 100.0]),
+            ]);
+            let energy = |simulation: &Simulation| {
+                let [a, b] = [simulation.bodies().body(0), simulation.bodies().body(1)];
+                let distance = (a.position[0] - b.position[0]).hypot(a.position[1] - b.position[1]);
+                a.kinectic_energy() + b.kinectic_energy() - 100.0 * a.mass * b.mass / distance
+            };
+            let initial = energy(&simulation);
+            (0..500).for_each(|_| simulation.step());
+            ((energy(&simulation) - initial) / initial).abs()
+        };
+        let euler = energy_drift(Integrator::Euler);
+        let verlet = energy_drift(Integrator::VelocityVerlet);
+        let leapfrog = energy_drift(Integrator::Leapfrog);
+        assert!(verlet < euler / 10.0, "{verlet} vs {euler}");
+        assert!(leapfrog < euler / 10.0, "{leapfrog} vs {euler}");
+    }
+
+    #[test]
+    fn conservation_diagnostics_test() {
+        let mut simulation = Simulation::new();
+        simulation.set_solver_parameters(
+            SolverParameters::new(0.01, 0.0).with_integrator(Integrator::VelocityVerlet),
+        );
+        simulation.add_bodies(vec![
+            Body::default().with_mass(1000.0).with_velocity([0.0, -0.1]),
+            Body::default()
+                .with_position([10.0, 0.0])
+                .with_velocity([0.0, 100.0]),
+        ]);
+        assert_eq!(simulation.linear_momentum(), [0.0, 0.0]);
+        let angular_momentum = simulation.angular_momentum();
+        assert_eq!(angular_momentum, 1000.0);
+
+        simulation.step();
+        // At the positions the step ended at
+        let [a, b] = [simulation.bodies().body(0), simulation.bodies().body(1)];
+        let distance = (a.position[0] - b.position[0]).hypot(a.position[1] - b.position[1]);
+        let potential = -100.0 * a.mass * b.mass / distance;
+        assert!((simulation.potential_energy() - potential).abs() < 1e-9 * potential.abs());
+        let initial = 0.5 * 1000.0 * 0.01 + 0.5 * 100.0 * 100.0 - 100.0 * 1000.0 / 10.0;
+        (0..100).for_each(|_| simulation.step());
+        assert!(((simulation.total_energy() - initial) / initial).abs() < 1e-3);
+        assert!((simulation.angular_momentum() - angular_momentum).abs() < 1e-6);
+        let [px, py] = simulation.linear_momentum();
+        assert!(px.abs() < 1e-9 && py.abs() < 1e-9);
+    }
+
+    #[test]
+    fn static_body_test() {
+        let mut simulation = Simulation::new();
+        simulation.set_solver_parameters(SolverParameters::new(0.01, 0.0));
+        let star = simulation.add_body(
+            Body::default()
+                .with_mass(1000.0)
+                .with_velocity([5.0, 0.0])
+                .with_static(true),
+        );
+        let planet = simulation.add_body(
+            Body::default()
+                .with_position([50.0, 0.0])
+                .with_velocity([0.0, 1.0]),
+        );
+        for _ in 0..100 {
+            simulation.step();
+        }
+
+        // Pulls the planet without being pulled back
+        let star = simulation.get_body(star).unwrap();
+        assert_eq!(star.position, [0.0, 0.0]);
+        assert_eq!(star.velocity, [0.0, 0.0]);
+        assert!(simulation.get_body(planet).unwrap().position[0] < 50.0);
+        assert_eq!(simulation.get_acceleration(0), [0.0, 0.0]);
+    }
+
+    #[test]
+    fn trails_test() {
+        let mut simulation = Simulation::new();
+        simulation.set_solver_parameters(SolverParameters::new(1.0, 0.0));
+        simulation.set_physics_parameters(PhyiscsParameters::new(0.0));
+        simulation.enable_trails(2);
+        let id = simulation.add_body(Body::default().with_velocity([1.0, 0.0]));
+        for _ in 0..3 {
+            simulation.step();
+        }
+        assert_eq!(simulation.get_trail(id), [2.0, 0.0, 3.0, 0.0]);
+
+        simulation.remove_body(id);
+        let other = simulation.add_body(Body::default());
+        simulation.step();
+        let trails = simulation.trails().unwrap();
+        assert!(trails.get(id).is_none());
+        assert_eq!(trails.get(other).unwrap().len(), 1);
+
+        simulation.reset();
+        assert_eq!(simulation.trails().unwrap().iter().count(), 0);
+        simulation.enable_trails(0);
+        assert!(simulation.trails().is_none());
+    }
+
+    #[test]
+    fn adaptive_timestep_test() {
+        let mut simulation = Simulation::new();
+        simulation.set_solver_parameters(
+            SolverParameters::new(0.01, 0.0)
+                .with_adaptive_timestep(AdaptiveTimestep::new(1e-4, 0.1)),
+        );
+        simulation.add_bodies(vec![
+            Body::default(),
+            Body::default().with_position([1000.0, 0.0]),
+        ]);
+        simulation.step();
+        assert_eq!(simulation.get_last_dt(), 0.1);
+
+        // A heavy body close by: 0.2 sqrt(radius / (G 1000 / 3²))
+        simulation.add_body(Body::default().with_position([3.0, 0.0]).with_mass(1000.0));
+        simulation.step();
+        let expected = 0.2 * (9.0 / 100_000.0f64).sqrt();
+        assert!((simulation.get_last_dt() - expected).abs() < 1e-3 * expected);
+
+        // Grown back once the bodies are apart
+        simulation.remove_body(BodyId(2));
+        simulation.step();
+        assert_eq!(simulation.get_last_dt(), 0.1);
+    }
+
+    #[test]
+    fn remove_bodies_test() {
+        let mut simulation = Simulation::new();
+        simulation.add_bodies(
+            (0..5)
+                .map(|i| Body::default().with_position([10.0 * i as f64, 0.0]))
+                .collect(),
+        );
+        let removed = simulation.remove_bodies(&[3, 1, 3, 42].map(BodyId));
+        let x = |bodies: &BodyStorage| bodies.iter().map(|b| b.position[0]).collect::<Vec<_>>();
+        assert_eq!(x(&BodyStorage::from(removed)), [10.0, 30.0]);
+        assert_eq!(x(simulation.bodies()), [0.0, 20.0, 40.0]);
+        assert_eq!(simulation.forces.len(), 3);
+        assert_eq!(simulation.bodies_in(SquareBox::new([40.0, 0.0], 1.0)), [2]);
+    }
+
+    #[test]
+    fn body_id_test() {
+        let mut simulation = Simulation::new();
+        let first = simulation.add_body(Body::default());
+        simulation.add_bodies(vec![Body::default().with_position([5.0, 0.0]); 3]);
+        let ids = |simulation: &Simulation| {
+            simulation
+                .bodies()
+                .iter()
+                .map(|b| b.id.0)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&simulation), [0, 1, 2, 3]);
+
+        // Removed ids are not reused and the others do not move
+        simulation.remove_body(BodyId(1)).unwrap();
+        assert_eq!(simulation.add_body(Body::default()), BodyId(4));
+        assert_eq!(simulation.body_index(BodyId(2)), Some(1));
+        assert!(simulation.get_body(BodyId(1)).is_none());
+        assert!(matches!(
+            simulation.try_get_body(BodyId(1)),
+            Err(PhysicsError::UnknownBody(BodyId(1)))
+        ));
+
+        // The given id of the body is ignored
+        let update = Body::default().with_mass(3.0);
+        simulation
+            .update_body(
+                first,
+                Body {
+                    id: BodyId(9),
+                    ..update
+                },
+            )
+            .unwrap();
+        assert_eq!(simulation.get_body(first).unwrap().mass, 3.0);
+        assert!(simulation.update_body(BodyId(1), update).is_err());
+
+        // Restored bodies keep their ids, and new ones do not take the removed ones
+        simulation.remove_body(BodyId(4));
+        let mut restored = Simulation::new();
+        restored.restore(simulation.snapshot());
+        assert_eq!(ids(&restored), [0, 2, 3]);
+        assert_eq!(restored.add_body(Body::default()), BodyId(5));
+    }
 }
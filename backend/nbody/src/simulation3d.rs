@@ -0,0 +1,260 @@
+//! 3D counterpart of `Simulation`, with the same solver and physics parameters
+//!
+//! Only the gravity (Barnes-Hut over an octree) and the semi-implicit Euler integration
+//! are supported so far: the bodies do not collide, and the integrator, adaptive time step,
+//! collision model and boundary condition of the parameters are ignored
+
+use alloc::vec::Vec;
+#[cfg(not(any(feature = "std", test)))]
+use num_traits::Float;
+
+use crate::{
+    octree::{CubeBox, Octree},
+    physics::{Body3, BodyId},
+    simulation::{PhyiscsParameters, SimulationParameters, SolverParameters},
+    SMALL,
+};
+
+pub struct Simulation3 {
+    bodies: Vec<Body3>,
+    forces: Vec<[f64; 3]>,
+    tree: Octree,
+    parameters: SimulationParameters,
+    physical_time: f64,
+    kinetic_energy: f64,
+
+    /// Id of the next body added, never reused (the bodies stay sorted by id)
+    next_body_id: u64,
+}
+
+impl Default for Simulation3 {
+    fn default() -> Self {
+        Self {
+            bodies: Vec::new(),
+            forces: Vec::new(),
+            tree: Octree::new(CubeBox::new([0.0; 3], 1.0)),
+            parameters: SimulationParameters::default(),
+            physical_time: 0.0,
+            kinetic_energy: 0.0,
+            next_body_id: 0,
+        }
+    }
+}
+
+impl Simulation3 {
+    pub fn new() -> Self {
+        Simulation3::default()
+    }
+
+    /// Adds the body with a new id, which is returned
+    pub fn add_body(&mut self, body: Body3) -> BodyId {
+        let id = BodyId(self.next_body_id);
+        self.next_body_id += 1;
+        self.bodies.push(Body3 { id, ..body });
+        self.forces.push([0.0; 3]);
+        id
+    }
+
+    /// Adds the bodies with new ids
+    pub fn add_bodies(&mut self, bodies: impl IntoIterator<Item = Body3>) {
+        for body in bodies {
+            self.add_body(body);
+        }
+    }
+
+    /// Removes the body with that id and returns it (None if there is none)
+    pub fn remove_body(&mut self, id: BodyId) -> Option<Body3> {
+        let index = self.bodies.binary_search_by_key(&id, |body| body.id).ok()?;
+        self.forces.remove(index);
+        Some(self.bodies.remove(index))
+    }
+
+    pub fn bodies(&self) -> &[Body3] {
+        &self.bodies
+    }
+
+    pub fn parameters(&self) -> &SimulationParameters {
+        &self.parameters
+    }
+
+    pub fn octree(&self) -> &Octree {
+        &self.tree
+    }
+
+    pub fn set_solver_parameters(&mut self, parameters: SolverParameters) {
+        self.parameters.solver = parameters;
+    }
+
+    pub fn set_physics_parameters(&mut self, parameters: PhyiscsParameters) {
+        self.parameters.physics = parameters;
+    }
+
+    pub fn get_physical_time(&self) -> f64 {
+        self.physical_time
+    }
+
+    pub fn get_number_of_bodies(&self) -> usize {
+        self.bodies.len()
+    }
+
+    pub fn get_kinetic_energy(&self) -> f64 {
+        self.kinetic_energy
+    }
+
+    /// Advances the simulation by the solver's time step
+    pub fn step(&mut self) {
+        self.step_with_dt(self.parameters.solver.dt());
+    }
+
+    /// Advances the simulation by the given time step
+    pub fn step_with_dt(&mut self, dt: f64) {
+        self.update_octree();
+        self.compute_forces();
+        for (body, force) in self.bodies.iter_mut().zip(&self.forces) {
+            let Body3 {
+                position,
+                velocity,
+                mass,
+                ..
+            } = body;
+            for ((position, velocity), force) in position.iter_mut().zip(velocity).zip(force) {
+                *velocity += force / *mass * dt;
+                *position += *velocity * dt;
+            }
+        }
+        self.kinetic_energy = self.bodies.iter().map(Body3::kinectic_energy).sum();
+        self.physical_time += dt;
+    }
+
+    pub fn reset(&mut self) {
+        self.bodies.clear();
+        self.forces.clear();
+        self.physical_time = 0.0;
+        self.kinetic_energy = 0.0;
+    }
+}
+
+// Private helper functions
+impl Simulation3 {
+    fn update_octree(&mut self) {
+        let padding = self.parameters.solver.root_padding();
+        self.tree
+            .clear(CubeBox::from_bodies(&self.bodies).padded(padding));
+        for index in 0..self.bodies.len() {
+            self.tree.insert_unchecked(index, &self.bodies);
+        }
+    }
+
+    fn compute_forces(&mut self) {
+        let theta_sqr = self.parameters.solver.barnes_hut_theta().powi(2);
+        let gravity_constant = self.parameters.physics.gravity_constant();
+        let softening_sqr = self.parameters.physics.softening().powi(2);
+        let mut stack = Vec::new();
+        for index in 0..self.bodies.len() {
+            self.forces[index] = gravity_force(
+                index,
+                &self.bodies,
+                &self.tree,
+                theta_sqr,
+                gravity_constant,
+                softening_sqr,
+                &mut stack,
+            );
+        }
+    }
+}
+
+/// Barnes-Hut gravity force on the i-th body, `stack` being a scratch buffer
+fn gravity_force(
+    ith_body: usize,
+    bodies: &[Body3],
+    tree: &Octree,
+    theta_sqr_threshold: f64,
+    gravity_constant: f64,
+    softening_sqr: f64,
+    stack: &mut Vec<usize>,
+) -> [f64; 3] {
+    let body = &bodies[ith_body];
+    let nodes = tree.get_nodes();
+    let mut force = [0.0; 3];
+    let attract = |towards: [f64; 3], mass: f64, force: &mut [f64; 3]| {
+        let delta: [f64; 3] = core::array::from_fn(|axis| towards[axis] - body.position[axis]);
+        let distance_sqr = delta.iter().map(|d| d * d).sum::<f64>() + softening_sqr;
+        let magnitude = gravity_constant * body.mass * mass / distance_sqr;
+        let distance = distance_sqr.sqrt();
+        for axis in 0..3 {
+            force[axis] += magnitude * delta[axis] / distance;
+        }
+    };
+
+    stack.clear();
+    stack.push(0);
+    while let Some(node_idx) = stack.pop() {
+        let node = &nodes[node_idx];
+        if node.is_leaf() {
+            for &other in node.referenced_indices() {
+                if other != ith_body {
+                    attract(bodies[other].position, bodies[other].mass, &mut force);
+                }
+            }
+            continue;
+        }
+        let center = node.center_of_mass();
+        let distance_sqr: f64 = (0..3)
+            .map(|axis| (center[axis] - body.position[axis]).powi(2))
+            .sum();
+        let size = node.boundary().size();
+        if distance_sqr >= SMALL && size * size / distance_sqr < theta_sqr_threshold {
+            attract(center, node.mass(), &mut force);
+        } else {
+            stack.extend(node.children_idx()..node.children_idx() + 8);
+        }
+    }
+    force
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn two_bodies_test() {
+        let mut simulation = Simulation3::new();
+        simulation.set_physics_parameters(PhyiscsParameters::new(1.0));
+        let ids = [
+            simulation.add_body(Body3::default()),
+            simulation.add_body(Body3::default().with_position([0.0, 0.0, 2.0])),
+        ];
+        simulation.step_with_dt(0.1);
+
+        // Attracted towards each other along z, G m m / r^2 = 1 / 4
+        let bodies = simulation.bodies();
+        assert!((bodies[0].velocity[2] - 0.025).abs() < 1e-12);
+        assert_eq!(bodies[1].velocity[2], -bodies[0].velocity[2]);
+        assert_eq!(bodies[0].velocity[..2], [0.0, 0.0]);
+        assert!((simulation.get_physical_time() - 0.1).abs() < 1e-12);
+
+        assert!(simulation.remove_body(ids[0]).is_some());
+        assert!(simulation.remove_body(ids[0]).is_none());
+        assert_eq!(simulation.get_number_of_bodies(), 1);
+    }
+
+    #[test]
+    fn barnes_hut_test() {
+        // A far cluster approximated as a whole, close to the exact sum
+        let mut bodies = vec![Body3::default()];
+        for i in 0..20 {
+            let offset = (i as f64 / 20.0) - 0.5;
+            bodies.push(Body3::default().with_position([100.0 + offset, offset, -offset]));
+        }
+        let mut tree = Octree::new(CubeBox::from_bodies(&bodies).padded(0.1)).with_capacity(4);
+        (0..bodies.len()).for_each(|i| tree.insert_unchecked(i, &bodies));
+
+        let mut stack = Vec::new();
+        let exact = gravity_force(0, &bodies, &tree, 0.0, 1.0, 0.0, &mut stack);
+        let approximated = gravity_force(0, &bodies, &tree, 0.25, 1.0, 0.0, &mut stack);
+        assert!(exact[0] > 0.0);
+        assert!((approximated[0] - exact[0]).abs() / exact[0] < 1e-3);
+    }
+}
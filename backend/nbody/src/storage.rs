@@ -0,0 +1,291 @@
+//! Structure-of-arrays storage of the bodies of a `Simulation`
+//!
+//! Every field of `Body` lives in an array of its own, so that a pass reading a couple of them
+//! (e.g. the positions and masses of the gravity pass) streams through contiguous memory instead
+//! of striding over whole bodies. `Body` stays the type the bodies are added, serialized and
+//! viewed as, assembled from the arrays on the way out
+
+use alloc::vec::Vec;
+
+use crate::physics::{Body, BodyId};
+
+/// The arrays are always the same length, the i-th body being the i-th entry of each of them
+#[derive(Clone, Debug, Default)]
+pub struct BodyStorage {
+    pub(crate) ids: Vec<BodyId>,
+    pub(crate) positions: Vec<[f64; 2]>,
+    pub(crate) velocities: Vec<[f64; 2]>,
+    pub(crate) masses: Vec<f64>,
+    pub(crate) radii: Vec<f64>,
+    pub(crate) colors: Vec<[u8; 4]>,
+    pub(crate) statics: Vec<bool>,
+}
+
+impl BodyStorage {
+    pub fn new() -> Self {
+        BodyStorage::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// The i-th body, panics if there is none
+    pub fn body(&self, index: usize) -> Body {
+        Body {
+            id: self.ids[index],
+            position: self.positions[index],
+            velocity: self.velocities[index],
+            mass: self.masses[index],
+            radius: self.radii[index],
+            color: self.colors[index],
+            is_static: self.statics[index],
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<Body> {
+        (index < self.len()).then(|| self.body(index))
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            storage: self,
+            range: 0..self.len(),
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<Body> {
+        self.iter().collect()
+    }
+
+    pub fn ids(&self) -> &[BodyId] {
+        &self.ids
+    }
+
+    pub fn positions(&self) -> &[[f64; 2]] {
+        &self.positions
+    }
+
+    pub fn velocities(&self) -> &[[f64; 2]] {
+        &self.velocities
+    }
+
+    pub fn masses(&self) -> &[f64] {
+        &self.masses
+    }
+
+    pub fn radii(&self) -> &[f64] {
+        &self.radii
+    }
+
+    pub fn colors(&self) -> &[[u8; 4]] {
+        &self.colors
+    }
+
+    pub fn statics(&self) -> &[bool] {
+        &self.statics
+    }
+
+    /// The velocity of a static body is zeroed
+    pub fn push(&mut self, body: Body) {
+        self.ids.push(body.id);
+        self.positions.push(body.position);
+        self.velocities.push(initial_velocity(&body));
+        self.masses.push(body.mass);
+        self.radii.push(body.radius);
+        self.colors.push(body.color);
+        self.statics.push(body.is_static);
+    }
+
+    /// Replaces the i-th body, panics if there is none
+    pub fn set(&mut self, index: usize, body: Body) {
+        self.ids[index] = body.id;
+        self.positions[index] = body.position;
+        self.velocities[index] = initial_velocity(&body);
+        self.masses[index] = body.mass;
+        self.radii[index] = body.radius;
+        self.colors[index] = body.color;
+        self.statics[index] = body.is_static;
+    }
+
+    /// One over the mass, zero for a static body
+    pub(crate) fn inverse_mass(&self, index: usize) -> f64 {
+        if self.statics[index] {
+            0.0
+        } else {
+            1.0 / self.masses[index]
+        }
+    }
+
+    /// Removes the i-th body, shifting the ones after it down by one index
+    pub fn remove(&mut self, index: usize) -> Body {
+        let body = self.body(index);
+        self.ids.remove(index);
+        self.positions.remove(index);
+        self.velocities.remove(index);
+        self.masses.remove(index);
+        self.radii.remove(index);
+        self.colors.remove(index);
+        self.statics.remove(index);
+        body
+    }
+
+    /// Keeps the bodies for which `keep` returns true (given their index), in order
+    pub fn retain_indices(&mut self, mut keep: impl FnMut(usize) -> bool) {
+        let kept: Vec<bool> = (0..self.len()).map(&mut keep).collect();
+        fn retain<T>(values: &mut Vec<T>, kept: &[bool]) {
+            let mut flags = kept.iter();
+            values.retain(|_| flags.next().is_some_and(|&kept| kept));
+        }
+        retain(&mut self.ids, &kept);
+        retain(&mut self.positions, &kept);
+        retain(&mut self.velocities, &kept);
+        retain(&mut self.masses, &kept);
+        retain(&mut self.radii, &kept);
+        retain(&mut self.colors, &kept);
+        retain(&mut self.statics, &kept);
+    }
+
+    pub fn clear(&mut self) {
+        self.ids.clear();
+        self.positions.clear();
+        self.velocities.clear();
+        self.masses.clear();
+        self.radii.clear();
+        self.colors.clear();
+        self.statics.clear();
+    }
+
+    pub fn kinetic_energy(&self) -> f64 {
+        self.masses
+            .iter()
+            .zip(&self.velocities)
+            .map(|(mass, velocity)| {
+                0.5 * mass * (velocity[0] * velocity[0] + velocity[1] * velocity[1])
+            })
+            .sum()
+    }
+}
+
+fn initial_velocity(body: &Body) -> [f64; 2] {
+    if body.is_static {
+        [0.0, 0.0]
+    } else {
+        body.velocity
+    }
+}
+
+impl Extend<Body> for BodyStorage {
+    fn extend<I: IntoIterator<Item = Body>>(&mut self, bodies: I) {
+        let bodies = bodies.into_iter();
+        let additional = bodies.size_hint().0;
+        self.ids.reserve(additional);
+        self.positions.reserve(additional);
+        self.velocities.reserve(additional);
+        self.masses.reserve(additional);
+        self.radii.reserve(additional);
+        self.colors.reserve(additional);
+        self.statics.reserve(additional);
+        bodies.for_each(|body| self.push(body));
+    }
+}
+
+impl FromIterator<Body> for BodyStorage {
+    fn from_iter<I: IntoIterator<Item = Body>>(bodies: I) -> Self {
+        let mut storage = BodyStorage::new();
+        storage.extend(bodies);
+        storage
+    }
+}
+
+impl From<Vec<Body>> for BodyStorage {
+    fn from(bodies: Vec<Body>) -> Self {
+        bodies.into_iter().collect()
+    }
+}
+
+/// The bodies of a `BodyStorage`, assembled one by one
+#[derive(Clone)]
+pub struct Iter<'a> {
+    storage: &'a BodyStorage,
+    range: core::ops::Range<usize>,
+}
+
+impl Iterator for Iter<'_> {
+    type Item = Body;
+
+    fn next(&mut self) -> Option<Body> {
+        self.range.next().map(|index| self.storage.body(index))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.range.size_hint()
+    }
+}
+
+impl DoubleEndedIterator for Iter<'_> {
+    fn next_back(&mut self) -> Option<Body> {
+        self.range.next_back().map(|index| self.storage.body(index))
+    }
+}
+
+impl ExactSizeIterator for Iter<'_> {}
+
+impl<'a> IntoIterator for &'a BodyStorage {
+    type Item = Body;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn storage_test() {
+        let bodies: Vec<Body> = (0..4)
+            .map(|i| Body {
+                id: BodyId(i),
+                radius: 2.0,
+                color: [i as u8; 4],
+                ..Body::default()
+                    .with_position([i as f64, -(i as f64)])
+                    .with_velocity([1.0, i as f64])
+                    .with_mass(1.0 + i as f64)
+            })
+            .collect();
+        let mut storage = BodyStorage::from(bodies.clone());
+        assert_eq!(storage.len(), 4);
+        assert_eq!(storage.positions()[3], [3.0, -3.0]);
+        assert_eq!(storage.masses(), [1.0, 2.0, 3.0, 4.0]);
+        let round_trip = storage.to_vec();
+        assert!(round_trip
+            .iter()
+            .zip(&bodies)
+            .all(|(a, b)| a.id == b.id && a.velocity == b.velocity && a.color == b.color));
+
+        let removed = storage.remove(1);
+        assert_eq!(removed.id, BodyId(1));
+        assert_eq!(storage.ids(), [BodyId(0), BodyId(2), BodyId(3)]);
+        storage.retain_indices(|index| index != 0);
+        assert_eq!(storage.ids(), [BodyId(2), BodyId(3)]);
+        assert_eq!(storage.velocities(), [[1.0, 2.0], [1.0, 3.0]]);
+
+        storage.set(0, bodies[0]);
+        assert_eq!(storage.body(0).mass, 1.0);
+        assert!(storage.get(2).is_none());
+        assert_eq!(storage.kinetic_energy(), 0.5 + 0.5 * 4.0 * 10.0);
+
+        storage.push(bodies[1].with_static(true));
+        assert_eq!(storage.statics(), [false, false, true]);
+        assert_eq!(storage.velocities()[2], [0.0, 0.0]);
+        assert_eq!(storage.inverse_mass(2), 0.0);
+    }
+}
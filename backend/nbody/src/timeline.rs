@@ -0,0 +1,53 @@
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
+
+use crate::{
+    physics::{Body, Tracer},
+    scenarios::Scenario,
+    simulation::{PhyiscsParameters, SolverParameters},
+};
+
+/// Scripted events applied by the simulation as its physical time reaches them
+/// (e.g. `at t=5.0 spawn a disc`, `at t=10.0 set G=50`), so that demos can be authored
+/// as data and loaded from any serde format
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+pub struct Timeline {
+    /// Need not be sorted, events at the same time are applied in this order
+    pub events: Vec<TimelineEvent>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+pub struct TimelineEvent {
+    /// Physical time (seconds)
+    pub time: f64,
+    pub action: TimelineAction,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+#[serde(rename_all = "camelCase")]
+pub enum TimelineAction {
+    /// Generated with the gravity constant in use at that time
+    SpawnScenario(Scenario),
+    AddBodies(Vec<Body>),
+    AddTracers(Vec<Tracer>),
+    SetGravityConstant(f64),
+    SetSolverParameters(SolverParameters),
+    SetPhysicsParameters(PhyiscsParameters),
+}
+
+impl Timeline {
+    pub fn new() -> Self {
+        Timeline::default()
+    }
+
+    /// Builder method to append an event
+    pub fn with_event(mut self, time: f64, action: TimelineAction) -> Self {
+        self.events.push(TimelineEvent { time, action });
+        self
+    }
+}
@@ -0,0 +1,137 @@
+//! Last positions of every body, recorded after each step so that the frontends can draw
+//! orbit trails without keeping the past states themselves
+
+use alloc::{collections::VecDeque, vec::Vec};
+
+use crate::{physics::BodyId, storage::BodyStorage};
+
+/// One ring buffer of positions per body, sorted by id as the bodies are
+#[derive(Clone, Debug, Default)]
+pub struct Trails {
+    capacity: usize,
+    trails: Vec<Trail>,
+}
+
+/// Positions of a body after its last steps, oldest first
+#[derive(Clone, Debug)]
+pub struct Trail {
+    id: BodyId,
+    positions: VecDeque<[f64; 2]>,
+}
+
+impl Trails {
+    /// Keeps the last `capacity` positions of each body (at least one)
+    pub fn new(capacity: usize) -> Self {
+        Trails {
+            capacity: capacity.max(1),
+            trails: Vec::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Trails of the bodies as of the last step, in id order
+    pub fn iter(&self) -> core::slice::Iter<'_, Trail> {
+        self.trails.iter()
+    }
+
+    pub fn get(&self, id: BodyId) -> Option<&Trail> {
+        let index = self.trails.binary_search_by_key(&id, Trail::id).ok()?;
+        Some(&self.trails[index])
+    }
+
+    pub fn clear(&mut self) {
+        self.trails.clear();
+    }
+
+    /// Appends the current position of every body, dropping the oldest one of full trails
+    /// The trails of the bodies removed since the last call are dropped,
+    /// those of the bodies added since are started
+    pub(crate) fn record(&mut self, bodies: &BodyStorage) {
+        // Both are sorted by id
+        let mut ids = bodies.ids.iter().peekable();
+        self.trails.retain(|trail| {
+            while ids.next_if(|&&id| id < trail.id).is_some() {}
+            ids.next_if_eq(&&trail.id).is_some()
+        });
+        for (index, (&id, &position)) in bodies.ids.iter().zip(&bodies.positions).enumerate() {
+            if self.trails.get(index).is_none_or(|trail| trail.id != id) {
+                self.trails.insert(
+                    index,
+                    Trail {
+                        id,
+                        positions: VecDeque::with_capacity(self.capacity),
+                    },
+                );
+            }
+            let positions = &mut self.trails[index].positions;
+            if positions.len() == self.capacity {
+                positions.pop_front();
+            }
+            positions.push_back(position);
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a Trails {
+    type Item = &'a Trail;
+    type IntoIter = core::slice::Iter<'a, Trail>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl Trail {
+    pub fn id(&self) -> BodyId {
+        self.id
+    }
+
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    /// Oldest first
+    pub fn positions(&self) -> impl DoubleEndedIterator<Item = [f64; 2]> + ExactSizeIterator + '_ {
+        self.positions.iter().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::Body;
+    use alloc::vec;
+
+    #[test]
+    fn trails_test() {
+        let body = |id: u64, x: f64| Body {
+            id: BodyId(id),
+            ..Body::default().with_position([x, 0.0])
+        };
+        let mut trails = Trails::new(3);
+        for step in 0..4 {
+            let x = step as f64;
+            trails.record(&BodyStorage::from(vec![body(0, x), body(1, -x)]));
+        }
+        let positions: Vec<_> = trails.get(BodyId(1)).unwrap().positions().collect();
+        assert_eq!(positions, [[-1.0, 0.0], [-2.0, 0.0], [-3.0, 0.0]]);
+
+        // The first body is removed and another one is added
+        trails.record(&BodyStorage::from(vec![body(1, -4.0), body(2, 10.0)]));
+        let ids: Vec<_> = trails.iter().map(Trail::id).collect();
+        assert_eq!(ids, [BodyId(1), BodyId(2)]);
+        assert_eq!(
+            trails.get(BodyId(1)).unwrap().positions().last(),
+            Some([-4.0, 0.0])
+        );
+        assert_eq!(trails.get(BodyId(2)).unwrap().len(), 1);
+        assert!(trails.get(BodyId(0)).is_none());
+    }
+}
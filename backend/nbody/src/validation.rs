@@ -0,0 +1,304 @@
+use alloc::{vec, vec::Vec};
+use core::f64::consts::TAU;
+#[cfg(not(any(feature = "std", test)))]
+use num_traits::Float;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    simulation::{Simulation, SimulationParameters},
+    storage::BodyStorage,
+};
+
+/// Fewer steps per orbit than this and close orbits are integrated poorly
+const MIN_STEPS_PER_ORBIT: f64 = 20.0;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Severity {
+    /// The parameters can be used but the results may be inaccurate
+    Warning,
+
+    /// The parameters must be rejected
+    Error,
+}
+
+/// A problem found in a set of parameters
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Error)]
+#[serde(rename_all = "camelCase")]
+pub enum ParameterIssue {
+    #[error("The time step must be positive and finite (got {0})")]
+    NonPositiveTimeStep(f64),
+
+    #[error("The Barnes-Hut theta must be positive and finite (got {0})")]
+    InvalidTheta(f64),
+
+    #[error("A Barnes-Hut theta of {0} (above 1) gives inaccurate forces")]
+    LargeTheta(f64),
+
+    #[error("The gravity constant must be positive and finite (got {0})")]
+    InvalidGravityConstant(f64),
+
+    #[error("The gravity softening must be positive and finite (got {0})")]
+    InvalidSoftening(f64),
+
+    #[error("The restitution must be between 0 and 1 (got {0})")]
+    InvalidRestitution(f64),
+
+    #[error("The world of the boundary condition must have a positive size (got {0})")]
+    InvalidWorld(f64),
+
+    #[error("The quadtree root padding must be positive and finite (got {0})")]
+    InvalidRootPadding(f64),
+
+    #[serde(rename_all = "camelCase")]
+    #[error(
+        "The adaptive time step bounds must be positive and ordered (got {min_dt} to {max_dt})"
+    )]
+    InvalidTimeStepBounds { min_dt: f64, max_dt: f64 },
+
+    #[error("The adaptive time step tolerance must be positive and finite (got {0})")]
+    InvalidTolerance(f64),
+
+    /// Index of the force in `external_forces`
+    #[error("The external force {0} must have finite parameters and a positive drag coefficient")]
+    InvalidExternalForce(usize),
+
+    /// Compared to the period of the tightest orbit the bodies could have
+    #[serde(rename_all = "camelCase")]
+    #[error("The time step {dt} is too large for orbits as short as {orbital_period} seconds")]
+    TimeStepTooLarge { dt: f64, orbital_period: f64 },
+}
+
+impl ParameterIssue {
+    pub fn severity(&self) -> Severity {
+        match self {
+            ParameterIssue::LargeTheta(_) | ParameterIssue::TimeStepTooLarge { .. } => {
+                Severity::Warning
+            }
+            _ => Severity::Error,
+        }
+    }
+}
+
+impl SimulationParameters {
+    /// The problems of the parameters on their own (whatever the bodies)
+    pub fn validate(&self) -> Vec<ParameterIssue> {
+        let mut issues = vec![];
+        let dt = self.solver.dt();
+        if !(dt > 0.0 && dt.is_finite()) {
+            issues.push(ParameterIssue::NonPositiveTimeStep(dt));
+        }
+        let theta = self.solver.barnes_hut_theta();
+        if !(theta >= 0.0 && theta.is_finite()) {
+            issues.push(ParameterIssue::InvalidTheta(theta));
+        } else if theta > 1.0 {
+            issues.push(ParameterIssue::LargeTheta(theta));
+        }
+        let gravity_constant = self.physics.gravity_constant();
+        if !(gravity_constant >= 0.0 && gravity_constant.is_finite()) {
+            issues.push(ParameterIssue::InvalidGravityConstant(gravity_constant));
+        }
+        let softening = self.physics.softening();
+        if !(softening >= 0.0 && softening.is_finite()) {
+            issues.push(ParameterIssue::InvalidSoftening(softening));
+        }
+        let restitution = self.physics.restitution();
+        if !(0.0..=1.0).contains(&restitution) {
+            issues.push(ParameterIssue::InvalidRestitution(restitution));
+        }
+        if let Some(world) = self.boundary.world() {
+            let half_size = world.half_size();
+            if !(half_size > 0.0 && half_size.is_finite()) {
+                issues.push(ParameterIssue::InvalidWorld(half_size));
+            }
+        }
+        let root_padding = self.solver.root_padding();
+        if !(root_padding >= 0.0 && root_padding.is_finite()) {
+            issues.push(ParameterIssue::InvalidRootPadding(root_padding));
+        }
+        if let Some(adaptive_timestep) = self.solver.adaptive_timestep() {
+            let (min_dt, max_dt) = (adaptive_timestep.min_dt(), adaptive_timestep.max_dt());
+            if !(min_dt > 0.0 && min_dt <= max_dt && max_dt.is_finite()) {
+                issues.push(ParameterIssue::InvalidTimeStepBounds { min_dt, max_dt });
+            }
+            let tolerance = adaptive_timestep.tolerance();
+            if !(tolerance > 0.0 && tolerance.is_finite()) {
+                issues.push(ParameterIssue::InvalidTolerance(tolerance));
+            }
+        }
+        for (index, force) in self.external_forces.iter().enumerate() {
+            if !force.is_valid() {
+                issues.push(ParameterIssue::InvalidExternalForce(index));
+            }
+        }
+        issues
+    }
+}
+
+impl Simulation {
+    /// The problems of the parameters if they were used with the current bodies
+    pub fn validate_parameters(&self, parameters: &SimulationParameters) -> Vec<ParameterIssue> {
+        let mut issues = parameters.validate();
+        if issues
+            .iter()
+            .any(|issue| issue.severity() == Severity::Error)
+        {
+            return issues;
+        }
+        // The adaptive time step shrinks on its own as the bodies get close
+        if parameters.solver.adaptive_timestep().is_some() {
+            return issues;
+        }
+        let dt = parameters.solver.dt();
+        if let Some(orbital_period) =
+            shortest_orbital_period(self.bodies(), parameters.physics.gravity_constant())
+        {
+            if dt * MIN_STEPS_PER_ORBIT > orbital_period {
+                issues.push(ParameterIssue::TimeStepTooLarge { dt, orbital_period });
+            }
+        }
+        issues
+    }
+}
+
+/// Lower bound of the period of a circular orbit between two of the bodies:
+/// the two heaviest ones orbiting at the smallest contact distance
+fn shortest_orbital_period(bodies: &BodyStorage, gravity_constant: f64) -> Option<f64> {
+    if bodies.len() < 2 || gravity_constant <= 0.0 {
+        return None;
+    }
+    let (mut heaviest, mut second) = (0.0, 0.0);
+    let mut min_radius = f64::INFINITY;
+    for (&mass, &radius) in bodies.masses().iter().zip(bodies.radii()) {
+        if mass > heaviest {
+            (heaviest, second) = (mass, heaviest);
+        } else if mass > second {
+            second = mass;
+        }
+        min_radius = min_radius.min(radius);
+    }
+    let distance = 2.0 * min_radius;
+    let total_mass = heaviest + second;
+    if distance <= 0.0 || total_mass <= 0.0 {
+        return None;
+    }
+    Some(TAU * (distance.powi(3) / (gravity_constant * total_mass)).sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        physics::{Body, ExternalForce},
+        quadtree::SquareBox,
+        simulation::{AdaptiveTimestep, BoundaryCondition, PhyiscsParameters, SolverParameters},
+    };
+
+    fn parameters(dt: f64, theta: f64, gravity_constant: f64) -> SimulationParameters {
+        SimulationParameters {
+            solver: SolverParameters::new(dt, theta),
+            physics: PhyiscsParameters::new(gravity_constant),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn validate_test() {
+        assert!(SimulationParameters::default().validate().is_empty());
+        assert_eq!(
+            parameters(0.0, 1.5, -1.0).validate(),
+            [
+                ParameterIssue::NonPositiveTimeStep(0.0),
+                ParameterIssue::LargeTheta(1.5),
+                ParameterIssue::InvalidGravityConstant(-1.0),
+            ]
+        );
+        let issues = parameters(f64::NAN, 0.5, 1.0).validate();
+        assert!(matches!(issues[..], [ParameterIssue::NonPositiveTimeStep(dt)] if dt.is_nan()));
+        assert_eq!(
+            parameters(0.1, f64::INFINITY, f64::INFINITY).validate(),
+            [
+                ParameterIssue::InvalidTheta(f64::INFINITY),
+                ParameterIssue::InvalidGravityConstant(f64::INFINITY),
+            ]
+        );
+        assert_eq!(
+            ParameterIssue::LargeTheta(1.5).severity(),
+            Severity::Warning
+        );
+
+        let mut soft = parameters(0.1, 0.5, 1.0);
+        soft.physics = soft.physics.with_softening(f64::INFINITY);
+        assert_eq!(
+            soft.validate(),
+            [ParameterIssue::InvalidSoftening(f64::INFINITY)]
+        );
+
+        let mut bouncy = parameters(0.1, 0.5, 1.0);
+        bouncy.physics = bouncy.physics.with_restitution(1.5);
+        assert_eq!(bouncy.validate(), [ParameterIssue::InvalidRestitution(1.5)]);
+
+        let mut bounded = parameters(0.1, 0.5, 1.0);
+        bounded.boundary = BoundaryCondition::Periodic(SquareBox::new([0.0, 0.0], 0.0));
+        assert_eq!(bounded.validate(), [ParameterIssue::InvalidWorld(0.0)]);
+
+        let mut adaptive = parameters(0.1, 0.5, 1.0);
+        adaptive.solver = adaptive
+            .solver
+            .with_adaptive_timestep(AdaptiveTimestep::new(0.1, 0.01).with_tolerance(0.0));
+        assert_eq!(
+            adaptive.validate(),
+            [
+                ParameterIssue::InvalidTimeStepBounds {
+                    min_dt: 0.1,
+                    max_dt: 0.01
+                },
+                ParameterIssue::InvalidTolerance(0.0),
+            ]
+        );
+
+        let mut dragged = parameters(0.1, 0.5, 1.0);
+        dragged.external_forces = vec![
+            ExternalForce::LinearDrag { coefficient: 0.5 },
+            ExternalForce::QuadraticDrag {
+                coefficient: f64::NAN,
+            },
+        ];
+        assert_eq!(
+            dragged.validate(),
+            [ParameterIssue::InvalidExternalForce(1)]
+        );
+    }
+
+    #[test]
+    fn orbital_period_test() {
+        let mut simulation = Simulation::new();
+        simulation.add_bodies(vec![
+            Body::default().with_mass(3.0),
+            Body::default().with_position([10.0, 0.0]).with_mass(5.0),
+            Body::default().with_position([20.0, 0.0]),
+        ]);
+        // Heaviest pair (3 + 5) in contact (2 apart): 2π sqrt(2³ / (G 8)) = 2π / sqrt(G)
+        let orbital_period = TAU / 10.0;
+        assert!(simulation
+            .validate_parameters(&parameters(orbital_period / 40.0, 0.0, 100.0))
+            .is_empty());
+
+        let issues = simulation.validate_parameters(&parameters(0.1, 0.0, 100.0));
+        let [ParameterIssue::TimeStepTooLarge {
+            dt,
+            orbital_period: period,
+        }] = issues[..]
+        else {
+            panic!("Expected a time step warning, got {issues:?}");
+        };
+        assert_eq!(dt, 0.1);
+        assert!((period - orbital_period).abs() < 1e-12);
+
+        // Without gravity there are no orbits
+        assert!(simulation
+            .validate_parameters(&parameters(0.1, 0.0, 0.0))
+            .is_empty());
+    }
+}
@@ -0,0 +1,66 @@
+//! Trains the zstd dictionary of the `nbody.bincode.zstd.v1` frames on the states
+//! of a few simulated scenarios, and writes it to the given path:
+//!
+//!     cargo run --release -p protocol --features zstd --example train_dictionary -- states.dict
+//!
+//! The server loads it from `NBODY_ZSTD_DICTIONARY`
+
+use nbody::{
+    scenarios::{Scenario, ScenarioKind},
+    simulation::Simulation,
+};
+use protocol::{
+    dictionary::{Dictionary, DEFAULT_DICTIONARY_SIZE},
+    ServerToClientMessage,
+};
+
+/// States recorded per scenario, one every `STEPS_PER_STATE` steps
+const STATES_PER_SCENARIO: usize = 200;
+const STEPS_PER_STATE: usize = 5;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::args()
+        .nth(1)
+        .ok_or("Usage: train_dictionary <output path>")?;
+
+    let kinds = [
+        ScenarioKind::Disc {
+            radius: 100.0,
+            rotating: true,
+        },
+        ScenarioKind::Spiral {
+            radius: 200.0,
+            arms: 2,
+            central_mass: 100.0,
+        },
+        ScenarioKind::Plummer { scale_radius: 50.0 },
+        ScenarioKind::Random {
+            half_size: 100.0,
+            max_speed: 1.0,
+        },
+    ];
+    let mut states = Vec::new();
+    for kind in kinds {
+        let mut simulation = Simulation::new();
+        simulation.add_bodies(
+            Scenario {
+                kind,
+                ..Scenario::default()
+            }
+            .generate(1.0),
+        );
+        for _ in 0..STATES_PER_SCENARIO {
+            (0..STEPS_PER_STATE).for_each(|_| simulation.step());
+            states.push(ServerToClientMessage::state_update(&simulation));
+        }
+    }
+
+    let dictionary = Dictionary::train(&states, DEFAULT_DICTIONARY_SIZE)?;
+    std::fs::write(&path, dictionary.bytes())?;
+    println!(
+        "Trained a {} bytes dictionary on {} states into {path}",
+        dictionary.bytes().len(),
+        states.len()
+    );
+    Ok(())
+}
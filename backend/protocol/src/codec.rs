@@ -0,0 +1,147 @@
+use std::io::{Read, Write};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use crate::{ProtocolError, PROTOCOL_VERSION};
+
+/// Payloads smaller than this are sent uncompressed by default
+/// (the gzip header and trailer alone take 18 bytes, control messages would get larger)
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 128;
+
+/// First byte of the frame shipping the zstd dictionary: [DICTIONARY_TAG, dictionary...]
+pub const DICTIONARY_TAG: u8 = 0xd1;
+
+/// Compression of the payload, second byte of every frame:
+/// [PROTOCOL_VERSION, codec, payload...]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[repr(u8)]
+pub enum Codec {
+    /// bincode(msg)
+    None = 0,
+
+    /// gzip(bincode(msg))
+    Gzip = 1,
+
+    /// zstd(bincode(msg)) with the dictionary shared at the start of the connection
+    Zstd = 2,
+}
+
+impl TryFrom<u8> for Codec {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Gzip),
+            2 => Ok(Codec::Zstd),
+            _ => Err(ProtocolError::UnknownCodec(Some(value))),
+        }
+    }
+}
+
+/// How `encode_with` compresses the frames
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CodecOptions {
+    /// Gzip level, from 0 (store) to 9 (best)
+    level: u32,
+
+    /// Payloads smaller than this (in bytes) are not compressed
+    threshold: usize,
+}
+
+impl Default for CodecOptions {
+    fn default() -> Self {
+        Self {
+            level: Compression::fast().level(),
+            threshold: DEFAULT_COMPRESSION_THRESHOLD,
+        }
+    }
+}
+
+impl CodecOptions {
+    pub fn new() -> Self {
+        CodecOptions::default()
+    }
+
+    /// Builder method to set the gzip level (clamped to 9)
+    pub fn with_level(mut self, level: u32) -> Self {
+        self.level = level.min(Compression::best().level());
+        self
+    }
+
+    /// Builder method to set the size (in bytes) under which payloads are sent uncompressed
+    pub fn with_threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    pub fn level(&self) -> u32 {
+        self.level
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    /// Codec picked for a payload of the given size
+    pub fn codec_for(&self, payload_size: usize) -> Codec {
+        if payload_size < self.threshold {
+            Codec::None
+        } else {
+            Codec::Gzip
+        }
+    }
+}
+
+/// Whether the frame ships the zstd dictionary instead of a message
+pub fn is_dictionary_frame(frame: &[u8]) -> bool {
+    frame.first() == Some(&DICTIONARY_TAG)
+}
+
+/// Returns the codec and the payload of a frame, after checking its version
+pub fn split_frame(frame: &[u8]) -> Result<(Codec, &[u8]), ProtocolError> {
+    match crate::check_version(frame)?.split_first() {
+        Some((&codec, payload)) => Ok((Codec::try_from(codec)?, payload)),
+        None => Err(ProtocolError::UnknownCodec(None)),
+    }
+}
+
+pub(crate) fn compress_data(
+    data: &[u8],
+    options: &CodecOptions,
+) -> Result<Vec<u8>, std::io::Error> {
+    let codec = options.codec_for(data.len());
+    let mut frame = Vec::with_capacity(2 + data.len());
+    frame.extend([PROTOCOL_VERSION, codec as u8]);
+    match codec {
+        Codec::None => {
+            frame.extend_from_slice(data);
+            Ok(frame)
+        }
+        Codec::Gzip => {
+            let mut e = GzEncoder::new(frame, Compression::new(options.level));
+            e.write_all(data)?;
+            e.finish()
+        }
+        Codec::Zstd => unreachable!("zstd frames are encoded with a dictionary"),
+    }
+}
+
+pub(crate) fn decompress_data(data: &[u8], max_size: usize) -> Result<Vec<u8>, ProtocolError> {
+    let mut buffer = Vec::new();
+    // Reading one byte past the limit tells apart a frame of exactly `max_size` bytes
+    GzDecoder::new(data)
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut buffer)
+        .map_err(ProtocolError::Decompression)?;
+    if buffer.len() > max_size {
+        return Err(ProtocolError::FrameTooLarge {
+            size: buffer.len(),
+            max_size,
+        });
+    }
+    Ok(buffer)
+}
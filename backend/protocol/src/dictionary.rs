@@ -0,0 +1,198 @@
+//! Zstd compression with a dictionary shared by the server and its clients
+//!
+//! `StateUpdate` frames are small and alike: a dictionary trained offline on representative
+//! frames (see `examples/train_dictionary.rs`) holds what they have in common,
+//! so that they compress better than with gzip at realtime speeds.
+//! The server ships it in the first frame of a `nbody.bincode.zstd.v1` connection:
+//! [DICTIONARY_TAG, dictionary...]
+
+use std::io::{Read, Write};
+
+use zstd::{
+    dict::{DecoderDictionary, EncoderDictionary},
+    stream::{read::Decoder, write::Encoder},
+};
+
+use crate::{
+    decode, read_message, split_frame, write_message, Codec, ProtocolError, WireMessage,
+    DEFAULT_COMPRESSION_THRESHOLD, DICTIONARY_TAG, MAX_DECOMPRESSED_SIZE, PROTOCOL_VERSION,
+};
+
+/// Zstd level of the frames, fast enough to compress every state of the simulation
+pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+/// Default size (in bytes) of a trained dictionary
+pub const DEFAULT_DICTIONARY_SIZE: usize = 16 * 1024;
+
+/// A zstd dictionary, prepared for both compression and decompression
+pub struct Dictionary {
+    bytes: Vec<u8>,
+    level: i32,
+    encoder: EncoderDictionary<'static>,
+    decoder: DecoderDictionary<'static>,
+}
+
+impl Dictionary {
+    /// Loads a dictionary (e.g. the file written by `train_dictionary`)
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, ProtocolError> {
+        Dictionary::from_bytes_with_level(bytes, DEFAULT_ZSTD_LEVEL)
+    }
+
+    /// Same as `from_bytes` with the given zstd level (1 to 22)
+    pub fn from_bytes_with_level(bytes: Vec<u8>, level: i32) -> Result<Self, ProtocolError> {
+        let encoder =
+            EncoderDictionary::try_copy(&bytes, level).map_err(ProtocolError::Dictionary)?;
+        let decoder = DecoderDictionary::try_copy(&bytes).map_err(ProtocolError::Dictionary)?;
+        Ok(Self {
+            bytes,
+            level,
+            encoder,
+            decoder,
+        })
+    }
+
+    /// Trains a dictionary of at most `max_size` bytes on the given messages
+    /// (the more alike they are to the messages that will be sent, the better)
+    pub fn train<T: WireMessage>(messages: &[T], max_size: usize) -> Result<Self, ProtocolError> {
+        let samples = messages
+            .iter()
+            .map(write_message)
+            .collect::<Result<Vec<_>, _>>()?;
+        let bytes =
+            zstd::dict::from_samples(&samples, max_size).map_err(ProtocolError::Dictionary)?;
+        Dictionary::from_bytes(bytes)
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn level(&self) -> i32 {
+        self.level
+    }
+
+    /// The frame shipping the dictionary to a client
+    pub fn to_frame(&self) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(1 + self.bytes.len());
+        frame.push(DICTIONARY_TAG);
+        frame.extend_from_slice(&self.bytes);
+        frame
+    }
+
+    /// Loads the dictionary shipped by the server
+    pub fn from_frame(frame: &[u8]) -> Result<Self, ProtocolError> {
+        match frame.split_first() {
+            Some((&DICTIONARY_TAG, bytes)) => Dictionary::from_bytes(bytes.to_vec()),
+            _ => Err(ProtocolError::MissingDictionary),
+        }
+    }
+
+    /// Same as `encode` but compressing the payload with the dictionary
+    /// (small payloads are still sent uncompressed)
+    pub fn encode<T: WireMessage>(&self, msg: &T) -> Result<Vec<u8>, ProtocolError> {
+        let data = write_message(msg)?;
+        if data.len() < DEFAULT_COMPRESSION_THRESHOLD {
+            let mut frame = vec![PROTOCOL_VERSION, Codec::None as u8];
+            frame.extend(data);
+            return Ok(frame);
+        }
+        self.compress(&data).map_err(ProtocolError::Compression)
+    }
+
+    /// Decodes a frame of any codec, zstd ones with this dictionary
+    pub fn decode<T: WireMessage>(&self, frame: &[u8]) -> Result<T, ProtocolError> {
+        match split_frame(frame)? {
+            (Codec::Zstd, payload) => read_message(&self.decompress(payload)?),
+            _ => decode(frame),
+        }
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        let mut frame = Vec::with_capacity(2 + data.len() / 2);
+        frame.extend([PROTOCOL_VERSION, Codec::Zstd as u8]);
+        let mut encoder = Encoder::with_prepared_dictionary(frame, &self.encoder)?;
+        encoder.write_all(data)?;
+        encoder.finish()
+    }
+
+    fn decompress(&self, payload: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        let mut buffer = Vec::new();
+        // Reading one byte past the limit tells apart a frame of exactly the maximum size
+        Decoder::with_prepared_dictionary(payload, &self.decoder)
+            .and_then(|decoder| {
+                decoder
+                    .take(MAX_DECOMPRESSED_SIZE as u64 + 1)
+                    .read_to_end(&mut buffer)
+            })
+            .map_err(ProtocolError::Decompression)?;
+        if buffer.len() > MAX_DECOMPRESSED_SIZE {
+            return Err(ProtocolError::FrameTooLarge {
+                size: buffer.len(),
+                max_size: MAX_DECOMPRESSED_SIZE,
+            });
+        }
+        Ok(buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encode, ServerToClientMessage};
+    use nbody::{scenarios::Scenario, simulation::Simulation};
+
+    /// States of a rotating disc, one every few steps
+    fn states(count: usize, seed: u64) -> Vec<ServerToClientMessage> {
+        let mut simulation = Simulation::new();
+        simulation.add_bodies(
+            Scenario {
+                count: 50,
+                seed,
+                ..Scenario::default()
+            }
+            .generate(1.0),
+        );
+        (0..count)
+            .map(|_| {
+                (0..5).for_each(|_| simulation.step());
+                ServerToClientMessage::state_update(&simulation)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dictionary_round_trip_test() {
+        let dictionary = Dictionary::train(&states(100, 0), 4 * 1024).unwrap();
+        let loaded = Dictionary::from_frame(&dictionary.to_frame()).unwrap();
+        assert_eq!(loaded.bytes(), dictionary.bytes());
+
+        // A simulation it was not trained on
+        for state in states(3, 1) {
+            let frame = dictionary.encode(&state).unwrap();
+            assert_eq!(frame[..2], [PROTOCOL_VERSION, Codec::Zstd as u8]);
+            assert!(frame.len() < encode(&state).unwrap().len());
+            let Ok(ServerToClientMessage::StateUpdate { bodies, .. }) = loaded.decode(&frame)
+            else {
+                panic!("Expected a StateUpdate");
+            };
+            assert_eq!(bodies.len(), 50);
+            assert!(matches!(
+                decode::<ServerToClientMessage>(&frame),
+                Err(ProtocolError::MissingDictionary)
+            ));
+        }
+
+        // Frames of the other codecs are decoded as usual
+        let small = ServerToClientMessage::Diagnostics(vec![]);
+        let frame = dictionary.encode(&small).unwrap();
+        assert_eq!(frame[1], Codec::None as u8);
+        assert!(dictionary.decode::<ServerToClientMessage>(&frame).is_ok());
+        let gzipped = encode(&states(1, 2)[0]).unwrap();
+        assert!(dictionary.decode::<ServerToClientMessage>(&gzipped).is_ok());
+
+        assert!(matches!(
+            Dictionary::from_frame(&[PROTOCOL_VERSION]),
+            Err(ProtocolError::MissingDictionary)
+        ));
+    }
+}
@@ -0,0 +1,390 @@
+//! Field-level difference of a body against a baseline, so that unchanged fields
+//! are not sent again, and of whole states (`BodiesDelta`)
+//!
+//! Binary layout (bincode): (id, mask, changed fields in declaration order...)
+//! where each bit of the mask flags a field (see `BodyDiff::mask`).
+//! Human readable formats (JSON) get an object without the unchanged fields
+
+use std::fmt;
+
+use nbody::physics::{Body, BodyId};
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    ser::SerializeTuple,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
+
+pub const POSITION_CHANGED: u8 = 1 << 0;
+pub const VELOCITY_CHANGED: u8 = 1 << 1;
+pub const MASS_CHANGED: u8 = 1 << 2;
+pub const RADIUS_CHANGED: u8 = 1 << 3;
+pub const COLOR_CHANGED: u8 = 1 << 4;
+pub const STATIC_CHANGED: u8 = 1 << 5;
+
+const ALL_FIELDS: u8 = POSITION_CHANGED
+    | VELOCITY_CHANGED
+    | MASS_CHANGED
+    | RADIUS_CHANGED
+    | COLOR_CHANGED
+    | STATIC_CHANGED;
+
+/// The fields of a body that changed, None for the unchanged ones
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+pub struct BodyDiff {
+    pub id: BodyId,
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub position: Option<[f64; 2]>,
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub velocity: Option<[f64; 2]>,
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub mass: Option<f64>,
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub radius: Option<f64>,
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub color: Option<[u8; 4]>,
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub is_static: Option<bool>,
+}
+
+impl BodyDiff {
+    /// Fields of `new` differing from `old`, None if there is none
+    pub fn between(id: BodyId, old: &Body, new: &Body) -> Option<Self> {
+        let changed = |a, b| (a != b).then_some(b);
+        let diff = BodyDiff {
+            id,
+            position: changed(old.position, new.position),
+            velocity: changed(old.velocity, new.velocity),
+            mass: (old.mass != new.mass).then_some(new.mass),
+            radius: (old.radius != new.radius).then_some(new.radius),
+            color: (old.color != new.color).then_some(new.color),
+            is_static: (old.is_static != new.is_static).then_some(new.is_static),
+        };
+        (diff.mask() != 0).then_some(diff)
+    }
+
+    /// A bit per changed field (`POSITION_CHANGED`, `VELOCITY_CHANGED`...)
+    pub fn mask(&self) -> u8 {
+        let flag = |changed: bool, bit: u8| if changed { bit } else { 0 };
+        flag(self.position.is_some(), POSITION_CHANGED)
+            | flag(self.velocity.is_some(), VELOCITY_CHANGED)
+            | flag(self.mass.is_some(), MASS_CHANGED)
+            | flag(self.radius.is_some(), RADIUS_CHANGED)
+            | flag(self.color.is_some(), COLOR_CHANGED)
+            | flag(self.is_static.is_some(), STATIC_CHANGED)
+    }
+
+    /// Updates the baseline body with the changed fields
+    pub fn apply(&self, body: &mut Body) {
+        if let Some(position) = self.position {
+            body.position = position;
+        }
+        if let Some(velocity) = self.velocity {
+            body.velocity = velocity;
+        }
+        if let Some(mass) = self.mass {
+            body.mass = mass;
+        }
+        if let Some(radius) = self.radius {
+            body.radius = radius;
+        }
+        if let Some(color) = self.color {
+            body.color = color;
+        }
+        if let Some(is_static) = self.is_static {
+            body.is_static = is_static;
+        }
+    }
+}
+
+/// Difference between two states of the bodies, both sorted by id (as in a simulation)
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+pub struct BodiesDelta {
+    /// Bodies of both states that changed, in id order
+    pub changed: Vec<BodyDiff>,
+
+    /// Bodies missing from the baseline
+    pub added: Vec<Body>,
+
+    /// Bodies of the baseline that are gone, in id order
+    pub removed: Vec<BodyId>,
+}
+
+impl BodiesDelta {
+    pub fn between(old: &[Body], new: &[Body]) -> Self {
+        let mut delta = BodiesDelta::default();
+        let (mut old, mut new) = (old.iter().peekable(), new.iter().peekable());
+        loop {
+            match (old.peek(), new.peek()) {
+                (Some(o), Some(n)) if o.id == n.id => {
+                    delta.changed.extend(BodyDiff::between(n.id, o, n));
+                    old.next();
+                    new.next();
+                }
+                (Some(o), Some(n)) if o.id < n.id => {
+                    delta.removed.push(o.id);
+                    old.next();
+                }
+                (_, Some(n)) => {
+                    delta.added.push(**n);
+                    new.next();
+                }
+                (Some(o), None) => {
+                    delta.removed.push(o.id);
+                    old.next();
+                }
+                (None, None) => return delta,
+            }
+        }
+    }
+
+    /// The whole state, against an empty baseline
+    pub fn full(bodies: &[Body]) -> Self {
+        BodiesDelta {
+            added: bodies.to_vec(),
+            ..BodiesDelta::default()
+        }
+    }
+
+    /// The bodies of the new state, from those of the baseline
+    pub fn apply(&self, baseline: &[Body]) -> Vec<Body> {
+        let mut bodies: Vec<Body> = baseline
+            .iter()
+            .filter(|body| self.removed.binary_search(&body.id).is_err())
+            .copied()
+            .collect();
+        for diff in &self.changed {
+            if let Ok(i) = bodies.binary_search_by_key(&diff.id, |body| body.id) {
+                diff.apply(&mut bodies[i]);
+            }
+        }
+        bodies.extend_from_slice(&self.added);
+        bodies.sort_by_key(|body| body.id);
+        bodies
+    }
+}
+
+/// Shape of a `BodyDiff` in human readable formats
+#[derive(Serialize, Deserialize)]
+struct ReadableBodyDiff {
+    id: BodyId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    position: Option<[f64; 2]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    velocity: Option<[f64; 2]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mass: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    radius: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    color: Option<[u8; 4]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    is_static: Option<bool>,
+}
+
+impl Serialize for BodyDiff {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            let BodyDiff {
+                id,
+                position,
+                velocity,
+                mass,
+                radius,
+                color,
+                is_static,
+            } = *self;
+            return ReadableBodyDiff {
+                id,
+                position,
+                velocity,
+                mass,
+                radius,
+                color,
+                is_static,
+            }
+            .serialize(serializer);
+        }
+        let mask = self.mask();
+        let mut tuple = serializer.serialize_tuple(2 + mask.count_ones() as usize)?;
+        tuple.serialize_element(&self.id)?;
+        tuple.serialize_element(&mask)?;
+        if let Some(position) = &self.position {
+            tuple.serialize_element(position)?;
+        }
+        if let Some(velocity) = &self.velocity {
+            tuple.serialize_element(velocity)?;
+        }
+        if let Some(mass) = &self.mass {
+            tuple.serialize_element(mass)?;
+        }
+        if let Some(radius) = &self.radius {
+            tuple.serialize_element(radius)?;
+        }
+        if let Some(color) = &self.color {
+            tuple.serialize_element(color)?;
+        }
+        if let Some(is_static) = &self.is_static {
+            tuple.serialize_element(is_static)?;
+        }
+        tuple.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for BodyDiff {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let ReadableBodyDiff {
+                id,
+                position,
+                velocity,
+                mass,
+                radius,
+                color,
+                is_static,
+            } = ReadableBodyDiff::deserialize(deserializer)?;
+            return Ok(BodyDiff {
+                id,
+                position,
+                velocity,
+                mass,
+                radius,
+                color,
+                is_static,
+            });
+        }
+        // At most: id, mask and every field
+        deserializer.deserialize_tuple(2 + ALL_FIELDS.count_ones() as usize, CompactVisitor)
+    }
+}
+
+struct CompactVisitor;
+
+impl<'de> Visitor<'de> for CompactVisitor {
+    type Value = BodyDiff;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a body id, a field mask and the changed fields")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<BodyDiff, A::Error> {
+        let mut read = 0;
+        let id = next_element(&mut seq, &mut read)?;
+        let mask: u8 = next_element(&mut seq, &mut read)?;
+        if mask & !ALL_FIELDS != 0 {
+            return Err(de::Error::custom(format!("unknown body fields {mask:#x}")));
+        }
+        let mut diff = BodyDiff {
+            id,
+            ..BodyDiff::default()
+        };
+        if mask & POSITION_CHANGED != 0 {
+            diff.position = Some(next_element(&mut seq, &mut read)?);
+        }
+        if mask & VELOCITY_CHANGED != 0 {
+            diff.velocity = Some(next_element(&mut seq, &mut read)?);
+        }
+        if mask & MASS_CHANGED != 0 {
+            diff.mass = Some(next_element(&mut seq, &mut read)?);
+        }
+        if mask & RADIUS_CHANGED != 0 {
+            diff.radius = Some(next_element(&mut seq, &mut read)?);
+        }
+        if mask & COLOR_CHANGED != 0 {
+            diff.color = Some(next_element(&mut seq, &mut read)?);
+        }
+        if mask & STATIC_CHANGED != 0 {
+            diff.is_static = Some(next_element(&mut seq, &mut read)?);
+        }
+        Ok(diff)
+    }
+}
+
+fn next_element<'de, A: SeqAccess<'de>, T: Deserialize<'de>>(
+    seq: &mut A,
+    read: &mut usize,
+) -> Result<T, A::Error> {
+    let element = seq
+        .next_element()?
+        .ok_or_else(|| de::Error::invalid_length(*read, &CompactVisitor))?;
+    *read += 1;
+    Ok(element)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn body_diff_test() {
+        let old = Body::default().with_position([1.0, 2.0]);
+        assert_eq!(BodyDiff::between(BodyId(3), &old, &old), None);
+
+        let new = old.with_position([1.5, 2.0]).with_mass(4.0);
+        let diff = BodyDiff::between(BodyId(3), &old, &new).unwrap();
+        assert_eq!(diff.mask(), POSITION_CHANGED | MASS_CHANGED);
+        assert_eq!(diff.velocity, None);
+
+        let mut body = old;
+        diff.apply(&mut body);
+        assert_eq!(body.position, new.position);
+        assert_eq!(body.mass, 4.0);
+
+        // id + mask + position + mass
+        let bytes = bincode::serialize(&diff).unwrap();
+        assert_eq!(bytes.len(), 8 + 1 + 16 + 8);
+        assert_eq!(bincode::deserialize::<BodyDiff>(&bytes).unwrap(), diff);
+        let diffs = vec![diff, BodyDiff::between(BodyId(4), &new, &old).unwrap()];
+        let bytes = bincode::serialize(&diffs).unwrap();
+        assert_eq!(
+            bincode::deserialize::<Vec<BodyDiff>>(&bytes).unwrap(),
+            diffs
+        );
+
+        let json = serde_json::to_string(&diff).unwrap();
+        assert_eq!(json, r#"{"id":3,"position":[1.5,2.0],"mass":4.0}"#);
+        assert_eq!(serde_json::from_str::<BodyDiff>(&json).unwrap(), diff);
+
+        let mut unknown_field = bytes.clone();
+        unknown_field[16] = 0x80;
+        assert!(bincode::deserialize::<Vec<BodyDiff>>(&unknown_field).is_err());
+
+        let diff = BodyDiff::between(BodyId(3), &old, &old.with_static(true)).unwrap();
+        assert_eq!(diff.mask(), STATIC_CHANGED);
+        let bytes = bincode::serialize(&diff).unwrap();
+        assert_eq!(bincode::deserialize::<BodyDiff>(&bytes).unwrap(), diff);
+    }
+
+    #[test]
+    fn bodies_delta_test() {
+        let bodies = |ids: &[u64]| -> Vec<Body> {
+            ids.iter()
+                .map(|&id| Body {
+                    id: BodyId(id),
+                    ..Body::default().with_position([id as f64, 0.0])
+                })
+                .collect()
+        };
+        let old = bodies(&[1, 2, 4, 5]);
+        let mut new = bodies(&[2, 3, 5, 6]);
+        new[2].velocity = [1.0, 0.0];
+
+        let delta = BodiesDelta::between(&old, &new);
+        assert_eq!(delta.removed, [BodyId(1), BodyId(4)]);
+        let added: Vec<_> = delta.added.iter().map(|body| body.id.0).collect();
+        assert_eq!(added, [3, 6]);
+        assert_eq!(delta.changed.len(), 1);
+        assert_eq!(delta.changed[0].mask(), VELOCITY_CHANGED);
+
+        let applied = delta.apply(&old);
+        assert_eq!(applied.len(), new.len());
+        assert!(applied
+            .iter()
+            .zip(&new)
+            .all(|(a, b)| a.id == b.id && BodyDiff::between(a.id, a, b).is_none()));
+        assert_eq!(BodiesDelta::full(&new).apply(&[]).len(), 4);
+    }
+}
@@ -0,0 +1,72 @@
+use thiserror::Error;
+
+/// Errors raised while encoding or decoding a protocol frame
+///
+/// The prefix of each message identifies the kind of error on the JS side
+#[derive(Debug, Error)]
+pub enum ProtocolError {
+    /// The frame was produced by an incompatible version of the protocol
+    /// (`found` is None when the frame is empty)
+    #[error("VersionMismatch: {}", version_mismatch(.expected, .found))]
+    VersionMismatch { expected: u8, found: Option<u8> },
+
+    /// The frame announces a codec unknown to this version (`None` when missing)
+    #[error("UnknownCodec: {0:?}")]
+    UnknownCodec(Option<u8>),
+
+    /// The frame holds a message unknown to this version (`None` when the id is missing)
+    /// Sent by a newer peer: can be skipped without closing the session
+    #[error("UnknownMessage: {0:?}")]
+    UnknownMessage(Option<u16>),
+
+    /// Gzip compression failed
+    #[error("CompressionError: {0}")]
+    Compression(#[source] std::io::Error),
+
+    /// The frame is not a valid gzip stream (truncated, corrupted...)
+    #[error("DecompressionError: {0}")]
+    Decompression(#[source] std::io::Error),
+
+    /// The frame is compressed with a zstd dictionary that was not received (or not supported)
+    #[error("MissingDictionary: the frame needs the zstd dictionary of the connection")]
+    MissingDictionary,
+
+    /// The zstd dictionary could not be trained or loaded
+    #[error("DictionaryError: {0}")]
+    Dictionary(#[source] std::io::Error),
+
+    /// The decompressed frame is larger than the accepted limit
+    #[error("FrameTooLarge: {size} bytes exceeds the limit of {max_size} bytes")]
+    FrameTooLarge { size: usize, max_size: usize },
+
+    /// A fragment of a chunked message is malformed
+    #[error("InvalidFragment: {0}")]
+    InvalidFragment(&'static str),
+
+    /// The frame is not a valid protobuf message
+    #[error("ProtobufError: {0}")]
+    Protobuf(String),
+
+    /// The frame is not a valid FlatBuffers message
+    #[error("FlatBuffersError: {0}")]
+    FlatBuffers(String),
+
+    /// The frame is not a valid JSON message
+    #[error("JsonError: {0}")]
+    Json(String),
+
+    /// The message could not be serialized
+    #[error("EncodingError: {0}")]
+    Encoding(#[source] bincode::Error),
+
+    /// The decompressed bytes do not hold a valid message
+    #[error("DecodingError: {0}")]
+    Decoding(#[source] bincode::Error),
+}
+
+fn version_mismatch(expected: &u8, found: &Option<u8>) -> String {
+    match found {
+        Some(found) => format!("expected protocol version {expected}, found {found}"),
+        None => "empty frame".to_string(),
+    }
+}
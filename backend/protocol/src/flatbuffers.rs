@@ -0,0 +1,334 @@
+//! FlatBuffers encoding of `StateUpdate` (schema in `proto/state_update.fbs`)
+//!
+//! The body data is read in place from the received frame, without deserializing
+//! (and allocating) the whole message, which dominates the frame cost for large N
+//! Frames are not compressed: reading in place requires the raw buffer
+
+use flatbuffers::{FlatBufferBuilder, Follow, ForwardsUOffset, Table, Vector, Verifiable};
+use nbody::physics::{Body, BodyId};
+
+use crate::{ProtocolError, ServerToClientMessage};
+
+/// First byte of a FlatBuffers frame: [FLATBUFFERS_TAG, flatbuffer(StateUpdate)...]
+pub const FLATBUFFERS_TAG: u8 = 0xfb;
+
+// vtable offsets of the `StateUpdate` table fields
+const VT_PHYSICAL_TIME: u16 = 4;
+const VT_KINETIC_ENERGY: u16 = 6;
+const VT_POSITIONS: u16 = 8;
+const VT_VELOCITIES: u16 = 10;
+const VT_MASSES: u16 = 12;
+const VT_RADII: u16 = 14;
+const VT_COLORS: u16 = 16;
+const VT_IDS: u16 = 18;
+const VT_POTENTIAL_ENERGY: u16 = 20;
+const VT_LINEAR_MOMENTUM_X: u16 = 22;
+const VT_LINEAR_MOMENTUM_Y: u16 = 24;
+const VT_ANGULAR_MOMENTUM: u16 = 26;
+const VT_STATICS: u16 = 28;
+
+/// Encodes `StateUpdate`s reusing the same builder between frames
+#[derive(Default)]
+pub struct StateUpdateEncoder {
+    builder: FlatBufferBuilder<'static>,
+}
+
+impl StateUpdateEncoder {
+    pub fn new() -> Self {
+        StateUpdateEncoder::default()
+    }
+
+    /// None for any message other than `StateUpdate`
+    pub fn encode(&mut self, msg: &ServerToClientMessage) -> Option<Vec<u8>> {
+        let ServerToClientMessage::StateUpdate {
+            bodies,
+            physical_time,
+            kinetic_energy,
+            potential_energy,
+            linear_momentum,
+            angular_momentum,
+        } = msg
+        else {
+            return None;
+        };
+
+        let builder = &mut self.builder;
+        builder.reset();
+        let coordinates = 0..2 * bodies.len();
+        let positions = builder
+            .create_vector_from_iter(coordinates.clone().map(|k| bodies[k / 2].position[k % 2]));
+        let velocities =
+            builder.create_vector_from_iter(coordinates.map(|k| bodies[k / 2].velocity[k % 2]));
+        let masses = builder.create_vector_from_iter(bodies.iter().map(|b| b.mass));
+        let radii = builder.create_vector_from_iter(bodies.iter().map(|b| b.radius));
+        let colors =
+            builder.create_vector_from_iter(bodies.iter().map(|b| u32::from_be_bytes(b.color)));
+        let ids = builder.create_vector_from_iter(bodies.iter().map(|b| b.id.0));
+        let statics = builder.create_vector_from_iter(bodies.iter().map(|b| b.is_static));
+
+        let start = builder.start_table();
+        builder.push_slot(VT_PHYSICAL_TIME, *physical_time, 0.0);
+        builder.push_slot(VT_KINETIC_ENERGY, *kinetic_energy, 0.0);
+        builder.push_slot_always(VT_POSITIONS, positions);
+        builder.push_slot_always(VT_VELOCITIES, velocities);
+        builder.push_slot_always(VT_MASSES, masses);
+        builder.push_slot_always(VT_RADII, radii);
+        builder.push_slot_always(VT_COLORS, colors);
+        builder.push_slot_always(VT_IDS, ids);
+        builder.push_slot(VT_POTENTIAL_ENERGY, *potential_energy, 0.0);
+        builder.push_slot(VT_LINEAR_MOMENTUM_X, linear_momentum[0], 0.0);
+        builder.push_slot(VT_LINEAR_MOMENTUM_Y, linear_momentum[1], 0.0);
+        builder.push_slot(VT_ANGULAR_MOMENTUM, *angular_momentum, 0.0);
+        builder.push_slot_always(VT_STATICS, statics);
+        let root = builder.end_table(start);
+        builder.finish(root, None);
+
+        let data = builder.finished_data();
+        let mut frame = Vec::with_capacity(data.len() + 1);
+        frame.push(FLATBUFFERS_TAG);
+        frame.extend_from_slice(data);
+        Some(frame)
+    }
+}
+
+/// Encodes a single `StateUpdate` frame
+pub fn encode_state_update(msg: &ServerToClientMessage) -> Option<Vec<u8>> {
+    StateUpdateEncoder::new().encode(msg)
+}
+
+/// Read-only view over a `StateUpdate` frame, nothing is copied
+#[derive(Clone, Copy)]
+pub struct StateUpdateView<'a> {
+    table: Table<'a>,
+}
+
+impl<'a> StateUpdateView<'a> {
+    /// Verifies the frame and wraps it
+    pub fn from_frame(frame: &'a [u8]) -> Result<Self, ProtocolError> {
+        match frame.split_first() {
+            Some((&FLATBUFFERS_TAG, data)) => flatbuffers::root::<StateUpdateView>(data)
+                .map_err(|e| ProtocolError::FlatBuffers(e.to_string())),
+            _ => Err(ProtocolError::FlatBuffers(
+                "not a FlatBuffers frame".to_string(),
+            )),
+        }
+    }
+
+    pub fn physical_time(&self) -> f64 {
+        self.scalar(VT_PHYSICAL_TIME)
+    }
+
+    pub fn kinetic_energy(&self) -> f64 {
+        self.scalar(VT_KINETIC_ENERGY)
+    }
+
+    /// 0 in the frames of a server predating it
+    pub fn potential_energy(&self) -> f64 {
+        self.scalar(VT_POTENTIAL_ENERGY)
+    }
+
+    pub fn linear_momentum(&self) -> [f64; 2] {
+        [
+            self.scalar(VT_LINEAR_MOMENTUM_X),
+            self.scalar(VT_LINEAR_MOMENTUM_Y),
+        ]
+    }
+
+    pub fn angular_momentum(&self) -> f64 {
+        self.scalar(VT_ANGULAR_MOMENTUM)
+    }
+
+    /// Number of bodies
+    pub fn len(&self) -> usize {
+        self.masses().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// [x0, y0, x1, y1, ...]
+    pub fn positions(&self) -> Vector<'a, f64> {
+        self.vector(VT_POSITIONS)
+    }
+
+    /// [vx0, vy0, vx1, vy1, ...]
+    pub fn velocities(&self) -> Vector<'a, f64> {
+        self.vector(VT_VELOCITIES)
+    }
+
+    pub fn masses(&self) -> Vector<'a, f64> {
+        self.vector(VT_MASSES)
+    }
+
+    pub fn radii(&self) -> Vector<'a, f64> {
+        self.vector(VT_RADII)
+    }
+
+    /// 0xRRGGBBAA
+    pub fn colors(&self) -> Vector<'a, u32> {
+        self.vector(VT_COLORS)
+    }
+
+    pub fn ids(&self) -> Vector<'a, u64> {
+        self.vector(VT_IDS)
+    }
+
+    /// Empty in the frames of a server predating static bodies
+    pub fn statics(&self) -> Vector<'a, bool> {
+        self.vector(VT_STATICS)
+    }
+
+    /// Copies the i-th body out of the frame (or None if the frame is inconsistent)
+    pub fn body(&self, i: usize) -> Option<Body> {
+        let (positions, velocities) = (self.positions(), self.velocities());
+        let (radii, colors, ids) = (self.radii(), self.colors(), self.ids());
+        if i >= self.len() || 2 * i + 1 >= positions.len().min(velocities.len()) {
+            return None;
+        }
+        if i >= radii.len().min(colors.len()).min(ids.len()) {
+            return None;
+        }
+        Some(Body {
+            id: BodyId(ids.get(i)),
+            position: [positions.get(2 * i), positions.get(2 * i + 1)],
+            velocity: [velocities.get(2 * i), velocities.get(2 * i + 1)],
+            mass: self.masses().get(i),
+            radius: radii.get(i),
+            color: colors.get(i).to_be_bytes(),
+            is_static: i < self.statics().len() && self.statics().get(i),
+        })
+    }
+
+    /// Copies the whole frame into a regular message
+    pub fn to_message(&self) -> ServerToClientMessage {
+        ServerToClientMessage::StateUpdate {
+            bodies: (0..self.len()).map_while(|i| self.body(i)).collect(),
+            physical_time: self.physical_time(),
+            kinetic_energy: self.kinetic_energy(),
+            potential_energy: self.potential_energy(),
+            linear_momentum: self.linear_momentum(),
+            angular_momentum: self.angular_momentum(),
+        }
+    }
+
+    fn scalar(&self, slot: u16) -> f64 {
+        // SAFETY: the slot type matches the schema (and the buffer was verified)
+        unsafe { self.table.get::<f64>(slot, Some(0.0)) }.unwrap_or_default()
+    }
+
+    fn vector<T: Follow<'a, Inner = T> + 'a>(&self, slot: u16) -> Vector<'a, T> {
+        // SAFETY: the slot type matches the schema (and the buffer was verified)
+        unsafe { self.table.get::<ForwardsUOffset<Vector<'a, T>>>(slot, None) }.unwrap_or_default()
+    }
+}
+
+impl<'a> Follow<'a> for StateUpdateView<'a> {
+    type Inner = StateUpdateView<'a>;
+
+    unsafe fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+        StateUpdateView {
+            table: Table::new(buf, loc),
+        }
+    }
+}
+
+impl Verifiable for StateUpdateView<'_> {
+    fn run_verifier(
+        v: &mut flatbuffers::Verifier,
+        pos: usize,
+    ) -> Result<(), flatbuffers::InvalidFlatbuffer> {
+        v.visit_table(pos)?
+            .visit_field::<f64>("physical_time", VT_PHYSICAL_TIME, false)?
+            .visit_field::<f64>("kinetic_energy", VT_KINETIC_ENERGY, false)?
+            .visit_field::<ForwardsUOffset<Vector<f64>>>("positions", VT_POSITIONS, false)?
+            .visit_field::<ForwardsUOffset<Vector<f64>>>("velocities", VT_VELOCITIES, false)?
+            .visit_field::<ForwardsUOffset<Vector<f64>>>("masses", VT_MASSES, false)?
+            .visit_field::<ForwardsUOffset<Vector<f64>>>("radii", VT_RADII, false)?
+            .visit_field::<ForwardsUOffset<Vector<u32>>>("colors", VT_COLORS, false)?
+            .visit_field::<ForwardsUOffset<Vector<u64>>>("ids", VT_IDS, false)?
+            .visit_field::<f64>("potential_energy", VT_POTENTIAL_ENERGY, false)?
+            .visit_field::<f64>("linear_momentum_x", VT_LINEAR_MOMENTUM_X, false)?
+            .visit_field::<f64>("linear_momentum_y", VT_LINEAR_MOMENTUM_Y, false)?
+            .visit_field::<f64>("angular_momentum", VT_ANGULAR_MOMENTUM, false)?
+            .visit_field::<ForwardsUOffset<Vector<bool>>>("statics", VT_STATICS, false)?
+            .finish();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flatbuffers_round_trip_test() {
+        let bodies: Vec<Body> = (0..4)
+            .map(|i| Body {
+                id: BodyId(10 + i as u64),
+                color: [i, 2, 3, 4],
+                ..Body::default()
+                    .with_position([i as f64, -(i as f64)])
+                    .with_velocity([0.5, 0.25])
+                    .with_mass(i as f64 + 1.0)
+                    .with_static(i == 2)
+            })
+            .collect();
+        let msg = ServerToClientMessage::StateUpdate {
+            bodies: bodies.clone(),
+            physical_time: 1.5,
+            kinetic_energy: 2.5,
+            potential_energy: -4.0,
+            linear_momentum: [0.5, -0.5],
+            angular_momentum: 3.0,
+        };
+
+        let mut encoder = StateUpdateEncoder::new();
+        // The reused builder does not leak the previous frame
+        encoder.encode(&ServerToClientMessage::StateUpdate {
+            bodies: vec![Body::default(); 100],
+            physical_time: 0.0,
+            kinetic_energy: 0.0,
+            potential_energy: 1.0,
+            linear_momentum: [1.0, 1.0],
+            angular_momentum: 1.0,
+        });
+        let frame = encoder.encode(&msg).unwrap();
+        assert_eq!(Some(&frame), encode_state_update(&msg).as_ref());
+        assert!(encoder
+            .encode(&ServerToClientMessage::Diagnostics(Vec::new()))
+            .is_none());
+
+        let view = StateUpdateView::from_frame(&frame).unwrap();
+        assert_eq!(view.len(), 4);
+        assert_eq!(view.physical_time(), 1.5);
+        assert_eq!(view.kinetic_energy(), 2.5);
+        assert_eq!(view.potential_energy(), -4.0);
+        assert_eq!(view.linear_momentum(), [0.5, -0.5]);
+        assert_eq!(view.angular_momentum(), 3.0);
+        assert_eq!(
+            view.positions().iter().collect::<Vec<_>>()[2..4],
+            [1.0, -1.0]
+        );
+        assert_eq!(view.body(3).unwrap().color, [3, 2, 3, 4]);
+        assert_eq!(view.body(3).unwrap().id, BodyId(13));
+        assert!(view.body(2).unwrap().is_static);
+        assert!(!view.body(3).unwrap().is_static);
+        assert!(view.body(4).is_none());
+
+        let ServerToClientMessage::StateUpdate {
+            bodies: decoded, ..
+        } = view.to_message()
+        else {
+            unreachable!()
+        };
+        assert!(decoded
+            .iter()
+            .zip(&bodies)
+            .all(|(a, b)| a.position == b.position && a.mass == b.mass));
+
+        assert!(StateUpdateView::from_frame(&frame[..frame.len() / 2]).is_err());
+        assert!(StateUpdateView::from_frame(&[FLATBUFFERS_TAG]).is_err());
+        assert!(StateUpdateView::from_frame(&[]).is_err());
+    }
+}
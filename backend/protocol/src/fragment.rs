@@ -0,0 +1,32 @@
+use crate::ProtocolError;
+
+/// First byte of a fragment (a complete frame starts with `PROTOCOL_VERSION` instead)
+pub const FRAGMENT_TAG: u8 = 0xf1;
+
+/// Fragment layout: [FRAGMENT_TAG, message_id (u32 LE), index (u16 LE), count (u16 LE), payload...]
+/// The payloads of all the fragments of a message, in index order, form a complete frame
+pub const FRAGMENT_HEADER_SIZE: usize = 9;
+
+/// Splits a frame into fragments carrying at most `max_payload_size` bytes each
+pub fn fragment(
+    frame: &[u8],
+    message_id: u32,
+    max_payload_size: usize,
+) -> Result<Vec<Vec<u8>>, ProtocolError> {
+    let chunks: Vec<&[u8]> = frame.chunks(max_payload_size.max(1)).collect();
+    let count = u16::try_from(chunks.len())
+        .map_err(|_| ProtocolError::InvalidFragment("too many fragments for a single message"))?;
+    Ok(chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, payload)| {
+            let mut fragment = Vec::with_capacity(FRAGMENT_HEADER_SIZE + payload.len());
+            fragment.push(FRAGMENT_TAG);
+            fragment.extend_from_slice(&message_id.to_le_bytes());
+            fragment.extend_from_slice(&(index as u16).to_le_bytes());
+            fragment.extend_from_slice(&count.to_le_bytes());
+            fragment.extend_from_slice(payload);
+            fragment
+        })
+        .collect())
+}
@@ -0,0 +1,577 @@
+//! Messages exchanged between the server and its clients, and their wire format
+//!
+//! Free of any wasm dependency so that it can be shared by the server, the browser bindings
+//! and native clients (the `wasm` feature adds the TypeScript definitions of the messages)
+
+mod codec;
+#[cfg(feature = "zstd")]
+pub mod dictionary;
+mod diff;
+mod error;
+#[cfg(feature = "flatbuffers")]
+pub mod flatbuffers;
+mod fragment;
+#[cfg(feature = "protobuf")]
+pub mod protobuf;
+mod subprotocol;
+mod wire;
+
+use std::borrow::Cow;
+
+use nbody::{
+    constraints::Constraint,
+    physics::{Body, BodyId, ExternalForce},
+    quadtree::SquareBox,
+    scenarios::Scenario,
+    simulation::{BoundaryCondition, PhyiscsParameters, Simulation, SolverParameters},
+};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
+
+pub use codec::{
+    is_dictionary_frame, split_frame, Codec, CodecOptions, DEFAULT_COMPRESSION_THRESHOLD,
+    DICTIONARY_TAG,
+};
+pub use diff::{
+    BodiesDelta, BodyDiff, COLOR_CHANGED, MASS_CHANGED, POSITION_CHANGED, RADIUS_CHANGED,
+    STATIC_CHANGED, VELOCITY_CHANGED,
+};
+pub use error::ProtocolError;
+pub use fragment::{fragment, FRAGMENT_HEADER_SIZE, FRAGMENT_TAG};
+pub use subprotocol::{decode_json, encode_json, Subprotocol, SUBPROTOCOL_HEADER};
+pub use wire::{read_message, write_message, WireMessage, MESSAGE_ID_SIZE};
+
+/// Version of the wire format, first byte of every frame
+pub const PROTOCOL_VERSION: u8 = 5;
+
+/// Upper bound of `SubscribeBundled::ticks_per_bundle`, more would delay the states too much
+pub const MAX_TICKS_PER_BUNDLE: u32 = 8;
+
+/// Default upper bound of a decompressed frame, protects the decoders from gzip bombs
+pub const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+#[serde(rename_all = "camelCase")]
+pub enum ClientToServerMessage {
+    Subscribe,
+    AddBodies(Vec<Body>),
+    State,
+    Reset,
+    SetSolverParameters(SolverParameters),
+    SetPhysicsParameters(PhyiscsParameters),
+
+    /// Streams the recorded states whose physical time (seconds) is within [from_time, to_time]
+    /// to the requesting client only, as `StateUpdate`s sent at `rate` states per second
+    /// (at least 0.01, faster rates are capped at 240)
+    #[serde(rename_all = "camelCase")]
+    RequestHistory {
+        from_time: f64,
+        to_time: f64,
+        rate: f64,
+    },
+
+    /// Asks for the diagnostics recorded within [from_time, to_time] (physical time in seconds)
+    /// downsampled to at most `max_points` samples
+    #[serde(rename_all = "camelCase")]
+    QueryDiagnostics {
+        from_time: f64,
+        to_time: f64,
+        max_points: u32,
+    },
+
+    /// Asks for the bodies currently inside the region, without subscribing to the states
+    QueryRegion(SquareBox),
+
+    /// Asks for the state of a single body, by identifier (e.g. for an inspector panel)
+    GetBody(BodyId),
+
+    /// Asks for the last `last_n` recorded states at once (e.g. to draw trails right after joining)
+    #[serde(rename_all = "camelCase")]
+    GetHistory {
+        last_n: u32,
+    },
+
+    /// Same as `Subscribe` but the states are pushed in `StateBundle`s of `ticks_per_bundle`
+    /// consecutive ticks (up to `MAX_TICKS_PER_BUNDLE`), which the client interpolates between:
+    /// fewer but larger messages, that compress better
+    #[serde(rename_all = "camelCase")]
+    SubscribeBundled {
+        ticks_per_bundle: u32,
+    },
+
+    /// Answered right away by a `Pong`, to measure the round trip time and the clock offset
+    /// `client_time` is echoed back as is (milliseconds since the Unix epoch, e.g. `Date.now()`)
+    #[serde(rename_all = "camelCase")]
+    Ping {
+        client_time: f64,
+    },
+
+    /// Removes the bodies with these identifiers
+    RemoveBodies(Vec<BodyId>),
+
+    /// Same as `Subscribe` but the states are pushed as `StateDelta`s,
+    /// against the last one acknowledged with `AckState`
+    SubscribeDeltas,
+
+    /// Acknowledges the `StateDelta` of that tick, the next ones are encoded against it
+    #[serde(rename_all = "camelCase")]
+    AckState {
+        tick: u64,
+    },
+
+    /// Freezes the simulation, the commands are still applied
+    Pause,
+
+    /// Lets a paused simulation step on its own again
+    Resume,
+
+    /// Advances a paused simulation by one tick, answered once it has been taken
+    SingleStep,
+
+    /// Steps the simulation that many times per second of wall-clock time (the physical time
+    /// step is unchanged), rejected outside of the range allowed by the server
+    SetTickRate(f64),
+
+    /// Opens a new room, with its own simulation, and moves the client there
+    /// (answered by `RoomJoined`, to share the id with the other clients of the group)
+    CreateRoom,
+
+    /// Moves the client to another room, its subscriptions to the current one are dropped
+    JoinRoom(RoomId),
+
+    /// Moves the client back to the lobby, the room the clients join when they connect
+    /// The other rooms are closed once their last client leaves
+    LeaveRoom,
+
+    /// Keeps the bodies within a box (or lets them go with `Open`), from the next step on
+    SetBoundaryCondition(BoundaryCondition),
+
+    /// Grants the role of the token to the connection, answered by `Authenticated`
+    /// Must be the first message when the server requires tokens, which closes the connection
+    /// on any other (or on an unknown token)
+    Authenticate {
+        token: String,
+    },
+
+    /// Persists the simulation of the room on the server under `name`, answered by `SnapshotSaved`
+    /// (names are made of ASCII letters, digits, `-` and `_`)
+    SaveSnapshot {
+        name: String,
+    },
+
+    /// Replaces the simulation of the room with the snapshot saved under `name`
+    LoadSnapshot {
+        name: String,
+    },
+
+    /// Generates the bodies of the scenario on the server (with the gravity constant of the room)
+    /// and adds them to the simulation
+    LoadScenario(Scenario),
+
+    /// Asks for the last `last_n` positions of every body (at most as many as the server records),
+    /// e.g. to draw orbit trails right after joining
+    #[serde(rename_all = "camelCase")]
+    GetTrails {
+        last_n: u32,
+    },
+
+    /// Replaces the fields acting on the bodies besides their gravity (none to remove them all),
+    /// from the next step on
+    SetExternalForces(Vec<ExternalForce>),
+
+    /// Links two bodies with a spring or a rod, replacing the constraint between them if any
+    AddConstraint(Constraint),
+
+    /// Unlinks the two bodies (in either order)
+    RemoveConstraint(BodyId, BodyId),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+#[serde(rename_all = "camelCase")]
+pub enum ServerToClientMessage {
+    #[serde(rename_all = "camelCase")]
+    StateUpdate {
+        bodies: Vec<Body>,
+        physical_time: f64,
+        kinetic_energy: f64,
+
+        /// Conservation diagnostics of the simulation, the total energy being
+        /// `kinetic_energy + potential_energy` (see `Simulation::potential_energy`)
+        potential_energy: f64,
+        linear_momentum: [f64; 2],
+
+        /// Around the origin
+        angular_momentum: f64,
+    },
+
+    /// Reply to `QueryDiagnostics`, in increasing time order
+    Diagnostics(Vec<DiagnosticsSample>),
+
+    /// Reply to `QueryRegion`
+    #[serde(rename_all = "camelCase")]
+    Region {
+        bodies: Vec<Body>,
+        physical_time: f64,
+    },
+
+    /// Reply to `GetBody`
+    #[serde(rename_all = "camelCase")]
+    BodyDetails {
+        body: Body,
+        speed: f64,
+        kinetic_energy: f64,
+
+        /// Due to the forces of the last step
+        acceleration: [f64; 2],
+        physical_time: f64,
+    },
+
+    /// Reply to `GetHistory`, in increasing time order
+    History(Vec<RecordedState>),
+
+    /// Consecutive ticks pushed at once to the clients of `SubscribeBundled`,
+    /// in increasing time order
+    StateBundle(Vec<RecordedState>),
+
+    /// Reply to `Ping`
+    #[serde(rename_all = "camelCase")]
+    Pong {
+        client_time: f64,
+
+        /// Milliseconds since the Unix epoch when the ping was answered
+        server_time: f64,
+
+        /// Steps taken by the simulation so far
+        tick: u64,
+    },
+
+    /// State of `tick` as a delta against the state of `baseline_tick` acknowledged by the client,
+    /// or without a baseline the whole state (all the bodies are `added`)
+    #[serde(rename_all = "camelCase")]
+    StateDelta {
+        tick: u64,
+        baseline_tick: Option<u64>,
+        delta: BodiesDelta,
+        physical_time: f64,
+        kinetic_energy: f64,
+    },
+
+    /// Reply to `CreateRoom`, `JoinRoom` and `LeaveRoom`, the requests of the client
+    /// are served by that room from now on
+    RoomJoined(RoomId),
+
+    /// Reply to `Authenticate`, with the role granted to the connection
+    Authenticated(Role),
+
+    /// Reply to `SaveSnapshot`, once the snapshot is on disk
+    SnapshotSaved { name: String, physical_time: f64 },
+
+    /// Reply to `GetTrails`, in id order
+    #[serde(rename_all = "camelCase")]
+    Trails {
+        trails: Vec<BodyTrail>,
+        physical_time: f64,
+    },
+}
+
+impl ServerToClientMessage {
+    /// `StateUpdate` with the current state of the simulation
+    pub fn state_update(simulation: &Simulation) -> Self {
+        ServerToClientMessage::StateUpdate {
+            bodies: simulation.bodies().to_vec(),
+            physical_time: simulation.get_physical_time(),
+            kinetic_energy: simulation.get_kinetic_energy(),
+            potential_energy: simulation.potential_energy(),
+            linear_momentum: simulation.linear_momentum(),
+            angular_momentum: simulation.angular_momentum(),
+        }
+    }
+}
+
+/// What a client is allowed to do, each role being allowed what the previous ones are
+#[derive(
+    Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+#[serde(rename_all = "camelCase")]
+pub enum Role {
+    /// Watches the simulation: subscriptions and queries
+    #[default]
+    Spectator,
+
+    /// Adds and removes bodies, and drives the clock (pause, steps, tick rate) and the rooms
+    Controller,
+
+    /// Resets the simulation and changes its parameters
+    Admin,
+}
+
+/// Identifier of a room, each room runs a simulation of its own
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+#[serde(transparent)]
+pub struct RoomId(pub u64);
+
+impl RoomId {
+    /// The room the clients join when they connect, never closed
+    pub const LOBBY: RoomId = RoomId(0);
+}
+
+/// Content of a past `StateUpdate`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+#[serde(rename_all = "camelCase")]
+pub struct RecordedState {
+    pub bodies: Vec<Body>,
+    pub physical_time: f64,
+    pub kinetic_energy: f64,
+}
+
+impl RecordedState {
+    /// Content of a `StateUpdate` (None for any other message), without its diagnostics
+    pub fn from_update(msg: ServerToClientMessage) -> Option<Self> {
+        match msg {
+            ServerToClientMessage::StateUpdate {
+                bodies,
+                physical_time,
+                kinetic_energy,
+                ..
+            } => Some(RecordedState {
+                bodies,
+                physical_time,
+                kinetic_energy,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Last positions of a body, oldest first (the last one being its current position)
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+pub struct BodyTrail {
+    pub id: BodyId,
+    pub positions: Vec<[f64; 2]>,
+}
+
+/// Diagnostics of the simulation after a step (or averaged over consecutive steps)
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(from_wasm_abi, into_wasm_abi))]
+#[serde(rename_all = "camelCase")]
+pub struct DiagnosticsSample {
+    pub physical_time: f64, // seconds
+    pub kinetic_energy: f64,
+    pub momentum: [f64; 2],
+    pub body_count: u32,
+}
+
+/// Serializes and compresses a message into a frame:
+/// [PROTOCOL_VERSION, codec, payload or gzip(payload)...]
+/// where payload is [message_id, bincode(fields)...] (see `WireMessage`)
+pub fn encode<T: WireMessage>(msg: &T) -> Result<Vec<u8>, ProtocolError> {
+    encode_with(msg, &CodecOptions::default())
+}
+
+/// Same as `encode` with the given compression level and threshold
+pub fn encode_with<T: WireMessage>(
+    msg: &T,
+    options: &CodecOptions,
+) -> Result<Vec<u8>, ProtocolError> {
+    let data = write_message(msg)?;
+    codec::compress_data(&data, options).map_err(ProtocolError::Compression)
+}
+
+/// Decompresses and deserializes a frame produced by `encode`
+pub fn decode<T: WireMessage>(frame: &[u8]) -> Result<T, ProtocolError> {
+    decode_with_limit(frame, MAX_DECOMPRESSED_SIZE)
+}
+
+/// Same as `decode` but rejecting frames that decompress into more than `max_size` bytes
+pub fn decode_with_limit<T: WireMessage>(
+    frame: &[u8],
+    max_size: usize,
+) -> Result<T, ProtocolError> {
+    let data = match split_frame(frame)? {
+        (Codec::None, payload) if payload.len() > max_size => {
+            return Err(ProtocolError::FrameTooLarge {
+                size: payload.len(),
+                max_size,
+            })
+        }
+        (Codec::None, payload) => Cow::Borrowed(payload),
+        (Codec::Gzip, payload) => Cow::Owned(codec::decompress_data(payload, max_size)?),
+        (Codec::Zstd, _) => return Err(ProtocolError::MissingDictionary),
+    };
+    read_message(&data)
+}
+
+/// Returns the payload of the frame if it was produced by this version of the protocol
+pub fn check_version(frame: &[u8]) -> Result<&[u8], ProtocolError> {
+    match frame.split_first() {
+        Some((&PROTOCOL_VERSION, payload)) => Ok(payload),
+        Some((&found, _)) => Err(ProtocolError::VersionMismatch {
+            expected: PROTOCOL_VERSION,
+            found: Some(found),
+        }),
+        None => Err(ProtocolError::VersionMismatch {
+            expected: PROTOCOL_VERSION,
+            found: None,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn serialization_test() {
+        let bodies = vec![Body::default(); 10];
+        let serialized = encode(&ClientToServerMessage::AddBodies(bodies.clone())).unwrap();
+        let deserialized = decode(&serialized).unwrap();
+
+        match deserialized {
+            ClientToServerMessage::AddBodies(bodies) => assert!(bodies.len() == 10),
+            _ => panic!("Expected Subscribe"),
+        };
+    }
+
+    #[test]
+    fn state_bundle_test() {
+        let states: Vec<_> = (0..3)
+            .map(|tick| RecordedState {
+                bodies: (0..50)
+                    .map(|i| Body::default().with_position([i as f64, tick as f64 * 0.01]))
+                    .collect(),
+                physical_time: tick as f64,
+                kinetic_energy: 1.0,
+            })
+            .collect();
+        let frame = encode(&ServerToClientMessage::StateBundle(states.clone())).unwrap();
+        let Ok(ServerToClientMessage::StateBundle(decoded)) = decode(&frame) else {
+            panic!("Expected a StateBundle");
+        };
+        assert_eq!(decoded.len(), 3);
+        assert_eq!(decoded[2].bodies[4].position, [4.0, 0.02]);
+
+        // Smaller than the same ticks sent one by one
+        let separate: usize = states
+            .into_iter()
+            .map(|state| {
+                encode(&ServerToClientMessage::StateUpdate {
+                    bodies: state.bodies,
+                    physical_time: state.physical_time,
+                    kinetic_energy: state.kinetic_energy,
+                    potential_energy: 0.0,
+                    linear_momentum: [0.0, 0.0],
+                    angular_momentum: 0.0,
+                })
+                .unwrap()
+                .len()
+            })
+            .sum();
+        assert!(frame.len() < separate);
+    }
+
+    #[test]
+    fn parameters_message_test() {
+        let frame = encode(&ClientToServerMessage::SetSolverParameters(
+            SolverParameters::default(),
+        ))
+        .unwrap();
+        assert!(matches!(
+            decode(&frame),
+            Ok(ClientToServerMessage::SetSolverParameters(_))
+        ));
+    }
+
+    #[test]
+    fn error_kinds_test() {
+        let compressed = CodecOptions::new().with_threshold(0);
+        let mut frame = encode_with(&ClientToServerMessage::Reset, &compressed).unwrap();
+        assert!(matches!(
+            decode::<ClientToServerMessage>(&frame[..frame.len() - 4]),
+            Err(ProtocolError::Decompression(_))
+        ));
+        assert!(matches!(
+            decode::<ClientToServerMessage>(&[]),
+            Err(ProtocolError::VersionMismatch { found: None, .. })
+        ));
+
+        // AddBodies with a truncated list of bodies
+        let payload = [PROTOCOL_VERSION, Codec::None as u8, 2, 0, 1];
+        assert!(matches!(
+            decode::<ClientToServerMessage>(&payload),
+            Err(ProtocolError::Decoding(_))
+        ));
+
+        frame[0] = PROTOCOL_VERSION + 1;
+        let err = decode::<ClientToServerMessage>(&frame).unwrap_err();
+        assert!(matches!(err, ProtocolError::VersionMismatch { .. }));
+        assert!(err.to_string().starts_with("VersionMismatch"));
+
+        assert!(matches!(
+            decode::<ClientToServerMessage>(&[PROTOCOL_VERSION]),
+            Err(ProtocolError::UnknownCodec(None))
+        ));
+        assert!(matches!(
+            decode::<ClientToServerMessage>(&[PROTOCOL_VERSION, 0xff]),
+            Err(ProtocolError::UnknownCodec(Some(0xff)))
+        ));
+    }
+
+    #[test]
+    fn compression_threshold_test() {
+        // Control messages are not worth compressing
+        let frame = encode(&ClientToServerMessage::Reset).unwrap();
+        assert_eq!(frame[..2], [PROTOCOL_VERSION, Codec::None as u8]);
+        assert!(matches!(decode(&frame), Ok(ClientToServerMessage::Reset)));
+
+        let msg = ClientToServerMessage::AddBodies(vec![Body::default(); 100]);
+        let frame = encode(&msg).unwrap();
+        assert_eq!(frame[..2], [PROTOCOL_VERSION, Codec::Gzip as u8]);
+        assert!(
+            matches!(decode(&frame), Ok(ClientToServerMessage::AddBodies(b)) if b.len() == 100)
+        );
+
+        let never = CodecOptions::new().with_threshold(usize::MAX);
+        let raw = encode_with(&msg, &never).unwrap();
+        assert_eq!(raw[1], Codec::None as u8);
+        assert!(raw.len() > frame.len());
+        assert!(matches!(
+            decode_with_limit::<ClientToServerMessage>(&raw, raw.len() - 3),
+            Err(ProtocolError::FrameTooLarge { .. })
+        ));
+
+        let stored = encode_with(&msg, &CodecOptions::new().with_level(0)).unwrap();
+        let best = encode_with(&msg, &CodecOptions::new().with_level(42)).unwrap();
+        assert!(best.len() < stored.len());
+        assert!(matches!(
+            decode(&best),
+            Ok(ClientToServerMessage::AddBodies(_))
+        ));
+    }
+
+    #[test]
+    fn decompression_limit_test() {
+        let msg = ClientToServerMessage::AddBodies(vec![Body::default(); 20_000]);
+        let frame = encode(&msg).unwrap();
+        assert!(frame.len() < 1 << 15);
+        assert!(matches!(
+            decode_with_limit::<ClientToServerMessage>(&frame, 1 << 16),
+            Err(ProtocolError::FrameTooLarge { max_size, .. }) if max_size == 1 << 16
+        ));
+
+        let size = MESSAGE_ID_SIZE
+            + bincode::serialized_size(&vec![Body::default(); 20_000]).unwrap() as usize;
+        assert!(matches!(
+            decode_with_limit::<ClientToServerMessage>(&frame, size),
+            Ok(ClientToServerMessage::AddBodies(bodies)) if bodies.len() == 20_000
+        ));
+        assert!(decode_with_limit::<ClientToServerMessage>(&frame, size - 1).is_err());
+    }
+}
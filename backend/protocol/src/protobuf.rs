@@ -0,0 +1,1778 @@
+//! Protobuf encoding of the messages (schema in `proto/nbody.proto`)
+//!
+//! Alternative to the default bincode frames for clients written in other languages,
+//! which can generate their bindings from the schema instead of reimplementing the bincode layout
+
+use nbody::{
+    constraints::{Constraint, ConstraintKind},
+    physics::{Body, BodyId, CollisionModel, ExternalForce},
+    quadtree::SquareBox,
+    scenarios::{MassDistribution, Scenario, ScenarioKind},
+    simulation::{
+        AdaptiveTimestep, BoundaryCondition, Integrator, PhyiscsParameters, SolverParameters,
+    },
+};
+use prost::Message;
+
+use crate::{
+    decode, encode, BodiesDelta, BodyDiff, BodyTrail, ClientToServerMessage, DiagnosticsSample,
+    ProtocolError, RecordedState, Role, RoomId, ServerToClientMessage, WireMessage,
+};
+
+/// First byte of a protobuf frame: [PROTOBUF_TAG, protobuf(msg)...] (not compressed)
+pub const PROTOBUF_TAG: u8 = 0xb1;
+
+/// Wire format of a frame
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// [PROTOCOL_VERSION, codec, payload or gzip(payload)...]
+    #[default]
+    Bincode,
+
+    /// [PROTOBUF_TAG, protobuf(msg)...]
+    Protobuf,
+}
+
+/// Messages having a protobuf representation
+pub trait ProtobufMessage: Sized {
+    fn to_protobuf(&self) -> Vec<u8>;
+    fn from_protobuf(bytes: &[u8]) -> Result<Self, ProtocolError>;
+}
+
+/// Encodes a message into a frame of the given encoding
+pub fn encode_as<T: WireMessage + ProtobufMessage>(
+    msg: &T,
+    encoding: Encoding,
+) -> Result<Vec<u8>, ProtocolError> {
+    match encoding {
+        Encoding::Bincode => encode(msg),
+        Encoding::Protobuf => {
+            let mut frame = vec![PROTOBUF_TAG];
+            frame.extend(msg.to_protobuf());
+            Ok(frame)
+        }
+    }
+}
+
+/// Decodes a frame of any encoding, telling which one it was
+/// (e.g. for the server to reply in the same encoding)
+pub fn decode_any<T: WireMessage + ProtobufMessage>(
+    frame: &[u8],
+) -> Result<(T, Encoding), ProtocolError> {
+    match frame.split_first() {
+        Some((&PROTOBUF_TAG, payload)) => Ok((T::from_protobuf(payload)?, Encoding::Protobuf)),
+        _ => Ok((decode(frame)?, Encoding::Bincode)),
+    }
+}
+
+impl ProtobufMessage for ClientToServerMessage {
+    fn to_protobuf(&self) -> Vec<u8> {
+        use schema::client_message::Kind;
+        let kind = match self {
+            ClientToServerMessage::Subscribe => Kind::Subscribe(schema::Empty {}),
+            ClientToServerMessage::AddBodies(bodies) => Kind::AddBodies(schema::AddBodies {
+                bodies: bodies.iter().map(Into::into).collect(),
+            }),
+            ClientToServerMessage::State => Kind::State(schema::Empty {}),
+            ClientToServerMessage::Reset => Kind::Reset(schema::Empty {}),
+            ClientToServerMessage::SetSolverParameters(parameters) => {
+                Kind::SetSolverParameters(schema::SolverParameters {
+                    dt: parameters.dt(),
+                    barnes_hut_theta: parameters.barnes_hut_theta(),
+                    root_padding: Some(parameters.root_padding()),
+                    root_shrink_delay: Some(parameters.root_shrink_delay()),
+                    integrator: Some(schema::Integrator::from(parameters.integrator()).into()),
+                    adaptive_timestep: parameters.adaptive_timestep().map(|adaptive| {
+                        schema::AdaptiveTimestep {
+                            min_dt: adaptive.min_dt(),
+                            max_dt: adaptive.max_dt(),
+                            tolerance: adaptive.tolerance(),
+                        }
+                    }),
+                })
+            }
+            ClientToServerMessage::SetPhysicsParameters(parameters) => {
+                Kind::SetPhysicsParameters(schema::PhysicsParameters {
+                    gravity_constant: parameters.gravity_constant(),
+                    softening: parameters.softening(),
+                    collision_model: Some(
+                        schema::CollisionModel::from(parameters.collision_model()).into(),
+                    ),
+                    restitution: Some(parameters.restitution()),
+                })
+            }
+            ClientToServerMessage::RequestHistory {
+                from_time,
+                to_time,
+                rate,
+            } => Kind::RequestHistory(schema::RequestHistory {
+                from_time: *from_time,
+                to_time: *to_time,
+                rate: *rate,
+            }),
+            ClientToServerMessage::QueryDiagnostics {
+                from_time,
+                to_time,
+                max_points,
+            } => Kind::QueryDiagnostics(schema::QueryDiagnostics {
+                from_time: *from_time,
+                to_time: *to_time,
+                max_points: *max_points,
+            }),
+            ClientToServerMessage::QueryRegion(region) => Kind::QueryRegion(schema::QueryRegion {
+                center_x: region.center()[0],
+                center_y: region.center()[1],
+                half_size: region.half_size(),
+            }),
+            ClientToServerMessage::GetBody(id) => Kind::GetBody(schema::GetBody { id: id.0 }),
+            ClientToServerMessage::GetHistory { last_n } => {
+                Kind::GetHistory(schema::GetHistory { last_n: *last_n })
+            }
+            ClientToServerMessage::SubscribeBundled { ticks_per_bundle } => {
+                Kind::SubscribeBundled(schema::SubscribeBundled {
+                    ticks_per_bundle: *ticks_per_bundle,
+                })
+            }
+            ClientToServerMessage::Ping { client_time } => Kind::Ping(schema::Ping {
+                client_time: *client_time,
+            }),
+            ClientToServerMessage::RemoveBodies(ids) => Kind::RemoveBodies(schema::RemoveBodies {
+                ids: ids.iter().map(|id| id.0).collect(),
+            }),
+            ClientToServerMessage::SubscribeDeltas => Kind::SubscribeDeltas(schema::Empty {}),
+            ClientToServerMessage::AckState { tick } => {
+                Kind::AckState(schema::AckState { tick: *tick })
+            }
+            ClientToServerMessage::Pause => Kind::Pause(schema::Empty {}),
+            ClientToServerMessage::Resume => Kind::Resume(schema::Empty {}),
+            ClientToServerMessage::SingleStep => Kind::SingleStep(schema::Empty {}),
+            ClientToServerMessage::SetTickRate(tick_rate) => {
+                Kind::SetTickRate(schema::SetTickRate {
+                    tick_rate: *tick_rate,
+                })
+            }
+            ClientToServerMessage::CreateRoom => Kind::CreateRoom(schema::Empty {}),
+            ClientToServerMessage::JoinRoom(room) => Kind::JoinRoom(schema::Room { id: room.0 }),
+            ClientToServerMessage::LeaveRoom => Kind::LeaveRoom(schema::Empty {}),
+            ClientToServerMessage::Authenticate { token } => {
+                Kind::Authenticate(schema::Authenticate {
+                    token: token.clone(),
+                })
+            }
+            ClientToServerMessage::SaveSnapshot { name } => {
+                Kind::SaveSnapshot(schema::Snapshot { name: name.clone() })
+            }
+            ClientToServerMessage::LoadSnapshot { name } => {
+                Kind::LoadSnapshot(schema::Snapshot { name: name.clone() })
+            }
+            ClientToServerMessage::LoadScenario(scenario) => Kind::LoadScenario(scenario.into()),
+            ClientToServerMessage::GetTrails { last_n } => {
+                Kind::GetTrails(schema::GetTrails { last_n: *last_n })
+            }
+            ClientToServerMessage::SetBoundaryCondition(boundary) => {
+                Kind::SetBoundaryCondition(boundary.into())
+            }
+            ClientToServerMessage::SetExternalForces(forces) => {
+                Kind::SetExternalForces(schema::SetExternalForces {
+                    forces: forces.iter().map(Into::into).collect(),
+                })
+            }
+            ClientToServerMessage::AddConstraint(constraint) => {
+                Kind::AddConstraint(constraint.into())
+            }
+            ClientToServerMessage::RemoveConstraint(a, b) => {
+                Kind::RemoveConstraint(schema::RemoveConstraint { a: a.0, b: b.0 })
+            }
+        };
+        schema::ClientMessage { kind: Some(kind) }.encode_to_vec()
+    }
+
+    fn from_protobuf(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        use schema::client_message::Kind;
+        let msg = schema::ClientMessage::decode(bytes).map_err(protobuf_error)?;
+        Ok(match msg.kind.ok_or_else(missing_kind)? {
+            Kind::Subscribe(_) => ClientToServerMessage::Subscribe,
+            Kind::AddBodies(msg) => {
+                ClientToServerMessage::AddBodies(msg.bodies.into_iter().map(Into::into).collect())
+            }
+            Kind::State(_) => ClientToServerMessage::State,
+            Kind::Reset(_) => ClientToServerMessage::Reset,
+            Kind::SetSolverParameters(parameters) => {
+                let mut solver = SolverParameters::new(parameters.dt, parameters.barnes_hut_theta);
+                if let Some(root_padding) = parameters.root_padding {
+                    solver = solver.with_root_padding(root_padding);
+                }
+                if let Some(root_shrink_delay) = parameters.root_shrink_delay {
+                    solver = solver.with_root_shrink_delay(root_shrink_delay);
+                }
+                if let Some(integrator) = parameters.integrator {
+                    let integrator = schema::Integrator::try_from(integrator)
+                        .map_err(|e| ProtocolError::Protobuf(e.to_string()))?;
+                    solver = solver.with_integrator(integrator.into());
+                }
+                if let Some(adaptive) = parameters.adaptive_timestep {
+                    solver = solver.with_adaptive_timestep(
+                        AdaptiveTimestep::new(adaptive.min_dt, adaptive.max_dt)
+                            .with_tolerance(adaptive.tolerance),
+                    );
+                }
+                ClientToServerMessage::SetSolverParameters(solver)
+            }
+            Kind::SetPhysicsParameters(parameters) => {
+                let mut physics = PhyiscsParameters::new(parameters.gravity_constant)
+                    .with_softening(parameters.softening);
+                if let Some(collision_model) = parameters.collision_model {
+                    let collision_model = schema::CollisionModel::try_from(collision_model)
+                        .map_err(|e| ProtocolError::Protobuf(e.to_string()))?;
+                    physics = physics.with_collision_model(collision_model.into());
+                }
+                if let Some(restitution) = parameters.restitution {
+                    physics = physics.with_restitution(restitution);
+                }
+                ClientToServerMessage::SetPhysicsParameters(physics)
+            }
+            Kind::RequestHistory(request) => ClientToServerMessage::RequestHistory {
+                from_time: request.from_time,
+                to_time: request.to_time,
+                rate: request.rate,
+            },
+            Kind::QueryDiagnostics(query) => ClientToServerMessage::QueryDiagnostics {
+                from_time: query.from_time,
+                to_time: query.to_time,
+                max_points: query.max_points,
+            },
+            Kind::QueryRegion(query) => ClientToServerMessage::QueryRegion(SquareBox::new(
+                [query.center_x, query.center_y],
+                query.half_size,
+            )),
+            Kind::GetBody(query) => ClientToServerMessage::GetBody(BodyId(query.id)),
+            Kind::GetHistory(query) => ClientToServerMessage::GetHistory {
+                last_n: query.last_n,
+            },
+            Kind::SubscribeBundled(subscription) => ClientToServerMessage::SubscribeBundled {
+                ticks_per_bundle: subscription.ticks_per_bundle,
+            },
+            Kind::Ping(ping) => ClientToServerMessage::Ping {
+                client_time: ping.client_time,
+            },
+            Kind::RemoveBodies(msg) => {
+                ClientToServerMessage::RemoveBodies(msg.ids.into_iter().map(BodyId).collect())
+            }
+            Kind::SubscribeDeltas(_) => ClientToServerMessage::SubscribeDeltas,
+            Kind::AckState(msg) => ClientToServerMessage::AckState { tick: msg.tick },
+            Kind::Pause(_) => ClientToServerMessage::Pause,
+            Kind::Resume(_) => ClientToServerMessage::Resume,
+            Kind::SingleStep(_) => ClientToServerMessage::SingleStep,
+            Kind::SetTickRate(msg) => ClientToServerMessage::SetTickRate(msg.tick_rate),
+            Kind::CreateRoom(_) => ClientToServerMessage::CreateRoom,
+            Kind::JoinRoom(msg) => ClientToServerMessage::JoinRoom(RoomId(msg.id)),
+            Kind::LeaveRoom(_) => ClientToServerMessage::LeaveRoom,
+            Kind::Authenticate(msg) => ClientToServerMessage::Authenticate { token: msg.token },
+            Kind::SaveSnapshot(msg) => ClientToServerMessage::SaveSnapshot { name: msg.name },
+            Kind::LoadSnapshot(msg) => ClientToServerMessage::LoadSnapshot { name: msg.name },
+            Kind::LoadScenario(msg) => ClientToServerMessage::LoadScenario(msg.try_into()?),
+            Kind::GetTrails(query) => ClientToServerMessage::GetTrails {
+                last_n: query.last_n,
+            },
+            Kind::SetBoundaryCondition(msg) => {
+                ClientToServerMessage::SetBoundaryCondition(msg.try_into()?)
+            }
+            Kind::SetExternalForces(msg) => ClientToServerMessage::SetExternalForces(
+                msg.forces
+                    .into_iter()
+                    .map(TryInto::try_into)
+                    .collect::<Result<_, _>>()?,
+            ),
+            Kind::AddConstraint(msg) => ClientToServerMessage::AddConstraint(msg.try_into()?),
+            Kind::RemoveConstraint(msg) => {
+                ClientToServerMessage::RemoveConstraint(BodyId(msg.a), BodyId(msg.b))
+            }
+        })
+    }
+}
+
+impl ProtobufMessage for ServerToClientMessage {
+    fn to_protobuf(&self) -> Vec<u8> {
+        use schema::server_message::Kind;
+        let kind = match self {
+            ServerToClientMessage::StateUpdate {
+                bodies,
+                physical_time,
+                kinetic_energy,
+                potential_energy,
+                linear_momentum,
+                angular_momentum,
+            } => Kind::StateUpdate(schema::StateUpdate {
+                bodies: bodies.iter().map(Into::into).collect(),
+                physical_time: *physical_time,
+                kinetic_energy: *kinetic_energy,
+                potential_energy: *potential_energy,
+                linear_momentum_x: linear_momentum[0],
+                linear_momentum_y: linear_momentum[1],
+                angular_momentum: *angular_momentum,
+            }),
+            ServerToClientMessage::Diagnostics(samples) => Kind::Diagnostics(schema::Diagnostics {
+                samples: samples.iter().map(Into::into).collect(),
+            }),
+            ServerToClientMessage::Region {
+                bodies,
+                physical_time,
+            } => Kind::Region(schema::Region {
+                bodies: bodies.iter().map(Into::into).collect(),
+                physical_time: *physical_time,
+            }),
+            ServerToClientMessage::BodyDetails {
+                body,
+                speed,
+                kinetic_energy,
+                acceleration,
+                physical_time,
+            } => Kind::BodyDetails(schema::BodyDetails {
+                body: Some(body.into()),
+                speed: *speed,
+                kinetic_energy: *kinetic_energy,
+                acceleration_x: acceleration[0],
+                acceleration_y: acceleration[1],
+                physical_time: *physical_time,
+            }),
+            ServerToClientMessage::History(states) => Kind::History(schema::History {
+                states: states.iter().map(Into::into).collect(),
+            }),
+            ServerToClientMessage::StateBundle(states) => Kind::StateBundle(schema::StateBundle {
+                states: states.iter().map(Into::into).collect(),
+            }),
+            ServerToClientMessage::Pong {
+                client_time,
+                server_time,
+                tick,
+            } => Kind::Pong(schema::Pong {
+                client_time: *client_time,
+                server_time: *server_time,
+                tick: *tick,
+            }),
+            ServerToClientMessage::StateDelta {
+                tick,
+                baseline_tick,
+                delta,
+                physical_time,
+                kinetic_energy,
+            } => Kind::StateDelta(schema::StateDelta {
+                tick: *tick,
+                baseline_tick: *baseline_tick,
+                changed: delta.changed.iter().map(Into::into).collect(),
+                added: delta.added.iter().map(Into::into).collect(),
+                removed: delta.removed.iter().map(|id| id.0).collect(),
+                physical_time: *physical_time,
+                kinetic_energy: *kinetic_energy,
+            }),
+            ServerToClientMessage::RoomJoined(room) => {
+                Kind::RoomJoined(schema::Room { id: room.0 })
+            }
+            ServerToClientMessage::Authenticated(role) => {
+                Kind::Authenticated(schema::Authenticated {
+                    role: schema::Role::from(*role).into(),
+                })
+            }
+            ServerToClientMessage::SnapshotSaved {
+                name,
+                physical_time,
+            } => Kind::SnapshotSaved(schema::SnapshotSaved {
+                name: name.clone(),
+                physical_time: *physical_time,
+            }),
+            ServerToClientMessage::Trails {
+                trails,
+                physical_time,
+            } => Kind::Trails(schema::Trails {
+                trails: trails.iter().map(Into::into).collect(),
+                physical_time: *physical_time,
+            }),
+        };
+        schema::ServerMessage { kind: Some(kind) }.encode_to_vec()
+    }
+
+    fn from_protobuf(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        use schema::server_message::Kind;
+        let msg = schema::ServerMessage::decode(bytes).map_err(protobuf_error)?;
+        Ok(match msg.kind.ok_or_else(missing_kind)? {
+            Kind::StateUpdate(msg) => ServerToClientMessage::StateUpdate {
+                bodies: msg.bodies.into_iter().map(Into::into).collect(),
+                physical_time: msg.physical_time,
+                kinetic_energy: msg.kinetic_energy,
+                potential_energy: msg.potential_energy,
+                linear_momentum: [msg.linear_momentum_x, msg.linear_momentum_y],
+                angular_momentum: msg.angular_momentum,
+            },
+            Kind::Diagnostics(msg) => ServerToClientMessage::Diagnostics(
+                msg.samples.into_iter().map(Into::into).collect(),
+            ),
+            Kind::Region(msg) => ServerToClientMessage::Region {
+                bodies: msg.bodies.into_iter().map(Into::into).collect(),
+                physical_time: msg.physical_time,
+            },
+            Kind::BodyDetails(msg) => ServerToClientMessage::BodyDetails {
+                body: msg.body.map(Into::into).unwrap_or_default(),
+                speed: msg.speed,
+                kinetic_energy: msg.kinetic_energy,
+                acceleration: [msg.acceleration_x, msg.acceleration_y],
+                physical_time: msg.physical_time,
+            },
+            Kind::History(msg) => {
+                ServerToClientMessage::History(msg.states.into_iter().map(Into::into).collect())
+            }
+            Kind::StateBundle(msg) => {
+                ServerToClientMessage::StateBundle(msg.states.into_iter().map(Into::into).collect())
+            }
+            Kind::Pong(msg) => ServerToClientMessage::Pong {
+                client_time: msg.client_time,
+                server_time: msg.server_time,
+                tick: msg.tick,
+            },
+            Kind::StateDelta(msg) => ServerToClientMessage::StateDelta {
+                tick: msg.tick,
+                baseline_tick: msg.baseline_tick,
+                delta: BodiesDelta {
+                    changed: msg.changed.into_iter().map(Into::into).collect(),
+                    added: msg.added.into_iter().map(Into::into).collect(),
+                    removed: msg.removed.into_iter().map(BodyId).collect(),
+                },
+                physical_time: msg.physical_time,
+                kinetic_energy: msg.kinetic_energy,
+            },
+            Kind::RoomJoined(msg) => ServerToClientMessage::RoomJoined(RoomId(msg.id)),
+            Kind::Authenticated(msg) => {
+                let role = schema::Role::try_from(msg.role)
+                    .map_err(|e| ProtocolError::Protobuf(e.to_string()))?;
+                ServerToClientMessage::Authenticated(role.into())
+            }
+            Kind::SnapshotSaved(msg) => ServerToClientMessage::SnapshotSaved {
+                name: msg.name,
+                physical_time: msg.physical_time,
+            },
+            Kind::Trails(msg) => ServerToClientMessage::Trails {
+                trails: msg.trails.into_iter().map(Into::into).collect(),
+                physical_time: msg.physical_time,
+            },
+        })
+    }
+}
+
+fn protobuf_error(e: prost::DecodeError) -> ProtocolError {
+    ProtocolError::Protobuf(e.to_string())
+}
+
+fn missing_kind() -> ProtocolError {
+    ProtocolError::Protobuf("missing message kind".to_string())
+}
+
+impl From<&Body> for schema::Body {
+    fn from(body: &Body) -> Self {
+        schema::Body {
+            x: body.position[0],
+            y: body.position[1],
+            vx: body.velocity[0],
+            vy: body.velocity[1],
+            mass: body.mass,
+            radius: body.radius,
+            color: u32::from_be_bytes(body.color),
+            id: body.id.0,
+            is_static: body.is_static,
+        }
+    }
+}
+
+impl From<schema::Body> for Body {
+    fn from(body: schema::Body) -> Self {
+        Body {
+            id: BodyId(body.id),
+            position: [body.x, body.y],
+            velocity: [body.vx, body.vy],
+            mass: body.mass,
+            radius: body.radius,
+            color: body.color.to_be_bytes(),
+            is_static: body.is_static,
+        }
+    }
+}
+
+impl From<Integrator> for schema::Integrator {
+    fn from(integrator: Integrator) -> Self {
+        match integrator {
+            Integrator::Euler => schema::Integrator::Euler,
+            Integrator::VelocityVerlet => schema::Integrator::VelocityVerlet,
+            Integrator::Leapfrog => schema::Integrator::Leapfrog,
+        }
+    }
+}
+
+impl From<schema::Integrator> for Integrator {
+    fn from(integrator: schema::Integrator) -> Self {
+        match integrator {
+            schema::Integrator::Euler => Integrator::Euler,
+            schema::Integrator::VelocityVerlet => Integrator::VelocityVerlet,
+            schema::Integrator::Leapfrog => Integrator::Leapfrog,
+        }
+    }
+}
+
+impl From<CollisionModel> for schema::CollisionModel {
+    fn from(collision_model: CollisionModel) -> Self {
+        match collision_model {
+            CollisionModel::Elastic => schema::CollisionModel::Elastic,
+            CollisionModel::Merge => schema::CollisionModel::Merge,
+        }
+    }
+}
+
+impl From<schema::CollisionModel> for CollisionModel {
+    fn from(collision_model: schema::CollisionModel) -> Self {
+        match collision_model {
+            schema::CollisionModel::Elastic => CollisionModel::Elastic,
+            schema::CollisionModel::Merge => CollisionModel::Merge,
+        }
+    }
+}
+
+impl From<Role> for schema::Role {
+    fn from(role: Role) -> Self {
+        match role {
+            Role::Spectator => schema::Role::Spectator,
+            Role::Controller => schema::Role::Controller,
+            Role::Admin => schema::Role::Admin,
+        }
+    }
+}
+
+impl From<schema::Role> for Role {
+    fn from(role: schema::Role) -> Self {
+        match role {
+            schema::Role::Spectator => Role::Spectator,
+            schema::Role::Controller => Role::Controller,
+            schema::Role::Admin => Role::Admin,
+        }
+    }
+}
+
+impl From<&BoundaryCondition> for schema::BoundaryCondition {
+    fn from(boundary: &BoundaryCondition) -> Self {
+        let kind = match boundary {
+            BoundaryCondition::Open => schema::BoundaryKind::Open,
+            BoundaryCondition::Reflective(_) => schema::BoundaryKind::Reflective,
+            BoundaryCondition::Periodic(_) => schema::BoundaryKind::Periodic,
+        };
+        let world = boundary.world().unwrap_or(SquareBox::new([0.0, 0.0], 0.0));
+        schema::BoundaryCondition {
+            kind: kind.into(),
+            center_x: world.center()[0],
+            center_y: world.center()[1],
+            half_size: world.half_size(),
+        }
+    }
+}
+
+impl TryFrom<schema::BoundaryCondition> for BoundaryCondition {
+    type Error = ProtocolError;
+
+    fn try_from(boundary: schema::BoundaryCondition) -> Result<Self, Self::Error> {
+        let world = SquareBox::new([boundary.center_x, boundary.center_y], boundary.half_size);
+        let kind = schema::BoundaryKind::try_from(boundary.kind)
+            .map_err(|e| ProtocolError::Protobuf(e.to_string()))?;
+        Ok(match kind {
+            schema::BoundaryKind::Open => BoundaryCondition::Open,
+            schema::BoundaryKind::Reflective => BoundaryCondition::Reflective(world),
+            schema::BoundaryKind::Periodic => BoundaryCondition::Periodic(world),
+        })
+    }
+}
+
+impl From<&ExternalForce> for schema::ExternalForce {
+    fn from(force: &ExternalForce) -> Self {
+        let (kind, [x, y], strength) = match *force {
+            ExternalForce::Uniform { acceleration } => {
+                (schema::ExternalForceKind::Uniform, acceleration, 0.0)
+            }
+            ExternalForce::Attractor { position, strength } => {
+                (schema::ExternalForceKind::Attractor, position, strength)
+            }
+            ExternalForce::LinearDrag { coefficient } => (
+                schema::ExternalForceKind::LinearDrag,
+                [0.0, 0.0],
+                coefficient,
+            ),
+            ExternalForce::QuadraticDrag { coefficient } => (
+                schema::ExternalForceKind::QuadraticDrag,
+                [0.0, 0.0],
+                coefficient,
+            ),
+        };
+        schema::ExternalForce {
+            kind: kind.into(),
+            x,
+            y,
+            strength,
+        }
+    }
+}
+
+impl TryFrom<schema::ExternalForce> for ExternalForce {
+    type Error = ProtocolError;
+
+    fn try_from(force: schema::ExternalForce) -> Result<Self, Self::Error> {
+        let kind = schema::ExternalForceKind::try_from(force.kind)
+            .map_err(|e| ProtocolError::Protobuf(e.to_string()))?;
+        Ok(match kind {
+            schema::ExternalForceKind::Uniform => ExternalForce::Uniform {
+                acceleration: [force.x, force.y],
+            },
+            schema::ExternalForceKind::Attractor => ExternalForce::Attractor {
+                position: [force.x, force.y],
+                strength: force.strength,
+            },
+            schema::ExternalForceKind::LinearDrag => ExternalForce::LinearDrag {
+                coefficient: force.strength,
+            },
+            schema::ExternalForceKind::QuadraticDrag => ExternalForce::QuadraticDrag {
+                coefficient: force.strength,
+            },
+        })
+    }
+}
+
+impl From<&Constraint> for schema::Constraint {
+    fn from(constraint: &Constraint) -> Self {
+        let [a, b] = constraint.bodies.map(|id| id.0);
+        let (kind, length, stiffness, damping) = match constraint.kind {
+            ConstraintKind::Spring {
+                rest_length,
+                stiffness,
+                damping,
+            } => (
+                schema::ConstraintKind::Spring,
+                rest_length,
+                stiffness,
+                damping,
+            ),
+            ConstraintKind::Rod { length } => (schema::ConstraintKind::Rod, length, 0.0, 0.0),
+        };
+        schema::Constraint {
+            a,
+            b,
+            kind: kind.into(),
+            length,
+            stiffness,
+            damping,
+        }
+    }
+}
+
+impl TryFrom<schema::Constraint> for Constraint {
+    type Error = ProtocolError;
+
+    fn try_from(constraint: schema::Constraint) -> Result<Self, Self::Error> {
+        let kind = schema::ConstraintKind::try_from(constraint.kind)
+            .map_err(|e| ProtocolError::Protobuf(e.to_string()))?;
+        Ok(Constraint {
+            bodies: [BodyId(constraint.a), BodyId(constraint.b)],
+            kind: match kind {
+                schema::ConstraintKind::Spring => ConstraintKind::Spring {
+                    rest_length: constraint.length,
+                    stiffness: constraint.stiffness,
+                    damping: constraint.damping,
+                },
+                schema::ConstraintKind::Rod => ConstraintKind::Rod {
+                    length: constraint.length,
+                },
+            },
+        })
+    }
+}
+
+impl From<&Scenario> for schema::Scenario {
+    fn from(scenario: &Scenario) -> Self {
+        let mut msg = schema::Scenario {
+            count: scenario.count as u64,
+            center_x: scenario.center[0],
+            center_y: scenario.center[1],
+            body_mass: scenario.body_mass,
+            body_radius: scenario.body_radius,
+            seed: scenario.seed,
+            spin: scenario.spin,
+            ..Default::default()
+        };
+        match scenario.kind {
+            ScenarioKind::Disc { radius, rotating } => {
+                msg.kind = schema::ScenarioKind::Disc.into();
+                msg.radius = radius;
+                msg.rotating = rotating;
+            }
+            ScenarioKind::Spiral {
+                radius,
+                arms,
+                central_mass,
+            } => {
+                msg.kind = schema::ScenarioKind::Spiral.into();
+                msg.radius = radius;
+                msg.arms = arms;
+                msg.central_mass = central_mass;
+            }
+            ScenarioKind::Plummer { scale_radius } => {
+                msg.kind = schema::ScenarioKind::Plummer.into();
+                msg.scale_radius = scale_radius;
+            }
+            ScenarioKind::Random {
+                half_size,
+                max_speed,
+            } => {
+                msg.kind = schema::ScenarioKind::Random.into();
+                msg.half_size = half_size;
+                msg.max_speed = max_speed;
+            }
+            ScenarioKind::Binary {
+                semi_major_axis,
+                eccentricity,
+                mass_ratio,
+            } => {
+                msg.kind = schema::ScenarioKind::Binary.into();
+                msg.semi_major_axis = semi_major_axis;
+                msg.eccentricity = eccentricity;
+                msg.mass_ratio = mass_ratio;
+            }
+        }
+        match scenario.masses {
+            MassDistribution::Equal => msg.masses = schema::MassDistribution::Equal.into(),
+            MassDistribution::Uniform { max_mass } => {
+                msg.masses = schema::MassDistribution::Uniform.into();
+                msg.max_mass = max_mass;
+            }
+            MassDistribution::PowerLaw { max_mass, exponent } => {
+                msg.masses = schema::MassDistribution::PowerLaw.into();
+                msg.max_mass = max_mass;
+                msg.mass_exponent = exponent;
+            }
+        }
+        msg
+    }
+}
+
+impl TryFrom<schema::Scenario> for Scenario {
+    type Error = ProtocolError;
+
+    fn try_from(msg: schema::Scenario) -> Result<Self, Self::Error> {
+        let kind = schema::ScenarioKind::try_from(msg.kind)
+            .map_err(|e| ProtocolError::Protobuf(e.to_string()))?;
+        let masses = schema::MassDistribution::try_from(msg.masses)
+            .map_err(|e| ProtocolError::Protobuf(e.to_string()))?;
+        Ok(Scenario {
+            kind: match kind {
+                schema::ScenarioKind::Disc => ScenarioKind::Disc {
+                    radius: msg.radius,
+                    rotating: msg.rotating,
+                },
+                schema::ScenarioKind::Spiral => ScenarioKind::Spiral {
+                    radius: msg.radius,
+                    arms: msg.arms,
+                    central_mass: msg.central_mass,
+                },
+                schema::ScenarioKind::Plummer => ScenarioKind::Plummer {
+                    scale_radius: msg.scale_radius,
+                },
+                schema::ScenarioKind::Random => ScenarioKind::Random {
+                    half_size: msg.half_size,
+                    max_speed: msg.max_speed,
+                },
+                schema::ScenarioKind::Binary => ScenarioKind::Binary {
+                    semi_major_axis: msg.semi_major_axis,
+                    eccentricity: msg.eccentricity,
+                    mass_ratio: msg.mass_ratio,
+                },
+            },
+            count: msg.count as usize,
+            center: [msg.center_x, msg.center_y],
+            body_mass: msg.body_mass,
+            body_radius: msg.body_radius,
+            seed: msg.seed,
+            masses: match masses {
+                schema::MassDistribution::Equal => MassDistribution::Equal,
+                schema::MassDistribution::Uniform => MassDistribution::Uniform {
+                    max_mass: msg.max_mass,
+                },
+                schema::MassDistribution::PowerLaw => MassDistribution::PowerLaw {
+                    max_mass: msg.max_mass,
+                    exponent: msg.mass_exponent,
+                },
+            },
+            spin: msg.spin,
+        })
+    }
+}
+
+impl From<&DiagnosticsSample> for schema::DiagnosticsSample {
+    fn from(sample: &DiagnosticsSample) -> Self {
+        schema::DiagnosticsSample {
+            physical_time: sample.physical_time,
+            kinetic_energy: sample.kinetic_energy,
+            momentum_x: sample.momentum[0],
+            momentum_y: sample.momentum[1],
+            body_count: sample.body_count,
+        }
+    }
+}
+
+impl From<schema::DiagnosticsSample> for DiagnosticsSample {
+    fn from(sample: schema::DiagnosticsSample) -> Self {
+        DiagnosticsSample {
+            physical_time: sample.physical_time,
+            kinetic_energy: sample.kinetic_energy,
+            momentum: [sample.momentum_x, sample.momentum_y],
+            body_count: sample.body_count,
+        }
+    }
+}
+
+impl From<&RecordedState> for schema::StateUpdate {
+    fn from(state: &RecordedState) -> Self {
+        schema::StateUpdate {
+            bodies: state.bodies.iter().map(Into::into).collect(),
+            physical_time: state.physical_time,
+            kinetic_energy: state.kinetic_energy,
+            ..schema::StateUpdate::default()
+        }
+    }
+}
+
+impl From<schema::StateUpdate> for RecordedState {
+    fn from(state: schema::StateUpdate) -> Self {
+        RecordedState {
+            bodies: state.bodies.into_iter().map(Into::into).collect(),
+            physical_time: state.physical_time,
+            kinetic_energy: state.kinetic_energy,
+        }
+    }
+}
+
+impl From<&BodyDiff> for schema::BodyDiff {
+    fn from(diff: &BodyDiff) -> Self {
+        schema::BodyDiff {
+            id: diff.id.0,
+            x: diff.position.map(|p| p[0]),
+            y: diff.position.map(|p| p[1]),
+            vx: diff.velocity.map(|v| v[0]),
+            vy: diff.velocity.map(|v| v[1]),
+            mass: diff.mass,
+            radius: diff.radius,
+            color: diff.color.map(u32::from_be_bytes),
+            is_static: diff.is_static,
+        }
+    }
+}
+
+impl From<schema::BodyDiff> for BodyDiff {
+    fn from(diff: schema::BodyDiff) -> Self {
+        let pair = |a: Option<f64>, b: Option<f64>| Some([a?, b?]);
+        BodyDiff {
+            id: BodyId(diff.id),
+            position: pair(diff.x, diff.y),
+            velocity: pair(diff.vx, diff.vy),
+            mass: diff.mass,
+            radius: diff.radius,
+            color: diff.color.map(u32::to_be_bytes),
+            is_static: diff.is_static,
+        }
+    }
+}
+
+impl From<&BodyTrail> for schema::Trail {
+    fn from(trail: &BodyTrail) -> Self {
+        schema::Trail {
+            id: trail.id.0,
+            positions: trail.positions.as_flattened().to_vec(),
+        }
+    }
+}
+
+impl From<schema::Trail> for BodyTrail {
+    fn from(trail: schema::Trail) -> Self {
+        BodyTrail {
+            id: BodyId(trail.id),
+            positions: trail
+                .positions
+                .chunks_exact(2)
+                .map(|point| [point[0], point[1]])
+                .collect(),
+        }
+    }
+}
+
+/// Mirror of `proto/nbody.proto`
+mod schema {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Body {
+        #[prost(double, tag = "1")]
+        pub x: f64,
+        #[prost(double, tag = "2")]
+        pub y: f64,
+        #[prost(double, tag = "3")]
+        pub vx: f64,
+        #[prost(double, tag = "4")]
+        pub vy: f64,
+        #[prost(double, tag = "5")]
+        pub mass: f64,
+        #[prost(double, tag = "6")]
+        pub radius: f64,
+        #[prost(fixed32, tag = "7")]
+        pub color: u32,
+        #[prost(uint64, tag = "8")]
+        pub id: u64,
+        #[prost(bool, tag = "9")]
+        pub is_static: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SolverParameters {
+        #[prost(double, tag = "1")]
+        pub dt: f64,
+        #[prost(double, tag = "2")]
+        pub barnes_hut_theta: f64,
+        #[prost(double, optional, tag = "3")]
+        pub root_padding: Option<f64>,
+        #[prost(uint32, optional, tag = "4")]
+        pub root_shrink_delay: Option<u32>,
+        #[prost(enumeration = "Integrator", optional, tag = "5")]
+        pub integrator: Option<i32>,
+        #[prost(message, optional, tag = "6")]
+        pub adaptive_timestep: Option<AdaptiveTimestep>,
+    }
+
+    #[derive(Clone, Copy, PartialEq, prost::Message)]
+    pub struct AdaptiveTimestep {
+        #[prost(double, tag = "1")]
+        pub min_dt: f64,
+        #[prost(double, tag = "2")]
+        pub max_dt: f64,
+        #[prost(double, tag = "3")]
+        pub tolerance: f64,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Integrator {
+        Euler = 0,
+        VelocityVerlet = 1,
+        Leapfrog = 2,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PhysicsParameters {
+        #[prost(double, tag = "1")]
+        pub gravity_constant: f64,
+        #[prost(double, tag = "2")]
+        pub softening: f64,
+        #[prost(enumeration = "CollisionModel", optional, tag = "3")]
+        pub collision_model: Option<i32>,
+        #[prost(double, optional, tag = "4")]
+        pub restitution: Option<f64>,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum CollisionModel {
+        Elastic = 0,
+        Merge = 1,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum BoundaryKind {
+        Open = 0,
+        Reflective = 1,
+        Periodic = 2,
+    }
+
+    #[derive(Clone, Copy, PartialEq, prost::Message)]
+    pub struct BoundaryCondition {
+        #[prost(enumeration = "BoundaryKind", tag = "1")]
+        pub kind: i32,
+        #[prost(double, tag = "2")]
+        pub center_x: f64,
+        #[prost(double, tag = "3")]
+        pub center_y: f64,
+        #[prost(double, tag = "4")]
+        pub half_size: f64,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum ExternalForceKind {
+        Uniform = 0,
+        Attractor = 1,
+        LinearDrag = 2,
+        QuadraticDrag = 3,
+    }
+
+    #[derive(Clone, Copy, PartialEq, prost::Message)]
+    pub struct ExternalForce {
+        #[prost(enumeration = "ExternalForceKind", tag = "1")]
+        pub kind: i32,
+        #[prost(double, tag = "2")]
+        pub x: f64,
+        #[prost(double, tag = "3")]
+        pub y: f64,
+        #[prost(double, tag = "4")]
+        pub strength: f64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SetExternalForces {
+        #[prost(message, repeated, tag = "1")]
+        pub forces: Vec<ExternalForce>,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum ConstraintKind {
+        Spring = 0,
+        Rod = 1,
+    }
+
+    #[derive(Clone, Copy, PartialEq, prost::Message)]
+    pub struct Constraint {
+        #[prost(uint64, tag = "1")]
+        pub a: u64,
+        #[prost(uint64, tag = "2")]
+        pub b: u64,
+        #[prost(enumeration = "ConstraintKind", tag = "3")]
+        pub kind: i32,
+        #[prost(double, tag = "4")]
+        pub length: f64,
+        #[prost(double, tag = "5")]
+        pub stiffness: f64,
+        #[prost(double, tag = "6")]
+        pub damping: f64,
+    }
+
+    #[derive(Clone, Copy, PartialEq, prost::Message)]
+    pub struct RemoveConstraint {
+        #[prost(uint64, tag = "1")]
+        pub a: u64,
+        #[prost(uint64, tag = "2")]
+        pub b: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Empty {}
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct AddBodies {
+        #[prost(message, repeated, tag = "1")]
+        pub bodies: Vec<Body>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct RequestHistory {
+        #[prost(double, tag = "1")]
+        pub from_time: f64,
+        #[prost(double, tag = "2")]
+        pub to_time: f64,
+        #[prost(double, tag = "3")]
+        pub rate: f64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct QueryDiagnostics {
+        #[prost(double, tag = "1")]
+        pub from_time: f64,
+        #[prost(double, tag = "2")]
+        pub to_time: f64,
+        #[prost(uint32, tag = "3")]
+        pub max_points: u32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct QueryRegion {
+        #[prost(double, tag = "1")]
+        pub center_x: f64,
+        #[prost(double, tag = "2")]
+        pub center_y: f64,
+        #[prost(double, tag = "3")]
+        pub half_size: f64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct GetBody {
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct GetHistory {
+        #[prost(uint32, tag = "1")]
+        pub last_n: u32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct GetTrails {
+        #[prost(uint32, tag = "1")]
+        pub last_n: u32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SubscribeBundled {
+        #[prost(uint32, tag = "1")]
+        pub ticks_per_bundle: u32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Ping {
+        #[prost(double, tag = "1")]
+        pub client_time: f64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct RemoveBodies {
+        #[prost(uint64, repeated, tag = "1")]
+        pub ids: Vec<u64>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct AckState {
+        #[prost(uint64, tag = "1")]
+        pub tick: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SetTickRate {
+        #[prost(double, tag = "1")]
+        pub tick_rate: f64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Room {
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Authenticate {
+        #[prost(string, tag = "1")]
+        pub token: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Snapshot {
+        #[prost(string, tag = "1")]
+        pub name: String,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum ScenarioKind {
+        Disc = 0,
+        Spiral = 1,
+        Plummer = 2,
+        Random = 3,
+        Binary = 4,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum MassDistribution {
+        Equal = 0,
+        Uniform = 1,
+        PowerLaw = 2,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Scenario {
+        #[prost(enumeration = "ScenarioKind", tag = "1")]
+        pub kind: i32,
+        #[prost(uint64, tag = "2")]
+        pub count: u64,
+        #[prost(double, tag = "3")]
+        pub center_x: f64,
+        #[prost(double, tag = "4")]
+        pub center_y: f64,
+        #[prost(double, tag = "5")]
+        pub body_mass: f64,
+        #[prost(double, tag = "6")]
+        pub body_radius: f64,
+        #[prost(uint64, tag = "7")]
+        pub seed: u64,
+        #[prost(enumeration = "MassDistribution", tag = "8")]
+        pub masses: i32,
+        #[prost(double, tag = "9")]
+        pub max_mass: f64,
+        #[prost(double, tag = "10")]
+        pub mass_exponent: f64,
+        #[prost(double, tag = "11")]
+        pub spin: f64,
+        #[prost(double, tag = "12")]
+        pub radius: f64,
+        #[prost(bool, tag = "13")]
+        pub rotating: bool,
+        #[prost(uint32, tag = "14")]
+        pub arms: u32,
+        #[prost(double, tag = "15")]
+        pub central_mass: f64,
+        #[prost(double, tag = "16")]
+        pub scale_radius: f64,
+        #[prost(double, tag = "17")]
+        pub half_size: f64,
+        #[prost(double, tag = "18")]
+        pub max_speed: f64,
+        #[prost(double, tag = "19")]
+        pub semi_major_axis: f64,
+        #[prost(double, tag = "20")]
+        pub eccentricity: f64,
+        #[prost(double, tag = "21")]
+        pub mass_ratio: f64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ClientMessage {
+        #[prost(
+            oneof = "client_message::Kind",
+            tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32"
+        )]
+        pub kind: Option<client_message::Kind>,
+    }
+
+    pub mod client_message {
+        #[derive(Clone, PartialEq, prost::Oneof)]
+        pub enum Kind {
+            #[prost(message, tag = "1")]
+            Subscribe(super::Empty),
+            #[prost(message, tag = "2")]
+            AddBodies(super::AddBodies),
+            #[prost(message, tag = "3")]
+            State(super::Empty),
+            #[prost(message, tag = "4")]
+            Reset(super::Empty),
+            #[prost(message, tag = "5")]
+            SetSolverParameters(super::SolverParameters),
+            #[prost(message, tag = "6")]
+            SetPhysicsParameters(super::PhysicsParameters),
+            #[prost(message, tag = "7")]
+            RequestHistory(super::RequestHistory),
+            #[prost(message, tag = "8")]
+            QueryDiagnostics(super::QueryDiagnostics),
+            #[prost(message, tag = "9")]
+            QueryRegion(super::QueryRegion),
+            #[prost(message, tag = "10")]
+            GetBody(super::GetBody),
+            #[prost(message, tag = "11")]
+            GetHistory(super::GetHistory),
+            #[prost(message, tag = "12")]
+            SubscribeBundled(super::SubscribeBundled),
+            #[prost(message, tag = "13")]
+            Ping(super::Ping),
+            #[prost(message, tag = "14")]
+            RemoveBodies(super::RemoveBodies),
+            #[prost(message, tag = "15")]
+            SubscribeDeltas(super::Empty),
+            #[prost(message, tag = "16")]
+            AckState(super::AckState),
+            #[prost(message, tag = "17")]
+            Pause(super::Empty),
+            #[prost(message, tag = "18")]
+            Resume(super::Empty),
+            #[prost(message, tag = "19")]
+            SingleStep(super::Empty),
+            #[prost(message, tag = "20")]
+            SetTickRate(super::SetTickRate),
+            #[prost(message, tag = "21")]
+            CreateRoom(super::Empty),
+            #[prost(message, tag = "22")]
+            JoinRoom(super::Room),
+            #[prost(message, tag = "23")]
+            LeaveRoom(super::Empty),
+            #[prost(message, tag = "24")]
+            SetBoundaryCondition(super::BoundaryCondition),
+            #[prost(message, tag = "25")]
+            Authenticate(super::Authenticate),
+            #[prost(message, tag = "26")]
+            SaveSnapshot(super::Snapshot),
+            #[prost(message, tag = "27")]
+            LoadSnapshot(super::Snapshot),
+            #[prost(message, tag = "28")]
+            LoadScenario(super::Scenario),
+            #[prost(message, tag = "29")]
+            GetTrails(super::GetTrails),
+            #[prost(message, tag = "30")]
+            SetExternalForces(super::SetExternalForces),
+            #[prost(message, tag = "31")]
+            AddConstraint(super::Constraint),
+            #[prost(message, tag = "32")]
+            RemoveConstraint(super::RemoveConstraint),
+        }
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct StateUpdate {
+        #[prost(message, repeated, tag = "1")]
+        pub bodies: Vec<Body>,
+        #[prost(double, tag = "2")]
+        pub physical_time: f64,
+        #[prost(double, tag = "3")]
+        pub kinetic_energy: f64,
+        #[prost(double, tag = "4")]
+        pub potential_energy: f64,
+        #[prost(double, tag = "5")]
+        pub linear_momentum_x: f64,
+        #[prost(double, tag = "6")]
+        pub linear_momentum_y: f64,
+        #[prost(double, tag = "7")]
+        pub angular_momentum: f64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct DiagnosticsSample {
+        #[prost(double, tag = "1")]
+        pub physical_time: f64,
+        #[prost(double, tag = "2")]
+        pub kinetic_energy: f64,
+        #[prost(double, tag = "3")]
+        pub momentum_x: f64,
+        #[prost(double, tag = "4")]
+        pub momentum_y: f64,
+        #[prost(uint32, tag = "5")]
+        pub body_count: u32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Diagnostics {
+        #[prost(message, repeated, tag = "1")]
+        pub samples: Vec<DiagnosticsSample>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Region {
+        #[prost(message, repeated, tag = "3")]
+        pub bodies: Vec<Body>,
+        #[prost(double, tag = "2")]
+        pub physical_time: f64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct BodyDetails {
+        #[prost(message, optional, tag = "2")]
+        pub body: Option<Body>,
+        #[prost(double, tag = "3")]
+        pub speed: f64,
+        #[prost(double, tag = "4")]
+        pub kinetic_energy: f64,
+        #[prost(double, tag = "5")]
+        pub acceleration_x: f64,
+        #[prost(double, tag = "6")]
+        pub acceleration_y: f64,
+        #[prost(double, tag = "7")]
+        pub physical_time: f64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct History {
+        #[prost(message, repeated, tag = "1")]
+        pub states: Vec<StateUpdate>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct StateBundle {
+        #[prost(message, repeated, tag = "1")]
+        pub states: Vec<StateUpdate>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Pong {
+        #[prost(double, tag = "1")]
+        pub client_time: f64,
+        #[prost(double, tag = "2")]
+        pub server_time: f64,
+        #[prost(uint64, tag = "3")]
+        pub tick: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct BodyDiff {
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+        #[prost(double, optional, tag = "2")]
+        pub x: Option<f64>,
+        #[prost(double, optional, tag = "3")]
+        pub y: Option<f64>,
+        #[prost(double, optional, tag = "4")]
+        pub vx: Option<f64>,
+        #[prost(double, optional, tag = "5")]
+        pub vy: Option<f64>,
+        #[prost(double, optional, tag = "6")]
+        pub mass: Option<f64>,
+        #[prost(double, optional, tag = "7")]
+        pub radius: Option<f64>,
+        #[prost(fixed32, optional, tag = "8")]
+        pub color: Option<u32>,
+        #[prost(bool, optional, tag = "9")]
+        pub is_static: Option<bool>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct StateDelta {
+        #[prost(uint64, tag = "1")]
+        pub tick: u64,
+        #[prost(uint64, optional, tag = "2")]
+        pub baseline_tick: Option<u64>,
+        #[prost(message, repeated, tag = "3")]
+        pub changed: Vec<BodyDiff>,
+        #[prost(message, repeated, tag = "4")]
+        pub added: Vec<Body>,
+        #[prost(uint64, repeated, tag = "5")]
+        pub removed: Vec<u64>,
+        #[prost(double, tag = "6")]
+        pub physical_time: f64,
+        #[prost(double, tag = "7")]
+        pub kinetic_energy: f64,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Role {
+        Spectator = 0,
+        Controller = 1,
+        Admin = 2,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Authenticated {
+        #[prost(enumeration = "Role", tag = "1")]
+        pub role: i32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SnapshotSaved {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(double, tag = "2")]
+        pub physical_time: f64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Trail {
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+        #[prost(double, repeated, tag = "2")]
+        pub positions: Vec<f64>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Trails {
+        #[prost(message, repeated, tag = "1")]
+        pub trails: Vec<Trail>,
+        #[prost(double, tag = "2")]
+        pub physical_time: f64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ServerMessage {
+        #[prost(
+            oneof = "server_message::Kind",
+            tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12"
+        )]
+        pub kind: Option<server_message::Kind>,
+    }
+
+    pub mod server_message {
+        #[derive(Clone, PartialEq, prost::Oneof)]
+        pub enum Kind {
+            #[prost(message, tag = "1")]
+            StateUpdate(super::StateUpdate),
+            #[prost(message, tag = "2")]
+            Diagnostics(super::Diagnostics),
+            #[prost(message, tag = "3")]
+            Region(super::Region),
+            #[prost(message, tag = "4")]
+            BodyDetails(super::BodyDetails),
+            #[prost(message, tag = "5")]
+            History(super::History),
+            #[prost(message, tag = "6")]
+            StateBundle(super::StateBundle),
+            #[prost(message, tag = "7")]
+            Pong(super::Pong),
+            #[prost(message, tag = "8")]
+            StateDelta(super::StateDelta),
+            #[prost(message, tag = "9")]
+            RoomJoined(super::Room),
+            #[prost(message, tag = "10")]
+            Authenticated(super::Authenticated),
+            #[prost(message, tag = "11")]
+            SnapshotSaved(super::SnapshotSaved),
+            #[prost(message, tag = "12")]
+            Trails(super::Trails),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protobuf_round_trip_test() {
+        let body = Body {
+            id: BodyId(7),
+            color: [1, 2, 3, 4],
+            ..Body::default()
+                .with_position([1.0, -2.0])
+                .with_velocity([0.5, 0.25])
+        };
+        let frame = encode_as(
+            &ServerToClientMessage::StateUpdate {
+                bodies: vec![body; 3],
+                physical_time: 1.5,
+                kinetic_energy: 2.5,
+                potential_energy: -4.0,
+                linear_momentum: [0.5, -0.5],
+                angular_momentum: 3.0,
+            },
+            Encoding::Protobuf,
+        )
+        .unwrap();
+        assert_eq!(frame[0], PROTOBUF_TAG);
+
+        let (msg, encoding) = decode_any::<ServerToClientMessage>(&frame).unwrap();
+        assert_eq!(encoding, Encoding::Protobuf);
+        let ServerToClientMessage::StateUpdate {
+            bodies,
+            physical_time,
+            potential_energy,
+            linear_momentum,
+            angular_momentum,
+            ..
+        } = msg
+        else {
+            panic!("Expected a StateUpdate");
+        };
+        assert_eq!(physical_time, 1.5);
+        assert_eq!(
+            (potential_energy, linear_momentum, angular_momentum),
+            (-4.0, [0.5, -0.5], 3.0)
+        );
+        assert_eq!(bodies.len(), 3);
+        assert_eq!(bodies[2].position, body.position);
+        assert_eq!(bodies[2].velocity, body.velocity);
+        assert_eq!(bodies[2].color, body.color);
+        assert_eq!(bodies[2].id, body.id);
+
+        let adaptive = AdaptiveTimestep::new(0.01, 0.5).with_tolerance(0.1);
+        let msg = ClientToServerMessage::SetSolverParameters(
+            SolverParameters::new(0.5, 0.7)
+                .with_integrator(Integrator::Leapfrog)
+                .with_adaptive_timestep(adaptive),
+        );
+        for encoding in [Encoding::Bincode, Encoding::Protobuf] {
+            let frame = encode_as(&msg, encoding).unwrap();
+            let (decoded, found) = decode_any::<ClientToServerMessage>(&frame).unwrap();
+            assert_eq!(found, encoding);
+            assert!(matches!(
+                decoded,
+                ClientToServerMessage::SetSolverParameters(p)
+                    if p.dt() == 0.5
+                        && p.integrator() == Integrator::Leapfrog
+                        && p.adaptive_timestep() == Some(adaptive)
+            ));
+        }
+
+        let msg = ClientToServerMessage::SetPhysicsParameters(
+            PhyiscsParameters::new(2.0)
+                .with_collision_model(CollisionModel::Merge)
+                .with_restitution(0.5),
+        );
+        let (decoded, _) = decode_any(&encode_as(&msg, Encoding::Protobuf).unwrap()).unwrap();
+        assert!(matches!(
+            decoded,
+            ClientToServerMessage::SetPhysicsParameters(p)
+                if p.collision_model() == CollisionModel::Merge && p.restitution() == 0.5
+        ));
+
+        let msg = ClientToServerMessage::SetBoundaryCondition(BoundaryCondition::Periodic(
+            SquareBox::new([1.0, 2.0], 3.0),
+        ));
+        let (decoded, _) = decode_any(&encode_as(&msg, Encoding::Protobuf).unwrap()).unwrap();
+        let ClientToServerMessage::SetBoundaryCondition(BoundaryCondition::Periodic(world)) =
+            decoded
+        else {
+            panic!("Expected a periodic boundary, got {decoded:?}");
+        };
+        assert_eq!((world.center(), world.half_size()), ([1.0, 2.0], 3.0));
+
+        let forces = vec![
+            ExternalForce::Uniform {
+                acceleration: [0.0, -9.8],
+            },
+            ExternalForce::Attractor {
+                position: [1.0, 2.0],
+                strength: 50.0,
+            },
+            ExternalForce::LinearDrag { coefficient: 0.1 },
+            ExternalForce::QuadraticDrag { coefficient: 0.2 },
+        ];
+        let msg = ClientToServerMessage::SetExternalForces(forces.clone());
+        let (decoded, _) = decode_any(&encode_as(&msg, Encoding::Protobuf).unwrap()).unwrap();
+        assert!(matches!(decoded, ClientToServerMessage::SetExternalForces(f) if f == forces));
+
+        let spring = Constraint {
+            bodies: [BodyId(1), BodyId(2)],
+            kind: ConstraintKind::Spring {
+                rest_length: 3.0,
+                stiffness: 4.0,
+                damping: 0.5,
+            },
+        };
+        for constraint in [spring, Constraint::rod(BodyId(2), BodyId(5), 1.5)] {
+            let msg = ClientToServerMessage::AddConstraint(constraint);
+            let (decoded, _) = decode_any(&encode_as(&msg, Encoding::Protobuf).unwrap()).unwrap();
+            assert!(matches!(decoded, ClientToServerMessage::AddConstraint(c) if c == constraint));
+        }
+        let msg = ClientToServerMessage::RemoveConstraint(BodyId(2), BodyId(1));
+        let (decoded, _) = decode_any(&encode_as(&msg, Encoding::Protobuf).unwrap()).unwrap();
+        assert!(matches!(
+            decoded,
+            ClientToServerMessage::RemoveConstraint(BodyId(2), BodyId(1))
+        ));
+
+        let msg = ClientToServerMessage::Authenticate {
+            token: "secret".into(),
+        };
+        let (decoded, _) = decode_any(&encode_as(&msg, Encoding::Protobuf).unwrap()).unwrap();
+        assert!(
+            matches!(decoded, ClientToServerMessage::Authenticate { token } if token == "secret")
+        );
+        let msg = ServerToClientMessage::Authenticated(Role::Controller);
+        let (decoded, _) = decode_any(&encode_as(&msg, Encoding::Protobuf).unwrap()).unwrap();
+        assert!(matches!(
+            decoded,
+            ServerToClientMessage::Authenticated(Role::Controller)
+        ));
+
+        let msg = ClientToServerMessage::LoadSnapshot {
+            name: "orbit".into(),
+        };
+        let (decoded, _) = decode_any(&encode_as(&msg, Encoding::Protobuf).unwrap()).unwrap();
+        assert!(matches!(decoded, ClientToServerMessage::LoadSnapshot { name } if name == "orbit"));
+        let msg = ServerToClientMessage::SnapshotSaved {
+            name: "orbit".into(),
+            physical_time: 2.5,
+        };
+        let (decoded, _) = decode_any(&encode_as(&msg, Encoding::Protobuf).unwrap()).unwrap();
+        assert!(matches!(
+            decoded,
+            ServerToClientMessage::SnapshotSaved { name, physical_time }
+                if name == "orbit" && physical_time == 2.5
+        ));
+
+        let msg = ClientToServerMessage::LoadScenario(Scenario {
+            kind: ScenarioKind::Binary {
+                semi_major_axis: 10.0,
+                eccentricity: 0.3,
+                mass_ratio: 0.5,
+            },
+            masses: MassDistribution::PowerLaw {
+                max_mass: 50.0,
+                exponent: 2.35,
+            },
+            spin: -0.2,
+            seed: 9,
+            ..Scenario::default()
+        });
+        let (decoded, _) = decode_any(&encode_as(&msg, Encoding::Protobuf).unwrap()).unwrap();
+        let ClientToServerMessage::LoadScenario(scenario) = decoded else {
+            panic!("Expected a scenario, got {decoded:?}");
+        };
+        assert!(matches!(
+            scenario.kind,
+            ScenarioKind::Binary { semi_major_axis, eccentricity, mass_ratio }
+                if (semi_major_axis, eccentricity, mass_ratio) == (10.0, 0.3, 0.5)
+        ));
+        assert_eq!(
+            scenario.masses,
+            MassDistribution::PowerLaw {
+                max_mass: 50.0,
+                exponent: 2.35
+            }
+        );
+        assert_eq!(
+            (scenario.spin, scenario.seed, scenario.count),
+            (-0.2, 9, 100)
+        );
+
+        let msg = ClientToServerMessage::RemoveBodies(vec![BodyId(4), BodyId(2)]);
+        let (decoded, _) = decode_any(&encode_as(&msg, Encoding::Protobuf).unwrap()).unwrap();
+        assert!(
+            matches!(decoded, ClientToServerMessage::RemoveBodies(ids) if ids == [BodyId(4), BodyId(2)])
+        );
+
+        let msg = ClientToServerMessage::QueryRegion(SquareBox::new([1.0, 2.0], 3.0));
+        let (decoded, _) = decode_any(&encode_as(&msg, Encoding::Protobuf).unwrap()).unwrap();
+        assert!(matches!(
+            decoded,
+            ClientToServerMessage::QueryRegion(region)
+                if region.center() == [1.0, 2.0] && region.half_size() == 3.0
+        ));
+
+        let trail = BodyTrail {
+            id: BodyId(7),
+            positions: vec![[1.0, -2.0], [1.5, -2.5]],
+        };
+        let msg = ServerToClientMessage::Trails {
+            trails: vec![trail.clone()],
+            physical_time: 0.5,
+        };
+        let (decoded, _) = decode_any(&encode_as(&msg, Encoding::Protobuf).unwrap()).unwrap();
+        assert!(matches!(
+            decoded,
+            ServerToClientMessage::Trails { trails, physical_time }
+                if trails == [trail] && physical_time == 0.5
+        ));
+
+        let msg = ServerToClientMessage::History(vec![RecordedState {
+            bodies: vec![body],
+            physical_time: 0.5,
+            kinetic_energy: 1.0,
+        }]);
+        let (decoded, _) = decode_any(&encode_as(&msg, Encoding::Protobuf).unwrap()).unwrap();
+        assert!(matches!(
+            decoded,
+            ServerToClientMessage::History(states)
+                if states.len() == 1 && states[0].physical_time == 0.5
+        ));
+
+        let moved = body.with_position([3.0, -2.0]);
+        let msg = ServerToClientMessage::StateDelta {
+            tick: 12,
+            baseline_tick: Some(10),
+            delta: BodiesDelta {
+                changed: vec![BodyDiff::between(body.id, &body, &moved).unwrap()],
+                added: vec![Body::default()],
+                removed: vec![BodyId(3)],
+            },
+            physical_time: 0.5,
+            kinetic_energy: 1.0,
+        };
+        let (decoded, _) = decode_any(&encode_as(&msg, Encoding::Protobuf).unwrap()).unwrap();
+        let ServerToClientMessage::StateDelta {
+            tick,
+            baseline_tick,
+            delta,
+            ..
+        } = decoded
+        else {
+            panic!("Expected a StateDelta");
+        };
+        assert_eq!((tick, baseline_tick), (12, Some(10)));
+        assert_eq!(delta.changed[0].position, Some([3.0, -2.0]));
+        assert_eq!(delta.changed[0].velocity, None);
+        assert_eq!(delta.added.len(), 1);
+        assert_eq!(delta.removed, [BodyId(3)]);
+
+        assert!(matches!(
+            decode_any::<ClientToServerMessage>(&[PROTOBUF_TAG]),
+            Err(ProtocolError::Protobuf(_))
+        ));
+    }
+}
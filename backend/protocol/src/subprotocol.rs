@@ -0,0 +1,142 @@
+//! WebSocket subprotocols (`Sec-WebSocket-Protocol`) naming the frame formats,
+//! so that both peers agree on the encoding during the handshake instead of guessing it
+//! from the first frame
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{CodecOptions, ProtocolError};
+
+/// Name of the handshake header carrying the subprotocols
+pub const SUBPROTOCOL_HEADER: &str = "Sec-WebSocket-Protocol";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Subprotocol {
+    /// `nbody.bincode.v1`: bincode frames, never compressed
+    Bincode,
+
+    /// `nbody.bincode.gz.v1`: bincode frames, gzipped above the compression threshold
+    BincodeGzip,
+
+    /// `nbody.bincode.zstd.v1`: bincode frames, compressed with the zstd dictionary
+    /// the server ships in the first frame of the connection (see `dictionary`)
+    BincodeZstd,
+
+    /// `nbody.json.v1`: JSON text frames, shaped like the TypeScript definitions of the messages
+    Json,
+
+    /// `nbody.protobuf.v1`: protobuf frames (schema in `proto/nbody.proto`)
+    Protobuf,
+
+    /// `nbody.flatbuffers.v1`: `StateUpdate`s as FlatBuffers frames, any other message as bincode
+    FlatBuffers,
+}
+
+impl Subprotocol {
+    pub const ALL: [Subprotocol; 6] = [
+        Subprotocol::Bincode,
+        Subprotocol::BincodeGzip,
+        Subprotocol::BincodeZstd,
+        Subprotocol::Json,
+        Subprotocol::Protobuf,
+        Subprotocol::FlatBuffers,
+    ];
+
+    /// Value of the subprotocol in the handshake header
+    pub fn name(&self) -> &'static str {
+        match self {
+            Subprotocol::Bincode => "nbody.bincode.v1",
+            Subprotocol::BincodeGzip => "nbody.bincode.gz.v1",
+            Subprotocol::BincodeZstd => "nbody.bincode.zstd.v1",
+            Subprotocol::Json => "nbody.json.v1",
+            Subprotocol::Protobuf => "nbody.protobuf.v1",
+            Subprotocol::FlatBuffers => "nbody.flatbuffers.v1",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Subprotocol::ALL
+            .into_iter()
+            .find(|subprotocol| subprotocol.name() == name)
+    }
+
+    /// Picks the first of the offered subprotocols (comma separated, in order of preference)
+    /// that is known, None if there is none
+    pub fn negotiate(offered: &str) -> Option<Self> {
+        Subprotocol::negotiate_among(offered, &Subprotocol::ALL)
+    }
+
+    /// Same as `negotiate` but only picking one of the `supported` subprotocols
+    /// (e.g. `nbody.bincode.zstd.v1` when the server has a dictionary)
+    pub fn negotiate_among(offered: &str, supported: &[Subprotocol]) -> Option<Self> {
+        offered
+            .split(',')
+            .filter_map(|name| Subprotocol::from_name(name.trim()))
+            .find(|subprotocol| supported.contains(subprotocol))
+    }
+
+    /// Compression of the bincode frames
+    pub fn codec_options(&self) -> CodecOptions {
+        match self {
+            Subprotocol::Bincode => CodecOptions::new().with_threshold(usize::MAX),
+            _ => CodecOptions::new(),
+        }
+    }
+}
+
+/// Encodes a message into a `nbody.json.v1` text frame
+pub fn encode_json<T: Serialize>(msg: &T) -> Result<String, ProtocolError> {
+    serde_json::to_string(msg).map_err(|e| ProtocolError::Json(e.to_string()))
+}
+
+/// Decodes a `nbody.json.v1` text frame
+pub fn decode_json<T: DeserializeOwned>(frame: &str) -> Result<T, ProtocolError> {
+    serde_json::from_str(frame).map_err(|e| ProtocolError::Json(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ClientToServerMessage, ServerToClientMessage};
+    use nbody::physics::Body;
+
+    #[test]
+    fn negotiate_test() {
+        assert_eq!(
+            Subprotocol::negotiate("chat, nbody.json.v1 ,nbody.bincode.gz.v1"),
+            Some(Subprotocol::Json)
+        );
+        assert_eq!(Subprotocol::negotiate("nbody.bincode.v2"), None);
+        assert_eq!(Subprotocol::negotiate(""), None);
+        assert_eq!(
+            Subprotocol::negotiate_among(
+                "nbody.bincode.zstd.v1, nbody.bincode.gz.v1",
+                &[Subprotocol::Json, Subprotocol::BincodeGzip]
+            ),
+            Some(Subprotocol::BincodeGzip)
+        );
+        for subprotocol in Subprotocol::ALL {
+            assert_eq!(
+                Subprotocol::from_name(subprotocol.name()),
+                Some(subprotocol)
+            );
+        }
+    }
+
+    #[test]
+    fn json_round_trip_test() {
+        let frame = encode_json(&ClientToServerMessage::AddBodies(vec![Body::default()])).unwrap();
+        assert!(frame.starts_with("{\"addBodies\":"));
+        assert!(matches!(
+            decode_json(&frame),
+            Ok(ClientToServerMessage::AddBodies(bodies)) if bodies.len() == 1
+        ));
+        assert!(matches!(
+            decode_json::<ClientToServerMessage>("\"subscribe\""),
+            Ok(ClientToServerMessage::Subscribe)
+        ));
+        assert!(matches!(
+            decode_json::<ServerToClientMessage>("{\"bogus\": 1}"),
+            Err(ProtocolError::Json(_))
+        ));
+    }
+}
@@ -0,0 +1,448 @@
+//! Explicit, stable identifiers of the message variants
+//!
+//! Payload of a frame (before compression): [message_id (u16 LE), bincode(fields)...]
+//! instead of bincode's variant index, which depends on the declaration order
+//!
+//! Evolution rules, so that a session keeps working with a peer deployed at another version:
+//! - A new variant takes a new id, older peers fail with `UnknownMessage` and skip the frame
+//! - Fields can only be appended to a variant, older peers ignore the trailing bytes
+//! - Any other layout change takes a new id (the old id keeps decoding the old layout)
+//! - Ids are never reused nor renumbered
+//! - A layout change of a type shared by many messages (e.g. `Body`) bumps `PROTOCOL_VERSION`
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{ClientToServerMessage, ProtocolError, ServerToClientMessage};
+
+/// Size of the message id heading the payload
+pub const MESSAGE_ID_SIZE: usize = 2;
+
+/// Messages framed with an explicit id per variant
+pub trait WireMessage: Sized {
+    fn message_id(&self) -> u16;
+    fn write_fields(&self, out: &mut Vec<u8>) -> Result<(), bincode::Error>;
+    fn read_fields(message_id: u16, fields: &[u8]) -> Result<Self, ProtocolError>;
+}
+
+/// [message_id, bincode(fields)...]
+pub fn write_message<T: WireMessage>(msg: &T) -> Result<Vec<u8>, ProtocolError> {
+    let mut payload = msg.message_id().to_le_bytes().to_vec();
+    msg.write_fields(&mut payload)
+        .map_err(ProtocolError::Encoding)?;
+    Ok(payload)
+}
+
+/// Inverse of `write_message`
+pub fn read_message<T: WireMessage>(payload: &[u8]) -> Result<T, ProtocolError> {
+    match payload.split_first_chunk::<MESSAGE_ID_SIZE>() {
+        Some((&id, fields)) => T::read_fields(u16::from_le_bytes(id), fields),
+        None => Err(ProtocolError::UnknownMessage(None)),
+    }
+}
+
+fn write<T: Serialize>(out: &mut Vec<u8>, value: &T) -> Result<(), bincode::Error> {
+    bincode::serialize_into(out, value)
+}
+
+/// bincode ignores the trailing bytes: fields appended by a newer peer are skipped
+fn read<T: DeserializeOwned>(fields: &[u8]) -> Result<T, ProtocolError> {
+    bincode::deserialize(fields).map_err(ProtocolError::Decoding)
+}
+
+// Ids of `ClientToServerMessage`
+const SUBSCRIBE: u16 = 1;
+const ADD_BODIES: u16 = 2;
+const STATE: u16 = 3;
+const RESET: u16 = 4;
+const SET_SOLVER_PARAMETERS: u16 = 5;
+const SET_PHYSICS_PARAMETERS: u16 = 6;
+const REQUEST_HISTORY: u16 = 7;
+const QUERY_DIAGNOSTICS: u16 = 8;
+const QUERY_REGION: u16 = 9;
+const GET_BODY: u16 = 10;
+const GET_HISTORY: u16 = 11;
+const SUBSCRIBE_BUNDLED: u16 = 12;
+const PING: u16 = 13;
+const REMOVE_BODIES: u16 = 14;
+const SUBSCRIBE_DELTAS: u16 = 15;
+const ACK_STATE: u16 = 16;
+const PAUSE: u16 = 17;
+const RESUME: u16 = 18;
+const SINGLE_STEP: u16 = 19;
+const SET_TICK_RATE: u16 = 20;
+const CREATE_ROOM: u16 = 21;
+const JOIN_ROOM: u16 = 22;
+const LEAVE_ROOM: u16 = 23;
+const SET_BOUNDARY_CONDITION: u16 = 24;
+const AUTHENTICATE: u16 = 25;
+const SAVE_SNAPSHOT: u16 = 26;
+const LOAD_SNAPSHOT: u16 = 27;
+const LOAD_SCENARIO: u16 = 28;
+const GET_TRAILS: u16 = 29;
+const SET_EXTERNAL_FORCES: u16 = 30;
+const ADD_CONSTRAINT: u16 = 31;
+const REMOVE_CONSTRAINT: u16 = 32;
+
+impl WireMessage for ClientToServerMessage {
+    fn message_id(&self) -> u16 {
+        match self {
+            ClientToServerMessage::Subscribe => SUBSCRIBE,
+            ClientToServerMessage::AddBodies(_) => ADD_BODIES,
+            ClientToServerMessage::State => STATE,
+            ClientToServerMessage::Reset => RESET,
+            ClientToServerMessage::SetSolverParameters(_) => SET_SOLVER_PARAMETERS,
+            ClientToServerMessage::SetPhysicsParameters(_) => SET_PHYSICS_PARAMETERS,
+            ClientToServerMessage::RequestHistory { .. } => REQUEST_HISTORY,
+            ClientToServerMessage::QueryDiagnostics { .. } => QUERY_DIAGNOSTICS,
+            ClientToServerMessage::QueryRegion(_) => QUERY_REGION,
+            ClientToServerMessage::GetBody(_) => GET_BODY,
+            ClientToServerMessage::GetHistory { .. } => GET_HISTORY,
+            ClientToServerMessage::SubscribeBundled { .. } => SUBSCRIBE_BUNDLED,
+            ClientToServerMessage::Ping { .. } => PING,
+            ClientToServerMessage::RemoveBodies(_) => REMOVE_BODIES,
+            ClientToServerMessage::SubscribeDeltas => SUBSCRIBE_DELTAS,
+            ClientToServerMessage::AckState { .. } => ACK_STATE,
+            ClientToServerMessage::Pause => PAUSE,
+            ClientToServerMessage::Resume => RESUME,
+            ClientToServerMessage::SingleStep => SINGLE_STEP,
+            ClientToServerMessage::SetTickRate(_) => SET_TICK_RATE,
+            ClientToServerMessage::CreateRoom => CREATE_ROOM,
+            ClientToServerMessage::JoinRoom(_) => JOIN_ROOM,
+            ClientToServerMessage::LeaveRoom => LEAVE_ROOM,
+            ClientToServerMessage::SetBoundaryCondition(_) => SET_BOUNDARY_CONDITION,
+            ClientToServerMessage::Authenticate { .. } => AUTHENTICATE,
+            ClientToServerMessage::SaveSnapshot { .. } => SAVE_SNAPSHOT,
+            ClientToServerMessage::LoadSnapshot { .. } => LOAD_SNAPSHOT,
+            ClientToServerMessage::LoadScenario(_) => LOAD_SCENARIO,
+            ClientToServerMessage::GetTrails { .. } => GET_TRAILS,
+            ClientToServerMessage::SetExternalForces(_) => SET_EXTERNAL_FORCES,
+            ClientToServerMessage::AddConstraint(_) => ADD_CONSTRAINT,
+            ClientToServerMessage::RemoveConstraint(..) => REMOVE_CONSTRAINT,
+        }
+    }
+
+    fn write_fields(&self, out: &mut Vec<u8>) -> Result<(), bincode::Error> {
+        match self {
+            ClientToServerMessage::Subscribe
+            | ClientToServerMessage::State
+            | ClientToServerMessage::Reset
+            | ClientToServerMessage::SubscribeDeltas
+            | ClientToServerMessage::Pause
+            | ClientToServerMessage::Resume
+            | ClientToServerMessage::SingleStep
+            | ClientToServerMessage::CreateRoom
+            | ClientToServerMessage::LeaveRoom => Ok(()),
+            ClientToServerMessage::AddBodies(bodies) => write(out, bodies),
+            ClientToServerMessage::SetSolverParameters(parameters) => write(out, parameters),
+            ClientToServerMessage::SetPhysicsParameters(parameters) => write(out, parameters),
+            ClientToServerMessage::RequestHistory {
+                from_time,
+                to_time,
+                rate,
+            } => write(out, &(from_time, to_time, rate)),
+            ClientToServerMessage::QueryDiagnostics {
+                from_time,
+                to_time,
+                max_points,
+            } => write(out, &(from_time, to_time, max_points)),
+            ClientToServerMessage::QueryRegion(region) => write(out, region),
+            ClientToServerMessage::GetBody(id) => write(out, id),
+            ClientToServerMessage::GetHistory { last_n } => write(out, last_n),
+            ClientToServerMessage::SubscribeBundled { ticks_per_bundle } => {
+                write(out, ticks_per_bundle)
+            }
+            ClientToServerMessage::Ping { client_time } => write(out, client_time),
+            ClientToServerMessage::RemoveBodies(ids) => write(out, ids),
+            ClientToServerMessage::AckState { tick } => write(out, tick),
+            ClientToServerMessage::SetTickRate(tick_rate) => write(out, tick_rate),
+            ClientToServerMessage::JoinRoom(room) => write(out, room),
+            ClientToServerMessage::SetBoundaryCondition(boundary) => write(out, boundary),
+            ClientToServerMessage::Authenticate { token } => write(out, token),
+            ClientToServerMessage::SaveSnapshot { name } => write(out, name),
+            ClientToServerMessage::LoadSnapshot { name } => write(out, name),
+            ClientToServerMessage::LoadScenario(scenario) => write(out, scenario),
+            ClientToServerMessage::GetTrails { last_n } => write(out, last_n),
+            ClientToServerMessage::SetExternalForces(forces) => write(out, forces),
+            ClientToServerMessage::AddConstraint(constraint) => write(out, constraint),
+            ClientToServerMessage::RemoveConstraint(a, b) => write(out, &(a, b)),
+        }
+    }
+
+    fn read_fields(message_id: u16, fields: &[u8]) -> Result<Self, ProtocolError> {
+        Ok(match message_id {
+            SUBSCRIBE => ClientToServerMessage::Subscribe,
+            ADD_BODIES => ClientToServerMessage::AddBodies(read(fields)?),
+            STATE => ClientToServerMessage::State,
+            RESET => ClientToServerMessage::Reset,
+            SET_SOLVER_PARAMETERS => ClientToServerMessage::SetSolverParameters(read(fields)?),
+            SET_PHYSICS_PARAMETERS => ClientToServerMessage::SetPhysicsParameters(read(fields)?),
+            REQUEST_HISTORY => {
+                let (from_time, to_time, rate) = read(fields)?;
+                ClientToServerMessage::RequestHistory {
+                    from_time,
+                    to_time,
+                    rate,
+                }
+            }
+            QUERY_DIAGNOSTICS => {
+                let (from_time, to_time, max_points) = read(fields)?;
+                ClientToServerMessage::QueryDiagnostics {
+                    from_time,
+                    to_time,
+                    max_points,
+                }
+            }
+            QUERY_REGION => ClientToServerMessage::QueryRegion(read(fields)?),
+            GET_BODY => ClientToServerMessage::GetBody(read(fields)?),
+            GET_HISTORY => ClientToServerMessage::GetHistory {
+                last_n: read(fields)?,
+            },
+            SUBSCRIBE_BUNDLED => ClientToServerMessage::SubscribeBundled {
+                ticks_per_bundle: read(fields)?,
+            },
+            PING => ClientToServerMessage::Ping {
+                client_time: read(fields)?,
+            },
+            REMOVE_BODIES => ClientToServerMessage::RemoveBodies(read(fields)?),
+            SUBSCRIBE_DELTAS => ClientToServerMessage::SubscribeDeltas,
+            ACK_STATE => ClientToServerMessage::AckState {
+                tick: read(fields)?,
+            },
+            PAUSE => ClientToServerMessage::Pause,
+            RESUME => ClientToServerMessage::Resume,
+            SINGLE_STEP => ClientToServerMessage::SingleStep,
+            SET_TICK_RATE => ClientToServerMessage::SetTickRate(read(fields)?),
+            CREATE_ROOM => ClientToServerMessage::CreateRoom,
+            JOIN_ROOM => ClientToServerMessage::JoinRoom(read(fields)?),
+            LEAVE_ROOM => ClientToServerMessage::LeaveRoom,
+            SET_BOUNDARY_CONDITION => ClientToServerMessage::SetBoundaryCondition(read(fields)?),
+            AUTHENTICATE => ClientToServerMessage::Authenticate {
+                token: read(fields)?,
+            },
+            SAVE_SNAPSHOT => ClientToServerMessage::SaveSnapshot {
+                name: read(fields)?,
+            },
+            LOAD_SNAPSHOT => ClientToServerMessage::LoadSnapshot {
+                name: read(fields)?,
+            },
+            LOAD_SCENARIO => ClientToServerMessage::LoadScenario(read(fields)?),
+            GET_TRAILS => ClientToServerMessage::GetTrails {
+                last_n: read(fields)?,
+            },
+            SET_EXTERNAL_FORCES => ClientToServerMessage::SetExternalForces(read(fields)?),
+            ADD_CONSTRAINT => ClientToServerMessage::AddConstraint(read(fields)?),
+            REMOVE_CONSTRAINT => {
+                let (a, b) = read(fields)?;
+                ClientToServerMessage::RemoveConstraint(a, b)
+            }
+            id => return Err(ProtocolError::UnknownMessage(Some(id))),
+        })
+    }
+}
+
+// Ids of `ServerToClientMessage`
+const STATE_UPDATE: u16 = 1;
+const DIAGNOSTICS: u16 = 2;
+const REGION: u16 = 3;
+const BODY_DETAILS: u16 = 4;
+const HISTORY: u16 = 5;
+const STATE_BUNDLE: u16 = 6;
+const PONG: u16 = 7;
+const STATE_DELTA: u16 = 8;
+const ROOM_JOINED: u16 = 9;
+const AUTHENTICATED: u16 = 10;
+const SNAPSHOT_SAVED: u16 = 11;
+const TRAILS: u16 = 12;
+
+impl WireMessage for ServerToClientMessage {
+    fn message_id(&self) -> u16 {
+        match self {
+            ServerToClientMessage::StateUpdate { .. } => STATE_UPDATE,
+            ServerToClientMessage::Diagnostics(_) => DIAGNOSTICS,
+            ServerToClientMessage::Region { .. } => REGION,
+            ServerToClientMessage::BodyDetails { .. } => BODY_DETAILS,
+            ServerToClientMessage::History(_) => HISTORY,
+            ServerToClientMessage::StateBundle(_) => STATE_BUNDLE,
+            ServerToClientMessage::Pong { .. } => PONG,
+            ServerToClientMessage::StateDelta { .. } => STATE_DELTA,
+            ServerToClientMessage::RoomJoined(_) => ROOM_JOINED,
+            ServerToClientMessage::Authenticated(_) => AUTHENTICATED,
+            ServerToClientMessage::SnapshotSaved { .. } => SNAPSHOT_SAVED,
+            ServerToClientMessage::Trails { .. } => TRAILS,
+        }
+    }
+
+    fn write_fields(&self, out: &mut Vec<u8>) -> Result<(), bincode::Error> {
+        match self {
+            ServerToClientMessage::StateUpdate {
+                bodies,
+                physical_time,
+                kinetic_energy,
+                potential_energy,
+                linear_momentum,
+                angular_momentum,
+            } => write(
+                out,
+                &(
+                    bodies,
+                    physical_time,
+                    kinetic_energy,
+                    potential_energy,
+                    linear_momentum,
+                    angular_momentum,
+                ),
+            ),
+            ServerToClientMessage::Diagnostics(samples) => write(out, samples),
+            ServerToClientMessage::Region {
+                bodies,
+                physical_time,
+            } => write(out, &(bodies, physical_time)),
+            ServerToClientMessage::BodyDetails {
+                body,
+                speed,
+                kinetic_energy,
+                acceleration,
+                physical_time,
+            } => write(
+                out,
+                &(body, speed, kinetic_energy, acceleration, physical_time),
+            ),
+            ServerToClientMessage::History(states) | ServerToClientMessage::StateBundle(states) => {
+                write(out, states)
+            }
+            ServerToClientMessage::Pong {
+                client_time,
+                server_time,
+                tick,
+            } => write(out, &(client_time, server_time, tick)),
+            ServerToClientMessage::StateDelta {
+                tick,
+                baseline_tick,
+                delta,
+                physical_time,
+                kinetic_energy,
+            } => write(
+                out,
+                &(tick, baseline_tick, delta, physical_time, kinetic_energy),
+            ),
+            ServerToClientMessage::RoomJoined(room) => write(out, room),
+            ServerToClientMessage::Authenticated(role) => write(out, role),
+            ServerToClientMessage::SnapshotSaved {
+                name,
+                physical_time,
+            } => write(out, &(name, physical_time)),
+            ServerToClientMessage::Trails {
+                trails,
+                physical_time,
+            } => write(out, &(trails, physical_time)),
+        }
+    }
+
+    fn read_fields(message_id: u16, fields: &[u8]) -> Result<Self, ProtocolError> {
+        Ok(match message_id {
+            STATE_UPDATE => {
+                let (
+                    bodies,
+                    physical_time,
+                    kinetic_energy,
+                    potential_energy,
+                    linear_momentum,
+                    angular_momentum,
+                ) = read(fields)?;
+                ServerToClientMessage::StateUpdate {
+                    bodies,
+                    physical_time,
+                    kinetic_energy,
+                    potential_energy,
+                    linear_momentum,
+                    angular_momentum,
+                }
+            }
+            DIAGNOSTICS => ServerToClientMessage::Diagnostics(read(fields)?),
+            REGION => {
+                let (bodies, physical_time) = read(fields)?;
+                ServerToClientMessage::Region {
+                    bodies,
+                    physical_time,
+                }
+            }
+            BODY_DETAILS => {
+                let (body, speed, kinetic_energy, acceleration, physical_time) = read(fields)?;
+                ServerToClientMessage::BodyDetails {
+                    body,
+                    speed,
+                    kinetic_energy,
+                    acceleration,
+                    physical_time,
+                }
+            }
+            HISTORY => ServerToClientMessage::History(read(fields)?),
+            STATE_BUNDLE => ServerToClientMessage::StateBundle(read(fields)?),
+            PONG => {
+                let (client_time, server_time, tick) = read(fields)?;
+                ServerToClientMessage::Pong {
+                    client_time,
+                    server_time,
+                    tick,
+                }
+            }
+            STATE_DELTA => {
+                let (tick, baseline_tick, delta, physical_time, kinetic_energy) = read(fields)?;
+                ServerToClientMessage::StateDelta {
+                    tick,
+                    baseline_tick,
+                    delta,
+                    physical_time,
+                    kinetic_energy,
+                }
+            }
+            ROOM_JOINED => ServerToClientMessage::RoomJoined(read(fields)?),
+            AUTHENTICATED => ServerToClientMessage::Authenticated(read(fields)?),
+            SNAPSHOT_SAVED => {
+                let (name, physical_time) = read(fields)?;
+                ServerToClientMessage::SnapshotSaved {
+                    name,
+                    physical_time,
+                }
+            }
+            TRAILS => {
+                let (trails, physical_time) = read(fields)?;
+                ServerToClientMessage::Trails {
+                    trails,
+                    physical_time,
+                }
+            }
+            id => return Err(ProtocolError::UnknownMessage(Some(id))),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nbody::physics::Body;
+
+    #[test]
+    fn message_evolution_test() {
+        let msg = ClientToServerMessage::AddBodies(vec![Body::default(); 2]);
+        let mut payload = write_message(&msg).unwrap();
+        assert_eq!(payload[..MESSAGE_ID_SIZE], ADD_BODIES.to_le_bytes());
+
+        // A field appended by a newer peer
+        payload.extend(42u64.to_le_bytes());
+        assert!(matches!(
+            read_message(&payload),
+            Ok(ClientToServerMessage::AddBodies(bodies)) if bodies.len() == 2
+        ));
+
+        // A variant added by a newer peer
+        assert!(matches!(
+            read_message::<ClientToServerMessage>(&[0xff, 0xff]),
+            Err(ProtocolError::UnknownMessage(Some(0xffff)))
+        ));
+        assert!(matches!(
+            read_message::<ServerToClientMessage>(&[1]),
+            Err(ProtocolError::UnknownMessage(None))
+        ));
+    }
+}
@@ -0,0 +1,491 @@
+use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+use serde::{Deserialize, Serialize};
+use tsify::Tsify;
+use wasm_bindgen::{prelude::*, JsCast};
+use web_sys::{BinaryType, CloseEvent, MessageEvent, WebSocket};
+
+use crate::{
+    encode, ClientToServerMessage, ProtocolError, ServerMsgDecoder, ServerToClientMessage,
+    Subprotocol, MAX_TICKS_PER_BUNDLE,
+};
+
+/// Messages sent while disconnected are kept (up to this limit) until the connection opens
+const MAX_QUEUED_MESSAGES: usize = 256;
+
+const INITIAL_BACKOFF_MS: u32 = 250;
+const MAX_BACKOFF_MS: u32 = 10_000;
+
+#[wasm_bindgen]
+extern "C" {
+    // Available both in windows and in workers
+    #[wasm_bindgen(js_name = setTimeout)]
+    fn set_timeout(handler: &js_sys::Function, timeout_ms: u32) -> JsValue;
+
+    #[wasm_bindgen(js_name = clearTimeout)]
+    fn clear_timeout(handle: &JsValue);
+}
+
+#[derive(Serialize, Deserialize, Tsify, Debug, Clone, Copy, PartialEq, Eq)]
+#[tsify(into_wasm_abi)]
+#[serde(rename_all = "camelCase")]
+pub enum ConnectionStatus {
+    Disconnected,
+    Connecting,
+    Open,
+}
+
+/// Events emitted by the `WasmClient` to the registered callback
+#[derive(Serialize, Deserialize, Tsify, Debug)]
+#[tsify(into_wasm_abi)]
+#[serde(rename_all = "camelCase")]
+pub enum ClientEvent {
+    #[serde(rename_all = "camelCase")]
+    Connecting {
+        attempt: u32,
+    },
+    Connected,
+    /// `reconnect_in_ms` is missing when the client was disconnected on purpose
+    #[serde(rename_all = "camelCase")]
+    Disconnected {
+        reconnect_in_ms: Option<u32>,
+    },
+    Message(ServerToClientMessage),
+    DecodeError(String),
+}
+
+/// Exponential backoff between reconnection attempts
+#[derive(Debug)]
+struct Backoff {
+    attempt: u32,
+}
+
+impl Backoff {
+    fn next_delay_ms(&mut self) -> u32 {
+        let delay = INITIAL_BACKOFF_MS.saturating_mul(1 << self.attempt.min(16));
+        self.attempt += 1;
+        delay.min(MAX_BACKOFF_MS)
+    }
+
+    fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+/// Protocol logic of the client, independent from the browser APIs
+#[derive(Debug)]
+struct ClientProtocol {
+    status: ConnectionStatus,
+    backoff: Backoff,
+
+    /// Already serialized messages waiting for the connection to open
+    queue: VecDeque<Vec<u8>>,
+
+    /// Whether the server should stream state updates to this client
+    /// (the subscription is renewed on every reconnection)
+    subscribed: bool,
+
+    /// Ticks per pushed message, 1 for plain `StateUpdate`s (see `subscribeBundled`)
+    ticks_per_bundle: u32,
+
+    /// Set when the user asked to disconnect, to stop reconnecting
+    closed_by_user: bool,
+
+    /// Presented first on every connection, for the servers requiring one (see `setToken`)
+    token: Option<String>,
+}
+
+impl ClientProtocol {
+    fn new() -> Self {
+        Self {
+            status: ConnectionStatus::Disconnected,
+            backoff: Backoff { attempt: 0 },
+            queue: VecDeque::new(),
+            subscribed: false,
+            ticks_per_bundle: 1,
+            closed_by_user: false,
+            token: None,
+        }
+    }
+
+    fn on_connecting(&mut self) -> ClientEvent {
+        self.status = ConnectionStatus::Connecting;
+        self.closed_by_user = false;
+        ClientEvent::Connecting {
+            attempt: self.backoff.attempt,
+        }
+    }
+
+    /// Handshake: returns the frames to send now that the connection is open
+    fn on_open(&mut self) -> Vec<Vec<u8>> {
+        self.status = ConnectionStatus::Open;
+        self.backoff.reset();
+
+        let mut frames = Vec::with_capacity(self.queue.len() + 2);
+        if let Some(token) = &self.token {
+            let authenticate = ClientToServerMessage::Authenticate {
+                token: token.clone(),
+            };
+            frames.extend(encode(&authenticate).ok());
+        }
+        if self.subscribed {
+            frames.extend(encode(&self.subscription()).ok());
+        }
+        frames.extend(self.queue.drain(..));
+        frames
+    }
+
+    fn subscription(&self) -> ClientToServerMessage {
+        match self.ticks_per_bundle {
+            1 => ClientToServerMessage::Subscribe,
+            ticks_per_bundle => ClientToServerMessage::SubscribeBundled { ticks_per_bundle },
+        }
+    }
+
+    /// Returns the delay before reconnecting (None if the client must stay closed)
+    fn on_close(&mut self) -> Option<u32> {
+        self.status = ConnectionStatus::Disconnected;
+        if self.closed_by_user {
+            None
+        } else {
+            Some(self.backoff.next_delay_ms())
+        }
+    }
+
+    fn on_user_close(&mut self) {
+        self.closed_by_user = true;
+        self.status = ConnectionStatus::Disconnected;
+    }
+
+    /// Returns the frame if it can be sent right away, otherwise it is queued
+    fn outgoing(&mut self, frame: Vec<u8>) -> Option<Vec<u8>> {
+        if self.status == ConnectionStatus::Open {
+            return Some(frame);
+        }
+        if self.queue.len() == MAX_QUEUED_MESSAGES {
+            self.queue.pop_front();
+        }
+        self.queue.push_back(frame);
+        None
+    }
+}
+
+/// JS handlers attached to the current socket (kept alive as long as the socket is)
+struct SocketHandlers {
+    _on_open: Closure<dyn FnMut()>,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_close: Closure<dyn FnMut(CloseEvent)>,
+}
+
+struct ClientInner {
+    url: String,
+    protocol: ClientProtocol,
+    socket: Option<WebSocket>,
+    handlers: Option<SocketHandlers>,
+    reconnect_timer: Option<(JsValue, Closure<dyn FnMut()>)>,
+    on_event: Option<js_sys::Function>,
+
+    /// Reused across the incoming frames
+    frame: Vec<u8>,
+    decoder: ServerMsgDecoder,
+}
+
+/// WebSocket client owning the protocol logic:
+/// reconnection with backoff, subscription renewal, queueing of outgoing messages
+/// while disconnected and decoding of the server messages into typed events
+#[wasm_bindgen]
+pub struct WasmClient {
+    inner: Rc<RefCell<ClientInner>>,
+}
+
+#[wasm_bindgen]
+impl WasmClient {
+    #[wasm_bindgen(constructor)]
+    pub fn new(url: String) -> Self {
+        Self {
+            inner: Rc::new(RefCell::new(ClientInner {
+                url,
+                protocol: ClientProtocol::new(),
+                socket: None,
+                handlers: None,
+                reconnect_timer: None,
+                on_event: None,
+                frame: Vec::new(),
+                decoder: ServerMsgDecoder::new(),
+            })),
+        }
+    }
+
+    /// Registers the callback receiving the `ClientEvent`s
+    #[wasm_bindgen(js_name = onEvent)]
+    pub fn on_event(&self, callback: js_sys::Function) {
+        self.inner.borrow_mut().on_event = Some(callback);
+    }
+
+    /// Opens the connection (no-op if there is already one open or being opened)
+    pub fn connect(&self) -> Result<(), JsValue> {
+        if self.inner.borrow().socket.is_some() {
+            return Ok(());
+        }
+        open_socket(&self.inner)
+    }
+
+    /// Closes the connection without reconnecting
+    pub fn disconnect(&self) {
+        {
+            let mut inner = self.inner.borrow_mut();
+            inner.protocol.on_user_close();
+            if let Some((handle, _)) = inner.reconnect_timer.take() {
+                clear_timeout(&handle);
+            }
+            if let Some(socket) = inner.socket.take() {
+                // Detach the handlers as they are dropped right after
+                socket.set_onopen(None);
+                socket.set_onmessage(None);
+                socket.set_onclose(None);
+                let _ = socket.close();
+            }
+            inner.handlers = None;
+        }
+        emit(
+            &self.inner,
+            ClientEvent::Disconnected {
+                reconnect_in_ms: None,
+            },
+        );
+    }
+
+    /// Token authenticating the client on every connection from the next one on
+    /// (answered by an `Authenticated` message)
+    #[wasm_bindgen(js_name = setToken)]
+    pub fn set_token(&self, token: Option<String>) {
+        self.inner.borrow_mut().protocol.token = token;
+    }
+
+    /// Subscribes to the state updates (renewed automatically after reconnecting)
+    pub fn subscribe(&self) {
+        self.subscribe_with(1);
+    }
+
+    /// Same as `subscribe` but the states come in `StateBundle`s of `ticks_per_bundle`
+    /// consecutive ticks, to be interpolated between
+    #[wasm_bindgen(js_name = subscribeBundled)]
+    pub fn subscribe_bundled(&self, ticks_per_bundle: u32) {
+        self.subscribe_with(ticks_per_bundle.clamp(1, MAX_TICKS_PER_BUNDLE));
+    }
+
+    fn subscribe_with(&self, ticks_per_bundle: u32) {
+        let mut inner = self.inner.borrow_mut();
+        inner.protocol.subscribed = true;
+        inner.protocol.ticks_per_bundle = ticks_per_bundle;
+        if inner.protocol.status != ConnectionStatus::Open {
+            return; // sent as part of the handshake
+        }
+        if let (Some(socket), Some(frame)) = (
+            inner.socket.as_ref(),
+            encode(&inner.protocol.subscription()).ok(),
+        ) {
+            let _ = socket.send_with_u8_array(&frame);
+        }
+    }
+
+    /// Sends a message, or queues it until the connection opens
+    /// Returns false if the message could not be serialized or sent
+    pub fn send(&self, msg: ClientToServerMessage) -> bool {
+        let Ok(frame) = encode(&msg) else {
+            return false;
+        };
+        let mut inner = self.inner.borrow_mut();
+        match (inner.protocol.outgoing(frame), inner.socket.as_ref()) {
+            (Some(frame), Some(socket)) => socket.send_with_u8_array(&frame).is_ok(),
+            _ => true,
+        }
+    }
+
+    pub fn status(&self) -> ConnectionStatus {
+        self.inner.borrow().protocol.status
+    }
+}
+
+fn emit(inner: &Rc<RefCell<ClientInner>>, event: ClientEvent) {
+    // The borrow must be released before calling back into JS (the callback may use the client)
+    let callback = inner.borrow().on_event.clone();
+    if let (Some(callback), Ok(event)) = (callback, event.into_js()) {
+        let _ = callback.call1(&JsValue::NULL, &event.into());
+    }
+}
+
+fn open_socket(inner: &Rc<RefCell<ClientInner>>) -> Result<(), JsValue> {
+    let url = inner.borrow().url.clone();
+    // The frames the decoder expects, agreed during the handshake
+    // (zstd when the server has a dictionary, it is then shipped in the first frame)
+    let subprotocols = js_sys::Array::of2(
+        &Subprotocol::BincodeZstd.name().into(),
+        &Subprotocol::BincodeGzip.name().into(),
+    );
+    let socket = WebSocket::new_with_str_sequence(&url, &subprotocols)?;
+    socket.set_binary_type(BinaryType::Arraybuffer);
+
+    let weak = Rc::downgrade(inner);
+    let on_open = Closure::<dyn FnMut()>::new(move || {
+        let Some(inner) = weak.upgrade() else { return };
+        {
+            let mut client = inner.borrow_mut();
+            let frames = client.protocol.on_open();
+            if let Some(socket) = client.socket.as_ref() {
+                for frame in frames {
+                    let _ = socket.send_with_u8_array(&frame);
+                }
+            }
+        }
+        emit(&inner, ClientEvent::Connected);
+    });
+
+    let weak = Rc::downgrade(inner);
+    let on_message = Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+        let Some(inner) = weak.upgrade() else { return };
+        let Ok(buffer) = event.data().dyn_into::<js_sys::ArrayBuffer>() else {
+            return; // text frames are only used for server-side error reports
+        };
+        let decoded = {
+            let mut client = inner.borrow_mut();
+            let client = &mut *client;
+            let array = js_sys::Uint8Array::new(&buffer);
+            client.frame.resize(array.length() as usize, 0);
+            array.copy_to(&mut client.frame);
+            match client.decoder.try_load_dictionary(&client.frame) {
+                Ok(true) => Ok(None),
+                Ok(false) => client.decoder.decode_as(&client.frame).map(Some),
+                Err(e) => Err(e),
+            }
+        };
+        match decoded {
+            Ok(Some(msg)) => emit(&inner, ClientEvent::Message(msg)),
+            Ok(None) => log::debug!("Loaded the zstd dictionary of the server"),
+            // Sent by a newer server
+            Err(ProtocolError::UnknownMessage(Some(id))) => {
+                log::debug!("Skipping unknown server message {id}")
+            }
+            Err(e) => {
+                log::warn!("Failed to decode server message: {e}");
+                emit(&inner, ClientEvent::DecodeError(e.to_string()))
+            }
+        }
+    });
+
+    let weak = Rc::downgrade(inner);
+    let on_close = Closure::<dyn FnMut(CloseEvent)>::new(move |_: CloseEvent| {
+        let Some(inner) = weak.upgrade() else { return };
+        let reconnect_in_ms = {
+            let mut client = inner.borrow_mut();
+            client.socket = None;
+            client.protocol.on_close()
+        };
+        if let Some(delay) = reconnect_in_ms {
+            log::info!("Connection lost, reconnecting in {delay}ms");
+            schedule_reconnect(&inner, delay);
+        }
+        emit(&inner, ClientEvent::Disconnected { reconnect_in_ms });
+    });
+
+    socket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+    socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+    socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+
+    let event = {
+        let mut client = inner.borrow_mut();
+        client.socket = Some(socket);
+        client.handlers = Some(SocketHandlers {
+            _on_open: on_open,
+            _on_message: on_message,
+            _on_close: on_close,
+        });
+        client.protocol.on_connecting()
+    };
+    emit(inner, event);
+    Ok(())
+}
+
+fn schedule_reconnect(inner: &Rc<RefCell<ClientInner>>, delay_ms: u32) {
+    let weak = Rc::downgrade(inner);
+    let reconnect = Closure::<dyn FnMut()>::new(move || {
+        let Some(inner) = weak.upgrade() else { return };
+        inner.borrow_mut().reconnect_timer = None;
+        if open_socket(&inner).is_err() {
+            let delay = inner.borrow_mut().protocol.on_close();
+            if let Some(delay) = delay {
+                schedule_reconnect(&inner, delay);
+            }
+        }
+    });
+    let handle = set_timeout(reconnect.as_ref().unchecked_ref(), delay_ms);
+    inner.borrow_mut().reconnect_timer = Some((handle, reconnect));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode;
+
+    #[test]
+    fn backoff_test() {
+        let mut protocol = ClientProtocol::new();
+        let delays: Vec<_> = (0..8).map(|_| protocol.on_close().unwrap()).collect();
+        assert_eq!(delays[..4], [250, 500, 1000, 2000]);
+        assert_eq!(delays[7], MAX_BACKOFF_MS);
+
+        protocol.on_open();
+        assert_eq!(protocol.on_close(), Some(INITIAL_BACKOFF_MS));
+
+        protocol.on_user_close();
+        assert_eq!(protocol.on_close(), None);
+    }
+
+    #[test]
+    fn handshake_test() {
+        let mut protocol = ClientProtocol::new();
+        protocol.on_connecting();
+        protocol.subscribed = true;
+        assert!(protocol.outgoing(vec![1]).is_none());
+        assert!(protocol.outgoing(vec![2]).is_none());
+
+        let frames = protocol.on_open();
+        assert_eq!(frames.len(), 3);
+        assert!(matches!(
+            decode(&frames[0]),
+            Ok(ClientToServerMessage::Subscribe)
+        ));
+        assert_eq!(frames[1..], [vec![1], vec![2]]);
+        assert_eq!(protocol.outgoing(vec![3]), Some(vec![3]));
+
+        protocol.ticks_per_bundle = 3;
+        assert!(matches!(
+            decode(&protocol.on_open()[0]),
+            Ok(ClientToServerMessage::SubscribeBundled {
+                ticks_per_bundle: 3
+            })
+        ));
+
+        // Authenticated before anything else
+        protocol.token = Some("secret".into());
+        let frames = protocol.on_open();
+        assert!(matches!(
+            decode(&frames[0]),
+            Ok(ClientToServerMessage::Authenticate { token }) if token == "secret"
+        ));
+        assert!(matches!(
+            decode(&frames[1]),
+            Ok(ClientToServerMessage::SubscribeBundled { .. })
+        ));
+    }
+
+    #[test]
+    fn bounded_queue_test() {
+        let mut protocol = ClientProtocol::new();
+        for i in 0..MAX_QUEUED_MESSAGES + 10 {
+            protocol.outgoing(vec![i as u8]);
+        }
+        assert_eq!(protocol.queue.len(), MAX_QUEUED_MESSAGES);
+        assert_eq!(protocol.queue.front(), Some(&vec![10]));
+    }
+}
@@ -0,0 +1,96 @@
+//! The codecs of the wire protocol for any bytes, e.g. snapshots cached by the frontend
+//! or replay files, so that they do not need another compression library
+//!
+//! Unlike the frames, the compressed bytes have no header: the codec must be known to decompress
+
+use std::io::{Read, Write};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use ruzstd::{
+    decoding::StreamingDecoder,
+    encoding::{compress_to_vec, CompressionLevel},
+};
+use wasm_bindgen::prelude::*;
+
+use crate::{Codec, ProtocolError, MAX_DECOMPRESSED_SIZE};
+
+#[wasm_bindgen]
+pub fn compress(data: &[u8], codec: Codec) -> Result<Vec<u8>, JsError> {
+    Ok(compress_bytes(data, codec)?)
+}
+
+/// Fails if the data decompresses into more than `MAX_DECOMPRESSED_SIZE` bytes
+#[wasm_bindgen]
+pub fn decompress(data: &[u8], codec: Codec) -> Result<Vec<u8>, JsError> {
+    Ok(decompress_bytes(data, codec, MAX_DECOMPRESSED_SIZE)?)
+}
+
+pub fn compress_bytes(data: &[u8], codec: Codec) -> Result<Vec<u8>, ProtocolError> {
+    match codec {
+        Codec::None => Ok(data.to_vec()),
+        Codec::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+            encoder
+                .write_all(data)
+                .and_then(|_| encoder.finish())
+                .map_err(ProtocolError::Compression)
+        }
+        Codec::Zstd => Ok(compress_to_vec(data, CompressionLevel::Fastest)),
+    }
+}
+
+pub fn decompress_bytes(
+    data: &[u8],
+    codec: Codec,
+    max_size: usize,
+) -> Result<Vec<u8>, ProtocolError> {
+    let reader: Box<dyn Read + '_> = match codec {
+        Codec::None => Box::new(data),
+        Codec::Gzip => Box::new(GzDecoder::new(data)),
+        Codec::Zstd => Box::new(StreamingDecoder::new(data).map_err(|e| {
+            ProtocolError::Decompression(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                e.to_string(),
+            ))
+        })?),
+    };
+    let mut buffer = Vec::new();
+    // Reading one byte past the limit tells apart data of exactly `max_size` bytes
+    reader
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut buffer)
+        .map_err(ProtocolError::Decompression)?;
+    if buffer.len() > max_size {
+        return Err(ProtocolError::FrameTooLarge {
+            size: buffer.len(),
+            max_size,
+        });
+    }
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codec_round_trip_test() {
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        for codec in [Codec::None, Codec::Gzip, Codec::Zstd] {
+            let compressed = compress_bytes(&data, codec).unwrap();
+            if codec != Codec::None {
+                assert!(compressed.len() < data.len() / 2, "{codec:?}");
+            }
+            assert_eq!(
+                decompress_bytes(&compressed, codec, data.len()).unwrap(),
+                data
+            );
+            assert!(matches!(
+                decompress_bytes(&compressed, codec, data.len() - 1),
+                Err(ProtocolError::FrameTooLarge { .. })
+            ));
+        }
+        assert!(decompress_bytes(&[1, 2, 3], Codec::Gzip, 100).is_err());
+        assert!(decompress_bytes(&[1, 2, 3], Codec::Zstd, 100).is_err());
+    }
+}
@@ -0,0 +1,297 @@
+use std::io::Read;
+
+use flate2::{Crc, Decompress, FlushDecompress, Status};
+use ruzstd::decoding::{Dictionary, FrameDecoder, StreamingDecoder};
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    read_message, split_frame, Codec, ProtocolError, ServerToClientMessage, WireMessage,
+    DICTIONARY_TAG, MAX_DECOMPRESSED_SIZE,
+};
+
+/// Gzip header flags (RFC 1952)
+const FHCRC: u8 = 1 << 1;
+const FEXTRA: u8 = 1 << 2;
+const FNAME: u8 = 1 << 3;
+const FCOMMENT: u8 = 1 << 4;
+
+/// Size of the gzip trailer (crc32 + uncompressed size)
+const TRAILER_SIZE: usize = 8;
+
+/// Decoder for repeated frames (e.g. the 60Hz `StateUpdate`s)
+///
+/// Unlike `deserializeServerMsg`, the inflate state and the decompressed bytes are kept
+/// between calls, so decoding a frame does not allocate them again
+#[wasm_bindgen]
+pub struct ServerMsgDecoder {
+    inflate: Decompress,
+    crc: Crc,
+
+    /// Holds the zstd dictionary once the server shipped it
+    zstd: Option<FrameDecoder>,
+
+    /// Scratch buffer holding the last decompressed frame
+    output: Vec<u8>,
+
+    /// Frames decompressing into more bytes than this are rejected
+    max_output_size: usize,
+}
+
+impl Default for ServerMsgDecoder {
+    fn default() -> Self {
+        Self {
+            inflate: Decompress::new(false),
+            crc: Crc::new(),
+            zstd: None,
+            output: Vec::new(),
+            max_output_size: MAX_DECOMPRESSED_SIZE,
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl ServerMsgDecoder {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        ServerMsgDecoder::default()
+    }
+
+    /// Builder method to set the maximum size (in bytes) of a decompressed frame
+    #[wasm_bindgen(js_name = withMaxOutputSize)]
+    pub fn with_max_output_size(mut self, max_output_size: usize) -> Self {
+        self.max_output_size = max_output_size;
+        self
+    }
+
+    pub fn decode(&mut self, frame: &[u8]) -> Result<ServerToClientMessage, JsError> {
+        Ok(self.decode_as(frame)?)
+    }
+
+    /// Loads the zstd dictionary if the frame ships one (first frame of a
+    /// `nbody.bincode.zstd.v1` connection), returns false for any other frame
+    #[wasm_bindgen(js_name = loadDictionary)]
+    pub fn load_dictionary(&mut self, frame: &[u8]) -> Result<bool, JsError> {
+        Ok(self.try_load_dictionary(frame)?)
+    }
+}
+
+impl ServerMsgDecoder {
+    /// Decompresses and deserializes any message type using the reused buffers
+    pub fn decode_as<T: WireMessage>(&mut self, frame: &[u8]) -> Result<T, ProtocolError> {
+        let bytes = match split_frame(frame)? {
+            // Small frames are sent uncompressed: nothing to copy
+            (Codec::None, payload) => payload,
+            (Codec::Gzip, payload) => self.inflate(payload)?,
+            (Codec::Zstd, payload) => self.unzstd(payload)?,
+        };
+        read_message(bytes)
+    }
+
+    /// Same as `loadDictionary`
+    pub fn try_load_dictionary(&mut self, frame: &[u8]) -> Result<bool, ProtocolError> {
+        let Some((&DICTIONARY_TAG, bytes)) = frame.split_first() else {
+            return Ok(false);
+        };
+        let dictionary = Dictionary::decode_dict(bytes).map_err(|e| {
+            ProtocolError::Dictionary(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                e.to_string(),
+            ))
+        })?;
+        let mut zstd = FrameDecoder::new();
+        zstd.add_dict(dictionary)
+            .map_err(|e| invalid_data(&e.to_string()))?;
+        self.zstd = Some(zstd);
+        Ok(true)
+    }
+
+    /// Decompresses a zstd frame into the scratch buffer
+    fn unzstd(&mut self, data: &[u8]) -> Result<&[u8], ProtocolError> {
+        let zstd = self.zstd.as_mut().ok_or(ProtocolError::MissingDictionary)?;
+        let decoder = StreamingDecoder::new_with_decoder(data, zstd)
+            .map_err(|e| invalid_data(&e.to_string()))?;
+        self.output.clear();
+        // Reading one byte past the limit tells apart a frame of exactly `max_output_size` bytes
+        decoder
+            .take(self.max_output_size as u64 + 1)
+            .read_to_end(&mut self.output)
+            .map_err(ProtocolError::Decompression)?;
+        if self.output.len() > self.max_output_size {
+            return Err(ProtocolError::FrameTooLarge {
+                size: self.output.len(),
+                max_size: self.max_output_size,
+            });
+        }
+        Ok(self.output.as_slice())
+    }
+
+    /// Decompresses a gzip member into the scratch buffer
+    fn inflate(&mut self, data: &[u8]) -> Result<&[u8], ProtocolError> {
+        let body = gzip_body(data).ok_or_else(|| invalid_data("invalid gzip header"))?;
+        let trailer = &data[data.len() - TRAILER_SIZE..];
+        let expected_crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+        let expected_size =
+            u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]) as usize;
+        if expected_size > self.max_output_size {
+            return Err(ProtocolError::FrameTooLarge {
+                size: expected_size,
+                max_size: self.max_output_size,
+            });
+        }
+
+        self.output.clear();
+        self.output.reserve(expected_size);
+        self.inflate.reset(false);
+        // decompress_vec never grows the buffer: the output is bounded by the announced size
+        let status = self
+            .inflate
+            .decompress_vec(body, &mut self.output, FlushDecompress::Finish)
+            .map_err(|e| ProtocolError::Decompression(e.into()))?;
+        if status != Status::StreamEnd || self.output.len() != expected_size {
+            return Err(invalid_data("size mismatch"));
+        }
+
+        self.crc.reset();
+        self.crc.update(&self.output);
+        if self.crc.sum() != expected_crc {
+            return Err(invalid_data("crc mismatch"));
+        }
+        Ok(self.output.as_slice())
+    }
+}
+
+fn invalid_data(msg: &str) -> ProtocolError {
+    ProtocolError::Decompression(std::io::Error::new(std::io::ErrorKind::InvalidData, msg))
+}
+
+/// Returns the deflate stream of a gzip member (skipping its header and trailer)
+fn gzip_body(data: &[u8]) -> Option<&[u8]> {
+    if data.len() < 10 + TRAILER_SIZE || data[..3] != [0x1f, 0x8b, 8] {
+        return None;
+    }
+    let flags = data[3];
+    let mut pos = 10;
+    if flags & FEXTRA != 0 {
+        let extra_len = u16::from_le_bytes([*data.get(pos)?, *data.get(pos + 1)?]) as usize;
+        pos += 2 + extra_len;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            // zero-terminated string
+            pos += data.get(pos..)?.iter().position(|&b| b == 0)? + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+    data.get(pos..data.len().checked_sub(TRAILER_SIZE)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode, encode};
+    use nbody::physics::Body;
+
+    fn state_update(nbodies: usize) -> Vec<u8> {
+        encode(&ServerToClientMessage::StateUpdate {
+            bodies: vec![Body::default(); nbodies],
+            physical_time: 1.0,
+            kinetic_energy: 2.0,
+            potential_energy: 0.0,
+            linear_momentum: [0.0, 0.0],
+            angular_momentum: 0.0,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn decoder_reuse_test() {
+        let mut decoder = ServerMsgDecoder::new();
+        for nbodies in [100, 10, 1000, 0] {
+            let frame = state_update(nbodies);
+            let Ok(ServerToClientMessage::StateUpdate { bodies, .. }) = decoder.decode_as(&frame)
+            else {
+                panic!("Expected a StateUpdate");
+            };
+            assert_eq!(bodies.len(), nbodies);
+
+            let Ok(ServerToClientMessage::StateUpdate { bodies, .. }) = decode(&frame) else {
+                panic!("Expected a StateUpdate");
+            };
+            assert_eq!(bodies.len(), nbodies);
+        }
+    }
+
+    #[test]
+    fn decoder_rejects_invalid_frames_test() {
+        let frame = state_update(100);
+
+        let mut decoder = ServerMsgDecoder::new().with_max_output_size(64);
+        assert!(matches!(
+            decoder.decode_as::<ServerToClientMessage>(&frame),
+            Err(ProtocolError::FrameTooLarge { .. })
+        ));
+
+        let mut decoder = ServerMsgDecoder::new();
+        let mut corrupted = frame.clone();
+        let crc_idx = corrupted.len() - TRAILER_SIZE;
+        corrupted[crc_idx] ^= 0xff;
+        for invalid in [&corrupted[..], &frame[..frame.len() / 2]] {
+            assert!(matches!(
+                decoder.decode_as::<ServerToClientMessage>(invalid),
+                // a truncated frame may announce any size in its (bogus) trailer
+                Err(ProtocolError::Decompression(_) | ProtocolError::FrameTooLarge { .. })
+            ));
+        }
+        assert!(matches!(
+            decoder.decode_as::<ServerToClientMessage>(&frame[..1]),
+            Err(ProtocolError::UnknownCodec(None))
+        ));
+        assert!(matches!(
+            decoder.decode_as::<ServerToClientMessage>(&[]),
+            Err(ProtocolError::VersionMismatch { .. })
+        ));
+
+        // Still usable after a failure
+        assert!(decoder.decode_as::<ServerToClientMessage>(&frame).is_ok());
+    }
+
+    #[test]
+    fn decoder_zstd_test() {
+        let states: Vec<_> = (0..100)
+            .map(|seed| ServerToClientMessage::StateUpdate {
+                bodies: crate::Scenario {
+                    count: 20,
+                    seed,
+                    ..Default::default()
+                }
+                .generate(1.0),
+                physical_time: seed as f64,
+                kinetic_energy: 0.0,
+                potential_energy: 0.0,
+                linear_momentum: [0.0, 0.0],
+                angular_momentum: 0.0,
+            })
+            .collect();
+        let dictionary = protocol::dictionary::Dictionary::train(&states, 4 * 1024).unwrap();
+        let frame = dictionary.encode(&states[0]).unwrap();
+
+        let mut decoder = ServerMsgDecoder::new();
+        assert!(matches!(
+            decoder.decode_as::<ServerToClientMessage>(&frame),
+            Err(ProtocolError::MissingDictionary)
+        ));
+        assert!(!decoder.try_load_dictionary(&frame).unwrap());
+        assert!(decoder.try_load_dictionary(&dictionary.to_frame()).unwrap());
+        let Ok(ServerToClientMessage::StateUpdate { bodies, .. }) = decoder.decode_as(&frame)
+        else {
+            panic!("Expected a StateUpdate");
+        };
+        assert_eq!(bodies.len(), 20);
+        // Gzipped frames are still decoded
+        assert!(decoder
+            .decode_as::<ServerToClientMessage>(&state_update(100))
+            .is_ok());
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    decode, ProtocolError, ServerToClientMessage, FRAGMENT_HEADER_SIZE, FRAGMENT_TAG,
+    PROTOCOL_VERSION,
+};
+
+/// Partial messages older than this are dropped
+const DEFAULT_TIMEOUT_MS: f64 = 5_000.0;
+
+/// Maximum number of messages being reassembled at the same time
+const DEFAULT_MAX_PENDING: usize = 16;
+
+/// Upper bound of a reassembled frame
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+struct PendingMessage {
+    chunks: Vec<Option<Vec<u8>>>,
+    received: usize,
+    size: usize,
+    first_seen_ms: f64,
+}
+
+/// Streaming parser reassembling fragmented messages
+///
+/// Fragments may arrive in any order and interleaved with other messages,
+/// duplicates are ignored and incomplete messages are dropped after a timeout
+/// Complete (non fragmented) frames are decoded right away
+#[wasm_bindgen]
+pub struct FragmentAssembler {
+    pending: HashMap<u32, PendingMessage>,
+    timeout_ms: f64,
+    max_pending: usize,
+    max_message_size: usize,
+}
+
+impl Default for FragmentAssembler {
+    fn default() -> Self {
+        Self {
+            pending: HashMap::new(),
+            timeout_ms: DEFAULT_TIMEOUT_MS,
+            max_pending: DEFAULT_MAX_PENDING,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl FragmentAssembler {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        FragmentAssembler::default()
+    }
+
+    /// Builder method to set how long (ms) an incomplete message is kept
+    #[wasm_bindgen(js_name = withTimeoutMs)]
+    pub fn with_timeout_ms(mut self, timeout_ms: f64) -> Self {
+        self.timeout_ms = timeout_ms;
+        self
+    }
+
+    /// Builder method to set the number of messages reassembled at the same time
+    #[wasm_bindgen(js_name = withMaxPending)]
+    pub fn with_max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = max_pending.max(1);
+        self
+    }
+
+    /// Builder method to set the maximum size (in bytes) of a reassembled frame
+    #[wasm_bindgen(js_name = withMaxMessageSize)]
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Feeds a received chunk (`nowMs` typically being `performance.now()`)
+    /// Returns the decoded message once all its fragments have been received
+    pub fn push(
+        &mut self,
+        chunk: &[u8],
+        now_ms: f64,
+    ) -> Result<Option<ServerToClientMessage>, JsError> {
+        match self.push_frame(chunk, now_ms)? {
+            Some(frame) => Ok(Some(decode(&frame)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Drops the incomplete messages that timed out, returns how many were dropped
+    pub fn expire(&mut self, now_ms: f64) -> usize {
+        let before = self.pending.len();
+        let timeout_ms = self.timeout_ms;
+        self.pending
+            .retain(|_, msg| now_ms - msg.first_seen_ms <= timeout_ms);
+        let expired = before - self.pending.len();
+        if expired > 0 {
+            log::warn!("Dropped {expired} incomplete fragmented message(s)");
+        }
+        expired
+    }
+
+    /// Number of messages waiting for more fragments
+    #[wasm_bindgen(getter, js_name = pendingCount)]
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+impl FragmentAssembler {
+    /// Same as `push` but returns the reassembled frame instead of decoding it
+    pub fn push_frame(
+        &mut self,
+        chunk: &[u8],
+        now_ms: f64,
+    ) -> Result<Option<Vec<u8>>, ProtocolError> {
+        match chunk.first() {
+            Some(&FRAGMENT_TAG) => {}
+            Some(&PROTOCOL_VERSION) => return Ok(Some(chunk.to_vec())),
+            found => {
+                return Err(ProtocolError::VersionMismatch {
+                    expected: PROTOCOL_VERSION,
+                    found: found.copied(),
+                })
+            }
+        }
+        if chunk.len() < FRAGMENT_HEADER_SIZE {
+            return Err(ProtocolError::InvalidFragment("truncated header"));
+        }
+        let message_id = u32::from_le_bytes([chunk[1], chunk[2], chunk[3], chunk[4]]);
+        let index = u16::from_le_bytes([chunk[5], chunk[6]]) as usize;
+        let count = u16::from_le_bytes([chunk[7], chunk[8]]) as usize;
+        let payload = &chunk[FRAGMENT_HEADER_SIZE..];
+        if index >= count {
+            return Err(ProtocolError::InvalidFragment("index out of range"));
+        }
+
+        self.expire(now_ms);
+        if !self.pending.contains_key(&message_id) && self.pending.len() >= self.max_pending {
+            self.drop_oldest();
+        }
+        let msg = self
+            .pending
+            .entry(message_id)
+            .or_insert_with(|| PendingMessage {
+                chunks: vec![None; count],
+                received: 0,
+                size: 0,
+                first_seen_ms: now_ms,
+            });
+        if msg.chunks.len() != count {
+            self.pending.remove(&message_id);
+            return Err(ProtocolError::InvalidFragment(
+                "inconsistent fragment count",
+            ));
+        }
+        if msg.chunks[index].is_some() {
+            // Duplicate
+            return Ok(None);
+        }
+        msg.size += payload.len();
+        if msg.size > self.max_message_size {
+            let size = msg.size;
+            self.pending.remove(&message_id);
+            return Err(ProtocolError::FrameTooLarge {
+                size,
+                max_size: self.max_message_size,
+            });
+        }
+        msg.chunks[index] = Some(payload.to_vec());
+        msg.received += 1;
+        if msg.received < count {
+            return Ok(None);
+        }
+
+        let msg = self.pending.remove(&message_id).expect("entry must exist");
+        let mut frame = Vec::with_capacity(msg.size);
+        for chunk in msg.chunks.into_iter().flatten() {
+            frame.extend_from_slice(&chunk);
+        }
+        Ok(Some(frame))
+    }
+
+    fn drop_oldest(&mut self) {
+        let oldest = self
+            .pending
+            .iter()
+            .min_by(|a, b| a.1.first_seen_ms.total_cmp(&b.1.first_seen_ms))
+            .map(|(&id, _)| id);
+        if let Some(id) = oldest {
+            log::warn!("Too many fragmented messages in flight, dropping message {id}");
+            self.pending.remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encode, fragment};
+    use nbody::physics::Body;
+
+    fn state_update(nbodies: usize) -> Vec<u8> {
+        encode(&ServerToClientMessage::StateUpdate {
+            bodies: vec![Body::default(); nbodies],
+            physical_time: 1.0,
+            kinetic_energy: 2.0,
+            potential_energy: 0.0,
+            linear_momentum: [0.0, 0.0],
+            angular_momentum: 0.0,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn out_of_order_reassembly_test() {
+        let frame = state_update(100);
+        let mut fragments = fragment(&frame, 7, 16).unwrap();
+        assert!(fragments.len() > 2);
+        fragments.reverse();
+        let duplicate = fragments[1].clone();
+        fragments.insert(2, duplicate);
+
+        // Interleaved with a complete frame and another message
+        let other = fragment(&state_update(1), 8, 16).unwrap();
+
+        let mut assembler = FragmentAssembler::new();
+        let (last, rest) = fragments.split_last().unwrap();
+        for chunk in rest.iter().chain(&other[1..]) {
+            assert_eq!(assembler.push_frame(chunk, 0.0).unwrap(), None);
+        }
+        assert_eq!(
+            assembler.push_frame(&state_update(2), 0.0).unwrap(),
+            Some(state_update(2))
+        );
+        assert_eq!(assembler.push_frame(last, 0.0).unwrap(), Some(frame));
+        assert_eq!(assembler.pending_count(), 1);
+        assert!(assembler.push_frame(&other[0], 0.0).unwrap().is_some());
+        assert_eq!(assembler.pending_count(), 0);
+    }
+
+    #[test]
+    fn timeout_and_limits_test() {
+        let fragments = fragment(&state_update(100), 1, 16).unwrap();
+
+        let mut assembler = FragmentAssembler::new().with_timeout_ms(100.0);
+        assembler.push_frame(&fragments[0], 0.0).unwrap();
+        assert_eq!(assembler.expire(50.0), 0);
+        assert_eq!(assembler.expire(150.0), 1);
+        // The remaining fragments alone never complete the message
+        for chunk in &fragments[1..] {
+            assert_eq!(assembler.push_frame(chunk, 200.0).unwrap(), None);
+        }
+
+        let mut assembler = FragmentAssembler::new().with_max_pending(1);
+        assembler.push_frame(&fragments[0], 0.0).unwrap();
+        assembler
+            .push_frame(&fragment(&state_update(1), 2, 16).unwrap()[0], 1.0)
+            .unwrap();
+        assert_eq!(assembler.pending_count(), 1);
+
+        let mut assembler = FragmentAssembler::new().with_max_message_size(32);
+        let result: Result<Vec<_>, _> = fragments
+            .iter()
+            .map(|chunk| assembler.push_frame(chunk, 0.0))
+            .collect();
+        assert!(matches!(result, Err(ProtocolError::FrameTooLarge { .. })));
+
+        assert!(matches!(
+            assembler.push_frame(&[FRAGMENT_TAG, 0, 0], 0.0),
+            Err(ProtocolError::InvalidFragment(_))
+        ));
+    }
+}
@@ -0,0 +1,128 @@
+//! Round trip time and clock offset estimated from the `Pong`s of the server
+//! (e.g. to tune how far behind the server the rendered states are interpolated)
+//!
+//! All the times are in milliseconds, on the clock used for `Ping::client_time` (`Date.now()`)
+
+use std::collections::VecDeque;
+
+use wasm_bindgen::prelude::*;
+
+/// Pongs kept by default by a `LatencyEstimator`
+const DEFAULT_WINDOW: usize = 16;
+
+/// Outcome of a single ping
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LatencySample {
+    pub rtt: f64,
+
+    /// To add to the client clock to read the server clock
+    #[wasm_bindgen(js_name = clockOffset)]
+    pub clock_offset: f64,
+}
+
+/// `received_at` is the client time at which the pong arrived
+#[wasm_bindgen(js_name = latencySample)]
+pub fn latency_sample(client_time: f64, server_time: f64, received_at: f64) -> LatencySample {
+    let rtt = (received_at - client_time).max(0.0);
+    LatencySample {
+        rtt,
+        // Assuming the ping was answered halfway through the round trip
+        clock_offset: server_time - (client_time + rtt / 2.0),
+    }
+}
+
+/// Estimates over the last pongs, robust to the ones delayed by congestion
+#[wasm_bindgen]
+pub struct LatencyEstimator {
+    samples: VecDeque<LatencySample>,
+    window: usize,
+}
+
+impl Default for LatencyEstimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[wasm_bindgen]
+impl LatencyEstimator {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self {
+            samples: VecDeque::with_capacity(DEFAULT_WINDOW),
+            window: DEFAULT_WINDOW,
+        }
+    }
+
+    /// Builder method to set the number of pongs kept
+    #[wasm_bindgen(js_name = withWindow)]
+    pub fn with_window(mut self, window: usize) -> Self {
+        self.window = window.max(1);
+        self
+    }
+
+    /// Adds the `Pong` received at `received_at`, returns its own sample
+    #[wasm_bindgen(js_name = addPong)]
+    pub fn add_pong(
+        &mut self,
+        client_time: f64,
+        server_time: f64,
+        received_at: f64,
+    ) -> LatencySample {
+        let sample = latency_sample(client_time, server_time, received_at);
+        while self.samples.len() >= self.window {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+        sample
+    }
+
+    /// Median round trip time, None before the first pong
+    pub fn rtt(&self) -> Option<f64> {
+        let mut rtts: Vec<_> = self.samples.iter().map(|sample| sample.rtt).collect();
+        rtts.sort_by(f64::total_cmp);
+        let mid = rtts.len() / 2;
+        match rtts.len() {
+            0 => None,
+            len if len % 2 == 0 => Some((rtts[mid - 1] + rtts[mid]) / 2.0),
+            _ => Some(rtts[mid]),
+        }
+    }
+
+    /// Offset of the fastest round trip, the least skewed by queueing
+    #[wasm_bindgen(js_name = clockOffset)]
+    pub fn clock_offset(&self) -> Option<f64> {
+        self.samples
+            .iter()
+            .min_by(|a, b| a.rtt.total_cmp(&b.rtt))
+            .map(|sample| sample.clock_offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latency_estimator_test() {
+        // The server clock is 1000ms ahead, the one way latency 20ms
+        let sample = latency_sample(5000.0, 6020.0, 5040.0);
+        assert_eq!(sample.rtt, 40.0);
+        assert_eq!(sample.clock_offset, 1000.0);
+
+        let mut estimator = LatencyEstimator::new().with_window(3);
+        assert_eq!(estimator.rtt(), None);
+        estimator.add_pong(0.0, 1020.0, 40.0);
+        // Delayed on the way back
+        estimator.add_pong(100.0, 1120.0, 300.0);
+        estimator.add_pong(200.0, 1225.0, 250.0);
+        assert_eq!(estimator.rtt(), Some(50.0));
+        assert_eq!(estimator.clock_offset(), Some(1000.0));
+
+        // The first pong leaves the window
+        estimator.add_pong(300.0, 1340.0, 380.0);
+        assert_eq!(estimator.rtt(), Some(80.0));
+        assert_eq!(estimator.clock_offset(), Some(1000.0));
+    }
+}
@@ -1,97 +1,75 @@
-use std::io::Write;
+mod client;
+mod codec;
+mod decoder;
+mod fragment;
+mod latency;
+mod typed;
+mod worker;
 
-use flate2::{
-    write::{GzDecoder, GzEncoder},
-    Compression,
+pub use nbody::{
+    constraints::{Constraint, ConstraintKind},
+    physics::{Bodies, Body, BodyId, CollisionModel, ExternalForce},
+    quadtree::SquareBox,
+    scenarios::{Scenario, ScenarioKind},
+    simulation::{
+        AdaptiveTimestep, BoundaryCondition, Integrator, PhyiscsParameters, SolverParameters,
+    },
 };
-use nbody::physics::Body;
-use serde::{Deserialize, Serialize};
-use tsify::Tsify;
 use wasm_bindgen::prelude::*;
 
-#[derive(Serialize, Deserialize, Tsify, Debug)]
-#[tsify(from_wasm_abi, into_wasm_abi)]
-#[serde(rename_all = "camelCase")]
-pub enum ClientToServerMessage {
-    Subscribe,
-    AddBodies(Vec<Body>),
-    State,
-    Reset,
+pub use client::{ClientEvent, ConnectionStatus, WasmClient};
+pub use codec::{compress, compress_bytes, decompress, decompress_bytes};
+pub use decoder::ServerMsgDecoder;
+pub use fragment::FragmentAssembler;
+pub use latency::{latency_sample, LatencyEstimator, LatencySample};
+pub use protocol::{
+    check_version, decode, decode_with_limit, encode, encode_with, fragment, is_dictionary_frame,
+    read_message, split_frame, BodiesDelta, BodyDiff, ClientToServerMessage, Codec, CodecOptions,
+    DiagnosticsSample, ProtocolError, RecordedState, Role, RoomId, ServerToClientMessage,
+    Subprotocol, WireMessage, DICTIONARY_TAG, FRAGMENT_HEADER_SIZE, FRAGMENT_TAG,
+    MAX_DECOMPRESSED_SIZE, MAX_TICKS_PER_BUNDLE, PROTOCOL_VERSION,
+};
+pub use typed::{decode_state_into, StateSummary};
+pub use worker::{
+    read_worker_message, MessageTarget, WorkerChannel, WorkerMessage, WorkerMessageKind,
+};
+
+/// Installs the panic hook and the console logger (when built with the `console` feature)
+/// so that failures show up readably in the browser console
+/// Meant to be called once, right after loading the module
+#[wasm_bindgen]
+pub fn init() {
+    #[cfg(feature = "console")]
+    {
+        console_error_panic_hook::set_once();
+        // Only fails if a logger is already installed
+        let _ = console_log::init_with_level(log::Level::Debug);
+    }
 }
 
-#[derive(Serialize, Deserialize, Tsify, Debug)]
-#[tsify(from_wasm_abi, into_wasm_abi)]
-#[serde(rename_all = "camelCase")]
-pub enum ServerToClientMessage {
-    #[serde(rename_all = "camelCase")]
-    StateUpdate {
-        bodies: Vec<Body>,
-        physical_time: f64,
-        kinetic_energy: f64,
-    },
+/// Generates the initial conditions of a scenario locally
+/// The bodies can be fed to a `WasmSimulation` or sent to the server as a single `addBodies` message
+#[wasm_bindgen(js_name = generateScenario)]
+pub fn generate_scenario(scenario: Scenario, gravity_constant: f64) -> Bodies {
+    Bodies(scenario.generate(gravity_constant))
 }
 
 #[wasm_bindgen(js_name = serializeServerMsg)]
-pub fn serialize_server_msg(msg: ServerToClientMessage) -> Option<Vec<u8>> {
-    serialize_and_compress(msg)
+pub fn serialize_server_msg(msg: ServerToClientMessage) -> Result<Vec<u8>, JsError> {
+    Ok(encode(&msg)?)
 }
 
 #[wasm_bindgen(js_name = deserializeServerMsg)]
-pub fn deserialize_server_msg(msg: &[u8]) -> Option<ServerToClientMessage> {
-    decompress_and_deserialize(msg)
+pub fn deserialize_server_msg(msg: &[u8]) -> Result<ServerToClientMessage, JsError> {
+    Ok(decode(msg)?)
 }
 
 #[wasm_bindgen(js_name = serializeClientMsg)]
-pub fn serialize_client_msg(msg: ClientToServerMessage) -> Option<Vec<u8>> {
-    serialize_and_compress(msg)
+pub fn serialize_client_msg(msg: ClientToServerMessage) -> Result<Vec<u8>, JsError> {
+    Ok(encode(&msg)?)
 }
 
 #[wasm_bindgen(js_name = deserializeClientMsg)]
-pub fn deserialize_client_msg(msg: &[u8]) -> Option<ClientToServerMessage> {
-    decompress_and_deserialize(msg)
-}
-
-fn compress_data(data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let mut e = GzEncoder::new(Vec::new(), Compression::fast());
-    e.write_all(data)?;
-    Ok(e.finish()?)
-}
-
-fn decompress_data(data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let mut d = GzDecoder::new(Vec::new());
-    d.write_all(data)?;
-    Ok(d.finish()?)
-}
-
-fn serialize_and_compress<T: Serialize>(msg: T) -> Option<Vec<u8>> {
-    bincode::serialize(&msg)
-        .map(|data| compress_data(&data).ok())
-        .ok()
-        .flatten()
-}
-
-fn decompress_and_deserialize<T: for<'de> Deserialize<'de>>(data: &[u8]) -> Option<T> {
-    decompress_data(data)
-        .map(|msg| bincode::deserialize(&msg).ok())
-        .ok()
-        .flatten()
-}
-
-#[cfg(test)]
-mod tests {
-
-    use super::*;
-
-    #[test]
-    fn serialization_test() {
-        let bodies = vec![Body::default(); 10];
-        let serialized =
-            serialize_client_msg(ClientToServerMessage::AddBodies(bodies.clone())).unwrap();
-        let deserialized = deserialize_client_msg(&serialized).unwrap();
-
-        match deserialized {
-            ClientToServerMessage::AddBodies(bodies) => assert!(bodies.len() == 10),
-            _ => panic!("Expected Subscribe"),
-        };
-    }
+pub fn deserialize_client_msg(msg: &[u8]) -> Result<ClientToServerMessage, JsError> {
+    Ok(decode(msg)?)
 }
@@ -0,0 +1,236 @@
+use nbody::physics::Body;
+use protocol::flatbuffers::{StateUpdateView, FLATBUFFERS_TAG};
+use wasm_bindgen::prelude::*;
+
+use crate::{decode, ServerMsgDecoder, ServerToClientMessage};
+
+/// Scalars of a `StateUpdate` decoded into typed arrays
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StateSummary {
+    #[wasm_bindgen(js_name = physicalTime)]
+    pub physical_time: f64,
+
+    #[wasm_bindgen(js_name = kineticEnergy)]
+    pub kinetic_energy: f64,
+
+    /// The total energy being `kineticEnergy + potentialEnergy`
+    #[wasm_bindgen(js_name = potentialEnergy)]
+    pub potential_energy: f64,
+
+    #[wasm_bindgen(js_name = linearMomentumX)]
+    pub linear_momentum_x: f64,
+
+    #[wasm_bindgen(js_name = linearMomentumY)]
+    pub linear_momentum_y: f64,
+
+    #[wasm_bindgen(js_name = angularMomentum)]
+    pub angular_momentum: f64,
+
+    /// Number of bodies in the frame, may exceed the number written
+    /// when the arrays were too small (grow them and keep going with the next frame)
+    pub count: usize,
+}
+
+/// Decodes a `StateUpdate` frame straight into the given arrays, without creating any JS `Body`
+/// - positions: [x0, y0, x1, y1, ...]
+/// - radii: [r0, r1, ...]
+/// - colors: [r0, g0, b0, a0, r1, ...] normalized to [0, 1]
+///
+/// FlatBuffers frames are read in place, without decoding the whole message
+#[wasm_bindgen(js_name = decodeStateInto)]
+pub fn decode_state_into(
+    frame: &[u8],
+    positions: &mut [f32],
+    radii: &mut [f32],
+    colors: &mut [f32],
+) -> Result<StateSummary, JsError> {
+    if frame.first() == Some(&FLATBUFFERS_TAG) {
+        let view = StateUpdateView::from_frame(frame)?;
+        return Ok(write_view(&view, positions, radii, colors));
+    }
+    let msg = decode(frame)?;
+    write_state(&msg, positions, radii, colors)
+}
+
+#[wasm_bindgen]
+impl ServerMsgDecoder {
+    /// Same as `decodeStateInto` but reusing the buffers of the decoder
+    #[wasm_bindgen(js_name = decodeStateInto)]
+    pub fn decode_state_into(
+        &mut self,
+        frame: &[u8],
+        positions: &mut [f32],
+        radii: &mut [f32],
+        colors: &mut [f32],
+    ) -> Result<StateSummary, JsError> {
+        if frame.first() == Some(&FLATBUFFERS_TAG) {
+            // Nothing to decompress nor to deserialize
+            return decode_state_into(frame, positions, radii, colors);
+        }
+        let msg = self.decode_as(frame)?;
+        write_state(&msg, positions, radii, colors)
+    }
+}
+
+fn write_state(
+    msg: &ServerToClientMessage,
+    positions: &mut [f32],
+    radii: &mut [f32],
+    colors: &mut [f32],
+) -> Result<StateSummary, JsError> {
+    let ServerToClientMessage::StateUpdate {
+        bodies,
+        physical_time,
+        kinetic_energy,
+        potential_energy,
+        linear_momentum,
+        angular_momentum,
+    } = msg
+    else {
+        return Err(JsError::new("not a StateUpdate frame"));
+    };
+    write_bodies(bodies, positions, radii, colors);
+    Ok(StateSummary {
+        physical_time: *physical_time,
+        kinetic_energy: *kinetic_energy,
+        potential_energy: *potential_energy,
+        linear_momentum_x: linear_momentum[0],
+        linear_momentum_y: linear_momentum[1],
+        angular_momentum: *angular_momentum,
+        count: bodies.len(),
+    })
+}
+
+fn write_view(
+    view: &StateUpdateView,
+    positions: &mut [f32],
+    radii: &mut [f32],
+    colors: &mut [f32],
+) -> StateSummary {
+    for (value, position) in view.positions().iter().zip(positions.iter_mut()) {
+        *position = value as f32;
+    }
+    for (value, radius) in view.radii().iter().zip(radii.iter_mut()) {
+        *radius = value as f32;
+    }
+    for (value, color) in view.colors().iter().zip(colors.chunks_exact_mut(4)) {
+        for (channel, value) in color.iter_mut().zip(value.to_be_bytes()) {
+            *channel = value as f32 / 255.0;
+        }
+    }
+    let [linear_momentum_x, linear_momentum_y] = view.linear_momentum();
+    StateSummary {
+        physical_time: view.physical_time(),
+        kinetic_energy: view.kinetic_energy(),
+        potential_energy: view.potential_energy(),
+        linear_momentum_x,
+        linear_momentum_y,
+        angular_momentum: view.angular_momentum(),
+        count: view.len(),
+    }
+}
+
+fn write_bodies(bodies: &[Body], positions: &mut [f32], radii: &mut [f32], colors: &mut [f32]) {
+    for (body, position) in bodies.iter().zip(positions.chunks_exact_mut(2)) {
+        position[0] = body.position[0] as f32;
+        position[1] = body.position[1] as f32;
+    }
+    for (body, radius) in bodies.iter().zip(radii.iter_mut()) {
+        *radius = body.radius as f32;
+    }
+    for (body, color) in bodies.iter().zip(colors.chunks_exact_mut(4)) {
+        for (channel, value) in color.iter_mut().zip(body.color) {
+            *channel = value as f32 / 255.0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode;
+
+    fn state_update(bodies: Vec<Body>) -> Vec<u8> {
+        encode(&ServerToClientMessage::StateUpdate {
+            bodies,
+            physical_time: 1.5,
+            kinetic_energy: 3.0,
+            potential_energy: -4.0,
+            linear_momentum: [0.5, -0.5],
+            angular_momentum: 2.0,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn decode_state_into_test() {
+        let bodies: Vec<Body> = (0..3)
+            .map(|i| Body {
+                radius: 2.0,
+                color: [255, 0, 51, 255],
+                ..Body::default().with_position([i as f64, -(i as f64)])
+            })
+            .collect();
+        let frame = state_update(bodies);
+
+        let (mut positions, mut radii, mut colors) = (vec![0.0; 6], vec![0.0; 3], vec![0.0; 12]);
+        let summary = ServerMsgDecoder::new()
+            .decode_state_into(&frame, &mut positions, &mut radii, &mut colors)
+            .unwrap();
+        assert_eq!(
+            summary,
+            StateSummary {
+                physical_time: 1.5,
+                kinetic_energy: 3.0,
+                potential_energy: -4.0,
+                linear_momentum_x: 0.5,
+                linear_momentum_y: -0.5,
+                angular_momentum: 2.0,
+                count: 3
+            }
+        );
+        assert_eq!(positions, [0.0, 0.0, 1.0, -1.0, 2.0, -2.0]);
+        assert_eq!(radii, [2.0; 3]);
+        assert_eq!(colors[4..8], [1.0, 0.0, 0.2, 1.0]);
+
+        // Arrays too small: only the first bodies are written
+        let (mut positions, mut radii, mut colors) = (vec![0.0; 2], vec![0.0; 1], vec![0.0; 4]);
+        let summary = decode_state_into(&frame, &mut positions, &mut radii, &mut colors).unwrap();
+        assert_eq!(summary.count, 3);
+        assert_eq!(positions, [0.0, 0.0]);
+    }
+
+    #[test]
+    fn decode_flatbuffers_state_into_test() {
+        let msg = ServerToClientMessage::StateUpdate {
+            bodies: (0..3)
+                .map(|i| Body {
+                    color: [255, 0, 51, 255],
+                    ..Body::default().with_position([i as f64, -(i as f64)])
+                })
+                .collect(),
+            physical_time: 1.5,
+            kinetic_energy: 3.0,
+            potential_energy: -4.0,
+            linear_momentum: [0.5, -0.5],
+            angular_momentum: 2.0,
+        };
+        let frames = [
+            encode(&msg).unwrap(),
+            protocol::flatbuffers::encode_state_update(&msg).unwrap(),
+        ];
+
+        let mut decoder = ServerMsgDecoder::new();
+        let mut decoded = Vec::new();
+        for frame in &frames {
+            let (mut positions, mut radii, mut colors) =
+                (vec![0.0; 6], vec![0.0; 3], vec![0.0; 12]);
+            let summary = decoder
+                .decode_state_into(frame, &mut positions, &mut radii, &mut colors)
+                .unwrap();
+            decoded.push((summary, positions, radii, colors));
+        }
+        assert_eq!(decoded[0], decoded[1]);
+    }
+}
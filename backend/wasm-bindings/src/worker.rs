@@ -0,0 +1,158 @@
+//! Glue between a worker owning the `WasmClient` (or a `WasmSimulation`) and the main thread
+//!
+//! Messages are posted as `{ type, frame }`, where `frame` is an uncompressed frame
+//! of the wire protocol in an ArrayBuffer transferred to the other side (not copied):
+//!
+//! ```js
+//! // worker.js
+//! const channel = new WorkerChannel(self);
+//! client.onEvent(event => event.message && channel.postServerMessage(event.message));
+//! self.onmessage = ({ data }) => client.send(readWorkerMessage(data).clientMessage);
+//!
+//! // main thread
+//! const channel = new WorkerChannel(worker);
+//! worker.onmessage = ({ data }) => render(readWorkerMessage(data).serverMessage);
+//! ```
+
+use js_sys::{Array, Object, Reflect, Uint8Array};
+use serde::{Deserialize, Serialize};
+use tsify::Tsify;
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    decode, encode_with, ClientToServerMessage, CodecOptions, ProtocolError, ServerToClientMessage,
+    WireMessage,
+};
+
+#[wasm_bindgen]
+extern "C" {
+    /// Anything with `postMessage(message, transfer)`
+    #[wasm_bindgen(typescript_type = "Worker | DedicatedWorkerGlobalScope | MessagePort")]
+    pub type MessageTarget;
+
+    #[wasm_bindgen(method, catch, js_name = postMessage)]
+    fn post_message(
+        this: &MessageTarget,
+        message: &JsValue,
+        transfer: &Array,
+    ) -> Result<(), JsValue>;
+}
+
+/// The `type` of the posted objects
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WorkerMessageKind {
+    /// To be sent to the server by the worker owning the connection
+    ClientMessage = "clientMessage",
+
+    /// Received from the server (or produced by a local simulation)
+    ServerMessage = "serverMessage",
+}
+
+/// A message posted by a `WorkerChannel`, as read on the other side
+#[derive(Serialize, Deserialize, Tsify, Debug)]
+#[tsify(into_wasm_abi)]
+#[serde(rename_all = "camelCase")]
+pub enum WorkerMessage {
+    ClientMessage(ClientToServerMessage),
+    ServerMessage(ServerToClientMessage),
+}
+
+/// Posts the protocol messages to a worker, or from a worker to the main thread
+#[wasm_bindgen]
+pub struct WorkerChannel {
+    target: MessageTarget,
+}
+
+#[wasm_bindgen]
+impl WorkerChannel {
+    #[wasm_bindgen(constructor)]
+    pub fn new(target: MessageTarget) -> Self {
+        Self { target }
+    }
+
+    #[wasm_bindgen(js_name = postClientMessage)]
+    pub fn post_client_message(&self, msg: ClientToServerMessage) -> Result<(), JsValue> {
+        self.post(WorkerMessageKind::ClientMessage, &msg)
+    }
+
+    #[wasm_bindgen(js_name = postServerMessage)]
+    pub fn post_server_message(&self, msg: ServerToClientMessage) -> Result<(), JsValue> {
+        self.post(WorkerMessageKind::ServerMessage, &msg)
+    }
+}
+
+impl WorkerChannel {
+    fn post<T: WireMessage>(&self, kind: WorkerMessageKind, msg: &T) -> Result<(), JsValue> {
+        let frame = encode_for_worker(msg).map_err(JsError::from)?;
+        let buffer = Uint8Array::from(frame.as_slice()).buffer();
+        let envelope = Object::new();
+        Reflect::set(&envelope, &"type".into(), &kind.to_str().into())?;
+        Reflect::set(&envelope, &"frame".into(), &buffer)?;
+        self.target.post_message(&envelope, &Array::of1(&buffer))
+    }
+}
+
+/// Decodes the `data` of a `MessageEvent` posted by a `WorkerChannel`
+#[wasm_bindgen(js_name = readWorkerMessage)]
+pub fn read_worker_message(data: JsValue) -> Result<WorkerMessage, JsError> {
+    let kind = Reflect::get(&data, &"type".into())
+        .ok()
+        .and_then(|kind| WorkerMessageKind::from_js_value(&kind))
+        .ok_or_else(|| JsError::new("not a message of a WorkerChannel"))?;
+    let frame = Reflect::get(&data, &"frame".into()).map_err(|_| JsError::new("missing frame"))?;
+    Ok(read_frame(kind, &Uint8Array::new(&frame).to_vec())?)
+}
+
+/// Both sides share the memory of the machine, compressing would only waste time
+fn encode_for_worker<T: WireMessage>(msg: &T) -> Result<Vec<u8>, ProtocolError> {
+    encode_with(msg, &CodecOptions::new().with_threshold(usize::MAX))
+}
+
+fn read_frame(kind: WorkerMessageKind, frame: &[u8]) -> Result<WorkerMessage, ProtocolError> {
+    match kind {
+        WorkerMessageKind::ClientMessage => decode(frame).map(WorkerMessage::ClientMessage),
+        WorkerMessageKind::ServerMessage => decode(frame).map(WorkerMessage::ServerMessage),
+        // Never produced by the bindings
+        _ => Err(ProtocolError::UnknownMessage(None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Body, Codec};
+
+    #[test]
+    fn worker_frame_test() {
+        let msg = ServerToClientMessage::StateUpdate {
+            bodies: vec![Body::default(); 100],
+            physical_time: 2.0,
+            kinetic_energy: 0.0,
+            potential_energy: 0.0,
+            linear_momentum: [0.0, 0.0],
+            angular_momentum: 0.0,
+        };
+        let frame = encode_for_worker(&msg).unwrap();
+        assert_eq!(frame[1], Codec::None as u8);
+        assert!(matches!(
+            read_frame(WorkerMessageKind::ServerMessage, &frame),
+            Ok(WorkerMessage::ServerMessage(ServerToClientMessage::StateUpdate { bodies, .. }))
+                if bodies.len() == 100
+        ));
+
+        let frame = encode_for_worker(&ClientToServerMessage::Reset).unwrap();
+        assert!(matches!(
+            read_frame(WorkerMessageKind::ClientMessage, &frame),
+            Ok(WorkerMessage::ClientMessage(ClientToServerMessage::Reset))
+        ));
+        // Posted with the wrong type
+        assert!(read_frame(WorkerMessageKind::ServerMessage, &frame).is_err());
+
+        assert_eq!(
+            WorkerMessageKind::from_str("serverMessage"),
+            Some(WorkerMessageKind::ServerMessage)
+        );
+        assert_eq!(WorkerMessageKind::ClientMessage.to_str(), "clientMessage");
+    }
+}
@@ -0,0 +1,1020 @@
+mod simulation3d;
+
+pub use nbody::physics::Bodies;
+use nbody::{
+    constraints::Constraint,
+    determinism::DeterministicMode,
+    physics::{Body, BodyId, CollisionEvent, ExternalForce, Tracer},
+    simulation::{
+        BoundaryCondition, PhyiscsParameters, Simulation, SimulationSnapshot, SolverParameters,
+    },
+    timeline::Timeline,
+};
+pub use simulation3d::WasmSimulation3;
+use std::collections::VecDeque;
+use wasm_bindgen::prelude::*;
+
+/// Number of values stored per node by `WasmSimulation::quadtree_boxes`
+const QUADTREE_BOX_STRIDE: usize = 6;
+
+/// Number of values stored per event by `WasmSimulation::drain_collision_events`
+const COLLISION_EVENT_STRIDE: usize = 5;
+
+/// Number of values returned per point by `WasmSimulation::sample_field`
+const FIELD_SAMPLE_STRIDE: usize = 3;
+
+/// Installs the panic hook and the console logger (when built with the `console` feature)
+/// so that failures show up readably in the browser console
+/// Meant to be called once, right after loading the module
+#[wasm_bindgen]
+pub fn init() {
+    #[cfg(feature = "console")]
+    {
+        console_error_panic_hook::set_once();
+        // Only fails if a logger is already installed
+        let _ = console_log::init_with_level(log::Level::Debug);
+    }
+}
+
+/// Spawns the Web Workers used by the parallel engine
+/// (to be awaited once, only when `supportsThreads()` is true)
+#[cfg(all(feature = "threads", target_arch = "wasm32"))]
+pub use wasm_bindgen_rayon::init_thread_pool;
+
+/// Whether the parallel engine can be used: built with the `threads` feature
+/// and running in a cross-origin isolated page where SharedArrayBuffer is available
+#[wasm_bindgen(js_name = supportsThreads)]
+pub fn supports_threads() -> bool {
+    if !cfg!(feature = "threads") {
+        return false;
+    }
+    let global = js_sys::global();
+    let isolated = js_sys::Reflect::get(&global, &JsValue::from_str("crossOriginIsolated"))
+        .is_ok_and(|value| value.is_truthy());
+    let shared_memory =
+        js_sys::Reflect::has(&global, &JsValue::from_str("SharedArrayBuffer")).unwrap_or(false);
+    isolated && shared_memory
+}
+
+/// Browser-local simulation engine
+///
+/// Body positions (and velocities, masses, radii and colors) are mirrored into f32 buffers
+/// living in the wasm memory
+/// so that the renderer can read them in bulk instead of calling a getter per body
+///
+/// The typed arrays returned by the buffer getters are views over the wasm memory (no copy).
+/// A view must be considered invalid, and requested again, after any call that adds bodies or tracers
+/// (the buffer may be reallocated) or that grows the wasm memory (the underlying
+/// ArrayBuffer is detached and the view becomes empty). Views are read-only by contract:
+/// writes from JavaScript are overwritten on the next step
+#[wasm_bindgen]
+pub struct WasmSimulation {
+    simulation: Simulation,
+    x_positions: Vec<f32>,
+    y_positions: Vec<f32>,
+
+    /// Positions before the last step, so that the renderer can interpolate
+    /// between two physics steps (same as the current ones after adding or removing bodies)
+    previous_x_positions: Vec<f32>,
+    previous_y_positions: Vec<f32>,
+
+    /// Positions laid out as [x0, y0, x1, y1, ...] (as expected by WebGL instanced rendering)
+    /// Only kept up to date when enabled
+    interleaved_positions: Option<Vec<f32>>,
+
+    /// Accelerations due to gravity during the last step, laid out as [ax0, ay0, ax1, ay1, ...]
+    accelerations: Vec<f32>,
+
+    /// Laid out as [vx0, vy0, vx1, vy1, ...]
+    velocities: Vec<f32>,
+    masses: Vec<f32>,
+    radii: Vec<f32>,
+
+    /// Laid out as [r0, g0, b0, a0, r1, ...], from 0 to 1 (as expected by WebGL vertex colors)
+    colors: Vec<f32>,
+
+    /// Tracer positions laid out as [x0, y0, x1, y1, ...]
+    tracer_positions: Vec<f32>,
+
+    /// Collisions resolved since the last drain
+    /// Only recorded when enabled
+    pending_collisions: Option<Vec<CollisionEvent>>,
+}
+
+impl Default for WasmSimulation {
+    fn default() -> Self {
+        Self {
+            simulation: Simulation::new(),
+            x_positions: Vec::new(),
+            y_positions: Vec::new(),
+            previous_x_positions: Vec::new(),
+            previous_y_positions: Vec::new(),
+            interleaved_positions: None,
+            accelerations: Vec::new(),
+            velocities: Vec::new(),
+            masses: Vec::new(),
+            radii: Vec::new(),
+            colors: Vec::new(),
+            tracer_positions: Vec::new(),
+            pending_collisions: None,
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl WasmSimulation {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        WasmSimulation::default()
+    }
+
+    #[wasm_bindgen(js_name = addBody)]
+    pub fn add_body(&mut self, x: f64, y: f64, mass: f64) {
+        self.simulation
+            .add_body(Body::default().with_position([x, y]).with_mass(mass));
+        self.sync_buffers();
+        self.keep_previous_positions();
+    }
+
+    /// Adds a body with its full state (velocity, radius, color...)
+    #[wasm_bindgen(js_name = addFullBody)]
+    pub fn add_full_body(&mut self, body: Body) {
+        self.simulation.add_body(body);
+        self.sync_buffers();
+        self.keep_previous_positions();
+    }
+
+    /// Adds several bodies at once (e.g. a whole scenario)
+    /// cheaper than adding them one by one as the buffers are synchronized only once
+    #[wasm_bindgen(js_name = addBodies)]
+    pub fn add_bodies(&mut self, bodies: Bodies) {
+        self.simulation.add_bodies(bodies.0);
+        self.sync_buffers();
+        self.keep_previous_positions();
+    }
+
+    /// Removes the body at the given index, compacting the shared buffers
+    /// (the bodies after it are shifted down by one index)
+    /// Returns false if there is no body at that index
+    #[wasm_bindgen(js_name = removeBody)]
+    pub fn remove_body(&mut self, body_idx: usize) -> bool {
+        let Some(&id) = self.simulation.bodies().ids().get(body_idx) else {
+            log::warn!("removeBody: there is no body at index {body_idx}");
+            return false;
+        };
+        self.simulation.remove_body(id);
+        self.sync_buffers();
+        self.keep_previous_positions();
+        true
+    }
+
+    /// Removes the bodies at the given indices at once (invalid ones are skipped)
+    /// Returns the number of bodies removed
+    #[wasm_bindgen(js_name = removeBodies)]
+    pub fn remove_bodies(&mut self, indices: &[u32]) -> usize {
+        let bodies = self.simulation.bodies();
+        let ids: Vec<BodyId> = indices
+            .iter()
+            .filter_map(|&index| bodies.ids().get(index as usize).copied())
+            .collect();
+        let removed = self.simulation.remove_bodies(&ids).len();
+        self.sync_buffers();
+        self.keep_previous_positions();
+        removed
+    }
+
+    /// Index of the body under the point (e.g. the one clicked), if any
+    #[wasm_bindgen(js_name = bodyAt)]
+    pub fn body_at(&self, x: f64, y: f64) -> Option<usize> {
+        let id = self.simulation.body_at([x, y])?;
+        self.simulation.body_index(id)
+    }
+
+    pub fn step(&mut self) {
+        self.keep_previous_positions();
+        self.simulation.step();
+        self.record_collisions();
+        self.sync_buffers();
+    }
+
+    /// Advances the simulation `n` steps (e.g. to catch up after the tab was in the background)
+    /// The shared buffers are synchronized only once, after the last step
+    #[wasm_bindgen(js_name = stepMany)]
+    pub fn step_many(&mut self, n: u32) {
+        for i in 0..n {
+            if i + 1 == n {
+                // Only the last step is interpolated
+                fill_positions(
+                    &self.simulation,
+                    &mut self.previous_x_positions,
+                    &mut self.previous_y_positions,
+                );
+            }
+            self.simulation.step();
+            self.record_collisions();
+        }
+        self.sync_buffers();
+    }
+
+    /// Advances the simulation by `dt` seconds without changing the solver parameters
+    /// (e.g. smaller values for slow motion), a negative or non-finite dt is ignored
+    #[wasm_bindgen(js_name = stepDt)]
+    pub fn step_dt(&mut self, dt: f64) {
+        self.keep_previous_positions();
+        self.simulation.step_with_dt(dt);
+        self.record_collisions();
+        self.sync_buffers();
+    }
+
+    #[wasm_bindgen(js_name = setSolverParameters)]
+    pub fn set_solver_parameters(&mut self, parameters: SolverParameters) {
+        self.simulation.set_solver_parameters(parameters);
+    }
+
+    #[wasm_bindgen(js_name = setPhysicsParameters)]
+    pub fn set_physics_parameters(&mut self, parameters: PhyiscsParameters) {
+        self.simulation.set_physics_parameters(parameters);
+    }
+
+    #[wasm_bindgen(js_name = setBoundaryCondition)]
+    pub fn set_boundary_condition(&mut self, boundary: BoundaryCondition) {
+        self.simulation.set_boundary_condition(boundary);
+    }
+
+    /// Adds a field acting on the bodies besides their gravity (e.g. a uniform gravity or a drag)
+    #[wasm_bindgen(js_name = addExternalForce)]
+    pub fn add_external_force(&mut self, force: ExternalForce) {
+        self.simulation.add_external_force(force);
+    }
+
+    #[wasm_bindgen(js_name = clearExternalForces)]
+    pub fn clear_external_forces(&mut self) {
+        self.simulation.clear_external_forces();
+    }
+
+    /// Links two bodies with a spring or a rod, replacing the constraint between them if any
+    #[wasm_bindgen(js_name = addConstraint)]
+    pub fn add_constraint(&mut self, constraint: Constraint) -> Result<(), JsError> {
+        Ok(self.simulation.add_constraint(constraint)?)
+    }
+
+    /// False if the bodies were not linked
+    #[wasm_bindgen(js_name = removeConstraint)]
+    pub fn remove_constraint(&mut self, a: BodyId, b: BodyId) -> bool {
+        self.simulation.remove_constraint(a, b)
+    }
+
+    /// Scripted events applied as the physical time reaches them (e.g. parsed from a JSON file)
+    /// Bodies they spawn appear in the buffers after the step that applied them
+    #[wasm_bindgen(js_name = setTimeline)]
+    pub fn set_timeline(&mut self, timeline: Timeline) {
+        self.simulation.set_timeline(timeline);
+    }
+
+    /// Seeds the simulation and rebuilds its quadtree from scratch at each step,
+    /// so that `stateHash` matches other runs from the same state (None to turn it off)
+    #[wasm_bindgen(js_name = setDeterministicMode)]
+    pub fn set_deterministic_mode(&mut self, mode: Option<DeterministicMode>) {
+        self.simulation.set_deterministic_mode(mode);
+    }
+
+    /// Fingerprint of the bodies, tracers and time, to check a run against another
+    #[wasm_bindgen(js_name = stateHash)]
+    pub fn state_hash(&self) -> u64 {
+        self.simulation.state_hash()
+    }
+
+    /// Removes all the bodies and releases the memory held by the shared buffers
+    /// (any previously returned view is invalidated)
+    pub fn reset(&mut self) {
+        self.simulation.reset();
+        self.x_positions = Vec::new();
+        self.y_positions = Vec::new();
+        self.previous_x_positions = Vec::new();
+        self.previous_y_positions = Vec::new();
+        if self.interleaved_positions.is_some() {
+            self.interleaved_positions = Some(Vec::new());
+        }
+        self.accelerations = Vec::new();
+        self.velocities = Vec::new();
+        self.masses = Vec::new();
+        self.radii = Vec::new();
+        self.colors = Vec::new();
+        self.tracer_positions = Vec::new();
+        if let Some(pending) = self.pending_collisions.as_mut() {
+            pending.clear();
+        }
+    }
+
+    /// Serializes the whole simulation (bodies, parameters, time) into a compact buffer
+    /// whose `.buffer` can be transferred between workers (no structured clone)
+    /// Same format as the snapshots persisted by the server
+    #[wasm_bindgen(js_name = exportState)]
+    pub fn export_state(&self) -> Result<Vec<u8>, JsError> {
+        Ok(self.simulation.snapshot().to_bytes()?)
+    }
+
+    /// Replaces the whole simulation with a state produced by `exportState`
+    /// or by the server persistence layer
+    #[wasm_bindgen(js_name = importState)]
+    pub fn import_state(&mut self, state: &[u8]) -> Result<(), JsError> {
+        self.simulation
+            .restore(SimulationSnapshot::from_bytes(state)?);
+        self.sync_buffers();
+        self.keep_previous_positions();
+        Ok(())
+    }
+
+    /// Continues locally from the content of a server `StateUpdate`
+    /// (e.g. when going offline), keeping the local parameters
+    #[wasm_bindgen(js_name = importServerState)]
+    pub fn import_server_state(&mut self, bodies: Bodies, physical_time: f64) {
+        self.simulation.restore_bodies(bodies.0, physical_time);
+        self.sync_buffers();
+        self.keep_previous_positions();
+    }
+
+    #[wasm_bindgen(js_name = getNumberOfBodies)]
+    pub fn get_number_of_bodies(&self) -> usize {
+        self.simulation.get_number_of_bodies()
+    }
+
+    #[wasm_bindgen(js_name = getPhysicalTime)]
+    pub fn get_physical_time(&self) -> f64 {
+        self.simulation.get_physical_time()
+    }
+
+    /// Time step of the last step, which varies with an adaptive time step
+    #[wasm_bindgen(js_name = getLastDt)]
+    pub fn get_last_dt(&self) -> f64 {
+        self.simulation.get_last_dt()
+    }
+
+    /// View over the x coordinates (one f32 per body)
+    #[wasm_bindgen(js_name = xPositions)]
+    pub fn x_positions(&self) -> js_sys::Float32Array {
+        // SAFETY: see the invalidation rules documented on `WasmSimulation`
+        unsafe { js_sys::Float32Array::view(&self.x_positions) }
+    }
+
+    /// View over the y coordinates (one f32 per body)
+    #[wasm_bindgen(js_name = yPositions)]
+    pub fn y_positions(&self) -> js_sys::Float32Array {
+        // SAFETY: see the invalidation rules documented on `WasmSimulation`
+        unsafe { js_sys::Float32Array::view(&self.y_positions) }
+    }
+
+    /// View over the x coordinates before the last step (one f32 per body)
+    #[wasm_bindgen(js_name = previousXPositions)]
+    pub fn previous_x_positions(&self) -> js_sys::Float32Array {
+        // SAFETY: see the invalidation rules documented on `WasmSimulation`
+        unsafe { js_sys::Float32Array::view(&self.previous_x_positions) }
+    }
+
+    /// View over the y coordinates before the last step (one f32 per body)
+    #[wasm_bindgen(js_name = previousYPositions)]
+    pub fn previous_y_positions(&self) -> js_sys::Float32Array {
+        // SAFETY: see the invalidation rules documented on `WasmSimulation`
+        unsafe { js_sys::Float32Array::view(&self.previous_y_positions) }
+    }
+
+    /// View over the accelerations due to gravity during the last step (two f32 per body)
+    /// e.g. to draw force arrows or color the bodies by acceleration
+    pub fn accelerations(&self) -> js_sys::Float32Array {
+        // SAFETY: see the invalidation rules documented on `WasmSimulation`
+        unsafe { js_sys::Float32Array::view(&self.accelerations) }
+    }
+
+    /// View over the velocities (two f32 per body), e.g. to color the bodies by speed
+    pub fn velocities(&self) -> js_sys::Float32Array {
+        // SAFETY: see the invalidation rules documented on `WasmSimulation`
+        unsafe { js_sys::Float32Array::view(&self.velocities) }
+    }
+
+    /// View over the masses (one f32 per body)
+    pub fn masses(&self) -> js_sys::Float32Array {
+        // SAFETY: see the invalidation rules documented on `WasmSimulation`
+        unsafe { js_sys::Float32Array::view(&self.masses) }
+    }
+
+    /// View over the radii (one f32 per body)
+    pub fn radii(&self) -> js_sys::Float32Array {
+        // SAFETY: see the invalidation rules documented on `WasmSimulation`
+        unsafe { js_sys::Float32Array::view(&self.radii) }
+    }
+
+    /// View over the normalized rgba colors (four f32 per body)
+    pub fn colors(&self) -> js_sys::Float32Array {
+        // SAFETY: see the invalidation rules documented on `WasmSimulation`
+        unsafe { js_sys::Float32Array::view(&self.colors) }
+    }
+
+    /// Interpolation factor between the previous (0) and the current (1) positions
+    /// given the time accumulated since the last step and the time between two steps
+    /// i.e. `position = previous + alpha * (current - previous)`
+    pub fn alpha(elapsed_since_step: f64, step_interval: f64) -> f32 {
+        if step_interval <= 0.0 {
+            return 1.0;
+        }
+        (elapsed_since_step / step_interval).clamp(0.0, 1.0) as f32
+    }
+
+    /// The nodes of the current Barnes-Hut quadtree as a flat array
+    /// with 6 values per node: [x_min, y_min, x_max, y_max, mass, depth]
+    /// Nodes are listed breadth-first starting from the root (depth 0)
+    #[wasm_bindgen(js_name = quadtreeBoxes)]
+    pub fn quadtree_boxes(&self) -> Vec<f32> {
+        let nodes = self.simulation.quadtree().get_nodes();
+        let mut boxes = Vec::with_capacity(nodes.len() * QUADTREE_BOX_STRIDE);
+
+        let mut deque: VecDeque<(usize, usize)> = vec![(0, 0)].into();
+        while let Some((depth, node_idx)) = deque.pop_front() {
+            let node = &nodes[node_idx];
+            let boundary = node.boundary();
+            boxes.extend([
+                boundary.x_min() as f32,
+                boundary.y_min() as f32,
+                boundary.x_max() as f32,
+                boundary.y_max() as f32,
+                node.mass() as f32,
+                depth as f32,
+            ]);
+            if !node.is_leaf() {
+                let first_idx = node.children_idx();
+                deque.extend((first_idx..first_idx + 4).map(|child| (depth + 1, child)));
+            }
+        }
+        boxes
+    }
+
+    /// Enables (or disables) recording the collisions resolved on every step
+    /// so that the renderer can trigger effects (sounds, particles...) for them
+    #[wasm_bindgen(js_name = setCollisionEvents)]
+    pub fn set_collision_events(&mut self, enabled: bool) {
+        self.pending_collisions = enabled.then(Vec::new);
+    }
+
+    /// Returns and clears the collisions recorded since the last call as a flat array
+    /// with 5 values per collision: [ith, jth, x, y, impact_speed]
+    /// where (x, y) is the contact point
+    #[wasm_bindgen(js_name = drainCollisionEvents)]
+    pub fn drain_collision_events(&mut self) -> Vec<f64> {
+        let Some(pending) = self.pending_collisions.as_mut() else {
+            return Vec::new();
+        };
+        let mut events = Vec::with_capacity(pending.len() * COLLISION_EVENT_STRIDE);
+        for event in pending.drain(..) {
+            events.extend([
+                event.ith as f64,
+                event.jth as f64,
+                event.position[0],
+                event.position[1],
+                event.impact_speed,
+            ]);
+        }
+        events
+    }
+
+    /// Samples the gravity field at the given points [x0, y0, x1, y1, ...]
+    /// Returns 3 values per point: [fx, fy, potential], per unit of mass
+    /// (e.g. for vector-field overlays or the orbital velocity of a body about to be spawned)
+    #[wasm_bindgen(js_name = sampleField)]
+    pub fn sample_field(&self, points: &[f64]) -> Vec<f64> {
+        let mut samples = Vec::with_capacity(points.len() / 2 * FIELD_SAMPLE_STRIDE);
+        for point in points.chunks_exact(2) {
+            let sample = self.simulation.field_at([point[0], point[1]]);
+            samples.extend([sample.force[0], sample.force[1], sample.potential]);
+        }
+        samples
+    }
+
+    /// Adds massless tracers at rest at the given points [x0, y0, x1, y1, ...]
+    /// They follow the gravity field and the external forces without affecting the bodies
+    /// nor colliding, cheap enough for tens of thousands of them (e.g. to draw the flow)
+    #[wasm_bindgen(js_name = addTracers)]
+    pub fn add_tracers(&mut self, points: &[f64]) {
+        self.simulation.add_tracers(
+            points
+                .chunks_exact(2)
+                .map(|point| Tracer::new([point[0], point[1]])),
+        );
+        self.sync_buffers();
+    }
+
+    #[wasm_bindgen(js_name = clearTracers)]
+    pub fn clear_tracers(&mut self) {
+        self.simulation.clear_tracers();
+        self.sync_buffers();
+    }
+
+    #[wasm_bindgen(js_name = getNumberOfTracers)]
+    pub fn get_number_of_tracers(&self) -> usize {
+        self.simulation.get_number_of_tracers()
+    }
+
+    /// View over the tracer positions (two f32 per tracer)
+    #[wasm_bindgen(js_name = tracerPositions)]
+    pub fn tracer_positions(&self) -> js_sys::Float32Array {
+        // SAFETY: see the invalidation rules documented on `WasmSimulation`
+        unsafe { js_sys::Float32Array::view(&self.tracer_positions) }
+    }
+
+    /// Enables (or disables) keeping the interleaved position buffer up to date
+    #[wasm_bindgen(js_name = setInterleavedPositions)]
+    pub fn set_interleaved_positions(&mut self, enabled: bool) {
+        self.interleaved_positions = enabled.then(Vec::new);
+        self.sync_buffers();
+    }
+
+    /// View over the interleaved positions (two f32 per body)
+    /// Undefined unless enabled through `setInterleavedPositions`
+    #[wasm_bindgen(js_name = interleavedPositions)]
+    pub fn interleaved_positions(&self) -> Option<js_sys::Float32Array> {
+        self.interleaved_positions
+            .as_ref()
+            // SAFETY: see the invalidation rules documented on `WasmSimulation`
+            .map(|buffer| unsafe { js_sys::Float32Array::view(buffer) })
+    }
+
+    /// Records the last `length` positions of every body after each step (0 stops recording)
+    #[wasm_bindgen(js_name = setTrailLength)]
+    pub fn set_trail_length(&mut self, length: usize) {
+        self.simulation.enable_trails(length);
+    }
+
+    #[wasm_bindgen(js_name = getTrailLength)]
+    pub fn get_trail_length(&self) -> usize {
+        self.simulation
+            .trails()
+            .map_or(0, |trails| trails.capacity())
+    }
+
+    /// Trails of the bodies in body order, `getTrailLength` points [x, y] per body, oldest first
+    /// Shorter trails (bodies added since) repeat their oldest point, so that every trail can be
+    /// drawn as a line strip of the same length. Empty unless enabled with `setTrailLength`
+    pub fn trails(&self) -> Vec<f32> {
+        let Some(trails) = self.simulation.trails() else {
+            return Vec::new();
+        };
+        let length = trails.capacity();
+        let bodies = self.simulation.bodies();
+        let mut points = Vec::with_capacity(2 * length * bodies.len());
+        for (&id, &position) in bodies.ids().iter().zip(bodies.positions()) {
+            match trails.get(id).filter(|trail| !trail.is_empty()) {
+                Some(trail) => {
+                    let oldest = trail.positions().next().unwrap_or(position);
+                    let padding = std::iter::repeat_n(oldest, length - trail.len());
+                    points.extend(padding.chain(trail.positions()).flatten());
+                }
+                None => points.extend(std::iter::repeat_n(position, length).flatten()),
+            }
+        }
+        points.into_iter().map(|value| value as f32).collect()
+    }
+}
+
+fn fill_positions(simulation: &Simulation, x_positions: &mut Vec<f32>, y_positions: &mut Vec<f32>) {
+    x_positions.clear();
+    y_positions.clear();
+    for position in simulation.bodies().positions() {
+        x_positions.push(position[0] as f32);
+        y_positions.push(position[1] as f32);
+    }
+}
+
+// Private helper functions
+impl WasmSimulation {
+    fn record_collisions(&mut self) {
+        if let Some(pending) = self.pending_collisions.as_mut() {
+            pending.extend_from_slice(self.simulation.collision_events());
+        }
+    }
+
+    /// Copies the current positions into the previous ones, before stepping or after
+    /// the bodies changed (nothing to interpolate then)
+    /// Copied rather than swapped so that the views stay valid across steps
+    fn keep_previous_positions(&mut self) {
+        self.previous_x_positions.clone_from(&self.x_positions);
+        self.previous_y_positions.clone_from(&self.y_positions);
+    }
+
+    fn sync_buffers(&mut self) {
+        fill_positions(
+            &self.simulation,
+            &mut self.x_positions,
+            &mut self.y_positions,
+        );
+
+        if let Some(interleaved) = self.interleaved_positions.as_mut() {
+            interleaved.clear();
+            interleaved.extend(
+                self.x_positions
+                    .iter()
+                    .zip(self.y_positions.iter())
+                    .flat_map(|(&x, &y)| [x, y]),
+            );
+        }
+
+        self.accelerations.clear();
+        self.accelerations.extend(
+            (0..self.simulation.get_number_of_bodies())
+                .flat_map(|i| self.simulation.get_acceleration(i).map(|a| a as f32)),
+        );
+
+        // Merges change the masses, radii and colors too, all of them are copied on every sync
+        let bodies = self.simulation.bodies();
+        self.velocities.clear();
+        self.velocities.extend(
+            bodies
+                .velocities()
+                .iter()
+                .flat_map(|velocity| velocity.map(|v| v as f32)),
+        );
+        self.masses.clear();
+        self.masses
+            .extend(bodies.masses().iter().map(|&mass| mass as f32));
+        self.radii.clear();
+        self.radii
+            .extend(bodies.radii().iter().map(|&radius| radius as f32));
+        self.colors.clear();
+        self.colors.extend(
+            bodies
+                .colors()
+                .iter()
+                .flat_map(|color| color.map(|c| c as f32 / 255.0)),
+        );
+
+        // Bodies added while stepping (by the timeline): nothing to interpolate
+        if self.previous_x_positions.len() != self.x_positions.len() {
+            self.keep_previous_positions();
+        }
+
+        self.tracer_positions.clear();
+        self.tracer_positions.extend(
+            self.simulation
+                .tracers()
+                .iter()
+                .flat_map(|tracer| tracer.position.map(|x| x as f32)),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nbody::timeline::TimelineAction;
+
+    #[test]
+    fn interleaved_positions_test() {
+        let mut simulation = WasmSimulation::new();
+        simulation.add_body(1.0, 2.0, 1.0);
+        simulation.add_body(3.0, 4.0, 1.0);
+        assert!(simulation.interleaved_positions.is_none());
+
+        simulation.set_interleaved_positions(true);
+        assert_eq!(
+            simulation.interleaved_positions.as_deref(),
+            Some([1.0, 2.0, 3.0, 4.0].as_slice())
+        );
+
+        simulation.step();
+        let interleaved = simulation.interleaved_positions.as_ref().unwrap();
+        for i in 0..simulation.get_number_of_bodies() {
+            assert_eq!(interleaved[2 * i], simulation.x_positions[i]);
+            assert_eq!(interleaved[2 * i + 1], simulation.y_positions[i]);
+        }
+    }
+
+    #[test]
+    fn sample_field_test() {
+        let mut simulation = WasmSimulation::new();
+        simulation.add_body(0.0, 0.0, 1.0);
+        simulation.set_physics_parameters(PhyiscsParameters::new(1.0));
+
+        let samples = simulation.sample_field(&[2.0, 0.0, 0.0, -1.0, 5.0]);
+        assert_eq!(samples, [-0.25, 0.0, -0.5, 0.0, 1.0, -1.0]);
+    }
+
+    #[test]
+    fn accelerations_test() {
+        let mut simulation = WasmSimulation::new();
+        simulation.set_physics_parameters(PhyiscsParameters::new(1.0));
+        simulation.add_body(-2.0, 0.0, 1.0);
+        simulation.add_body(2.0, 0.0, 1.0);
+        assert_eq!(simulation.accelerations, vec![0.0; 4]);
+
+        simulation.step();
+        assert_eq!(simulation.accelerations, vec![0.0625, 0.0, -0.0625, 0.0]);
+    }
+
+    #[test]
+    fn body_buffers_test() {
+        let mut simulation = WasmSimulation::new();
+        simulation.add_full_body(Body {
+            radius: 2.0,
+            color: [255, 0, 51, 255],
+            ..Body::default().with_mass(3.0).with_velocity([1.0, -1.0])
+        });
+        simulation.add_body(10.0, 0.0, 1.0);
+        assert_eq!(simulation.velocities, vec![1.0, -1.0, 0.0, 0.0]);
+        assert_eq!(simulation.masses, vec![3.0, 1.0]);
+        assert_eq!(simulation.radii[0], 2.0);
+        assert_eq!(simulation.colors[..4], [1.0, 0.0, 0.2, 1.0]);
+        assert_eq!(simulation.colors.len(), 8);
+
+        simulation.step();
+        assert_eq!(simulation.velocities.len(), 4);
+        assert_ne!(simulation.velocities[2], 0.0);
+
+        simulation.reset();
+        assert_eq!(simulation.masses.capacity(), 0);
+        assert_eq!(simulation.colors.capacity(), 0);
+    }
+
+    #[test]
+    fn timeline_test() {
+        let mut simulation = WasmSimulation::new();
+        simulation.set_solver_parameters(SolverParameters::new(1.0, 0.0));
+        simulation.set_timeline(Timeline::new().with_event(
+            1.0,
+            TimelineAction::AddBodies(vec![Body::default().with_position([1.0, 2.0])]),
+        ));
+
+        simulation.step_many(2);
+        assert_eq!(simulation.x_positions, vec![1.0]);
+        assert_eq!(simulation.previous_x_positions, vec![1.0]);
+    }
+
+    #[test]
+    fn tracers_test() {
+        let mut simulation = WasmSimulation::new();
+        simulation.add_body(0.0, 0.0, 1.0);
+        simulation.add_tracers(&[2.0, 0.0, -4.0, 0.0, 9.0]);
+        assert_eq!(simulation.get_number_of_tracers(), 2);
+        assert_eq!(simulation.tracer_positions, vec![2.0, 0.0, -4.0, 0.0]);
+
+        simulation.step();
+        assert!(simulation.tracer_positions[0] < 2.0);
+        assert!(simulation.tracer_positions[2] > -4.0);
+        assert_eq!(simulation.x_positions, vec![0.0]);
+
+        simulation.clear_tracers();
+        assert!(simulation.tracer_positions.is_empty());
+    }
+
+    #[test]
+    fn reset_test() {
+        let mut simulation = WasmSimulation::new();
+        simulation.set_interleaved_positions(true);
+        simulation.add_body(1.0, 2.0, 1.0);
+        simulation.reset();
+
+        assert_eq!(simulation.get_number_of_bodies(), 0);
+        assert_eq!(simulation.get_physical_time(), 0.0);
+        assert_eq!(simulation.x_positions.capacity(), 0);
+        assert_eq!(simulation.y_positions.capacity(), 0);
+        assert_eq!(
+            simulation
+                .interleaved_positions
+                .as_ref()
+                .unwrap()
+                .capacity(),
+            0
+        );
+
+        simulation.add_body(3.0, 4.0, 1.0);
+        assert_eq!(simulation.x_positions, vec![3.0]);
+        assert_eq!(
+            simulation.interleaved_positions.as_deref(),
+            Some([3.0, 4.0].as_slice())
+        );
+    }
+
+    #[test]
+    fn add_bodies_test() {
+        let mut simulation = WasmSimulation::new();
+        let body = Body::default()
+            .with_position([1.0, -1.0])
+            .with_velocity([2.0, 0.0]);
+        simulation.add_full_body(body);
+        simulation.add_bodies(Bodies(vec![
+            body.with_position([5.0, 5.0]),
+            body.with_position([-5.0, 5.0]),
+        ]));
+
+        assert_eq!(simulation.get_number_of_bodies(), 3);
+        assert_eq!(simulation.x_positions, vec![1.0, 5.0, -5.0]);
+        assert_eq!(simulation.y_positions, vec![-1.0, 5.0, 5.0]);
+    }
+
+    #[test]
+    fn remove_body_test() {
+        let mut simulation = WasmSimulation::new();
+        simulation.set_interleaved_positions(true);
+        simulation.add_body(1.0, 2.0, 1.0);
+        simulation.add_body(3.0, 4.0, 1.0);
+        simulation.add_body(5.0, 6.0, 1.0);
+
+        assert!(simulation.remove_body(1));
+        assert!(!simulation.remove_body(2));
+        assert_eq!(simulation.get_number_of_bodies(), 2);
+        assert_eq!(simulation.x_positions, vec![1.0, 5.0]);
+        assert_eq!(simulation.y_positions, vec![2.0, 6.0]);
+        assert_eq!(
+            simulation.interleaved_positions.as_deref(),
+            Some([1.0, 2.0, 5.0, 6.0].as_slice())
+        );
+    }
+
+    #[test]
+    fn step_many_test() {
+        let mut stepped_once = WasmSimulation::new();
+        let mut stepped_many = WasmSimulation::new();
+        for simulation in [&mut stepped_once, &mut stepped_many] {
+            simulation.add_body(1.0, 2.0, 1.0);
+            simulation.add_body(-3.0, 4.0, 1.0);
+        }
+
+        for _ in 0..10 {
+            stepped_once.step();
+        }
+        stepped_many.step_many(10);
+
+        assert_eq!(stepped_once.x_positions, stepped_many.x_positions);
+        assert_eq!(stepped_once.y_positions, stepped_many.y_positions);
+        assert_eq!(
+            stepped_once.get_physical_time(),
+            stepped_many.get_physical_time()
+        );
+
+        stepped_many.step_dt(0.5);
+        assert!(
+            (stepped_many.get_physical_time() - stepped_once.get_physical_time() - 0.5).abs()
+                < 1e-9
+        );
+    }
+
+    #[test]
+    fn quadtree_boxes_test() {
+        let mut simulation = WasmSimulation::new();
+        for i in 0..100 {
+            let angle = i as f64 * 0.1;
+            simulation.add_body(
+                10.0 * i as f64 * angle.cos(),
+                10.0 * i as f64 * angle.sin(),
+                1.0,
+            );
+        }
+
+        let boxes = simulation.quadtree_boxes();
+        let nodes = simulation.simulation.quadtree().get_nodes();
+        assert_eq!(boxes.len(), nodes.len() * QUADTREE_BOX_STRIDE);
+
+        // Root node first, holding the whole mass
+        assert_eq!(boxes[4], 100.0);
+        assert_eq!(boxes[5], 0.0);
+
+        let max_depth = boxes
+            .chunks(QUADTREE_BOX_STRIDE)
+            .map(|node| node[5] as usize)
+            .max();
+        assert_eq!(max_depth, Some(simulation.simulation.quadtree().depth()));
+    }
+
+    #[test]
+    fn collision_events_test() {
+        let mut simulation = WasmSimulation::new();
+        simulation.add_full_body(
+            Body::default()
+                .with_position([-1.5, 0.0])
+                .with_velocity([10.0, 0.0]),
+        );
+        simulation.add_full_body(
+            Body::default()
+                .with_position([1.5, 0.0])
+                .with_velocity([-10.0, 0.0]),
+        );
+
+        // Not recorded unless enabled
+        simulation.step_many(10);
+        assert!(simulation.drain_collision_events().is_empty());
+
+        simulation.reset();
+        simulation.set_collision_events(true);
+        simulation.add_full_body(
+            Body::default()
+                .with_position([-1.5, 0.0])
+                .with_velocity([10.0, 0.0]),
+        );
+        simulation.add_full_body(
+            Body::default()
+                .with_position([1.5, 0.0])
+                .with_velocity([-10.0, 0.0]),
+        );
+        simulation.step_many(10);
+
+        let events = simulation.drain_collision_events();
+        assert_eq!(events.len(), COLLISION_EVENT_STRIDE);
+        assert_eq!(events[..2], [0.0, 1.0]);
+        assert!(events[2].abs() < 1.0 && events[3].abs() < 1e-6);
+        assert!(events[4] > 0.0);
+        assert!(simulation.drain_collision_events().is_empty());
+    }
+
+    #[test]
+    fn trails_test() {
+        let mut simulation = WasmSimulation::new();
+        assert!(simulation.trails().is_empty());
+        simulation.set_solver_parameters(SolverParameters::new(1.0, 0.0));
+        simulation.set_physics_parameters(PhyiscsParameters::new(0.0));
+        simulation.set_trail_length(3);
+        simulation.add_full_body(Body::default().with_velocity([1.0, 0.0]));
+        simulation.step_many(2);
+        simulation.add_full_body(Body::default().with_position([5.0, 5.0]));
+
+        assert_eq!(simulation.get_trail_length(), 3);
+        assert_eq!(
+            simulation.trails(),
+            [1.0, 0.0, 1.0, 0.0, 2.0, 0.0, 5.0, 5.0, 5.0, 5.0, 5.0, 5.0]
+        );
+    }
+
+    #[test]
+    fn export_import_state_test() {
+        let mut simulation = WasmSimulation::new();
+        simulation.add_full_body(Body::default().with_velocity([1.0, 0.0]));
+        simulation.add_full_body(Body::default().with_position([5.0, 5.0]));
+        simulation.step_many(5);
+        let state = simulation.export_state().unwrap();
+
+        let mut imported = WasmSimulation::new();
+        imported.add_body(100.0, 100.0, 1.0);
+        imported.import_state(&state).unwrap();
+        assert_eq!(imported.get_number_of_bodies(), 2);
+        assert_eq!(imported.get_physical_time(), simulation.get_physical_time());
+        assert_eq!(imported.x_positions, simulation.x_positions);
+
+        // Both continue identically
+        simulation.step_many(5);
+        imported.step_many(5);
+        assert_eq!(imported.x_positions, simulation.x_positions);
+        assert_eq!(imported.y_positions, simulation.y_positions);
+    }
+
+    #[test]
+    fn previous_positions_test() {
+        let mut stepped_once = WasmSimulation::new();
+        let mut stepped_many = WasmSimulation::new();
+        for simulation in [&mut stepped_once, &mut stepped_many] {
+            simulation.add_body(1.0, 2.0, 1.0);
+            simulation.add_body(-3.0, 4.0, 1.0);
+            assert_eq!(simulation.previous_x_positions, simulation.x_positions);
+        }
+
+        stepped_once.step_many(4);
+        let before_last_step = stepped_once.x_positions.clone();
+        stepped_once.step();
+        assert_eq!(stepped_once.previous_x_positions, before_last_step);
+        assert_ne!(stepped_once.previous_x_positions, stepped_once.x_positions);
+
+        stepped_many.step_many(5);
+        assert_eq!(stepped_many.previous_x_positions, before_last_step);
+        assert_eq!(
+            stepped_many.previous_y_positions,
+            stepped_once.previous_y_positions
+        );
+
+        stepped_many.add_body(0.0, 0.0, 1.0);
+        assert_eq!(stepped_many.previous_x_positions, stepped_many.x_positions);
+
+        assert_eq!(WasmSimulation::alpha(5.0, 10.0), 0.5);
+        assert_eq!(WasmSimulation::alpha(15.0, 10.0), 1.0);
+        assert_eq!(WasmSimulation::alpha(1.0, 0.0), 1.0);
+    }
+
+    #[test]
+    fn import_server_state_test() {
+        let bodies = vec![
+            Body::default().with_velocity([1.0, 0.0]),
+            Body::default().with_position([5.0, 5.0]),
+        ];
+        let mut server = Simulation::new();
+        server.add_bodies(bodies.clone());
+        server.step();
+
+        let mut simulation = WasmSimulation::new();
+        simulation.import_server_state(Bodies(server.bodies().to_vec()), 1.5);
+        assert_eq!(simulation.get_number_of_bodies(), 2);
+        assert_eq!(simulation.get_physical_time(), 1.5);
+        assert_eq!(
+            simulation.x_positions[0],
+            server.bodies().positions()[0][0] as f32
+        );
+
+        // A persisted snapshot is imported as is
+        let state = server.snapshot().to_bytes().unwrap();
+        simulation.import_state(&state).unwrap();
+        assert_eq!(simulation.get_physical_time(), server.get_physical_time());
+        assert!(SimulationSnapshot::from_bytes(&state[1..]).is_err());
+    }
+}
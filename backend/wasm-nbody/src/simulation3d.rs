@@ -0,0 +1,116 @@
+use nbody::{
+    physics::{Body3, BodyId},
+    simulation::{PhyiscsParameters, SolverParameters},
+    simulation3d::Simulation3,
+};
+use wasm_bindgen::prelude::*;
+
+/// Browser-local 3D simulation engine
+///
+/// Same buffer contract as `WasmSimulation`: the positions are mirrored into a f32 buffer
+/// whose view must be requested again after adding bodies or growing the wasm memory
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct WasmSimulation3 {
+    simulation: Simulation3,
+
+    /// Positions laid out as [x0, y0, z0, x1, y1, z1, ...]
+    positions: Vec<f32>,
+}
+
+#[wasm_bindgen]
+impl WasmSimulation3 {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        WasmSimulation3::default()
+    }
+
+    /// Adds the body with a new id, which is returned
+    #[wasm_bindgen(js_name = addBody)]
+    pub fn add_body(&mut self, body: Body3) -> BodyId {
+        let id = self.simulation.add_body(body);
+        self.sync_buffers();
+        id
+    }
+
+    #[wasm_bindgen(js_name = removeBody)]
+    pub fn remove_body(&mut self, id: BodyId) -> bool {
+        let removed = self.simulation.remove_body(id).is_some();
+        self.sync_buffers();
+        removed
+    }
+
+    pub fn step(&mut self) {
+        self.simulation.step();
+        self.sync_buffers();
+    }
+
+    #[wasm_bindgen(js_name = stepDt)]
+    pub fn step_dt(&mut self, dt: f64) {
+        self.simulation.step_with_dt(dt);
+        self.sync_buffers();
+    }
+
+    #[wasm_bindgen(js_name = setSolverParameters)]
+    pub fn set_solver_parameters(&mut self, parameters: SolverParameters) {
+        self.simulation.set_solver_parameters(parameters);
+    }
+
+    #[wasm_bindgen(js_name = setPhysicsParameters)]
+    pub fn set_physics_parameters(&mut self, parameters: PhyiscsParameters) {
+        self.simulation.set_physics_parameters(parameters);
+    }
+
+    pub fn reset(&mut self) {
+        self.simulation.reset();
+        self.positions = Vec::new();
+    }
+
+    #[wasm_bindgen(js_name = getNumberOfBodies)]
+    pub fn get_number_of_bodies(&self) -> usize {
+        self.simulation.get_number_of_bodies()
+    }
+
+    #[wasm_bindgen(js_name = getPhysicalTime)]
+    pub fn get_physical_time(&self) -> f64 {
+        self.simulation.get_physical_time()
+    }
+
+    #[wasm_bindgen(js_name = getKineticEnergy)]
+    pub fn get_kinetic_energy(&self) -> f64 {
+        self.simulation.get_kinetic_energy()
+    }
+
+    /// View over the positions (three f32 per body)
+    pub fn positions(&self) -> js_sys::Float32Array {
+        // SAFETY: see the invalidation rules documented on `WasmSimulation3`
+        unsafe { js_sys::Float32Array::view(&self.positions) }
+    }
+}
+
+impl WasmSimulation3 {
+    fn sync_buffers(&mut self) {
+        self.positions.clear();
+        self.positions.extend(
+            self.simulation
+                .bodies()
+                .iter()
+                .flat_map(|body| body.position.map(|x| x as f32)),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions_test() {
+        let mut simulation = WasmSimulation3::new();
+        simulation.add_body(Body3::default().with_position([1.0, 2.0, 3.0]));
+        simulation.add_body(Body3::default().with_velocity([0.0, 0.0, 1.0]));
+        simulation.set_physics_parameters(PhyiscsParameters::new(0.0));
+        simulation.step_dt(0.5);
+        assert_eq!(simulation.positions, [1.0, 2.0, 3.0, 0.0, 0.0, 0.5]);
+    }
+}
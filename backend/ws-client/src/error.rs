@@ -0,0 +1,19 @@
+use protocol::ProtocolError;
+use thiserror::Error;
+use tokio_tungstenite::tungstenite;
+
+/// Errors raised while talking to the server
+#[derive(Debug, Error)]
+pub enum ClientError {
+    /// Connection failure or WebSocket protocol violation
+    #[error("WebSocketError: {0}")]
+    WebSocket(#[from] tungstenite::Error),
+
+    /// A frame could not be encoded or decoded
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+
+    /// The server rejected a message (sent back as a text frame)
+    #[error("ServerError: {0}")]
+    Server(String),
+}
@@ -0,0 +1,720 @@
+//! Async native client of the simulation server
+//!
+//! Lets bots, recorders and integration tests talk to the server without a browser
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), ws_client::ClientError> {
+//! let mut client = ws_client::Client::connect("ws://localhost:5000").await?;
+//! client.subscribe().await?;
+//! while let Some(update) = client.next_state_update().await {
+//!     println!("t = {}", update?.physical_time);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+mod error;
+
+use std::{
+    collections::VecDeque,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures_util::{
+    stream::{self, SplitSink, SplitStream},
+    SinkExt, Stream, StreamExt,
+};
+use nbody::{
+    constraints::Constraint,
+    physics::{Body, BodyId, ExternalForce},
+    quadtree::SquareBox,
+    scenarios::Scenario,
+    simulation::{BoundaryCondition, PhyiscsParameters, SolverParameters},
+};
+use protocol::{
+    decode_json,
+    dictionary::Dictionary,
+    encode, encode_json, encode_with,
+    flatbuffers::{StateUpdateView, FLATBUFFERS_TAG},
+    is_dictionary_frame,
+    protobuf::{decode_any, encode_as, Encoding},
+    split_frame, ClientToServerMessage, Codec, ProtocolError, RoomId, ServerToClientMessage,
+    Subprotocol, SUBPROTOCOL_HEADER,
+};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{client::IntoClientRequest, http::HeaderValue, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+pub use error::ClientError;
+
+type Connection = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Content of a `StateUpdate` message
+#[derive(Debug, Clone)]
+pub struct StateUpdate {
+    pub bodies: Vec<Body>,
+    pub physical_time: f64,
+    pub kinetic_energy: f64,
+
+    /// None for the states of a `StateBundle`, which do not carry them
+    pub conservation: Option<Conservation>,
+}
+
+/// Conservation diagnostics of a `StateUpdate`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Conservation {
+    pub potential_energy: f64,
+    pub linear_momentum: [f64; 2],
+    pub angular_momentum: f64,
+}
+
+impl StateUpdate {
+    /// Kinetic plus potential energy
+    pub fn total_energy(&self) -> Option<f64> {
+        self.conservation
+            .map(|conservation| self.kinetic_energy + conservation.potential_energy)
+    }
+}
+
+/// Connection to the server
+///
+/// Can be split into its sending and receiving halves
+/// so that they are driven from different tasks
+pub struct Client {
+    sender: ClientSender,
+    receiver: ClientReceiver,
+}
+
+impl Client {
+    pub async fn connect(url: &str) -> Result<Self, ClientError> {
+        let (connection, _) = connect_async(url).await?;
+        Ok(Self::from_connection(connection, None))
+    }
+
+    /// Connects agreeing on the frame format during the handshake
+    /// (fails if the server does not support it)
+    pub async fn connect_with_subprotocol(
+        url: &str,
+        subprotocol: Subprotocol,
+    ) -> Result<Self, ClientError> {
+        let mut request = url.into_client_request()?;
+        request.headers_mut().insert(
+            SUBPROTOCOL_HEADER,
+            HeaderValue::from_static(subprotocol.name()),
+        );
+        let (connection, _) = connect_async(request).await?;
+        Ok(Self::from_connection(connection, Some(subprotocol)))
+    }
+
+    fn from_connection(connection: Connection, subprotocol: Option<Subprotocol>) -> Self {
+        let (sink, stream) = connection.split();
+        Self {
+            sender: ClientSender { sink, subprotocol },
+            receiver: ClientReceiver {
+                stream,
+                subprotocol,
+                dictionary: None,
+                bundled: VecDeque::new(),
+            },
+        }
+    }
+
+    pub fn into_split(self) -> (ClientSender, ClientReceiver) {
+        (self.sender, self.receiver)
+    }
+
+    pub async fn send(&mut self, msg: &ClientToServerMessage) -> Result<(), ClientError> {
+        self.sender.send(msg).await
+    }
+
+    /// Presents the token to a server requiring one (as the first message), answered by
+    /// `Authenticated` with the role granted
+    pub async fn authenticate(&mut self, token: &str) -> Result<(), ClientError> {
+        self.sender.authenticate(token).await
+    }
+
+    /// Asks the server to push a `StateUpdate` after every step
+    pub async fn subscribe(&mut self) -> Result<(), ClientError> {
+        self.sender.subscribe().await
+    }
+
+    /// Same as `subscribe` but the states are pushed in bundles of consecutive ticks
+    /// (still handed out one by one by `next_state_update`)
+    pub async fn subscribe_bundled(&mut self, ticks_per_bundle: u32) -> Result<(), ClientError> {
+        self.sender.subscribe_bundled(ticks_per_bundle).await
+    }
+
+    /// Same as `subscribe` but the states are pushed as `StateDelta`s
+    /// against the last one acknowledged with `ack_state` (see `BodiesDelta::apply`)
+    pub async fn subscribe_deltas(&mut self) -> Result<(), ClientError> {
+        self.sender.subscribe_deltas().await
+    }
+
+    pub async fn ack_state(&mut self, tick: u64) -> Result<(), ClientError> {
+        self.sender.ack_state(tick).await
+    }
+
+    /// Asks the server for a single `StateUpdate`
+    pub async fn request_state(&mut self) -> Result<(), ClientError> {
+        self.sender.request_state().await
+    }
+
+    pub async fn add_bodies(&mut self, bodies: Vec<Body>) -> Result<(), ClientError> {
+        self.sender.add_bodies(bodies).await
+    }
+
+    pub async fn reset(&mut self) -> Result<(), ClientError> {
+        self.sender.reset().await
+    }
+
+    /// Freezes the simulation until `resume`, it can be advanced with `single_step` meanwhile
+    pub async fn pause(&mut self) -> Result<(), ClientError> {
+        self.sender.pause().await
+    }
+
+    pub async fn resume(&mut self) -> Result<(), ClientError> {
+        self.sender.resume().await
+    }
+
+    pub async fn single_step(&mut self) -> Result<(), ClientError> {
+        self.sender.single_step().await
+    }
+
+    /// Steps the simulation `tick_rate` times per second of wall-clock time
+    pub async fn set_tick_rate(&mut self, tick_rate: f64) -> Result<(), ClientError> {
+        self.sender.set_tick_rate(tick_rate).await
+    }
+
+    /// Opens a room with a simulation of its own and moves there, answered by `RoomJoined`
+    pub async fn create_room(&mut self) -> Result<(), ClientError> {
+        self.sender.create_room().await
+    }
+
+    pub async fn join_room(&mut self, room: RoomId) -> Result<(), ClientError> {
+        self.sender.join_room(room).await
+    }
+
+    /// Back to the lobby
+    pub async fn leave_room(&mut self) -> Result<(), ClientError> {
+        self.sender.leave_room().await
+    }
+
+    /// Removes the bodies with these ids
+    pub async fn remove_bodies(&mut self, ids: Vec<BodyId>) -> Result<(), ClientError> {
+        self.sender.remove_bodies(ids).await
+    }
+
+    /// Saves the simulation on the server under `name`, answered by `SnapshotSaved`
+    pub async fn save_snapshot(&mut self, name: &str) -> Result<(), ClientError> {
+        self.sender.save_snapshot(name).await
+    }
+
+    /// Replaces the simulation with the one saved under `name`
+    pub async fn load_snapshot(&mut self, name: &str) -> Result<(), ClientError> {
+        self.sender.load_snapshot(name).await
+    }
+
+    /// Adds the bodies of the scenario, generated by the server
+    pub async fn load_scenario(&mut self, scenario: Scenario) -> Result<(), ClientError> {
+        self.sender.load_scenario(scenario).await
+    }
+
+    pub async fn set_solver_parameters(
+        &mut self,
+        parameters: SolverParameters,
+    ) -> Result<(), ClientError> {
+        self.sender.set_solver_parameters(parameters).await
+    }
+
+    pub async fn set_physics_parameters(
+        &mut self,
+        parameters: PhyiscsParameters,
+    ) -> Result<(), ClientError> {
+        self.sender.set_physics_parameters(parameters).await
+    }
+
+    pub async fn set_boundary_condition(
+        &mut self,
+        boundary: BoundaryCondition,
+    ) -> Result<(), ClientError> {
+        self.sender.set_boundary_condition(boundary).await
+    }
+
+    /// Replaces the fields acting on the bodies, an empty list removes them
+    pub async fn set_external_forces(
+        &mut self,
+        forces: Vec<ExternalForce>,
+    ) -> Result<(), ClientError> {
+        self.sender.set_external_forces(forces).await
+    }
+
+    /// Links two bodies, replacing the constraint between them if any
+    pub async fn add_constraint(&mut self, constraint: Constraint) -> Result<(), ClientError> {
+        self.sender.add_constraint(constraint).await
+    }
+
+    pub async fn remove_constraint(&mut self, a: BodyId, b: BodyId) -> Result<(), ClientError> {
+        self.sender.remove_constraint(a, b).await
+    }
+
+    /// Asks the server to replay the recorded states within [from_time, to_time]
+    /// (physical time in seconds), `rate` `StateUpdate`s per second
+    pub async fn request_history(
+        &mut self,
+        from_time: f64,
+        to_time: f64,
+        rate: f64,
+    ) -> Result<(), ClientError> {
+        self.sender.request_history(from_time, to_time, rate).await
+    }
+
+    /// Asks the server for at most `max_points` `DiagnosticsSample`s between `from_time` and `to_time`
+    pub async fn query_diagnostics(
+        &mut self,
+        from_time: f64,
+        to_time: f64,
+        max_points: u32,
+    ) -> Result<(), ClientError> {
+        self.sender
+            .query_diagnostics(from_time, to_time, max_points)
+            .await
+    }
+
+    pub async fn query_region(&mut self, region: SquareBox) -> Result<(), ClientError> {
+        self.sender.query_region(region).await
+    }
+
+    pub async fn get_body(&mut self, id: BodyId) -> Result<(), ClientError> {
+        self.sender.get_body(id).await
+    }
+
+    pub async fn get_history(&mut self, last_n: u32) -> Result<(), ClientError> {
+        self.sender.get_history(last_n).await
+    }
+
+    /// Asks for the last `last_n` positions of every body, answered by `Trails`
+    pub async fn get_trails(&mut self, last_n: u32) -> Result<(), ClientError> {
+        self.sender.get_trails(last_n).await
+    }
+
+    /// Asks for a `Pong` echoing the current time
+    pub async fn ping(&mut self) -> Result<(), ClientError> {
+        self.sender.ping().await
+    }
+
+    pub async fn next_message(&mut self) -> Option<Result<ServerToClientMessage, ClientError>> {
+        self.receiver.next_message().await
+    }
+
+    pub async fn next_state_update(&mut self) -> Option<Result<StateUpdate, ClientError>> {
+        self.receiver.next_state_update().await
+    }
+
+    /// Closes the connection gracefully
+    pub async fn close(mut self) -> Result<(), ClientError> {
+        self.sender.close().await
+    }
+}
+
+/// Sending half of a `Client`
+pub struct ClientSender {
+    sink: SplitSink<Connection, Message>,
+    subprotocol: Option<Subprotocol>,
+}
+
+impl ClientSender {
+    pub async fn send(&mut self, msg: &ClientToServerMessage) -> Result<(), ClientError> {
+        let frame = match self.subprotocol {
+            None => Message::binary(encode(msg)?),
+            Some(Subprotocol::Json) => Message::text(encode_json(msg)?),
+            Some(Subprotocol::Protobuf) => Message::binary(encode_as(msg, Encoding::Protobuf)?),
+            Some(subprotocol) => Message::binary(encode_with(msg, &subprotocol.codec_options())?),
+        };
+        self.sink.send(frame).await?;
+        Ok(())
+    }
+
+    pub async fn authenticate(&mut self, token: &str) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::Authenticate {
+            token: token.to_owned(),
+        })
+        .await
+    }
+
+    pub async fn subscribe(&mut self) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::Subscribe).await
+    }
+
+    pub async fn subscribe_bundled(&mut self, ticks_per_bundle: u32) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::SubscribeBundled { ticks_per_bundle })
+            .await
+    }
+
+    pub async fn subscribe_deltas(&mut self) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::SubscribeDeltas).await
+    }
+
+    pub async fn ack_state(&mut self, tick: u64) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::AckState { tick }).await
+    }
+
+    pub async fn request_state(&mut self) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::State).await
+    }
+
+    pub async fn add_bodies(&mut self, bodies: Vec<Body>) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::AddBodies(bodies)).await
+    }
+
+    pub async fn reset(&mut self) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::Reset).await
+    }
+
+    pub async fn pause(&mut self) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::Pause).await
+    }
+
+    pub async fn resume(&mut self) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::Resume).await
+    }
+
+    pub async fn single_step(&mut self) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::SingleStep).await
+    }
+
+    pub async fn set_tick_rate(&mut self, tick_rate: f64) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::SetTickRate(tick_rate))
+            .await
+    }
+
+    pub async fn create_room(&mut self) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::CreateRoom).await
+    }
+
+    pub async fn join_room(&mut self, room: RoomId) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::JoinRoom(room)).await
+    }
+
+    pub async fn leave_room(&mut self) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::LeaveRoom).await
+    }
+
+    pub async fn remove_bodies(&mut self, ids: Vec<BodyId>) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::RemoveBodies(ids)).await
+    }
+
+    pub async fn save_snapshot(&mut self, name: &str) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::SaveSnapshot {
+            name: name.to_owned(),
+        })
+        .await
+    }
+
+    pub async fn load_snapshot(&mut self, name: &str) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::LoadSnapshot {
+            name: name.to_owned(),
+        })
+        .await
+    }
+
+    pub async fn load_scenario(&mut self, scenario: Scenario) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::LoadScenario(scenario))
+            .await
+    }
+
+    pub async fn set_solver_parameters(
+        &mut self,
+        parameters: SolverParameters,
+    ) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::SetSolverParameters(parameters))
+            .await
+    }
+
+    pub async fn set_physics_parameters(
+        &mut self,
+        parameters: PhyiscsParameters,
+    ) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::SetPhysicsParameters(parameters))
+            .await
+    }
+
+    pub async fn set_boundary_condition(
+        &mut self,
+        boundary: BoundaryCondition,
+    ) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::SetBoundaryCondition(boundary))
+            .await
+    }
+
+    pub async fn set_external_forces(
+        &mut self,
+        forces: Vec<ExternalForce>,
+    ) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::SetExternalForces(forces))
+            .await
+    }
+
+    pub async fn add_constraint(&mut self, constraint: Constraint) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::AddConstraint(constraint))
+            .await
+    }
+
+    pub async fn remove_constraint(&mut self, a: BodyId, b: BodyId) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::RemoveConstraint(a, b))
+            .await
+    }
+
+    pub async fn request_history(
+        &mut self,
+        from_time: f64,
+        to_time: f64,
+        rate: f64,
+    ) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::RequestHistory {
+            from_time,
+            to_time,
+            rate,
+        })
+        .await
+    }
+
+    pub async fn query_diagnostics(
+        &mut self,
+        from_time: f64,
+        to_time: f64,
+        max_points: u32,
+    ) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::QueryDiagnostics {
+            from_time,
+            to_time,
+            max_points,
+        })
+        .await
+    }
+
+    pub async fn query_region(&mut self, region: SquareBox) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::QueryRegion(region)).await
+    }
+
+    pub async fn get_body(&mut self, id: BodyId) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::GetBody(id)).await
+    }
+
+    pub async fn get_history(&mut self, last_n: u32) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::GetHistory { last_n })
+            .await
+    }
+
+    pub async fn get_trails(&mut self, last_n: u32) -> Result<(), ClientError> {
+        self.send(&ClientToServerMessage::GetTrails { last_n })
+            .await
+    }
+
+    pub async fn ping(&mut self) -> Result<(), ClientError> {
+        let client_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64()
+            * 1000.0;
+        self.send(&ClientToServerMessage::Ping { client_time })
+            .await
+    }
+
+    pub async fn close(&mut self) -> Result<(), ClientError> {
+        self.sink.close().await?;
+        Ok(())
+    }
+}
+
+/// Receiving half of a `Client`
+pub struct ClientReceiver {
+    stream: SplitStream<Connection>,
+    subprotocol: Option<Subprotocol>,
+
+    /// Shipped by the server at the start of a `nbody.bincode.zstd.v1` connection
+    dictionary: Option<Dictionary>,
+
+    /// States of the last `StateBundle` not handed out yet
+    bundled: VecDeque<StateUpdate>,
+}
+
+impl ClientReceiver {
+    /// Waits for the next message of the server
+    /// Returns None once the connection is closed
+    pub async fn next_message(&mut self) -> Option<Result<ServerToClientMessage, ClientError>> {
+        loop {
+            let msg = match self.stream.next().await? {
+                Ok(msg) => msg,
+                Err(e) => return Some(Err(e.into())),
+            };
+            match msg {
+                Message::Binary(data) if is_dictionary_frame(&data) => {
+                    match Dictionary::from_frame(&data) {
+                        Ok(dictionary) => self.dictionary = Some(dictionary),
+                        Err(e) => return Some(Err(e.into())),
+                    }
+                }
+                Message::Binary(data) => match decode_frame(&data, self.dictionary.as_ref()) {
+                    // Sent by a newer server
+                    Err(ProtocolError::UnknownMessage(Some(_))) => continue,
+                    msg => return Some(msg.map_err(ClientError::from)),
+                },
+                Message::Text(text) => {
+                    if self.subprotocol == Some(Subprotocol::Json) {
+                        if let Ok(msg) = decode_json(&text) {
+                            return Some(Ok(msg));
+                        }
+                    }
+                    // Otherwise the server rejected a message
+                    return Some(Err(ClientError::Server(text.to_string())));
+                }
+                Message::Close(_) => return None,
+                // Pings are answered by tungstenite itself
+                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => continue,
+            }
+        }
+    }
+
+    /// Waits for the next `StateUpdate`, skipping any other message
+    /// (the states of a `StateBundle` are handed out one by one)
+    pub async fn next_state_update(&mut self) -> Option<Result<StateUpdate, ClientError>> {
+        loop {
+            if let Some(update) = self.bundled.pop_front() {
+                return Some(Ok(update));
+            }
+            match self.next_message().await? {
+                Ok(ServerToClientMessage::StateUpdate {
+                    bodies,
+                    physical_time,
+                    kinetic_energy,
+                    potential_energy,
+                    linear_momentum,
+                    angular_momentum,
+                }) => {
+                    return Some(Ok(StateUpdate {
+                        bodies,
+                        physical_time,
+                        kinetic_energy,
+                        conservation: Some(Conservation {
+                            potential_energy,
+                            linear_momentum,
+                            angular_momentum,
+                        }),
+                    }))
+                }
+                Ok(ServerToClientMessage::StateBundle(states)) => {
+                    self.bundled
+                        .extend(states.into_iter().map(|state| StateUpdate {
+                            bodies: state.bodies,
+                            physical_time: state.physical_time,
+                            kinetic_energy: state.kinetic_energy,
+                            conservation: None,
+                        }))
+                }
+                Ok(_) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+
+    /// Stream of the `StateUpdate`s pushed by the server
+    pub fn state_updates(self) -> impl Stream<Item = Result<StateUpdate, ClientError>> {
+        stream::unfold(self, |mut receiver| async move {
+            let update = receiver.next_state_update().await?;
+            Some((update, receiver))
+        })
+    }
+}
+
+/// Decodes a binary frame of any encoding
+fn decode_frame(
+    frame: &[u8],
+    dictionary: Option<&Dictionary>,
+) -> Result<ServerToClientMessage, ProtocolError> {
+    if frame.first() == Some(&FLATBUFFERS_TAG) {
+        return Ok(StateUpdateView::from_frame(frame)?.to_message());
+    }
+    if let (Some(dictionary), Ok((Codec::Zstd, _))) = (dictionary, split_frame(frame)) {
+        return dictionary.decode(frame);
+    }
+    Ok(decode_any(frame)?.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::decode;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::accept_async;
+
+    /// Answers every `State` request with the number of received bodies
+    async fn mock_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut connection = accept_async(stream).await.unwrap();
+            let mut bodies = Vec::new();
+            while let Some(Ok(Message::Binary(data))) = connection.next().await {
+                let reply = match decode(&data) {
+                    Ok(ClientToServerMessage::AddBodies(new_bodies)) => {
+                        bodies.extend(new_bodies);
+                        continue;
+                    }
+                    Ok(ClientToServerMessage::State) => Message::binary(
+                        encode(&ServerToClientMessage::StateUpdate {
+                            bodies: bodies.clone(),
+                            physical_time: 1.0,
+                            kinetic_energy: 0.0,
+                            potential_energy: 0.0,
+                            linear_momentum: [0.0, 0.0],
+                            angular_momentum: 0.0,
+                        })
+                        .unwrap(),
+                    ),
+                    Ok(_) => continue,
+                    Err(e) => Message::text(e.to_string()),
+                };
+                connection.send(reply).await.unwrap();
+            }
+        });
+        format!("ws://{address}")
+    }
+
+    #[tokio::test]
+    async fn round_trip_test() {
+        let url = mock_server().await;
+        let (mut sender, receiver) = Client::connect(&url).await.unwrap().into_split();
+
+        sender.add_bodies(vec![Body::default(); 3]).await.unwrap();
+        sender.request_state().await.unwrap();
+        sender.add_bodies(vec![Body::default(); 2]).await.unwrap();
+        sender.request_state().await.unwrap();
+
+        let updates: Vec<_> = receiver.state_updates().take(2).collect().await;
+        let counts: Vec<_> = updates
+            .into_iter()
+            .map(|update| update.unwrap().bodies.len())
+            .collect();
+        assert_eq!(counts, [3, 5]);
+    }
+
+    #[tokio::test]
+    async fn server_error_test() {
+        let url = mock_server().await;
+        let mut client = Client::connect(&url).await.unwrap();
+        client
+            .sender
+            .sink
+            .send(Message::binary(vec![0xff]))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.next_message().await,
+            Some(Err(ClientError::Server(_)))
+        ));
+    }
+}
@@ -0,0 +1,44 @@
+//! Roles required by the requests of the clients
+//!
+//! The tokens granting the roles are configured on the `ServerState` (see `with_token`),
+//! without any the server trusts every client as an admin
+
+use protocol::{ClientToServerMessage, Role};
+
+/// Least privileged role allowed to make the request
+pub fn required_role(msg: &ClientToServerMessage) -> Role {
+    match msg {
+        ClientToServerMessage::Authenticate { .. }
+        | ClientToServerMessage::Subscribe
+        | ClientToServerMessage::SubscribeBundled { .. }
+        | ClientToServerMessage::SubscribeDeltas
+        | ClientToServerMessage::AckState { .. }
+        | ClientToServerMessage::State
+        | ClientToServerMessage::RequestHistory { .. }
+        | ClientToServerMessage::QueryDiagnostics { .. }
+        | ClientToServerMessage::QueryRegion(_)
+        | ClientToServerMessage::GetBody(_)
+        | ClientToServerMessage::GetHistory { .. }
+        | ClientToServerMessage::GetTrails { .. }
+        | ClientToServerMessage::Ping { .. }
+        | ClientToServerMessage::JoinRoom(_)
+        | ClientToServerMessage::LeaveRoom => Role::Spectator,
+        ClientToServerMessage::AddBodies(_)
+        | ClientToServerMessage::LoadScenario(_)
+        | ClientToServerMessage::RemoveBodies(_)
+        | ClientToServerMessage::AddConstraint(_)
+        | ClientToServerMessage::RemoveConstraint(..)
+        | ClientToServerMessage::Pause
+        | ClientToServerMessage::Resume
+        | ClientToServerMessage::SingleStep
+        | ClientToServerMessage::SetTickRate(_)
+        | ClientToServerMessage::CreateRoom
+        | ClientToServerMessage::SaveSnapshot { .. } => Role::Controller,
+        ClientToServerMessage::Reset
+        | ClientToServerMessage::LoadSnapshot { .. }
+        | ClientToServerMessage::SetSolverParameters(_)
+        | ClientToServerMessage::SetPhysicsParameters(_)
+        | ClientToServerMessage::SetBoundaryCondition(_)
+        | ClientToServerMessage::SetExternalForces(_) => Role::Admin,
+    }
+}
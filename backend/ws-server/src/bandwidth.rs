@@ -0,0 +1,193 @@
+use protocol::ServerToClientMessage;
+use std::{
+    borrow::Cow,
+    sync::{
+        atomic::{AtomicU64, AtomicU8, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Frames waiting in the send queue of a client above which its quality is lowered
+const BACKLOG_HIGH_WATERMARK: usize = 16;
+
+/// Time between two consecutive quality drops, so that the frames queued before a drop
+/// do not trigger the next one
+const LOWER_COOLDOWN: Duration = Duration::from_secs(1);
+
+/// Time the send queue must stay empty before the quality is raised again
+const DRAINED_DELAY: Duration = Duration::from_secs(2);
+
+/// Period over which the throughput is measured
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Quality of the states streamed to a client
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Quality {
+    Full,
+
+    /// Half the rate, positions and velocities rounded to f32 precision
+    Reduced,
+
+    /// A quarter of the rate, positions rounded to f32 precision and velocities culled
+    Minimal,
+}
+
+impl Quality {
+    const ALL: [Quality; 3] = [Quality::Full, Quality::Reduced, Quality::Minimal];
+
+    fn lower(self) -> Self {
+        Quality::ALL[(self as usize + 1).min(Quality::ALL.len() - 1)]
+    }
+
+    fn higher(self) -> Self {
+        Quality::ALL[(self as usize).saturating_sub(1)]
+    }
+
+    /// Only one streamed state out of `stride` is sent
+    pub fn stride(self) -> usize {
+        match self {
+            Quality::Full => 1,
+            Quality::Reduced => 2,
+            Quality::Minimal => 4,
+        }
+    }
+
+    /// The message as streamed at this quality
+    /// (the zeroed low bits of the rounded values compress far better)
+    pub fn degrade(self, msg: &ServerToClientMessage) -> Cow<'_, ServerToClientMessage> {
+        let ServerToClientMessage::StateUpdate {
+            bodies,
+            physical_time,
+            kinetic_energy,
+            potential_energy,
+            linear_momentum,
+            angular_momentum,
+        } = msg
+        else {
+            return Cow::Borrowed(msg);
+        };
+        if self == Quality::Full {
+            return Cow::Borrowed(msg);
+        }
+        let round = |values: [f64; 2]| values.map(|v| v as f32 as f64);
+        let bodies = bodies
+            .iter()
+            .map(|body| {
+                let mut body = *body;
+                body.position = round(body.position);
+                body.velocity = match self {
+                    Quality::Minimal => [0.0, 0.0],
+                    _ => round(body.velocity),
+                };
+                body
+            })
+            .collect();
+        Cow::Owned(ServerToClientMessage::StateUpdate {
+            bodies,
+            physical_time: *physical_time,
+            kinetic_energy: *kinetic_energy,
+            potential_energy: *potential_energy,
+            linear_momentum: *linear_momentum,
+            angular_momentum: *angular_momentum,
+        })
+    }
+}
+
+/// Traffic of a client, measured by its writer task
+/// and read by the tasks streaming states to it (which adapt to its quality)
+#[derive(Default)]
+pub struct ClientLink {
+    bytes_sent: AtomicU64,
+    bytes_per_second: AtomicU64,
+    quality: AtomicU8,
+}
+
+impl ClientLink {
+    pub fn new() -> Self {
+        ClientLink::default()
+    }
+
+    pub fn quality(&self) -> Quality {
+        Quality::ALL[self.quality.load(Ordering::Relaxed) as usize]
+    }
+
+    /// Total bytes sent to the client
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// Throughput over the last measurement window
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bytes_per_second.load(Ordering::Relaxed)
+    }
+}
+
+/// Owned by the writer task of a client: accounts the frames sent
+/// and steps the quality down while the send queue backs up, and back up once it drained
+pub struct LinkMonitor {
+    link: Arc<ClientLink>,
+    window_start: Instant,
+    window_bytes: u64,
+    last_lowered: Option<Instant>,
+    drained_since: Option<Instant>,
+}
+
+impl LinkMonitor {
+    pub fn new(link: Arc<ClientLink>) -> Self {
+        Self {
+            link,
+            window_start: Instant::now(),
+            window_bytes: 0,
+            last_lowered: None,
+            drained_since: None,
+        }
+    }
+
+    /// To be called after each frame sent, with the number of frames still queued
+    /// Returns the new quality when it changed
+    pub fn on_sent(&mut self, bytes: usize, backlog: usize) -> Option<Quality> {
+        let now = Instant::now();
+        self.link
+            .bytes_sent
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.window_bytes += bytes as u64;
+        let elapsed = now - self.window_start;
+        if elapsed >= RATE_WINDOW {
+            let rate = self.window_bytes as f64 / elapsed.as_secs_f64();
+            self.link
+                .bytes_per_second
+                .store(rate as u64, Ordering::Relaxed);
+            self.window_start = now;
+            self.window_bytes = 0;
+        }
+
+        let quality = self.link.quality();
+        let next = if backlog > BACKLOG_HIGH_WATERMARK {
+            self.drained_since = None;
+            let cooled_down = self
+                .last_lowered
+                .is_none_or(|last| now - last >= LOWER_COOLDOWN);
+            if !cooled_down {
+                return None;
+            }
+            self.last_lowered = Some(now);
+            quality.lower()
+        } else if backlog == 0 {
+            let drained_since = *self.drained_since.get_or_insert(now);
+            if now - drained_since < DRAINED_DELAY {
+                return None;
+            }
+            self.drained_since = Some(now);
+            quality.higher()
+        } else {
+            self.drained_since = None;
+            return None;
+        };
+        if next == quality {
+            return None;
+        }
+        self.link.quality.store(next as u8, Ordering::Relaxed);
+        Some(next)
+    }
+}
@@ -0,0 +1,193 @@
+use nbody::physics::Body;
+use protocol::{BodiesDelta, ServerToClientMessage, Subprotocol};
+use std::{
+    collections::VecDeque,
+    sync::{atomic::Ordering, Arc},
+};
+use tokio::time::MissedTickBehavior;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::{
+    bandwidth::Quality,
+    handler::{encode_reply, gather_state, Compression},
+    lock,
+    state::{RoomState, ServerState, Subscriber},
+};
+
+/// Pushed states kept as baselines of the deltas, older acknowledgements get the whole state
+const MAX_BASELINES: usize = 32;
+
+/// Pushes the state of the simulation of the room to its subscribers after each step
+/// (or at most once per `ServerState::broadcast_interval`)
+/// Steps taken while a state is being pushed are not pushed on their own
+///
+/// Runs until the simulation stops stepping (e.g. the room is closed)
+pub async fn broadcast_states(state: Arc<ServerState>, room: Arc<RoomState>) {
+    let mut steps = room.steps.clone();
+    let mut interval = state.broadcast_interval.map(|period| {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        interval
+    });
+    let mut baselines = VecDeque::with_capacity(MAX_BASELINES);
+    loop {
+        if let Some(interval) = &mut interval {
+            interval.tick().await;
+        }
+        if steps.changed().await.is_err() {
+            return;
+        }
+        let broadcast = {
+            let simulation = lock!(room.simulation.1);
+            Broadcast {
+                tick: room.simulation.0.load(Ordering::Relaxed) as u64,
+                update: gather_state(&simulation),
+            }
+        };
+        let mut frames = FrameCache::default();
+        let mut subscribers = lock!(room.connected_clients);
+        subscribers.retain_mut(|subscriber| {
+            push_state(subscriber, &broadcast, &baselines, &mut frames, &state)
+        });
+        if subscribers.iter().any(|subscriber| subscriber.deltas) {
+            if baselines.len() == MAX_BASELINES {
+                baselines.pop_front();
+            }
+            baselines.push_back((broadcast.tick, broadcast.bodies().to_vec()));
+        } else {
+            baselines.clear();
+        }
+    }
+}
+
+/// State pushed to the subscribers
+struct Broadcast {
+    tick: u64,
+
+    /// A `StateUpdate`
+    update: ServerToClientMessage,
+}
+
+impl Broadcast {
+    fn bodies(&self) -> &[Body] {
+        match &self.update {
+            ServerToClientMessage::StateUpdate { bodies, .. } => bodies,
+            _ => &[],
+        }
+    }
+
+    /// Against the bodies of the baseline tick, or the whole state without one
+    fn delta(&self, baseline: Option<&(u64, Vec<Body>)>) -> ServerToClientMessage {
+        let ServerToClientMessage::StateUpdate {
+            bodies,
+            physical_time,
+            kinetic_energy,
+            ..
+        } = &self.update
+        else {
+            unreachable!("the broadcast state is a StateUpdate");
+        };
+        ServerToClientMessage::StateDelta {
+            tick: self.tick,
+            baseline_tick: baseline.map(|(tick, _)| *tick),
+            delta: match baseline {
+                Some((_, baseline)) => BodiesDelta::between(baseline, bodies),
+                None => BodiesDelta::full(bodies),
+            },
+            physical_time: *physical_time,
+            kinetic_energy: *kinetic_energy,
+        }
+    }
+}
+
+/// What a frame of the broadcast state holds
+#[derive(Clone, Copy, PartialEq)]
+enum FrameKind {
+    Update(Quality),
+
+    /// Against the state of that tick
+    Delta(Option<u64>),
+}
+
+/// Frames of the state already encoded during a broadcast, so that it is encoded
+/// once per format and kind rather than once per subscriber
+#[derive(Default)]
+struct FrameCache {
+    frames: Vec<(Subprotocol, FrameKind, Message)>,
+}
+
+impl FrameCache {
+    fn get_or_encode(
+        &mut self,
+        format: Subprotocol,
+        kind: FrameKind,
+        encode: impl FnOnce() -> Option<Message>,
+    ) -> Option<Message> {
+        let cached = self
+            .frames
+            .iter()
+            .find(|(f, k, _)| (*f, *k) == (format, kind));
+        if let Some((_, _, frame)) = cached {
+            return Some(frame.clone());
+        }
+        let frame = encode()?;
+        self.frames.push((format, kind, frame.clone()));
+        Some(frame)
+    }
+}
+
+/// Streams the state to the subscriber at the quality of its link
+/// Returns false once the client is gone
+fn push_state(
+    subscriber: &mut Subscriber,
+    broadcast: &Broadcast,
+    baselines: &VecDeque<(u64, Vec<Body>)>,
+    frames: &mut FrameCache,
+    state: &ServerState,
+) -> bool {
+    let compression = &state.compression;
+    let quality = subscriber.link.quality();
+    let skipped = !subscriber.streamed.is_multiple_of(quality.stride() as u64);
+    subscriber.streamed += 1;
+    if skipped {
+        state.metrics.record_dropped_frame();
+        return !subscriber.tx.is_closed();
+    }
+    let format = subscriber.format;
+    let frame = if subscriber.deltas {
+        // Deltas are not degraded, the client would drift away from the server's state
+        let baseline = subscriber
+            .acked_tick
+            .and_then(|acked| baselines.iter().find(|(tick, _)| *tick == acked));
+        let kind = FrameKind::Delta(baseline.map(|(tick, _)| *tick));
+        frames.get_or_encode(format, kind, || {
+            encode_or_log(&broadcast.delta(baseline), format, compression)
+        })
+    } else if subscriber.bundler.ticks_per_bundle() == 1 {
+        frames.get_or_encode(format, FrameKind::Update(quality), || {
+            encode_or_log(&quality.degrade(&broadcast.update), format, compression)
+        })
+    } else {
+        match subscriber
+            .bundler
+            .push(quality.degrade(&broadcast.update).into_owned())
+        {
+            Some(bundle) => encode_or_log(&bundle, format, compression),
+            None => return !subscriber.tx.is_closed(),
+        }
+    };
+    match frame {
+        Some(frame) => subscriber.tx.send(frame).is_ok(),
+        None => true,
+    }
+}
+
+fn encode_or_log(
+    msg: &ServerToClientMessage,
+    format: Subprotocol,
+    compression: &Compression,
+) -> Option<Message> {
+    encode_reply(msg, format, compression)
+        .inspect_err(|e| eprintln!("Failed to encode a state for the subscribers: {e}"))
+        .ok()
+}
@@ -0,0 +1,38 @@
+use protocol::{RecordedState, ServerToClientMessage};
+
+/// Groups the states streamed to a subscriber into bundles of consecutive ticks
+/// (see `ClientToServerMessage::SubscribeBundled`)
+pub struct StateBundler {
+    ticks_per_bundle: usize,
+    pending: Vec<RecordedState>,
+}
+
+impl StateBundler {
+    /// A bundler of a single tick streams plain `StateUpdate`s
+    pub fn new(ticks_per_bundle: usize) -> Self {
+        let ticks_per_bundle = ticks_per_bundle.max(1);
+        Self {
+            ticks_per_bundle,
+            pending: Vec::with_capacity(ticks_per_bundle),
+        }
+    }
+
+    pub fn ticks_per_bundle(&self) -> usize {
+        self.ticks_per_bundle
+    }
+
+    /// Adds the `StateUpdate` of the last tick
+    /// Returns the message to push once the bundle is complete
+    pub fn push(&mut self, update: ServerToClientMessage) -> Option<ServerToClientMessage> {
+        if self.ticks_per_bundle == 1 {
+            return Some(update);
+        }
+        self.pending.push(RecordedState::from_update(update)?);
+        if self.pending.len() < self.ticks_per_bundle {
+            return None;
+        }
+        let bundle =
+            std::mem::replace(&mut self.pending, Vec::with_capacity(self.ticks_per_bundle));
+        Some(ServerToClientMessage::StateBundle(bundle))
+    }
+}
@@ -0,0 +1,99 @@
+use std::{
+    ops::RangeInclusive,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Default tick rate, the maximum front-end refresh rate
+pub const DEFAULT_TICK_RATE: f64 = 60.0;
+
+/// Tick rates (steps per second of wall-clock time) the clients can set
+/// The lower bound also bounds the time a room takes to notice a new rate
+pub const TICK_RATES: RangeInclusive<f64> = 1.0..=1000.0;
+
+/// Default catch-up policy, a room this late drops the ticks instead of stepping faster
+pub const DEFAULT_MAX_LAG: Duration = Duration::from_millis(250);
+
+/// Wall-clock pace of the steps of a room, changed at runtime with `SetTickRate`
+pub struct SimulationClock {
+    /// Bits of the tick rate (f64), read by the room at the start of each turn
+    tick_rate: AtomicU64,
+
+    /// Catch-up policy: a room behind its schedule steps faster (within its step budget)
+    /// to make up for up to that many microseconds, the ticks beyond are dropped
+    /// Zero drops the missed ticks right away (the simulation then loses time instead)
+    max_lag_micros: AtomicU64,
+
+    /// Microseconds the room was behind its schedule after its last turn
+    lag_micros: AtomicU64,
+
+    /// Ticks dropped so far to get back within `max_lag`
+    dropped_ticks: AtomicU64,
+}
+
+impl Default for SimulationClock {
+    fn default() -> Self {
+        Self::new(DEFAULT_TICK_RATE)
+    }
+}
+
+impl SimulationClock {
+    pub fn new(tick_rate: f64) -> Self {
+        Self {
+            tick_rate: AtomicU64::new(tick_rate.to_bits()),
+            max_lag_micros: AtomicU64::new(DEFAULT_MAX_LAG.as_micros() as u64),
+            lag_micros: AtomicU64::new(0),
+            dropped_ticks: AtomicU64::new(0),
+        }
+    }
+
+    /// Builder method to set the catch-up policy (`DEFAULT_MAX_LAG` otherwise)
+    pub fn with_max_lag(self, max_lag: Duration) -> Self {
+        self.set_max_lag(max_lag);
+        self
+    }
+
+    pub fn tick_rate(&self) -> f64 {
+        f64::from_bits(self.tick_rate.load(Ordering::Relaxed))
+    }
+
+    /// Taken from the next step on, the caller checks it is within `TICK_RATES`
+    pub fn set_tick_rate(&self, tick_rate: f64) {
+        self.tick_rate.store(tick_rate.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Wall-clock time between two consecutive steps
+    pub fn tick_interval(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / self.tick_rate())
+    }
+
+    pub fn set_max_lag(&self, max_lag: Duration) {
+        self.max_lag_micros
+            .store(max_lag.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn max_lag(&self) -> Duration {
+        Duration::from_micros(self.max_lag_micros.load(Ordering::Relaxed))
+    }
+
+    /// How late the last step of the room was
+    pub fn lag(&self) -> Duration {
+        Duration::from_micros(self.lag_micros.load(Ordering::Relaxed))
+    }
+
+    pub fn dropped_ticks(&self) -> u64 {
+        self.dropped_ticks.load(Ordering::Relaxed)
+    }
+
+    /// Accounts for a turn of the room that ended `lag` behind schedule, returns the lag to
+    /// catch up with in the next turns (the ticks beyond `max_lag` are dropped)
+    pub fn record_lag(&self, lag: Duration, tick_interval: Duration) -> Duration {
+        let kept = lag.min(self.max_lag());
+        let dropped = (lag - kept).as_nanos() / tick_interval.as_nanos().max(1);
+        self.lag_micros
+            .store(kept.as_micros() as u64, Ordering::Relaxed);
+        self.dropped_ticks
+            .fetch_add(dropped as u64, Ordering::Relaxed);
+        kept
+    }
+}
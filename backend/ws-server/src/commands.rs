@@ -0,0 +1,96 @@
+use nbody::{
+    constraints::Constraint,
+    physics::{Body, BodyId, ExternalForce},
+    simulation::{
+        BoundaryCondition, PhyiscsParameters, Simulation, SimulationSnapshot, SolverParameters,
+    },
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+
+use crate::lock;
+
+/// Mutation of the simulation requested by a client
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Command {
+    AddBodies(Vec<Body>),
+    RemoveBodies(Vec<BodyId>),
+    Reset,
+    SetSolverParameters(SolverParameters),
+    SetPhysicsParameters(PhyiscsParameters),
+    SetBoundaryCondition(BoundaryCondition),
+    SetExternalForces(Vec<ExternalForce>),
+    AddConstraint(Constraint),
+    RemoveConstraint(BodyId, BodyId),
+
+    /// Replaces the whole simulation (e.g. with a snapshot loaded from disk)
+    Restore(SimulationSnapshot),
+}
+
+impl Command {
+    pub fn apply(&self, simulation: &mut Simulation) {
+        match self {
+            Command::AddBodies(bodies) => simulation.add_bodies(bodies.clone()),
+            Command::RemoveBodies(ids) => {
+                simulation.remove_bodies(ids);
+            }
+            Command::Reset => simulation.reset(),
+            Command::SetSolverParameters(parameters) => {
+                simulation.set_solver_parameters(parameters.clone());
+            }
+            Command::SetPhysicsParameters(parameters) => {
+                simulation.set_physics_parameters(parameters.clone());
+            }
+            Command::SetBoundaryCondition(boundary) => {
+                simulation.set_boundary_condition(*boundary);
+            }
+            Command::SetExternalForces(forces) => {
+                simulation.set_external_forces(forces.clone());
+            }
+            Command::AddConstraint(constraint) => {
+                // Fails if a body was removed since the handler checked them
+                if let Err(e) = simulation.add_constraint(*constraint) {
+                    eprintln!("Failed to add a constraint: {e}");
+                }
+            }
+            Command::RemoveConstraint(a, b) => {
+                simulation.remove_constraint(*a, *b);
+            }
+            Command::Restore(snapshot) => simulation.restore(snapshot.clone()),
+        }
+    }
+}
+
+/// Mutations waiting for the next step boundary, applied in order by the room
+/// stepping the simulation (so that the clients never lock it in the middle of a turn)
+#[derive(Default)]
+pub struct CommandQueue {
+    pending: Mutex<Vec<(Command, oneshot::Sender<()>)>>,
+}
+
+impl CommandQueue {
+    pub fn new() -> Self {
+        CommandQueue::default()
+    }
+
+    /// Queues the command, the returned receiver completes once it has been applied
+    /// (and fails if the room is gone)
+    pub fn push(&self, command: Command) -> oneshot::Receiver<()> {
+        let (done, applied) = oneshot::channel();
+        lock!(self.pending).push((command, done));
+        applied
+    }
+
+    /// Applies the queued commands, `before_applied` runs right before each of them
+    /// (e.g. to journal it ahead of its effects)
+    pub fn apply(&self, simulation: &mut Simulation, mut before_applied: impl FnMut(&Command)) {
+        let pending = std::mem::take(&mut *lock!(self.pending));
+        for (command, done) in pending {
+            before_applied(&command);
+            command.apply(simulation);
+            // The client may have disconnected meanwhile
+            let _ = done.send(());
+        }
+    }
+}
@@ -0,0 +1,232 @@
+//! Settings of the server, read from a TOML file and overridden by the command line flags
+//!
+//! ```toml
+//! address = "127.0.0.1"
+//! port = 5000
+//! tick_rate = 60.0
+//! max_clients = 64
+//! max_bodies = 10000
+//! compression_level = 6
+//! metrics_port = 9100
+//! snapshot_dir = "snapshots"
+//! trail_length = 64
+//! worker_threads = 4
+//! ```
+
+use clap::Parser;
+use serde::Deserialize;
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+use crate::{clock::TICK_RATES, ServerState};
+
+/// Where the server listens unless configured otherwise
+pub const DEFAULT_ADDRESS: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 5000);
+
+/// Every field is optional in the file, the ones left out keep the defaults of `ServerState`
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Interface the server binds, all of them by default
+    pub address: IpAddr,
+
+    pub port: u16,
+
+    /// Steps per second of the simulations, see `ServerState::with_tick_rate`
+    pub tick_rate: Option<f64>,
+
+    /// Connections refused beyond this many clients
+    pub max_clients: Option<usize>,
+
+    /// Bodies a simulation is not grown beyond
+    pub max_bodies: Option<usize>,
+
+    /// Gzip level of `nbody.bincode.gz.v1`, from 0 (store) to 9 (best)
+    pub compression_level: Option<u32>,
+
+    /// Port of the Prometheus `/metrics` endpoint (on the same address), not served if None
+    pub metrics_port: Option<u16>,
+
+    /// Directory of the snapshots saved by the clients, `SaveSnapshot` is refused without it
+    pub snapshot_dir: Option<PathBuf>,
+
+    /// Positions recorded per body for `GetTrails`, see `ServerState::with_trail_length`
+    pub trail_length: Option<usize>,
+
+    /// Threads stepping the simulations, see `ServerState::with_worker_threads`
+    pub worker_threads: Option<usize>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            address: DEFAULT_ADDRESS.ip(),
+            port: DEFAULT_ADDRESS.port(),
+            tick_rate: None,
+            max_clients: None,
+            max_bodies: None,
+            compression_level: None,
+            metrics_port: None,
+            snapshot_dir: None,
+            trail_length: None,
+            worker_threads: None,
+        }
+    }
+}
+
+/// Errors raised while loading the configuration file
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Invalid configuration: {0}")]
+    Toml(#[from] toml::de::Error),
+
+    #[error("Invalid configuration: the {0}")]
+    OutOfRange(String),
+}
+
+impl ServerConfig {
+    pub fn from_toml(toml: &str) -> Result<Self, ConfigError> {
+        toml::from_str::<Self>(toml)?.validated()
+    }
+
+    /// Rejects the settings the server would panic on, from the file or the flags
+    fn validated(self) -> Result<Self, ConfigError> {
+        if let Some(tick_rate) = self.tick_rate {
+            // Also rejects NaNs
+            if !TICK_RATES.contains(&tick_rate) {
+                return Err(ConfigError::OutOfRange(format!(
+                    "tick rate of {tick_rate} Hz is not within [{}, {}]",
+                    TICK_RATES.start(),
+                    TICK_RATES.end()
+                )));
+            }
+        }
+        if let Some(level) = self.compression_level {
+            if level > 9 {
+                return Err(ConfigError::OutOfRange(format!(
+                    "compression level {level} is not within [0, 9]"
+                )));
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        ServerConfig::from_toml(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn socket_address(&self) -> SocketAddr {
+        SocketAddr::new(self.address, self.port)
+    }
+
+    pub fn metrics_address(&self) -> Option<SocketAddr> {
+        Some(SocketAddr::new(self.address, self.metrics_port?))
+    }
+
+    /// The state of a server with these threads, limits and pace
+    pub fn build_state(&self) -> ServerState {
+        let state = match self.worker_threads {
+            Some(threads) => ServerState::with_worker_threads(threads),
+            None => ServerState::new(),
+        };
+        self.apply(state)
+    }
+
+    /// Sets the limits and the pace of the server, the address is bound by `launch_ws_server`
+    /// (and the snapshot directory is opened with `ServerState::with_snapshot_dir`)
+    pub fn apply(&self, mut state: ServerState) -> ServerState {
+        if let Some(tick_rate) = self.tick_rate {
+            state = state.with_tick_rate(tick_rate);
+        }
+        if let Some(max_clients) = self.max_clients {
+            state = state.with_max_clients(max_clients);
+        }
+        if let Some(max_bodies) = self.max_bodies {
+            state = state.with_max_bodies(max_bodies);
+        }
+        if let Some(level) = self.compression_level {
+            state = state.with_compression_level(level);
+        }
+        if let Some(trail_length) = self.trail_length {
+            state = state.with_trail_length(trail_length);
+        }
+        state
+    }
+}
+
+/// Command line of the server, the flags given take precedence over the file
+#[derive(Debug, Parser)]
+#[command(
+    name = "ws-server",
+    about = "Runs the n-body simulations for WebSocket clients"
+)]
+pub struct ServerArgs {
+    /// TOML file with the settings (see the `config` module)
+    #[arg(long, short)]
+    pub config: Option<PathBuf>,
+
+    #[arg(long)]
+    pub address: Option<IpAddr>,
+
+    #[arg(long, short)]
+    pub port: Option<u16>,
+
+    /// Steps per second of wall-clock time (60 by default), the clients can change it
+    #[arg(long, env = "NBODY_TICK_RATE")]
+    pub tick_rate: Option<f64>,
+
+    #[arg(long)]
+    pub max_clients: Option<usize>,
+
+    #[arg(long)]
+    pub max_bodies: Option<usize>,
+
+    /// Gzip level of `nbody.bincode.gz.v1`, from 0 (store) to 9 (best)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(0..=9))]
+    pub compression_level: Option<u32>,
+
+    /// Serves the Prometheus metrics on `/metrics` at this port
+    #[arg(long)]
+    pub metrics_port: Option<u16>,
+
+    /// Directory the clients save their snapshots in (and load them from)
+    #[arg(long)]
+    pub snapshot_dir: Option<PathBuf>,
+
+    /// Positions recorded per body for the trails of the clients (64 by default, 0 for none)
+    #[arg(long)]
+    pub trail_length: Option<usize>,
+
+    /// Threads stepping the simulations (one per core by default), each room's gravity being
+    /// computed across the ones the other rooms leave idle
+    #[arg(long)]
+    pub worker_threads: Option<usize>,
+}
+
+impl ServerArgs {
+    /// The file given with `--config` (the defaults without it) with the flags applied over it
+    pub fn into_config(self) -> Result<ServerConfig, ConfigError> {
+        let mut config = match &self.config {
+            Some(path) => ServerConfig::load(path)?,
+            None => ServerConfig::default(),
+        };
+        config.address = self.address.unwrap_or(config.address);
+        config.port = self.port.unwrap_or(config.port);
+        config.tick_rate = self.tick_rate.or(config.tick_rate);
+        config.max_clients = self.max_clients.or(config.max_clients);
+        config.max_bodies = self.max_bodies.or(config.max_bodies);
+        config.compression_level = self.compression_level.or(config.compression_level);
+        config.metrics_port = self.metrics_port.or(config.metrics_port);
+        config.snapshot_dir = self.snapshot_dir.or(config.snapshot_dir);
+        config.trail_length = self.trail_length.or(config.trail_length);
+        config.worker_threads = self.worker_threads.or(config.worker_threads);
+        config.validated()
+    }
+}
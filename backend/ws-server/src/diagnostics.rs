@@ -0,0 +1,106 @@
+use nbody::simulation::Simulation;
+use protocol::DiagnosticsSample;
+use std::collections::VecDeque;
+
+/// Physical time kept by default (seconds)
+const DEFAULT_WINDOW: f64 = 600.0;
+
+/// Samples kept by default, whatever the physical time they span
+const DEFAULT_CAPACITY: usize = 100_000;
+
+/// Rolling time series of the diagnostics of the simulation after each step,
+/// queried (downsampled) on `QueryDiagnostics`
+pub struct DiagnosticsStore {
+    /// In increasing time order
+    samples: VecDeque<DiagnosticsSample>,
+
+    /// Physical time span kept (seconds)
+    window: f64,
+
+    /// Maximum number of samples kept
+    capacity: usize,
+}
+
+impl Default for DiagnosticsStore {
+    fn default() -> Self {
+        Self {
+            samples: VecDeque::new(),
+            window: DEFAULT_WINDOW,
+            capacity: DEFAULT_CAPACITY,
+        }
+    }
+}
+
+impl DiagnosticsStore {
+    pub fn new() -> Self {
+        DiagnosticsStore::default()
+    }
+
+    /// Builder method to set the physical time span kept (seconds)
+    pub fn with_window(mut self, window: f64) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Builder method to set the maximum number of samples kept
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Appends the diagnostics of the current state of the simulation
+    pub fn record(&mut self, simulation: &Simulation) {
+        let time = simulation.get_physical_time();
+        if let Some(last) = self.samples.back() {
+            // The simulation was reset: the series belongs to another run
+            if time < last.physical_time {
+                self.samples.clear();
+            }
+        }
+        self.samples.push_back(DiagnosticsSample {
+            physical_time: time,
+            kinetic_energy: simulation.get_kinetic_energy(),
+            momentum: simulation.linear_momentum(),
+            body_count: simulation.get_number_of_bodies() as u32,
+        });
+        while let Some(first) = self.samples.front() {
+            if first.physical_time >= time - self.window && self.samples.len() <= self.capacity {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    /// The samples within [from_time, to_time], averaged over consecutive buckets
+    /// so that at most `max_points` are returned
+    pub fn query(&self, from_time: f64, to_time: f64, max_points: usize) -> Vec<DiagnosticsSample> {
+        let samples: Vec<_> = self
+            .samples
+            .iter()
+            .filter(|sample| (from_time..=to_time).contains(&sample.physical_time))
+            .collect();
+        if max_points == 0 {
+            return Vec::new();
+        }
+        let bucket_size = samples.len().div_ceil(max_points).max(1);
+        samples.chunks(bucket_size).map(average).collect()
+    }
+}
+
+/// Mean of the bucket, keeping the body count of its last sample
+fn average(bucket: &[&DiagnosticsSample]) -> DiagnosticsSample {
+    let n = bucket.len() as f64;
+    let mut mean = DiagnosticsSample::default();
+    for sample in bucket {
+        mean.physical_time += sample.physical_time / n;
+        mean.kinetic_energy += sample.kinetic_energy / n;
+        mean.momentum[0] += sample.momentum[0] / n;
+        mean.momentum[1] += sample.momentum[1] / n;
+        mean.body_count = sample.body_count;
+    }
+    mean
+}
@@ -0,0 +1,64 @@
+use nbody::{validation::ParameterIssue, PhysicsError};
+use protocol::{ProtocolError, Role, RoomId};
+use thiserror::Error;
+
+use crate::snapshots::SnapshotError;
+use tokio_tungstenite::tungstenite;
+
+/// Errors raised while serving the clients
+#[derive(Debug, Error)]
+pub enum ServerError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("WebSocket error: {0}")]
+    WebSocket(#[from] tungstenite::Error),
+
+    /// A message could not be encoded or decoded
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+
+    /// A request was rejected by the simulation
+    #[error(transparent)]
+    Physics(#[from] PhysicsError),
+
+    /// The request is well-formed but cannot be served
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+
+    /// A snapshot could not be saved or loaded
+    #[error(transparent)]
+    Snapshot(#[from] SnapshotError),
+
+    /// The parameters were rejected, with all the problems found in them
+    #[error("Invalid parameters: {}", join(.0))]
+    InvalidParameters(Vec<ParameterIssue>),
+
+    /// The client did not authenticate, or with an unknown token
+    #[error("Authentication required")]
+    Unauthenticated,
+
+    /// The role of the client is not allowed to make the request
+    #[error("Permission denied: the {0:?} role is required")]
+    Forbidden(Role),
+
+    /// The room is not open (or no longer)
+    #[error("Unknown room {}", .0 .0)]
+    UnknownRoom(RoomId),
+
+    /// The room stepping the simulation is gone, its commands are never applied
+    #[error("The simulation is not running")]
+    SimulationStopped,
+
+    /// The connection task of the client is gone
+    #[error("The client disconnected")]
+    ClientDisconnected,
+}
+
+fn join(issues: &[ParameterIssue]) -> String {
+    issues
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
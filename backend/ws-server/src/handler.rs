@@ -1,50 +1,576 @@
-use nbody::simulation::Simulation;
-use std::sync::Arc;
+use nbody::{
+    scenarios::ScenarioKind,
+    simulation::{Simulation, SimulationParameters},
+    validation::{ParameterIssue, Severity},
+    PhysicsError,
+};
+use protocol::{
+    dictionary::Dictionary,
+    encode, encode_json, encode_with,
+    flatbuffers::encode_state_update,
+    protobuf::{encode_as, Encoding},
+    BodyTrail, ClientToServerMessage, ProtocolError, RecordedState, Role, RoomId,
+    ServerToClientMessage, Subprotocol, MAX_TICKS_PER_BUNDLE,
+};
+use std::{
+    io,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use tokio::sync::mpsc::UnboundedSender;
 use tokio_tungstenite::tungstenite::Message;
-use wasm_bindings::{serialize_server_msg, ClientToServerMessage, ServerToClientMessage};
 
-use crate::{lock, state::ServerState};
+use crate::{
+    auth::required_role,
+    bandwidth::ClientLink,
+    broadcast::broadcast_states,
+    clock::TICK_RATES,
+    commands::Command,
+    error::ServerError,
+    lock,
+    snapshots::{SnapshotError, SnapshotStore},
+    state::{RoomState, ServerState, Subscriber},
+};
 
+/// Bounds of the rate of a history playback (states per second)
+const MIN_PLAYBACK_RATE: f64 = 0.01;
+const MAX_PLAYBACK_RATE: f64 = 240.0;
+
+/// Upper bound of the bodies generated by `LoadScenario`, when the server sets no `max_bodies`
+const MAX_SCENARIO_BODIES: usize = 100_000;
+
+/// Replies are sent in the given format (the negotiated subprotocol or else the request's)
+/// The request is rejected unless the `role` of the client allows it, `Authenticate` sets it
 pub async fn handle_client_to_server_messages(
     msg: ClientToServerMessage,
+    format: Subprotocol,
     state: Arc<ServerState>,
+    room: &mut Arc<RoomState>,
     tx: UnboundedSender<Message>,
-) {
+    link: Arc<ClientLink>,
+    role: &mut Option<Role>,
+) -> Result<(), ServerError> {
+    let required = required_role(&msg);
+    match *role {
+        _ if matches!(msg, ClientToServerMessage::Authenticate { .. }) => {}
+        None => return Err(ServerError::Unauthenticated),
+        Some(granted) if granted < required => return Err(ServerError::Forbidden(required)),
+        Some(_) => {}
+    }
     match msg {
+        ClientToServerMessage::Authenticate { token } => {
+            *role = state.authenticate(&token);
+            let granted = role.ok_or(ServerError::Unauthenticated)?;
+            let reply = ServerToClientMessage::Authenticated(granted);
+            tx.send(encode_reply(&reply, format, &state.compression)?)
+                .map_err(|_| ServerError::ClientDisconnected)?;
+        }
         ClientToServerMessage::Subscribe => {
-            lock!(state.connected_clients).push(tx);
+            lock!(room.connected_clients).push(Subscriber::new(tx, format, link, 1));
+        }
+        ClientToServerMessage::SubscribeBundled { ticks_per_bundle } => {
+            if !(1..=MAX_TICKS_PER_BUNDLE).contains(&ticks_per_bundle) {
+                return Err(ServerError::InvalidRequest(format!(
+                    "bundles of {ticks_per_bundle} ticks (at most {MAX_TICKS_PER_BUNDLE})"
+                )));
+            }
+            lock!(room.connected_clients).push(Subscriber::new(
+                tx,
+                format,
+                link,
+                ticks_per_bundle as usize,
+            ));
+        }
+        ClientToServerMessage::SubscribeDeltas => {
+            lock!(room.connected_clients).push(Subscriber::new(tx, format, link, 1).with_deltas());
+        }
+        ClientToServerMessage::AckState { tick } => {
+            let mut subscribers = lock!(room.connected_clients);
+            let subscriber = subscribers
+                .iter_mut()
+                .find(|subscriber| subscriber.deltas && Arc::ptr_eq(&subscriber.link, &link))
+                .ok_or_else(|| {
+                    ServerError::InvalidRequest("acknowledgement without SubscribeDeltas".into())
+                })?;
+            // Acknowledgements can arrive out of order
+            subscriber.acked_tick = subscriber.acked_tick.max(Some(tick));
+        }
+        ClientToServerMessage::Pause => room.run_state.pause(),
+        ClientToServerMessage::Resume => room.run_state.resume(),
+        ClientToServerMessage::SingleStep => {
+            let mut steps = room.steps.clone();
+            let from = *steps.borrow_and_update();
+            if !room.run_state.single_step() {
+                return Err(ServerError::InvalidRequest(
+                    "single step while the simulation is running".into(),
+                ));
+            }
+            // Awaited so that the next requests of the client see the new tick
+            steps
+                .wait_for(|&tick| tick > from)
+                .await
+                .map_err(|_| ServerError::SimulationStopped)?;
+        }
+        ClientToServerMessage::SetTickRate(tick_rate) => {
+            // Also rejects NaNs
+            if !TICK_RATES.contains(&tick_rate) {
+                return Err(ServerError::InvalidRequest(format!(
+                    "tick rate of {tick_rate} Hz (within [{}, {}])",
+                    TICK_RATES.start(),
+                    TICK_RATES.end()
+                )));
+            }
+            room.clock.set_tick_rate(tick_rate);
+        }
+        ClientToServerMessage::CreateRoom => {
+            let created = state.open_room();
+            tokio::spawn(broadcast_states(Arc::clone(&state), Arc::clone(&created)));
+            move_to(&state, room, created, &link);
+            let reply = ServerToClientMessage::RoomJoined(room.id);
+            tx.send(encode_reply(&reply, format, &state.compression)?)
+                .map_err(|_| ServerError::ClientDisconnected)?;
+        }
+        ClientToServerMessage::JoinRoom(id) => {
+            let joined = state.join(id).ok_or(ServerError::UnknownRoom(id))?;
+            move_to(&state, room, joined, &link);
+            let reply = ServerToClientMessage::RoomJoined(room.id);
+            tx.send(encode_reply(&reply, format, &state.compression)?)
+                .map_err(|_| ServerError::ClientDisconnected)?;
         }
+        ClientToServerMessage::LeaveRoom => {
+            let lobby = state
+                .join(RoomId::LOBBY)
+                .expect("the lobby is never closed");
+            move_to(&state, room, lobby, &link);
+            let reply = ServerToClientMessage::RoomJoined(room.id);
+            tx.send(encode_reply(&reply, format, &state.compression)?)
+                .map_err(|_| ServerError::ClientDisconnected)?;
+        }
+        // Awaited so that the next requests of the client see the mutation
         ClientToServerMessage::AddBodies(bodies) => {
-            let mut simulation = lock!(state.simulation.1);
-            bodies
-                .into_iter()
-                .for_each(|body| simulation.add_body(body));
+            check_capacity(&state, room, bodies.len()).map_err(ServerError::InvalidRequest)?;
+            room.commands
+                .push(Command::AddBodies(bodies))
+                .await
+                .map_err(|_| ServerError::SimulationStopped)?;
+        }
+        ClientToServerMessage::LoadScenario(scenario) => {
+            // Checked before generating, the count could be anything
+            let count = match scenario.kind {
+                ScenarioKind::Binary { .. } => 2,
+                ScenarioKind::Spiral { .. } => scenario.count.saturating_add(1),
+                _ => scenario.count,
+            };
+            if count > MAX_SCENARIO_BODIES {
+                return Err(ServerError::InvalidRequest(format!(
+                    "Scenario of {count} bodies (at most {MAX_SCENARIO_BODIES})"
+                )));
+            }
+            check_capacity(&state, room, count).map_err(ServerError::InvalidRequest)?;
+            let gravity_constant = lock!(room.simulation.1)
+                .parameters()
+                .physics
+                .gravity_constant();
+            let bodies = scenario.generate(gravity_constant);
+            let finite = bodies.iter().all(|body| {
+                body.position
+                    .iter()
+                    .chain(&body.velocity)
+                    .chain([&body.mass, &body.radius])
+                    .all(|x| x.is_finite())
+            });
+            if !finite {
+                return Err(ServerError::InvalidRequest(
+                    "The scenario generates bodies out of bounds".to_owned(),
+                ));
+            }
+            room.commands
+                .push(Command::AddBodies(bodies))
+                .await
+                .map_err(|_| ServerError::SimulationStopped)?;
         }
         ClientToServerMessage::State => {
             let sim_state = {
-                let simulation = lock!(state.simulation.1);
+                let simulation = lock!(room.simulation.1);
                 gather_state(&simulation)
             };
-            match serialize_server_msg(sim_state).map(|msg| tx.send(Message::binary(msg))) {
-                Some(Ok(_)) => {}
-                Some(Err(e)) => eprintln!("Failed to send state update: {:?}", e),
-                None => eprintln!("Failed to serialize state update"),
-            }
+            tx.send(encode_reply(&sim_state, format, &state.compression)?)
+                .map_err(|_| ServerError::ClientDisconnected)?;
         }
         ClientToServerMessage::Reset => {
-            let mut simulation = lock!(state.simulation.1);
-            simulation.reset();
+            room.commands
+                .push(Command::Reset)
+                .await
+                .map_err(|_| ServerError::SimulationStopped)?;
+        }
+        ClientToServerMessage::SaveSnapshot { name } => {
+            let store = snapshot_store(&state)?;
+            let snapshot = lock!(room.simulation.1).snapshot();
+            let physical_time = snapshot.physical_time;
+            let saved = name.clone();
+            tokio::task::spawn_blocking(move || store.save(&saved, &snapshot))
+                .await
+                .map_err(io::Error::from)??;
+            let reply = ServerToClientMessage::SnapshotSaved {
+                name,
+                physical_time,
+            };
+            tx.send(encode_reply(&reply, format, &state.compression)?)
+                .map_err(|_| ServerError::ClientDisconnected)?;
+        }
+        ClientToServerMessage::LoadSnapshot { name } => {
+            let store = snapshot_store(&state)?;
+            let snapshot = tokio::task::spawn_blocking(move || store.load(&name))
+                .await
+                .map_err(io::Error::from)??;
+            room.commands
+                .push(Command::Restore(snapshot))
+                .await
+                .map_err(|_| ServerError::SimulationStopped)?;
+        }
+        ClientToServerMessage::SetSolverParameters(parameters) => {
+            let candidate = {
+                let simulation = lock!(room.simulation.1);
+                let candidate = SimulationParameters {
+                    solver: parameters,
+                    ..simulation.parameters().clone()
+                };
+                check_parameters(&simulation, &candidate)
+                    .map_err(ServerError::InvalidParameters)?;
+                candidate
+            };
+            room.commands
+                .push(Command::SetSolverParameters(candidate.solver))
+                .await
+                .map_err(|_| ServerError::SimulationStopped)?;
+        }
+        ClientToServerMessage::SetPhysicsParameters(parameters) => {
+            let candidate = {
+                let simulation = lock!(room.simulation.1);
+                let candidate = SimulationParameters {
+                    physics: parameters,
+                    ..simulation.parameters().clone()
+                };
+                check_parameters(&simulation, &candidate)
+                    .map_err(ServerError::InvalidParameters)?;
+                candidate
+            };
+            room.commands
+                .push(Command::SetPhysicsParameters(candidate.physics))
+                .await
+                .map_err(|_| ServerError::SimulationStopped)?;
+        }
+        ClientToServerMessage::SetBoundaryCondition(boundary) => {
+            {
+                let simulation = lock!(room.simulation.1);
+                let candidate = SimulationParameters {
+                    boundary,
+                    ..simulation.parameters().clone()
+                };
+                check_parameters(&simulation, &candidate)
+                    .map_err(ServerError::InvalidParameters)?;
+            }
+            room.commands
+                .push(Command::SetBoundaryCondition(boundary))
+                .await
+                .map_err(|_| ServerError::SimulationStopped)?;
+        }
+        ClientToServerMessage::SetExternalForces(forces) => {
+            {
+                let simulation = lock!(room.simulation.1);
+                let candidate = SimulationParameters {
+                    external_forces: forces.clone(),
+                    ..simulation.parameters().clone()
+                };
+                check_parameters(&simulation, &candidate)
+                    .map_err(ServerError::InvalidParameters)?;
+            }
+            room.commands
+                .push(Command::SetExternalForces(forces))
+                .await
+                .map_err(|_| ServerError::SimulationStopped)?;
+        }
+        ClientToServerMessage::RequestHistory {
+            from_time,
+            to_time,
+            rate,
+        } => {
+            // Also rejects NaNs (faster rates are capped rather than rejected)
+            let valid = from_time <= to_time && rate >= MIN_PLAYBACK_RATE && rate.is_finite();
+            if !valid {
+                return Err(ServerError::InvalidRequest(format!(
+                    "history window [{from_time}, {to_time}] at rate {rate}"
+                )));
+            }
+            let states = lock!(room.recorder).states_between(from_time, to_time);
+            tokio::spawn(play_back(
+                states,
+                rate,
+                format,
+                Arc::clone(&state),
+                tx,
+                link,
+            ));
+        }
+        ClientToServerMessage::QueryDiagnostics {
+            from_time,
+            to_time,
+            max_points,
+        } => {
+            let valid = from_time <= to_time && max_points > 0;
+            if !valid {
+                return Err(ServerError::InvalidRequest(format!(
+                    "diagnostics window [{from_time}, {to_time}] with {max_points} points"
+                )));
+            }
+            let samples = lock!(room.diagnostics).query(from_time, to_time, max_points as usize);
+            tx.send(encode_reply(
+                &ServerToClientMessage::Diagnostics(samples),
+                format,
+                &state.compression,
+            )?)
+            .map_err(|_| ServerError::ClientDisconnected)?;
+        }
+        ClientToServerMessage::QueryRegion(region) => {
+            let reply = {
+                let simulation = lock!(room.simulation.1);
+                let bodies = simulation
+                    .bodies_in(region)
+                    .into_iter()
+                    .map(|i| simulation.bodies().body(i))
+                    .collect();
+                ServerToClientMessage::Region {
+                    bodies,
+                    physical_time: simulation.get_physical_time(),
+                }
+            };
+            tx.send(encode_reply(&reply, format, &state.compression)?)
+                .map_err(|_| ServerError::ClientDisconnected)?;
+        }
+        ClientToServerMessage::GetBody(id) => {
+            let reply = {
+                let simulation = lock!(room.simulation.1);
+                let idx = simulation
+                    .body_index(id)
+                    .ok_or(PhysicsError::UnknownBody(id))?;
+                let body = simulation.bodies().body(idx);
+                ServerToClientMessage::BodyDetails {
+                    body,
+                    speed: body.speed(),
+                    kinetic_energy: body.kinectic_energy(),
+                    acceleration: simulation.get_acceleration(idx),
+                    physical_time: simulation.get_physical_time(),
+                }
+            };
+            tx.send(encode_reply(&reply, format, &state.compression)?)
+                .map_err(|_| ServerError::ClientDisconnected)?;
+        }
+        ClientToServerMessage::RemoveBodies(ids) => {
+            // Checked now to answer the client, the removal itself waits for the step boundary
+            {
+                let simulation = lock!(room.simulation.1);
+                if let Some(&id) = ids.iter().find(|&&id| simulation.body_index(id).is_none()) {
+                    return Err(PhysicsError::UnknownBody(id).into());
+                }
+            }
+            room.commands
+                .push(Command::RemoveBodies(ids))
+                .await
+                .map_err(|_| ServerError::SimulationStopped)?;
+        }
+        ClientToServerMessage::AddConstraint(constraint) => {
+            if !constraint.is_valid() {
+                return Err(PhysicsError::InvalidConstraint(constraint.bodies).into());
+            }
+            {
+                let simulation = lock!(room.simulation.1);
+                let bodies = constraint.bodies;
+                if let Some(&id) = bodies
+                    .iter()
+                    .find(|&&id| simulation.body_index(id).is_none())
+                {
+                    return Err(PhysicsError::UnknownBody(id).into());
+                }
+            }
+            room.commands
+                .push(Command::AddConstraint(constraint))
+                .await
+                .map_err(|_| ServerError::SimulationStopped)?;
+        }
+        ClientToServerMessage::RemoveConstraint(a, b) => {
+            room.commands
+                .push(Command::RemoveConstraint(a, b))
+                .await
+                .map_err(|_| ServerError::SimulationStopped)?;
+        }
+        ClientToServerMessage::Ping { client_time } => {
+            let server_time = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64()
+                * 1000.0;
+            let reply = ServerToClientMessage::Pong {
+                client_time,
+                server_time,
+                tick: room.simulation.0.load(Ordering::Relaxed) as u64,
+            };
+            tx.send(encode_reply(&reply, format, &state.compression)?)
+                .map_err(|_| ServerError::ClientDisconnected)?;
+        }
+        ClientToServerMessage::GetHistory { last_n } => {
+            let states = lock!(room.recorder).last_states(last_n as usize);
+            let reply = ServerToClientMessage::History(
+                states
+                    .iter()
+                    .filter_map(|state| RecordedState::from_update(state.as_ref().clone()))
+                    .collect(),
+            );
+            tx.send(encode_reply(&reply, format, &state.compression)?)
+                .map_err(|_| ServerError::ClientDisconnected)?;
+        }
+        ClientToServerMessage::GetTrails { last_n } => {
+            let reply = {
+                let simulation = lock!(room.simulation.1);
+                let trails = simulation.trails().ok_or_else(|| {
+                    ServerError::InvalidRequest("trails are not recorded by this server".into())
+                })?;
+                let trails = trails
+                    .iter()
+                    .map(|trail| BodyTrail {
+                        id: trail.id(),
+                        positions: trail
+                            .positions()
+                            .skip(trail.len().saturating_sub(last_n as usize))
+                            .collect(),
+                    })
+                    .collect();
+                ServerToClientMessage::Trails {
+                    trails,
+                    physical_time: simulation.get_physical_time(),
+                }
+            };
+            tx.send(encode_reply(&reply, format, &state.compression)?)
+                .map_err(|_| ServerError::ClientDisconnected)?;
         }
     }
+    Ok(())
 }
 
-pub fn gather_state(simulation: &Simulation) -> ServerToClientMessage {
-    let nbodies = simulation.get_number_of_bodies();
-    let bodies = (0..nbodies).map(|i| simulation.get_body(i)).collect();
-    ServerToClientMessage::StateUpdate {
-        bodies,
-        physical_time: simulation.get_physical_time(),
-        kinetic_energy: simulation.get_kinetic_energy(),
+/// Moves the client to a room it already joined, out of its current one
+fn move_to(
+    state: &ServerState,
+    room: &mut Arc<RoomState>,
+    joined: Arc<RoomState>,
+    link: &Arc<ClientLink>,
+) {
+    let left = std::mem::replace(room, joined);
+    state.leave(&left, link);
+}
+
+/// Fails with all the issues if any of them is an error, warnings alone are only logged
+fn check_parameters(
+    simulation: &Simulation,
+    candidate: &SimulationParameters,
+) -> Result<(), Vec<ParameterIssue>> {
+    let issues = simulation.validate_parameters(candidate);
+    if issues
+        .iter()
+        .any(|issue| issue.severity() == Severity::Error)
+    {
+        return Err(issues);
+    }
+    for issue in issues {
+        println!("Accepted parameters with a warning: {issue}");
+    }
+    Ok(())
+}
+
+/// Describes the problem if the simulation of the room would grow beyond `max_bodies`
+/// (the bodies still queued are not counted, the limit can be overshot by a batch)
+fn check_capacity(state: &ServerState, room: &RoomState, added: usize) -> Result<(), String> {
+    let Some(max_bodies) = state.max_bodies else {
+        return Ok(());
+    };
+    let simulated = lock!(room.simulation.1).bodies().len();
+    if simulated + added > max_bodies {
+        return Err(format!(
+            "{added} bodies added to {simulated} (at most {max_bodies})"
+        ));
     }
+    Ok(())
+}
+
+fn snapshot_store(state: &ServerState) -> Result<Arc<SnapshotStore>, SnapshotError> {
+    state.snapshots.clone().ok_or(SnapshotError::Disabled)
+}
+
+/// Sends the recorded states one by one, paced at `rate` states per second
+/// (some are skipped or degraded while the client cannot keep up, see `Quality`)
+async fn play_back(
+    states: Vec<Arc<ServerToClientMessage>>,
+    rate: f64,
+    format: Subprotocol,
+    server: Arc<ServerState>,
+    tx: UnboundedSender<Message>,
+    link: Arc<ClientLink>,
+) {
+    let period = Duration::from_secs_f64(1.0 / rate.min(MAX_PLAYBACK_RATE));
+    let mut interval = tokio::time::interval(period);
+    for (i, state) in states.iter().enumerate() {
+        interval.tick().await;
+        let quality = link.quality();
+        // The last state is always sent, so that the client ends up in sync
+        if i % quality.stride() != 0 && i + 1 != states.len() {
+            server.metrics.record_dropped_frame();
+            continue;
+        }
+        let Ok(frame) = encode_reply(&quality.degrade(state), format, &server.compression) else {
+            continue;
+        };
+        if tx.send(frame).is_err() {
+            // The client is gone
+            return;
+        }
+    }
+}
+
+/// How the bincode frames are compressed, for the replies and the streamed states alike
+#[derive(Clone, Default)]
+pub struct Compression {
+    /// Shipped to the clients of `nbody.bincode.zstd.v1`, which is refused without it
+    pub dictionary: Option<Arc<Dictionary>>,
+
+    /// Gzip level of `nbody.bincode.gz.v1`, the codec's default if None
+    pub level: Option<u32>,
+}
+
+/// Frames a message in the given format
+/// (zstd frames fall back to gzip without a dictionary, though it is refused in the handshake)
+pub fn encode_reply(
+    msg: &ServerToClientMessage,
+    format: Subprotocol,
+    compression: &Compression,
+) -> Result<Message, ProtocolError> {
+    let frame = match (format, &compression.dictionary) {
+        (Subprotocol::BincodeZstd, Some(dictionary)) => dictionary.encode(msg)?,
+        (Subprotocol::Bincode | Subprotocol::BincodeGzip | Subprotocol::BincodeZstd, _) => {
+            let options = format.codec_options();
+            let options = match compression.level {
+                Some(level) => options.with_level(level),
+                None => options,
+            };
+            encode_with(msg, &options)?
+        }
+        (Subprotocol::Json, _) => return Ok(Message::text(encode_json(msg)?)),
+        (Subprotocol::Protobuf, _) => encode_as(msg, Encoding::Protobuf)?,
+        (Subprotocol::FlatBuffers, _) => match encode_state_update(msg) {
+            Some(frame) => frame,
+            None => encode(msg)?,
+        },
+    };
+    Ok(Message::binary(frame))
+}
+
+pub fn gather_state(simulation: &Simulation) -> ServerToClientMessage {
+    ServerToClientMessage::state_update(simulation)
 }
@@ -0,0 +1,193 @@
+//! Write-ahead journal of the commands applied to the simulation, for crash recovery
+//!
+//! The file starts with a checkpoint (a snapshot of the simulation) followed by the commands
+//! applied since, each one with the tick it was applied at (and written before it is applied).
+//! Replaying them over the checkpoint brings the simulation back to the tick of the last command
+//! before the crash. For the steps replayed to land on the same states as the live run, the
+//! journaled simulations are stepped in deterministic mode (see `keep_deterministic`).
+//! Layout: [JOURNAL_FORMAT_VERSION, (u32 LE length, bincode(entry))...]
+
+use nbody::{
+    determinism::DeterministicMode,
+    simulation::{Simulation, SimulationSnapshot},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::commands::Command;
+
+/// Version of the journal format, first byte of the file
+pub const JOURNAL_FORMAT_VERSION: u8 = 8;
+
+/// Ticks between two checkpoints (a minute at the default tick rate),
+/// which bounds both the size of the journal and the steps replayed on recovery
+pub const DEFAULT_CHECKPOINT_INTERVAL: u64 = 3600;
+
+/// The journal of a room, None until the server enables it
+pub type SharedJournal = Arc<Mutex<Option<Journal>>>;
+
+/// Puts the simulation back in deterministic mode if a command took it out of it (e.g. the
+/// restore of a snapshot saved without it), live and on replay alike
+/// Otherwise its quadtree would depend on how the bodies moved before the checkpoint
+pub fn keep_deterministic(simulation: &mut Simulation) {
+    if simulation.deterministic_mode().is_none() {
+        simulation.set_deterministic_mode(Some(DeterministicMode::default()));
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum Record {
+    Checkpoint(SimulationSnapshot),
+    Command(Command),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    /// Steps taken by the simulation before the record
+    tick: u64,
+    record: Record,
+}
+
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    checkpoint_interval: u64,
+    last_checkpoint: u64,
+}
+
+impl Journal {
+    /// Starts a journal at `path` (replacing any previous one) with a checkpoint of the simulation
+    pub fn create(
+        path: impl Into<PathBuf>,
+        tick: u64,
+        simulation: &Simulation,
+    ) -> io::Result<Self> {
+        let path = path.into();
+        let file = write_checkpoint(&path, tick, simulation.snapshot())?;
+        Ok(Self {
+            path,
+            file,
+            checkpoint_interval: DEFAULT_CHECKPOINT_INTERVAL,
+            last_checkpoint: tick,
+        })
+    }
+
+    /// Builder method to checkpoint the simulation every `interval` ticks
+    pub fn with_checkpoint_interval(mut self, interval: u64) -> Self {
+        self.checkpoint_interval = interval.max(1);
+        self
+    }
+
+    /// Restores the simulation from the journal at `path`: its checkpoint and then the commands
+    /// replayed at their ticks. Returns the tick reached, None if there is no journal
+    pub fn recover(path: &Path, simulation: &mut Simulation) -> io::Result<Option<u64>> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut entries = read_entries(&bytes)?.into_iter();
+        let Some(Entry {
+            tick: mut current,
+            record: Record::Checkpoint(snapshot),
+        }) = entries.next()
+        else {
+            return Err(invalid_data("the journal does not start with a checkpoint"));
+        };
+        simulation.restore(snapshot);
+        keep_deterministic(simulation);
+        for Entry { tick, record } in entries {
+            let Record::Command(command) = record else {
+                return Err(invalid_data("checkpoint in the middle of the journal"));
+            };
+            // Deterministic mode: the command lands on the same state as before the crash
+            while current < tick {
+                simulation.step();
+                current += 1;
+            }
+            command.apply(simulation);
+            keep_deterministic(simulation);
+        }
+        Ok(Some(current))
+    }
+
+    /// Durably appends a command about to be applied after `tick` steps
+    pub fn append(&mut self, tick: u64, command: &Command) -> io::Result<()> {
+        let entry = Entry {
+            tick,
+            record: Record::Command(command.clone()),
+        };
+        self.file.write_all(&frame(&entry)?)?;
+        self.file.sync_data()
+    }
+
+    pub fn checkpoint_due(&self, tick: u64) -> bool {
+        tick >= self.last_checkpoint + self.checkpoint_interval
+    }
+
+    /// Replaces the journal with a checkpoint of the simulation (the commands before it are dropped)
+    pub fn checkpoint(&mut self, tick: u64, simulation: &Simulation) -> io::Result<()> {
+        self.file = write_checkpoint(&self.path, tick, simulation.snapshot())?;
+        self.last_checkpoint = tick;
+        Ok(())
+    }
+}
+
+/// Writes a new journal next to `path` and then renames it, so that a crash in the middle
+/// leaves the previous journal intact. Returns the new file, open for appending
+fn write_checkpoint(path: &Path, tick: u64, snapshot: SimulationSnapshot) -> io::Result<File> {
+    let entry = Entry {
+        tick,
+        record: Record::Checkpoint(snapshot),
+    };
+    let mut staging = path.as_os_str().to_owned();
+    staging.push(".tmp");
+    let mut file = File::create(&staging)?;
+    file.write_all(&[JOURNAL_FORMAT_VERSION])?;
+    file.write_all(&frame(&entry)?)?;
+    file.sync_all()?;
+    fs::rename(&staging, path)?;
+    OpenOptions::new().append(true).open(path)
+}
+
+fn frame(entry: &Entry) -> io::Result<Vec<u8>> {
+    let data = bincode::serialize(entry).map_err(io::Error::other)?;
+    let length = u32::try_from(data.len()).map_err(io::Error::other)?;
+    let mut buffer = Vec::with_capacity(4 + data.len());
+    buffer.extend(length.to_le_bytes());
+    buffer.extend(data);
+    Ok(buffer)
+}
+
+/// The entries of the journal, but the last one if it was torn by a crash
+fn read_entries(bytes: &[u8]) -> io::Result<Vec<Entry>> {
+    let mut rest = match bytes.split_first() {
+        Some((&JOURNAL_FORMAT_VERSION, rest)) => rest,
+        Some((&version, _)) => {
+            return Err(invalid_data(&format!(
+                "unsupported journal format {version} (expected {JOURNAL_FORMAT_VERSION})"
+            )))
+        }
+        None => return Err(invalid_data("empty journal")),
+    };
+    let mut entries = Vec::new();
+    while let Some((length, data)) = rest.split_first_chunk::<4>() {
+        let length = u32::from_le_bytes(*length) as usize;
+        if data.len() < length {
+            break;
+        }
+        let (entry, next) = data.split_at(length);
+        entries.push(bincode::deserialize(entry).map_err(|e| invalid_data(&e.to_string()))?);
+        rest = next;
+    }
+    Ok(entries)
+}
+
+fn invalid_data(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
@@ -0,0 +1,53 @@
+//! WebSocket server running the simulation for its clients
+//!
+//! Exposed as a library so that the server can be embedded (e.g. booted on an ephemeral port
+//! by the end-to-end tests), `main.rs` only launches it as configured (see `ServerConfig`)
+
+mod auth;
+mod bandwidth;
+mod broadcast;
+mod bundle;
+mod clock;
+mod commands;
+mod config;
+mod diagnostics;
+mod error;
+mod handler;
+mod journal;
+mod metrics;
+mod recorder;
+mod scheduler;
+#[cfg(feature = "scripting")]
+mod scripting;
+mod snapshots;
+mod state;
+mod transport;
+mod ws;
+
+pub use config::{ConfigError, ServerArgs, ServerConfig, DEFAULT_ADDRESS};
+pub use error::ServerError;
+pub use metrics::{launch_metrics_server, serve_metrics, Metrics};
+#[cfg(feature = "scripting")]
+pub use scripting::{ScriptError, ScriptedForce};
+pub use snapshots::SnapshotError;
+pub use state::{RoomState, ServerState, DEFAULT_TRAIL_LENGTH};
+#[cfg(feature = "tls")]
+pub use transport::Tls;
+pub use transport::{Acceptor, PlainTcp};
+pub use ws::{launch_ws_server, launch_ws_server_with, serve, serve_with};
+
+#[macro_export]
+macro_rules! lock {
+    ($e:expr) => {
+        // Relying on this macro such that we could add custom
+        // debugging tools to try figure out deadlocks or other issues
+        // for example printing the line number and file where the lock was acquired
+        //
+        // #[cfg(debug_assertions)]
+        // println!("Lock acquired at line {} in file {}", line!(), file!());
+
+        // This macro is used to lock a std::sync::Mutex
+        // and return the inner value whether it was poisoned or not
+        $e.lock().unwrap_or_else(|e| e.into_inner())
+    };
+}
@@ -1,31 +1,131 @@
-mod handler;
-mod state;
-mod ws;
-
-use state::ServerState;
-use std::sync::Arc;
-
-#[macro_export]
-macro_rules! lock {
-    ($e:expr) => {
-        // Relying on this macro such that we could add custom
-        // debugging tools to try figure out deadlocks or other issues
-        // for example printing the line number and file where the lock was acquired
-        //
-        // #[cfg(debug_assertions)]
-        // println!("Lock acquired at line {} in file {}", line!(), file!());
-
-        // This macro is used to lock a std::sync::Mutex
-        // and return the inner value whether it was poisoned or not
-        $e.lock().unwrap_or_else(|e| e.into_inner())
-    };
-}
+use clap::Parser;
+use protocol::{dictionary::Dictionary, Role};
+use std::{sync::Arc, time::Duration};
+use ws_server::{launch_metrics_server, launch_ws_server, ServerArgs};
 
 #[tokio::main]
 async fn main() {
-    let state = Arc::new(ServerState::new());
-    let r = ws::launch_ws_server(Arc::clone(&state)).await;
+    // Not ignored: the server would run with other limits than the configured ones
+    let config = ServerArgs::parse()
+        .into_config()
+        .unwrap_or_else(|e| panic!("Failed to load the configuration: {e}"));
+    let mut state = config.build_state();
+
+    // Path of a zstd dictionary (see `protocol/examples/train_dictionary.rs`),
+    // enables the `nbody.bincode.zstd.v1` subprotocol
+    if let Ok(path) = std::env::var("NBODY_ZSTD_DICTIONARY") {
+        match load_dictionary(&path) {
+            Ok(dictionary) => state = state.with_dictionary(dictionary),
+            Err(e) => eprintln!("Ignoring the zstd dictionary {path}: {e}"),
+        }
+    }
+
+    // Minimum time between two states pushed to the subscribers (every step by default)
+    if let Ok(interval) = std::env::var("NBODY_BROADCAST_INTERVAL_MS") {
+        match interval.parse() {
+            Ok(ms) => state = state.with_broadcast_interval(Duration::from_millis(ms)),
+            Err(e) => eprintln!("Ignoring the broadcast interval {interval}: {e}"),
+        }
+    }
+
+    // How far behind schedule an overloaded simulation steps faster to catch up (250 ms by default),
+    // the ticks beyond are dropped
+    if let Ok(max_lag) = std::env::var("NBODY_MAX_LAG_MS") {
+        match max_lag.parse() {
+            Ok(ms) => state = state.with_max_lag(Duration::from_millis(ms)),
+            Err(e) => eprintln!("Ignoring the maximum lag {max_lag}: {e}"),
+        }
+    }
+
+    // Tokens the clients must authenticate with, as `token:role` pairs separated by commas
+    // (roles: spectator, controller or admin), every client is an admin without any
+    if let Ok(tokens) = std::env::var("NBODY_AUTH_TOKENS") {
+        for pair in tokens.split(',').filter(|pair| !pair.is_empty()) {
+            match parse_token(pair) {
+                Some((token, role)) => state = state.with_token(token, role),
+                None => eprintln!("Ignoring the malformed token {pair}"),
+            }
+        }
+    }
+
+    if let Some(dir) = &config.snapshot_dir {
+        // Not ignored: the clients would lose the snapshots they save
+        state = state
+            .with_snapshot_dir(dir)
+            .unwrap_or_else(|e| panic!("Failed to open the snapshot directory {dir:?}: {e}"));
+    }
+
+    // Path of the write-ahead journal the simulation is recovered from after a crash
+    if let Ok(path) = std::env::var("NBODY_JOURNAL") {
+        // Not ignored: running without it would lose the state on the next crash
+        state = state
+            .with_journal(&path)
+            .unwrap_or_else(|e| panic!("Failed to open the journal {path}: {e}"));
+    }
+    let state = Arc::new(state);
+
+    // Path of a Rhai script defining the force added to the gravity of the lobby (see `ScriptedForce`)
+    #[cfg(feature = "scripting")]
+    if let Ok(path) = std::env::var("NBODY_FORCE_SCRIPT") {
+        if let Err(e) = load_force_script(&state, &path) {
+            eprintln!("Ignoring the force script {path}: {e}");
+        }
+    }
+
+    if let Some(address) = config.metrics_address() {
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(e) = launch_metrics_server(address, state).await {
+                eprintln!("Failed to serve the metrics at {address}: {e}");
+            }
+        });
+    }
+
+    let address = config.socket_address();
+    #[cfg(feature = "tls")]
+    let r = match load_tls() {
+        Some(tls) => ws_server::launch_ws_server_with(address, Arc::clone(&state), tls).await,
+        None => launch_ws_server(address, Arc::clone(&state)).await,
+    };
+    #[cfg(not(feature = "tls"))]
+    let r = launch_ws_server(address, Arc::clone(&state)).await;
     if let Err(e) = r {
         eprintln!("Existing server with error: {:?}", e);
     }
 }
+
+fn load_dictionary(path: &str) -> Result<Dictionary, Box<dyn std::error::Error>> {
+    let dictionary = Dictionary::from_bytes(std::fs::read(path)?)?;
+    println!("Loaded the zstd dictionary {path}");
+    Ok(dictionary)
+}
+
+fn parse_token(pair: &str) -> Option<(&str, Role)> {
+    let (token, role) = pair.rsplit_once(':')?;
+    let role = serde_json::from_value(serde_json::Value::String(role.trim().to_owned())).ok()?;
+    Some((token.trim(), role))
+}
+
+/// The PEM files of the certificate chain and of its private key to serve wss:// with,
+/// from `NBODY_TLS_CERT` and `NBODY_TLS_KEY` (plain ws:// without them)
+#[cfg(feature = "tls")]
+fn load_tls() -> Option<ws_server::Tls> {
+    let cert = std::env::var("NBODY_TLS_CERT").ok()?;
+    let key = std::env::var("NBODY_TLS_KEY").ok()?;
+    // Not ignored: falling back to plain ws:// would expose the traffic
+    let tls = ws_server::Tls::from_pem_files(&cert, &key)
+        .unwrap_or_else(|e| panic!("Failed to load the TLS certificate {cert}: {e}"));
+    println!("Serving wss:// with the certificate {cert}");
+    Some(tls)
+}
+
+#[cfg(feature = "scripting")]
+fn load_force_script(
+    state: &ws_server::ServerState,
+    path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let force = ws_server::ScriptedForce::compile(&std::fs::read_to_string(path)?)?;
+    ws_server::lock!(state.lobby().simulation.1).set_force_hook(Some(Box::new(force)));
+    println!("Loaded the force script {path}");
+    Ok(())
+}
@@ -0,0 +1,197 @@
+//! Health of the server for the operators, served on `/metrics` in the Prometheus text format
+//!
+//! The counters only grow, Prometheus derives the rates (e.g. messages per second) from them
+
+use std::{
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{lock, ServerError, ServerState};
+
+/// Bytes of the request line (and then of the headers) read at most
+const MAX_REQUEST_HEAD: u64 = 8 * 1024;
+
+/// Counters updated by the rooms and the connections as they go
+#[derive(Default)]
+pub struct Metrics {
+    steps: AtomicU64,
+    step_nanos: AtomicU64,
+    messages_received: AtomicU64,
+    messages_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+
+    /// States not sent to the clients that could not keep up, see `Quality`
+    dropped_frames: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    pub fn record_step(&self, duration: Duration) {
+        self.steps.fetch_add(1, Ordering::Relaxed);
+        self.step_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_received(&self) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_sent(&self, bytes: usize) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_dropped_frame(&self) {
+        self.dropped_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The counters and the current state of the rooms, in the Prometheus text format
+    pub fn render(&self, state: &ServerState) -> String {
+        let mut out = String::new();
+        let counter = |out: &mut String, name: &str, help: &str, value: u64| {
+            let _ = writeln!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}"
+            );
+        };
+        let steps = self.steps.load(Ordering::Relaxed);
+        let step_seconds = self.step_nanos.load(Ordering::Relaxed) as f64 * 1e-9;
+        let _ = writeln!(
+            out,
+            "# HELP nbody_step_duration_seconds Time spent stepping the simulations\n\
+             # TYPE nbody_step_duration_seconds summary\n\
+             nbody_step_duration_seconds_sum {step_seconds}\n\
+             nbody_step_duration_seconds_count {steps}"
+        );
+        counter(
+            &mut out,
+            "nbody_messages_received_total",
+            "Messages received from the clients",
+            self.messages_received.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "nbody_messages_sent_total",
+            "Messages sent to the clients",
+            self.messages_sent.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "nbody_sent_bytes_total",
+            "Bytes sent to the clients",
+            self.bytes_sent.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "nbody_dropped_frames_total",
+            "States skipped for the clients that could not keep up",
+            self.dropped_frames.load(Ordering::Relaxed),
+        );
+        let _ = writeln!(
+            out,
+            "# HELP nbody_connected_clients Clients connected, in any room\n\
+             # TYPE nbody_connected_clients gauge\n\
+             nbody_connected_clients {}",
+            state.connections.load(Ordering::Relaxed)
+        );
+
+        let mut rooms: Vec<_> = lock!(state.rooms).values().cloned().collect();
+        rooms.sort_by_key(|room| room.id.0);
+        let _ = writeln!(
+            out,
+            "# HELP nbody_bodies Bodies simulated in the room\n# TYPE nbody_bodies gauge"
+        );
+        for room in &rooms {
+            let bodies = lock!(room.simulation.1).bodies().len();
+            let _ = writeln!(out, "nbody_bodies{{room=\"{}\"}} {bodies}", room.id.0);
+        }
+        let _ = writeln!(
+            out,
+            "# HELP nbody_dropped_ticks_total Ticks the room dropped to get back on schedule\n\
+             # TYPE nbody_dropped_ticks_total counter"
+        );
+        for room in &rooms {
+            let dropped = room.clock.dropped_ticks();
+            let _ = writeln!(
+                out,
+                "nbody_dropped_ticks_total{{room=\"{}\"}} {dropped}",
+                room.id.0
+            );
+        }
+        out
+    }
+}
+
+/// Serves `/metrics` to the scrapers connecting to `address`
+pub async fn launch_metrics_server(
+    address: SocketAddr,
+    state: Arc<ServerState>,
+) -> Result<(), ServerError> {
+    println!("Serving the metrics at http://{address}/metrics");
+    serve_metrics(TcpListener::bind(address).await?, state).await
+}
+
+/// Same as `launch_metrics_server` on an already bound listener
+pub async fn serve_metrics(
+    listener: TcpListener,
+    state: Arc<ServerState>,
+) -> Result<(), ServerError> {
+    while let Ok((stream, socket)) = listener.accept().await {
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(e) = answer_scrape(stream, &state).await {
+                eprintln!("Failed to serve the metrics to {socket}: {e}");
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Answers a single HTTP/1.1 request, the connection is closed afterwards
+async fn answer_scrape(stream: TcpStream, state: &ServerState) -> std::io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let mut request_line = String::new();
+    (&mut stream)
+        .take(MAX_REQUEST_HEAD)
+        .read_line(&mut request_line)
+        .await?;
+    // The headers are read through, or closing the socket could reset the connection
+    // before the scraper reads the response
+    let mut header = String::new();
+    let mut budget = MAX_REQUEST_HEAD;
+    loop {
+        header.clear();
+        let read = (&mut stream).take(budget).read_line(&mut header).await? as u64;
+        budget -= read;
+        if read == 0 || budget == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", state.metrics.render(state)),
+        _ => ("404 Not Found", "Not found, try /metrics\n".to_owned()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.get_mut().write_all(response.as_bytes()).await?;
+    stream.get_mut().shutdown().await
+}
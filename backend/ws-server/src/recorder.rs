@@ -0,0 +1,109 @@
+use nbody::simulation::Simulation;
+use protocol::ServerToClientMessage;
+use std::{collections::VecDeque, sync::Arc};
+
+use crate::handler::gather_state;
+
+/// Physical time kept by default (seconds)
+const DEFAULT_WINDOW: f64 = 60.0;
+
+/// Physical time between two recorded states by default (seconds)
+const DEFAULT_INTERVAL: f64 = 0.1;
+
+/// Number of states kept by default, whatever the physical time they span
+const DEFAULT_MAX_STATES: usize = 600;
+
+/// Rolling recording of the last states of the simulation,
+/// replayed on `RequestHistory` and sent at once on `GetHistory`
+pub struct Recorder {
+    /// (physical time, state) in increasing time order
+    states: VecDeque<(f64, Arc<ServerToClientMessage>)>,
+
+    /// Physical time span kept (seconds)
+    window: f64,
+
+    /// Minimum physical time between two recorded states (seconds)
+    interval: f64,
+
+    max_states: usize,
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self {
+            states: VecDeque::new(),
+            window: DEFAULT_WINDOW,
+            interval: DEFAULT_INTERVAL,
+            max_states: DEFAULT_MAX_STATES,
+        }
+    }
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Recorder::default()
+    }
+
+    /// Builder method to set the physical time span kept (seconds)
+    pub fn with_window(mut self, window: f64) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Builder method to set the minimum physical time between two recorded states (seconds)
+    pub fn with_interval(mut self, interval: f64) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Builder method to set the number of states kept
+    pub fn with_max_states(mut self, max_states: usize) -> Self {
+        self.max_states = max_states;
+        self
+    }
+
+    /// Records the current state if enough physical time passed since the last one
+    pub fn record(&mut self, simulation: &Simulation) {
+        let time = simulation.get_physical_time();
+        match self.states.back() {
+            // The simulation was reset: the recording belongs to another run
+            Some(&(last, _)) if time < last => self.states.clear(),
+            Some(&(last, _)) if time - last < self.interval => return,
+            _ => {}
+        }
+        self.states
+            .push_back((time, Arc::new(gather_state(simulation))));
+        while let Some(&(first, _)) = self.states.front() {
+            if first >= time - self.window {
+                break;
+            }
+            self.states.pop_front();
+        }
+        while self.states.len() > self.max_states {
+            self.states.pop_front();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.states.clear();
+    }
+
+    /// The recorded states whose physical time is within [from_time, to_time]
+    pub fn states_between(&self, from_time: f64, to_time: f64) -> Vec<Arc<ServerToClientMessage>> {
+        self.states
+            .iter()
+            .filter(|(time, _)| (from_time..=to_time).contains(time))
+            .map(|(_, state)| Arc::clone(state))
+            .collect()
+    }
+
+    /// The last `n` recorded states, in increasing time order
+    pub fn last_states(&self, n: usize) -> Vec<Arc<ServerToClientMessage>> {
+        let skipped = self.states.len().saturating_sub(n);
+        self.states
+            .iter()
+            .skip(skipped)
+            .map(|(_, state)| Arc::clone(state))
+            .collect()
+    }
+}
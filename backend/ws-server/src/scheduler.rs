@@ -0,0 +1,408 @@
+use nbody::simulation::Simulation;
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    sync::{
+        atomic::{self, AtomicBool, AtomicU64, AtomicUsize},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+
+use crate::{
+    clock::SimulationClock,
+    commands::{Command, CommandQueue},
+    diagnostics::DiagnosticsStore,
+    journal::{keep_deterministic, SharedJournal},
+    lock,
+    metrics::Metrics,
+    recorder::Recorder,
+};
+
+// The scheduler sizes the pool the gravity passes are split across
+const _: () = assert!(
+    nbody::PARALLEL,
+    "nbody must be built with its parallel feature"
+);
+
+/// Maximum number of steps a room can take in a single turn when running behind schedule
+/// (it then yields the worker, still behind, see `SimulationClock::max_lag`)
+const DEFAULT_STEP_BUDGET: usize = 4;
+
+/// Whether a room steps on its own, shared with the clients that pause it
+#[derive(Default)]
+pub struct RunState {
+    paused: AtomicBool,
+
+    /// Steps requested while paused, taken one per tick
+    pending_steps: AtomicU64,
+
+    /// The room is dropped by the scheduler at its next turn
+    stopped: AtomicBool,
+}
+
+impl RunState {
+    pub fn new() -> Self {
+        RunState::default()
+    }
+
+    pub fn pause(&self) {
+        self.paused.store(true, atomic::Ordering::Relaxed);
+    }
+
+    /// Also drops the single steps not taken yet
+    pub fn resume(&self) {
+        self.paused.store(false, atomic::Ordering::Relaxed);
+        self.pending_steps.store(0, atomic::Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(atomic::Ordering::Relaxed)
+    }
+
+    /// For good, e.g. once the room is closed
+    pub fn stop(&self) {
+        self.stopped.store(true, atomic::Ordering::Relaxed);
+    }
+
+    /// Requests a step of the paused room, false if it is running
+    pub fn single_step(&self) -> bool {
+        if !self.is_paused() {
+            return false;
+        }
+        self.pending_steps.fetch_add(1, atomic::Ordering::Relaxed);
+        true
+    }
+
+    /// Whether the room may take its next step
+    fn take_step(&self) -> bool {
+        !self.is_paused()
+            || self
+                .pending_steps
+                .fetch_update(atomic::Ordering::Relaxed, atomic::Ordering::Relaxed, |n| {
+                    n.checked_sub(1)
+                })
+                .is_ok()
+    }
+}
+
+/// A simulation whose stepping is driven by the scheduler
+pub struct Room {
+    counter: Arc<AtomicUsize>,
+    simulation: Arc<Mutex<Simulation>>,
+
+    /// Wall-clock pace of the steps
+    clock: Arc<SimulationClock>,
+
+    /// Steps allowed per turn before yielding the worker to other rooms
+    step_budget: usize,
+
+    /// Records the states after each step
+    recorder: Option<Arc<Mutex<Recorder>>>,
+
+    /// Samples the diagnostics after each step
+    diagnostics: Option<Arc<Mutex<DiagnosticsStore>>>,
+
+    /// Mutations applied before each step
+    commands: Option<Arc<CommandQueue>>,
+
+    /// Journals the applied commands and checkpoints the simulation
+    journal: Option<SharedJournal>,
+
+    /// Told the tick of each step
+    step_notifier: Option<watch::Sender<u64>>,
+
+    /// Pauses the stepping, the room runs on its own without it
+    run_state: Option<Arc<RunState>>,
+
+    /// Told the duration of each step
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl Room {
+    pub fn new(counter: Arc<AtomicUsize>, simulation: Arc<Mutex<Simulation>>) -> Self {
+        Self {
+            counter,
+            simulation,
+            clock: Arc::new(SimulationClock::default()),
+            step_budget: DEFAULT_STEP_BUDGET,
+            recorder: None,
+            diagnostics: None,
+            commands: None,
+            journal: None,
+            step_notifier: None,
+            run_state: None,
+            metrics: None,
+        }
+    }
+
+    /// Builder method to pace the steps with a clock shared with the clients
+    pub fn with_clock(mut self, clock: Arc<SimulationClock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Builder method to record the states of the simulation after each step
+    pub fn with_recorder(mut self, recorder: Arc<Mutex<Recorder>>) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Builder method to sample the diagnostics of the simulation after each step
+    pub fn with_diagnostics(mut self, diagnostics: Arc<Mutex<DiagnosticsStore>>) -> Self {
+        self.diagnostics = Some(diagnostics);
+        self
+    }
+
+    /// Builder method to apply the queued commands at the step boundaries
+    pub fn with_commands(mut self, commands: Arc<CommandQueue>) -> Self {
+        self.commands = Some(commands);
+        self
+    }
+
+    /// Builder method to journal the commands applied (once a journal is set in the slot)
+    pub fn with_journal(mut self, journal: SharedJournal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// Builder method to notify the tick after each step (e.g. to push the new state)
+    pub fn with_step_notifier(mut self, step_notifier: watch::Sender<u64>) -> Self {
+        self.step_notifier = Some(step_notifier);
+        self
+    }
+
+    /// Builder method to let the clients pause the room and step it one tick at a time
+    pub fn with_run_state(mut self, run_state: Arc<RunState>) -> Self {
+        self.run_state = Some(run_state);
+        self
+    }
+
+    /// Builder method to export the duration of the steps
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Steps the room while it is behind schedule (up to its budget)
+    /// Returns when the room should be given its next turn, None once it is stopped
+    fn run_turn(&self, mut due: Instant) -> Option<Instant> {
+        if let Some(run_state) = &self.run_state {
+            if run_state.stopped.load(atomic::Ordering::Relaxed) {
+                return None;
+            }
+        }
+        let mut simulation = lock!(self.simulation);
+        let tick_interval = self.clock.tick_interval();
+        for _ in 0..self.step_budget {
+            if let Some(commands) = &self.commands {
+                let tick = self.counter.load(atomic::Ordering::Relaxed) as u64;
+                commands.apply(&mut simulation, |command| {
+                    if let Command::Reset | Command::Restore(_) = command {
+                        // No state of the old run must be served after a reset
+                        if let Some(recorder) = &self.recorder {
+                            lock!(recorder).clear();
+                        }
+                        if let Some(diagnostics) = &self.diagnostics {
+                            lock!(diagnostics).clear();
+                        }
+                    }
+                    if let Some(journal) = &self.journal {
+                        if let Some(journal) = lock!(journal).as_mut() {
+                            if let Err(e) = journal.append(tick, command) {
+                                eprintln!("Failed to journal a command: {e}");
+                            }
+                        }
+                    }
+                });
+                if let Some(journal) = &self.journal {
+                    if lock!(journal).is_some() {
+                        keep_deterministic(&mut simulation);
+                    }
+                }
+            }
+            if let Some(run_state) = &self.run_state {
+                if !run_state.take_step() {
+                    // Paused: keep applying the commands at the tick rate
+                    self.clock.record_lag(Duration::ZERO, tick_interval);
+                    return Some(Instant::now() + tick_interval);
+                }
+            }
+            let started = Instant::now();
+            simulation.step();
+            if let Some(metrics) = &self.metrics {
+                metrics.record_step(started.elapsed());
+            }
+            let tick = self.counter.fetch_add(1, atomic::Ordering::Relaxed) as u64 + 1;
+            if let Some(journal) = &self.journal {
+                if let Some(journal) = lock!(journal).as_mut().filter(|j| j.checkpoint_due(tick)) {
+                    if let Err(e) = journal.checkpoint(tick, &simulation) {
+                        eprintln!("Failed to checkpoint the simulation: {e}");
+                    }
+                }
+            }
+            if let Some(recorder) = &self.recorder {
+                lock!(recorder).record(&simulation);
+            }
+            if let Some(diagnostics) = &self.diagnostics {
+                lock!(diagnostics).record(&simulation);
+            }
+            if let Some(step_notifier) = &self.step_notifier {
+                step_notifier.send_replace(tick);
+            }
+            due += tick_interval;
+            if due > Instant::now() {
+                self.clock.record_lag(Duration::ZERO, tick_interval);
+                return Some(due);
+            }
+        }
+        // Budget exhausted: yield to the other rooms (the earlier due ones first),
+        // with the lag that is not dropped still to catch up with
+        let now = Instant::now();
+        let lag = self
+            .clock
+            .record_lag(now.saturating_duration_since(due), tick_interval);
+        Some(now.checked_sub(lag).unwrap_or(now))
+    }
+}
+
+/// Entry of the run queue, ordered by earliest deadline first
+/// and then by insertion order so that rooms due at the same time take turns
+struct QueuedRoom {
+    due: Instant,
+    seq: u64,
+    room: Arc<Room>,
+}
+
+impl PartialEq for QueuedRoom {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for QueuedRoom {}
+
+impl PartialOrd for QueuedRoom {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for QueuedRoom {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Reversed as BinaryHeap is a max-heap
+        other
+            .due
+            .cmp(&self.due)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+#[derive(Default)]
+struct RunQueue {
+    rooms: Mutex<BinaryHeap<QueuedRoom>>,
+    available: Condvar,
+    next_seq: AtomicU64,
+}
+
+impl RunQueue {
+    fn push(&self, room: Arc<Room>, due: Instant) {
+        let seq = self.next_seq.fetch_add(1, atomic::Ordering::Relaxed);
+        lock!(self.rooms).push(QueuedRoom { due, seq, room });
+        self.available.notify_one();
+    }
+
+    /// Blocks until a room is due and removes it from the queue
+    fn pop_due(&self) -> QueuedRoom {
+        let mut rooms = lock!(self.rooms);
+        loop {
+            let now = Instant::now();
+            rooms = match rooms.peek() {
+                Some(next) if next.due <= now => {
+                    return rooms.pop().expect("peeked element must exist")
+                }
+                Some(next) => {
+                    let timeout = next.due - now;
+                    self.available
+                        .wait_timeout(rooms, timeout)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+                None => self
+                    .available
+                    .wait(rooms)
+                    .unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+    }
+}
+
+/// Distributes the stepping of all rooms across a pool of worker threads
+///
+/// Each worker takes the room with the earliest deadline, steps it (within the room's budget)
+/// and puts it back in the queue, so that N rooms scale with the number of cores
+/// instead of competing for a single thread
+///
+/// The turns run in a rayon pool with as many threads as workers, which the gravity pass of
+/// a room is split across: a lone busy room gets all the threads, busy rooms share them,
+/// and the server never runs more computing threads than workers
+pub struct Scheduler {
+    queue: Arc<RunQueue>,
+    workers: Vec<std::thread::JoinHandle<()>>,
+    pool: Arc<rayon::ThreadPool>,
+}
+
+impl Scheduler {
+    pub fn new(num_workers: usize) -> Self {
+        let num_workers = num_workers.max(1);
+        let queue = Arc::new(RunQueue::default());
+        let pool = Arc::new(
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(num_workers)
+                .thread_name(|i| format!("room-compute-{i}"))
+                .build()
+                .expect("Failed to spawn the scheduler thread pool"),
+        );
+        let workers = (0..num_workers)
+            .map(|i| {
+                let queue = Arc::clone(&queue);
+                let pool = Arc::clone(&pool);
+                std::thread::Builder::new()
+                    .name(format!("room-worker-{i}"))
+                    .spawn(move || loop {
+                        let entry = queue.pop_due();
+                        // A stopped room is dropped along with its step notifier and commands
+                        if let Some(next_due) = pool.install(|| entry.room.run_turn(entry.due)) {
+                            queue.push(entry.room, next_due);
+                        }
+                    })
+                    .expect("Failed to spawn scheduler worker thread")
+            })
+            .collect();
+        Self {
+            queue,
+            workers,
+            pool,
+        }
+    }
+
+    /// Creates a scheduler with one worker per available core
+    pub fn with_available_cores() -> Self {
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Self::new(cores)
+    }
+
+    pub fn add_room(&self, room: Room) {
+        self.queue.push(Arc::new(room), Instant::now());
+    }
+
+    pub fn num_workers(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Threads the gravity passes of the rooms are split across
+    pub fn num_compute_threads(&self) -> usize {
+        self.pool.current_num_threads()
+    }
+}
@@ -0,0 +1,85 @@
+//! Custom force law written in Rhai, evaluated for every body on each step
+//!
+//! The script defines `fn force(body, t)` returning `[fx, fy]`, where `body` is a map
+//! with the fields `x`, `y`, `vx`, `vy`, `mass` and `radius`, e.g.
+//!     fn force(body, t) { [0.0, -9.81 * body.mass] }
+
+use nbody::physics::{Body, ForceHook};
+use rhai::{Array, Dynamic, Engine, Map, Scope, AST};
+use std::sync::atomic::{AtomicBool, Ordering};
+use thiserror::Error;
+
+/// The force script could not be compiled
+#[derive(Debug, Error)]
+#[error("Invalid force script: {0}")]
+pub struct ScriptError(String);
+
+pub struct ScriptedForce {
+    engine: Engine,
+    ast: AST,
+
+    /// Only the first failure is reported, the force is zero whenever the script fails
+    failed: AtomicBool,
+}
+
+impl ScriptedForce {
+    pub fn compile(source: &str) -> Result<Self, ScriptError> {
+        let engine = Engine::new();
+        let ast = engine
+            .compile(source)
+            .map_err(|e| ScriptError(e.to_string()))?;
+        if !ast
+            .iter_functions()
+            .any(|f| f.name == "force" && f.params.len() == 2)
+        {
+            return Err(ScriptError(
+                "the script must define `fn force(body, t)`".to_string(),
+            ));
+        }
+        Ok(Self {
+            engine,
+            ast,
+            failed: AtomicBool::new(false),
+        })
+    }
+}
+
+impl ForceHook for ScriptedForce {
+    fn force(&self, body: &Body, time: f64) -> [f64; 2] {
+        let mut map = Map::new();
+        for (name, value) in [
+            ("x", body.position[0]),
+            ("y", body.position[1]),
+            ("vx", body.velocity[0]),
+            ("vy", body.velocity[1]),
+            ("mass", body.mass),
+            ("radius", body.radius),
+        ] {
+            map.insert(name.into(), Dynamic::from_float(value));
+        }
+        let force = self
+            .engine
+            .call_fn::<Array>(&mut Scope::new(), &self.ast, "force", (map, time))
+            .map_err(|e| e.to_string())
+            .and_then(|force| to_force(&force));
+        force.unwrap_or_else(|e| {
+            if !self.failed.swap(true, Ordering::Relaxed) {
+                eprintln!("The force script failed (further failures are not reported): {e}");
+            }
+            [0.0, 0.0]
+        })
+    }
+}
+
+fn to_force(array: &Array) -> Result<[f64; 2], String> {
+    let component = |value: &Dynamic| {
+        value
+            .as_float()
+            .or_else(|_| value.as_int().map(|i| i as f64))
+            .map_err(|type_name| format!("expected a number, got {type_name}"))
+    };
+    match array.as_slice() {
+        [fx, fy] => Ok([component(fx)?, component(fy)?]),
+        _ => Err(format!("expected [fx, fy], got {} values", array.len())),
+    }
+}
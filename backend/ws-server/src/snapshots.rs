@@ -0,0 +1,77 @@
+//! Snapshots of the simulations saved by the clients, one file per name in a directory
+//!
+//! Each file holds `SimulationSnapshot::to_bytes`, so that it survives restarts of the server
+//! (and can be loaded in the local wasm engine)
+
+use nbody::{simulation::SimulationSnapshot, PhysicsError};
+use std::{fs, io, path::PathBuf};
+use thiserror::Error;
+
+/// Longest name of a snapshot
+const MAX_NAME_LENGTH: usize = 64;
+
+const EXTENSION: &str = "snapshot";
+
+/// Errors raised while saving or loading a snapshot
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    /// The server was not given a directory to keep them in
+    #[error("Snapshots are not enabled on this server")]
+    Disabled,
+
+    #[error("Invalid snapshot name {0:?} (1 to {MAX_NAME_LENGTH} letters, digits, - or _)")]
+    InvalidName(String),
+
+    #[error("No snapshot named {0}")]
+    NotFound(String),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    /// The file is not a snapshot (or of another format version)
+    #[error(transparent)]
+    Format(#[from] PhysicsError),
+}
+
+pub struct SnapshotStore {
+    dir: PathBuf,
+}
+
+impl SnapshotStore {
+    /// Keeps the snapshots in `dir`, which is created if needed
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// Writes the snapshot next to its file and then renames it,
+    /// so that a crash in the middle leaves the previous snapshot of that name intact
+    pub fn save(&self, name: &str, snapshot: &SimulationSnapshot) -> Result<(), SnapshotError> {
+        let path = self.path(name)?;
+        let staging = path.with_extension("tmp");
+        fs::write(&staging, snapshot.to_bytes()?)?;
+        fs::rename(&staging, path)?;
+        Ok(())
+    }
+
+    pub fn load(&self, name: &str) -> Result<SimulationSnapshot, SnapshotError> {
+        let bytes = fs::read(self.path(name)?).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => SnapshotError::NotFound(name.to_owned()),
+            _ => e.into(),
+        })?;
+        Ok(SimulationSnapshot::from_bytes(&bytes)?)
+    }
+
+    /// The names cannot point out of the directory
+    fn path(&self, name: &str) -> Result<PathBuf, SnapshotError> {
+        let valid = (1..=MAX_NAME_LENGTH).contains(&name.len())
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+        if !valid {
+            return Err(SnapshotError::InvalidName(name.to_owned()));
+        }
+        Ok(self.dir.join(name).with_extension(EXTENSION))
+    }
+}
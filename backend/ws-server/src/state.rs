@@ -1,42 +1,417 @@
 use nbody::simulation::Simulation;
-use std::sync::{atomic::AtomicUsize, Arc, Mutex};
-use tokio::sync::mpsc::UnboundedSender;
+use protocol::{dictionary::Dictionary, Role, RoomId, Subprotocol};
+use std::{
+    collections::HashMap,
+    io,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::sync::{mpsc::UnboundedSender, watch};
 use tokio_tungstenite::tungstenite::Message;
 
+use crate::{
+    bandwidth::ClientLink,
+    bundle::StateBundler,
+    clock::{SimulationClock, DEFAULT_MAX_LAG, DEFAULT_TICK_RATE},
+    commands::CommandQueue,
+    diagnostics::DiagnosticsStore,
+    handler::Compression,
+    journal::{keep_deterministic, Journal, SharedJournal},
+    lock,
+    metrics::Metrics,
+    recorder::Recorder,
+    scheduler::{Room, RunState, Scheduler},
+    snapshots::SnapshotStore,
+};
+
+/// Positions recorded per body for `GetTrails` unless configured otherwise
+pub const DEFAULT_TRAIL_LENGTH: usize = 64;
+
 pub struct ServerState {
-    pub simulation: (Arc<AtomicUsize>, Arc<Mutex<Simulation>>),
-    pub connected_clients: Arc<Mutex<Vec<UnboundedSender<Message>>>>,
+    /// The rooms open, each one with its own simulation and clients
+    pub rooms: Mutex<HashMap<RoomId, Arc<RoomState>>>,
+
+    /// Steps the simulations of all the rooms
+    scheduler: Scheduler,
+
+    next_room_id: AtomicU64,
+
+    /// Clients connected, in any room
+    pub connections: AtomicUsize,
+
+    /// Exported on `/metrics`, see `serve_metrics`
+    pub metrics: Arc<Metrics>,
+
+    /// Tick rate of the rooms opened from now on
+    tick_rate: f64,
+
+    /// Catch-up policy of the rooms opened from now on, see `SimulationClock::max_lag`
+    max_lag: Duration,
+
+    /// Positions recorded per body for `GetTrails` in the rooms opened from now on, 0 for none
+    trail_length: usize,
+
+    /// Dictionary and gzip level the frames are compressed with
+    pub compression: Compression,
+
+    /// Minimum time between two states pushed to the subscribers, None to push every step
+    pub broadcast_interval: Option<Duration>,
+
+    /// Role granted by each token, the clients must authenticate unless it is empty
+    tokens: HashMap<String, Role>,
+
+    /// Connections refused beyond this many clients, None for no limit
+    pub max_clients: Option<usize>,
+
+    /// Bodies a simulation is not grown beyond with `AddBodies`, None for no limit
+    pub max_bodies: Option<usize>,
+
+    /// Where `SaveSnapshot` writes, None until enabled with `with_snapshot_dir`
+    pub snapshots: Option<Arc<SnapshotStore>>,
 }
 
-impl ServerState {
-    pub fn new() -> Self {
-        let simulation = Arc::new(Mutex::new(Simulation::new()));
-        let stepper = Arc::new(AtomicUsize::new(0));
+/// A simulation and the clients sharing it
+pub struct RoomState {
+    pub id: RoomId,
+    pub simulation: (Arc<AtomicUsize>, Arc<Mutex<Simulation>>),
+    pub connected_clients: Arc<Mutex<Vec<Subscriber>>>,
+    pub recorder: Arc<Mutex<Recorder>>,
+    pub diagnostics: Arc<Mutex<DiagnosticsStore>>,
+
+    /// Mutations requested by the clients, applied between two steps
+    pub commands: Arc<CommandQueue>,
+
+    /// Paused and single-stepped by the clients, stopped once the room is closed
+    pub run_state: Arc<RunState>,
+
+    /// Pace of the steps, set by the clients with `SetTickRate`
+    pub clock: Arc<SimulationClock>,
+
+    /// Write-ahead journal of the commands, None unless enabled with `with_journal`
+    pub journal: SharedJournal,
 
-        // spawn a new task to run the simulation
-        spawn_simulation(Arc::clone(&stepper), Arc::clone(&simulation));
+    /// Tick of the last step, the subscribers are pushed the state when it changes
+    pub steps: watch::Receiver<u64>,
 
+    /// Clients in the room, only changed with the registry locked
+    members: AtomicUsize,
+}
+
+impl RoomState {
+    /// Creates the room and hands its simulation to the scheduler
+    fn start(
+        id: RoomId,
+        scheduler: &Scheduler,
+        clock: SimulationClock,
+        trail_length: usize,
+        metrics: &Arc<Metrics>,
+    ) -> Self {
+        let mut simulation = Simulation::new();
+        simulation.enable_trails(trail_length);
+        let simulation = Arc::new(Mutex::new(simulation));
+        let stepper = Arc::new(AtomicUsize::new(0));
+        let recorder = Arc::new(Mutex::new(Recorder::new()));
+        let diagnostics = Arc::new(Mutex::new(DiagnosticsStore::new()));
+        let commands = Arc::new(CommandQueue::new());
+        let run_state = Arc::new(RunState::new());
+        let clock = Arc::new(clock);
+        let journal = Arc::new(Mutex::new(None));
+        let (notifier, steps) = watch::channel(0);
+        scheduler.add_room(
+            Room::new(Arc::clone(&stepper), Arc::clone(&simulation))
+                .with_recorder(Arc::clone(&recorder))
+                .with_diagnostics(Arc::clone(&diagnostics))
+                .with_commands(Arc::clone(&commands))
+                .with_journal(Arc::clone(&journal))
+                .with_step_notifier(notifier)
+                .with_run_state(Arc::clone(&run_state))
+                .with_clock(Arc::clone(&clock))
+                .with_metrics(Arc::clone(metrics)),
+        );
         Self {
+            id,
             simulation: (stepper, simulation),
             connected_clients: Arc::new(Mutex::new(Vec::new())),
+            recorder,
+            diagnostics,
+            commands,
+            run_state,
+            clock,
+            journal,
+            steps,
+            members: AtomicUsize::new(0),
         }
     }
 }
 
-fn spawn_simulation(
-    counter: Arc<AtomicUsize>,
-    simulation: Arc<Mutex<Simulation>>,
-) -> tokio::task::JoinHandle<()> {
-    tokio::task::spawn_blocking(move || {
-        let mut last_update = std::time::Instant::now();
-        let max_fps = std::time::Duration::from_secs_f64(1.0 / 60.0); // maximum front-end limit
-        loop {
-            if last_update.elapsed() > max_fps {
-                let mut simulation = simulation.lock().unwrap_or_else(|p| p.into_inner());
-                simulation.step();
-                counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                last_update = std::time::Instant::now();
-            }
+/// A client subscribed to the states of the simulation
+pub struct Subscriber {
+    pub tx: UnboundedSender<Message>,
+
+    /// Format the states are encoded in
+    pub format: Subprotocol,
+
+    /// Quality the states must be streamed at
+    pub link: Arc<ClientLink>,
+
+    /// Ticks pushed per message
+    pub bundler: StateBundler,
+
+    /// States streamed so far, sent or skipped
+    pub streamed: u64,
+
+    /// Whether the states are pushed as `StateDelta`s
+    pub deltas: bool,
+
+    /// Last state acknowledged with `AckState`, the baseline of the next delta
+    pub acked_tick: Option<u64>,
+}
+
+impl Subscriber {
+    pub fn new(
+        tx: UnboundedSender<Message>,
+        format: Subprotocol,
+        link: Arc<ClientLink>,
+        ticks_per_bundle: usize,
+    ) -> Self {
+        Self {
+            tx,
+            format,
+            link,
+            bundler: StateBundler::new(ticks_per_bundle),
+            streamed: 0,
+            deltas: false,
+            acked_tick: None,
         }
-    })
+    }
+
+    /// Builder method to push `StateDelta`s against the last acknowledged state
+    pub fn with_deltas(mut self) -> Self {
+        self.deltas = true;
+        self
+    }
+}
+
+impl Default for ServerState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ServerState {
+    /// Steps the simulations on one thread per available core
+    pub fn new() -> Self {
+        Self::with_scheduler(Scheduler::with_available_cores())
+    }
+
+    /// Steps the simulations on `threads` threads, shared by the gravity passes of all the rooms
+    pub fn with_worker_threads(threads: usize) -> Self {
+        Self::with_scheduler(Scheduler::new(threads))
+    }
+
+    fn with_scheduler(scheduler: Scheduler) -> Self {
+        // the scheduler worker threads run the simulations (they outlive the handle)
+        println!(
+            "Stepping simulations on {} worker threads",
+            scheduler.num_workers()
+        );
+        let metrics = Arc::new(Metrics::new());
+        let lobby = RoomState::start(
+            RoomId::LOBBY,
+            &scheduler,
+            SimulationClock::default(),
+            DEFAULT_TRAIL_LENGTH,
+            &metrics,
+        );
+
+        Self {
+            rooms: Mutex::new(HashMap::from([(RoomId::LOBBY, Arc::new(lobby))])),
+            scheduler,
+            next_room_id: AtomicU64::new(RoomId::LOBBY.0 + 1),
+            connections: AtomicUsize::new(0),
+            metrics,
+            tick_rate: DEFAULT_TICK_RATE,
+            max_lag: DEFAULT_MAX_LAG,
+            trail_length: DEFAULT_TRAIL_LENGTH,
+            compression: Compression::default(),
+            broadcast_interval: None,
+            tokens: HashMap::new(),
+            max_clients: None,
+            max_bodies: None,
+            snapshots: None,
+        }
+    }
+
+    /// Role of the clients that did not authenticate, None if they must
+    pub fn anonymous_role(&self) -> Option<Role> {
+        self.tokens.is_empty().then_some(Role::Admin)
+    }
+
+    /// Role granted by the token, None if it is unknown
+    /// (any token is an admin's when the server requires none)
+    pub fn authenticate(&self, token: &str) -> Option<Role> {
+        self.anonymous_role()
+            .or_else(|| self.tokens.get(token).copied())
+    }
+
+    /// Threads the simulations of the rooms are stepped on
+    pub fn worker_threads(&self) -> usize {
+        self.scheduler.num_compute_threads()
+    }
+
+    /// The room the clients join when they connect
+    pub fn lobby(&self) -> Arc<RoomState> {
+        let rooms = lock!(self.rooms);
+        Arc::clone(&rooms[&RoomId::LOBBY])
+    }
+
+    /// Opens a new room with the client as its only member
+    /// (its states are pushed to the subscribers once `broadcast_states` runs for it)
+    pub fn open_room(&self) -> Arc<RoomState> {
+        let id = RoomId(self.next_room_id.fetch_add(1, Ordering::Relaxed));
+        let clock = SimulationClock::new(self.tick_rate).with_max_lag(self.max_lag);
+        let room = Arc::new(RoomState::start(
+            id,
+            &self.scheduler,
+            clock,
+            self.trail_length,
+            &self.metrics,
+        ));
+        room.members.store(1, Ordering::Relaxed);
+        lock!(self.rooms).insert(id, Arc::clone(&room));
+        room
+    }
+
+    /// Adds a member to the room, None if it is not open
+    pub fn join(&self, id: RoomId) -> Option<Arc<RoomState>> {
+        let rooms = lock!(self.rooms);
+        let room = rooms.get(&id)?;
+        room.members.fetch_add(1, Ordering::Relaxed);
+        Some(Arc::clone(room))
+    }
+
+    /// Drops the subscriptions of the client to the room and closes the room
+    /// if it was its last member (the lobby is never closed)
+    pub fn leave(&self, room: &RoomState, link: &Arc<ClientLink>) {
+        lock!(room.connected_clients).retain(|subscriber| !Arc::ptr_eq(&subscriber.link, link));
+        let mut rooms = lock!(self.rooms);
+        let left = room.members.fetch_sub(1, Ordering::Relaxed) - 1;
+        if left == 0 && room.id != RoomId::LOBBY {
+            rooms.remove(&room.id);
+            room.run_state.stop();
+            println!("Closed the room {}", room.id.0);
+        }
+    }
+
+    /// Builder method to compress the frames of `nbody.bincode.zstd.v1` with the dictionary
+    pub fn with_dictionary(mut self, dictionary: Dictionary) -> Self {
+        self.compression.dictionary = Some(Arc::new(dictionary));
+        self
+    }
+
+    /// Builder method to set the gzip level of `nbody.bincode.gz.v1`, from 0 (store) to 9 (best)
+    pub fn with_compression_level(mut self, level: u32) -> Self {
+        self.compression.level = Some(level);
+        self
+    }
+
+    /// Builder method to refuse the connections beyond `max_clients` clients
+    pub fn with_max_clients(mut self, max_clients: usize) -> Self {
+        self.max_clients = Some(max_clients);
+        self
+    }
+
+    /// Builder method to reject the bodies added beyond `max_bodies` in a simulation
+    pub fn with_max_bodies(mut self, max_bodies: usize) -> Self {
+        self.max_bodies = Some(max_bodies);
+        self
+    }
+
+    /// Builder method to grant the role to the clients authenticating with the token
+    /// Once a token is set, the clients must authenticate first
+    pub fn with_token(mut self, token: impl Into<String>, role: Role) -> Self {
+        self.tokens.insert(token.into(), role);
+        self
+    }
+
+    /// Builder method to push the states to the subscribers at most once per `interval`
+    /// (instead of after every step)
+    pub fn with_broadcast_interval(mut self, interval: Duration) -> Self {
+        self.broadcast_interval = Some(interval);
+        self
+    }
+
+    /// Builder method to step the simulations `tick_rate` times per second
+    /// (`DEFAULT_TICK_RATE` otherwise), the clients can change it with `SetTickRate`
+    pub fn with_tick_rate(mut self, tick_rate: f64) -> Self {
+        self.lobby().clock.set_tick_rate(tick_rate);
+        self.tick_rate = tick_rate;
+        self
+    }
+
+    /// Builder method to set how far behind schedule the rooms catch up
+    /// (`DEFAULT_MAX_LAG` otherwise), zero to drop the missed ticks right away
+    pub fn with_max_lag(mut self, max_lag: Duration) -> Self {
+        self.lobby().clock.set_max_lag(max_lag);
+        self.max_lag = max_lag;
+        self
+    }
+
+    /// Builder method to record the last `trail_length` positions of every body for `GetTrails`
+    /// (`DEFAULT_TRAIL_LENGTH` otherwise), 0 to record none
+    pub fn with_trail_length(mut self, trail_length: usize) -> Self {
+        lock!(self.lobby().simulation.1).enable_trails(trail_length);
+        self.trail_length = trail_length;
+        self
+    }
+
+    /// Builder method to let the clients save their simulations in `dir` (and load them back)
+    pub fn with_snapshot_dir(mut self, dir: impl Into<PathBuf>) -> io::Result<Self> {
+        self.snapshots = Some(Arc::new(SnapshotStore::open(dir)?));
+        Ok(self)
+    }
+
+    /// Builder method to recover the simulation of the lobby from the journal at `path`
+    /// (if there is one) and then to journal the commands applied to it there
+    /// The lobby is then stepped in deterministic mode, so that it can be replayed
+    /// The other rooms are not journaled, they are closed with their last client anyway
+    pub fn with_journal(self, path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let lobby = self.lobby();
+        // Locked throughout so that the room does not step before the journal is set
+        let mut simulation = lock!(lobby.simulation.1);
+        let tick = match Journal::recover(&path, &mut simulation)? {
+            Some(tick) => {
+                println!(
+                    "Recovered the simulation at tick {tick} from {}",
+                    path.display()
+                );
+                lobby.simulation.0.store(tick as usize, Ordering::Relaxed);
+                lock!(lobby.recorder).clear();
+                lock!(lobby.diagnostics).clear();
+                tick
+            }
+            None => lobby.simulation.0.load(Ordering::Relaxed) as u64,
+        };
+        keep_deterministic(&mut simulation);
+        *lock!(lobby.journal) = Some(Journal::create(path, tick, &simulation)?);
+        drop(simulation);
+        Ok(self)
+    }
+
+    /// Builder method to checkpoint the journaled lobby every `ticks` steps
+    /// (after `with_journal`, every minute at the default tick rate otherwise)
+    pub fn with_checkpoint_interval(self, ticks: u64) -> Self {
+        let lobby = self.lobby();
+        let mut journal = lock!(lobby.journal);
+        *journal = journal
+            .take()
+            .map(|journal| journal.with_checkpoint_interval(ticks));
+        drop(journal);
+        self
+    }
 }
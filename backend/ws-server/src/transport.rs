@@ -0,0 +1,78 @@
+//! What the WebSocket connections run over, plain TCP unless the server is given another acceptor
+//!
+//! e.g. `Tls` (with the `tls` feature) to serve `wss://` without a reverse proxy in front
+
+use std::{future::Future, io};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+
+/// Turns an accepted TCP connection into the stream the WebSocket handshake runs over
+pub trait Acceptor: Send + Sync + 'static {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    /// Runs in the task of the connection, a slow client does not hold up the others
+    fn accept(&self, stream: TcpStream) -> impl Future<Output = io::Result<Self::Stream>> + Send;
+}
+
+/// The TCP stream as is
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PlainTcp;
+
+impl Acceptor for PlainTcp {
+    type Stream = TcpStream;
+
+    async fn accept(&self, stream: TcpStream) -> io::Result<TcpStream> {
+        Ok(stream)
+    }
+}
+
+#[cfg(feature = "tls")]
+pub use tls::Tls;
+
+#[cfg(feature = "tls")]
+mod tls {
+    use std::{io, path::Path, sync::Arc};
+    use tokio::net::TcpStream;
+    use tokio_rustls::{
+        rustls::{
+            pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+            ServerConfig,
+        },
+        server::TlsStream,
+        TlsAcceptor,
+    };
+
+    use super::Acceptor;
+
+    /// Terminates TLS, the clients connect with `wss://`
+    #[derive(Clone)]
+    pub struct Tls(TlsAcceptor);
+
+    impl Tls {
+        /// From the PEM files of the certificate chain (leaf first) and of its private key
+        pub fn from_pem_files(
+            cert_path: impl AsRef<Path>,
+            key_path: impl AsRef<Path>,
+        ) -> io::Result<Self> {
+            let certs = CertificateDer::pem_file_iter(cert_path)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(io::Error::other)?;
+            let key = PrivateKeyDer::from_pem_file(key_path).map_err(io::Error::other)?;
+            let config = ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(certs, key)
+                .map_err(io::Error::other)?;
+            Ok(Tls(TlsAcceptor::from(Arc::new(config))))
+        }
+    }
+
+    impl Acceptor for Tls {
+        type Stream = TlsStream<TcpStream>;
+
+        async fn accept(&self, stream: TcpStream) -> io::Result<Self::Stream> {
+            self.0.accept(stream).await
+        }
+    }
+}
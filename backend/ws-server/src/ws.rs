@@ -1,74 +1,244 @@
-const ADDRESS: &str = "0.0.0.0:5000";
+/// How long the error is given to reach a client failing to authenticate before it is cut off
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
 
 use futures_util::{SinkExt, StreamExt};
-use std::{io::Error, sync::Arc};
+use std::{
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
 use tokio::{
-    net::{TcpListener, TcpStream},
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
     sync::mpsc::{unbounded_channel, UnboundedSender},
 };
-use tokio_tungstenite::{accept_async, tungstenite::Message};
+use tokio_tungstenite::{
+    accept_hdr_async,
+    tungstenite::{
+        handshake::server::{ErrorResponse, Request, Response},
+        http::{HeaderValue, StatusCode},
+        Message,
+    },
+};
+
+use crate::{
+    bandwidth::{ClientLink, LinkMonitor},
+    broadcast::broadcast_states,
+    error::ServerError,
+    handler::handle_client_to_server_messages,
+    state::{RoomState, ServerState},
+    transport::{Acceptor, PlainTcp},
+};
+use protocol::{
+    decode_json,
+    protobuf::{decode_any, Encoding},
+    Role, RoomId, Subprotocol, SUBPROTOCOL_HEADER,
+};
 
-use crate::{handler::handle_client_to_server_messages, state::ServerState};
-use wasm_bindings::deserialize_client_msg;
+/// Serves the clients connecting to `address` (see `ServerConfig` for the default one)
+pub async fn launch_ws_server(
+    address: SocketAddr,
+    state: Arc<ServerState>,
+) -> Result<(), ServerError> {
+    launch_ws_server_with(address, state, PlainTcp).await
+}
 
-pub async fn launch_ws_server(state: Arc<ServerState>) -> Result<(), Error> {
-    println!("Starting WebSocket server at {}", ADDRESS);
+/// Same as `launch_ws_server` with the connections going through the acceptor (e.g. `Tls`)
+pub async fn launch_ws_server_with<A: Acceptor>(
+    address: SocketAddr,
+    state: Arc<ServerState>,
+    acceptor: A,
+) -> Result<(), ServerError> {
+    println!("Starting WebSocket server at {}", address);
     // await for a new connection over TCP
     // for each connection spawn a new task
     // the task will await for messages from the client
 
-    let listener = TcpListener::bind(ADDRESS).await?;
+    let listener = TcpListener::bind(address).await?;
+    serve_with(listener, state, acceptor).await
+}
+
+/// Serves the clients connecting to an already bound listener
+/// (e.g. on an ephemeral port, see `launch_ws_server` to bind one)
+/// The subscribers of the lobby are pushed the states from here on
+pub async fn serve(listener: TcpListener, state: Arc<ServerState>) -> Result<(), ServerError> {
+    serve_with(listener, state, PlainTcp).await
+}
+
+/// Same as `serve` with the connections going through the acceptor first (e.g. TLS)
+pub async fn serve_with<A: Acceptor>(
+    listener: TcpListener,
+    state: Arc<ServerState>,
+    acceptor: A,
+) -> Result<(), ServerError> {
+    tokio::spawn(broadcast_states(Arc::clone(&state), state.lobby()));
+    let acceptor = Arc::new(acceptor);
     while let Ok((stream, socket)) = listener.accept().await {
         println!("Accepted connection from {:?}", socket);
-        tokio::spawn(handle_connection(stream, Arc::clone(&state)));
+        let acceptor = Arc::clone(&acceptor);
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            match acceptor.accept(stream).await {
+                Ok(stream) => handle_connection(stream, socket, state).await,
+                Err(e) => {
+                    eprintln!("Failed to accept the connection from {socket}: {e}");
+                    Ok(())
+                }
+            }
+        });
     }
     Ok(())
 }
 
-async fn handle_connection(tcp_stream: TcpStream, state: Arc<ServerState>) -> Result<(), Error> {
-    let connection = accept_async(tcp_stream)
-        .await
-        .map_err(|e| Error::new(std::io::ErrorKind::Other, e))?;
+/// Serves the client until it disconnects
+// The handshake callback's error type is tungstenite's
+#[allow(clippy::result_large_err)]
+async fn handle_connection(
+    stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    socket: SocketAddr,
+    state: Arc<ServerState>,
+) -> Result<(), ServerError> {
+    let supported: Vec<_> = Subprotocol::ALL
+        .into_iter()
+        .filter(|&subprotocol| {
+            subprotocol != Subprotocol::BincodeZstd || state.compression.dictionary.is_some()
+        })
+        .collect();
+    let mut subprotocol = None;
+    let connection = accept_hdr_async(stream, |request: &Request, mut response: Response| {
+        // Counted once the handshake is done, a burst of connections can overshoot the limit
+        if let Some(max_clients) = state.max_clients {
+            if state.connections.load(Ordering::Relaxed) >= max_clients {
+                let mut refusal =
+                    ErrorResponse::new(Some(format!("The server is full ({max_clients} clients)")));
+                *refusal.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                return Err(refusal);
+            }
+        }
+        subprotocol = request
+            .headers()
+            .get(SUBPROTOCOL_HEADER)
+            .and_then(|offered| offered.to_str().ok())
+            .and_then(|offered| Subprotocol::negotiate_among(offered, &supported));
+        if let Some(subprotocol) = subprotocol {
+            response.headers_mut().insert(
+                SUBPROTOCOL_HEADER,
+                HeaderValue::from_static(subprotocol.name()),
+            );
+        }
+        Ok(response)
+    })
+    .await?;
 
     let (mut to_client, mut from_client) = connection.split();
     let (tx, mut rx) = unbounded_channel();
+    let link = Arc::new(ClientLink::new());
 
-    // This task listens for incoming messages from the client
-    // and forwards them to the appropiate handler
-    tokio::spawn(async move {
-        loop {
-            let msg = from_client.next().await;
-            if let Some(Ok(msg)) = msg {
-                handle_msg(msg, Arc::clone(&state), tx.clone()).await;
-            }
-        }
-    });
+    // The dictionary goes first, the client needs it to decode any other frame
+    if let (Some(Subprotocol::BincodeZstd), Some(dictionary)) =
+        (subprotocol, &state.compression.dictionary)
+    {
+        let _ = tx.send(Message::binary(dictionary.to_frame()));
+    }
 
     // This task replies to the client with the messages
-    tokio::spawn(async move {
+    // and adapts the quality of the streamed states to the client's pace
+    let writer_link = Arc::clone(&link);
+    let metrics = Arc::clone(&state.metrics);
+    let mut writer = tokio::spawn(async move {
+        let mut monitor = LinkMonitor::new(Arc::clone(&writer_link));
         while let Some(msg) = rx.recv().await {
-            let _ = to_client.send(msg).await;
+            let bytes = msg.len();
+            if let Err(e) = to_client.send(msg).await {
+                eprintln!("Failed to send to the client {socket}: {e}");
+                return;
+            }
+            metrics.record_sent(bytes);
+            if let Some(quality) = monitor.on_sent(bytes, rx.len()) {
+                println!(
+                    "Streaming at {quality:?} quality to a client receiving {} bytes/s",
+                    writer_link.bytes_per_second()
+                );
+            }
         }
     });
 
-    Ok(())
-}
+    let connected = state.connections.fetch_add(1, Ordering::Relaxed) + 1;
+    println!("Client {socket} connected ({connected} connected)");
+    let mut room = state
+        .join(RoomId::LOBBY)
+        .expect("the lobby is never closed");
+    let mut role = state.anonymous_role();
 
-async fn handle_msg(msg: Message, state: Arc<ServerState>, tx: UnboundedSender<Message>) {
-    match msg {
-        Message::Binary(data) => match deserialize_client_msg(&data) {
-            Some(msg) => {
-                handle_client_to_server_messages(msg, state, tx).await;
-            }
-            None => {
-                match tx.send(Message::Text(
-                    format!("Failed to parse message: {:?} ", data).into(),
-                )) {
-                    Ok(_) => {}
-                    Err(_) => eprintln!("Failed to send invalid message response"),
+    // Listens for incoming messages from the client and forwards them to the appropiate handler
+    // Ends when the client closes the connection or stops receiving
+    loop {
+        let msg = tokio::select! {
+            msg = from_client.next() => msg,
+            _ = &mut writer => break,
+        };
+        match msg {
+            Some(Ok(msg)) => {
+                state.metrics.record_received();
+                handle_msg(
+                    msg,
+                    subprotocol,
+                    Arc::clone(&state),
+                    &mut room,
+                    tx.clone(),
+                    Arc::clone(&link),
+                    &mut role,
+                )
+                .await;
+                if role.is_none() {
+                    println!("Closing the connection of the unauthenticated client {socket}");
+                    let _ = tx.send(Message::Close(None));
+                    break;
                 }
             }
-        },
+            Some(Err(e)) => {
+                eprintln!("Failed to read from the client {socket}: {e}");
+                break;
+            }
+            None => break,
+        }
+    }
+
+    state.leave(&room, &link);
+    if role.is_none() {
+        // The writer stops once it sent the error and the close frame
+        drop(tx);
+        let _ = tokio::time::timeout(CLOSE_TIMEOUT, &mut writer).await;
+    }
+    // Drops the queue of the client, so that the tasks still serving it (e.g. a history
+    // playback) stop at their next message
+    writer.abort();
+    let connected = state.connections.fetch_sub(1, Ordering::Relaxed) - 1;
+    println!("Client {socket} disconnected ({connected} connected)");
+    Ok(())
+}
+
+/// Without a negotiated subprotocol the replies are sent in the encoding of the request
+async fn handle_msg(
+    msg: Message,
+    subprotocol: Option<Subprotocol>,
+    state: Arc<ServerState>,
+    room: &mut Arc<RoomState>,
+    tx: UnboundedSender<Message>,
+    link: Arc<ClientLink>,
+    role: &mut Option<Role>,
+) {
+    let decoded = match msg {
+        Message::Binary(data) => decode_any(&data).map(|(msg, encoding)| {
+            let format = subprotocol.unwrap_or(match encoding {
+                Encoding::Bincode => Subprotocol::BincodeGzip,
+                Encoding::Protobuf => Subprotocol::Protobuf,
+            });
+            (msg, format)
+        }),
+        Message::Text(data) if subprotocol == Some(Subprotocol::Json) => {
+            decode_json(&data).map(|msg| (msg, Subprotocol::Json))
+        }
         _ => {
             eprintln!("Received invalid message: {:?}", msg);
             match tx.send(Message::Text(
@@ -77,6 +247,28 @@ async fn handle_msg(msg: Message, state: Arc<ServerState>, tx: UnboundedSender<M
                 Ok(_) => {}
                 Err(_) => println!("Failed to send invalid message response"),
             }
+            return;
+        }
+    };
+    match decoded {
+        Ok((msg, format)) => {
+            if let Err(e) =
+                handle_client_to_server_messages(msg, format, state, room, tx.clone(), link, role)
+                    .await
+            {
+                eprintln!("Failed to handle client message: {e}");
+                let _ = tx.send(Message::Text(
+                    format!("Failed to handle message: {e}").into(),
+                ));
+            }
+        }
+        Err(e) => {
+            match tx.send(Message::Text(
+                format!("Failed to parse message: {} ", e).into(),
+            )) {
+                Ok(_) => {}
+                Err(_) => eprintln!("Failed to send invalid message response"),
+            }
         }
     }
 }
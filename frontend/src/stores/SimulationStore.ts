@@ -3,6 +3,7 @@ import { ClientToServerMessage, ServerToClientMessage, serializeClientMsg, deser
 
 // eslint-disable-next-line
 const wasmBidings = await wasm.default(); // Initialize memory
+wasm.init(); // Panic hook and console logging
 
 export abstract class Simulation {
     abstract step(): void;
@@ -36,7 +37,8 @@ class WasmSimulation implements Simulation {
     }
 
     getBody(idx: number) {
-        return this.simulation.getBody(idx);
+        // No body is ever removed here, so the ids match the indices
+        return this.simulation.getBody(idx)!;
     }
 
     addBody(body: wasm.Body) {
@@ -82,8 +84,11 @@ class SocketSimulation implements Simulation {
             }
         };
         this.ws.onmessage = (message) => {
-            const msg = deserializeServerMsg(new Uint8Array(message.data));
-            if (msg === undefined) {
+            let msg: ServerToClientMessage;
+            try {
+                msg = deserializeServerMsg(new Uint8Array(message.data));
+            } catch (e) {
+                console.error(e);
                 return;
             }
             this.handleServerMessage(msg);
@@ -94,8 +99,11 @@ class SocketSimulation implements Simulation {
     }
 
     private send(msg: ClientToServerMessage) {
-        const serialized = serializeClientMsg(msg);
-        if (serialized === undefined) {
+        let serialized: Uint8Array;
+        try {
+            serialized = serializeClientMsg(msg);
+        } catch (e) {
+            console.error(e);
             return;
         }
         if (this.ws.readyState === WebSocket.OPEN) {
@@ -144,11 +152,17 @@ class SocketSimulation implements Simulation {
     }
 
     setSolverParameters(params: wasm.SolverParameters) {
-        console.error("Not implemented");
+        const msg: ClientToServerMessage = {
+            setSolverParameters: params,
+        };
+        this.send(msg);
     }
 
     setPhysicsParameters(params: wasm.PhyiscsParameters): void {
-        console.error("Not implemented");
+        const msg: ClientToServerMessage = {
+            setPhysicsParameters: params,
+        };
+        this.send(msg);
     }
 
     getKineticEnergy(): number {
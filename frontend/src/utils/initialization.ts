@@ -57,6 +57,7 @@ export function randomDiskOfPlanets(simulation: Simulation, xCenter: number, yCe
     };
     const physicsParams: wasm.PhyiscsParameters = {
         gravityConstant: 10,
+        softening: 0,
     };
 
     simulation.setSolverParameters(solverParams);